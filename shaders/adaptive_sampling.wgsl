@@ -0,0 +1,127 @@
+// works out before every frame which tiles of the image still need samples. A pixel has
+// converged once the standard error of its mean luminance relative to that mean is below the
+// noise threshold; a tile has converged once all of its pixels have.
+// The tiles that haven't are flagged for the wavefront kernels and listed for the megakernel,
+// which is dispatched indirectly over them
+
+const TILE_SIZE = 16u;
+// the largest number of workgroups a dispatch can have along one dimension
+const MAX_DISPATCH_WORKGROUPS = 65535u;
+// the error of a pixel with too few samples to tell
+const UNKNOWN_ERROR = 1e30f;
+
+struct FrameBuffer {
+    width: u32,
+    height: u32,
+    frame: u32,
+    accumulated_samples: u32
+}
+
+struct SamplingParametersBuffer {
+    samples_per_frame: u32,
+    samples_per_pixel: u32,
+    number_of_bounces: u32,
+    clear_image_buffer: u32,
+    light_sampling: u32,
+    sampler_type: u32,
+    adaptive_sampling: u32,
+    noise_threshold: f32,
+    min_adaptive_samples: u32,
+    aov_output: u32,
+}
+
+struct PixelStats {
+    sample_count: u32,
+    luminance_squared: f32,
+    sample_luminance: f32,
+}
+
+struct ActiveTiles {
+    count: atomic<u32>,
+    tiles: array<u32>,
+}
+
+struct DispatchArgs {
+    x: u32,
+    y: u32,
+    z: u32,
+}
+
+@group(0) @binding(0) var<storage, read> image_buffer: array<array<f32, 3>>;
+@group(0) @binding(1) var<uniform> frame_buffer: FrameBuffer;
+@group(0) @binding(2) var<storage, read_write> pixel_stats: array<PixelStats>;
+@group(0) @binding(3) var<uniform> sampling_parameters: SamplingParametersBuffer;
+@group(0) @binding(4) var<storage, read_write> tile_flags: array<u32>;
+@group(0) @binding(5) var<storage, read_write> active_tiles: ActiveTiles;
+@group(0) @binding(6) var<storage, read_write> tile_dispatch: DispatchArgs;
+
+fn pixelError(idx: u32) -> f32 {
+    // the wavefront kernels add the luminance of a sample up over several kernels, so the
+    // last sample of a pixel only gets squared here
+    var stats = pixel_stats[idx];
+    stats.luminance_squared += stats.sample_luminance * stats.sample_luminance;
+    stats.sample_luminance = 0.0;
+    pixel_stats[idx] = stats;
+
+    let n = f32(stats.sample_count);
+    if stats.sample_count < max(sampling_parameters.min_adaptive_samples, 2u) {
+        return UNKNOWN_ERROR;
+    }
+
+    let color = vec3(image_buffer[idx][0], image_buffer[idx][1], image_buffer[idx][2]);
+    let mean = dot(color, vec3(0.2126, 0.7152, 0.0722)) / n;
+    let variance = max(stats.luminance_squared / n - mean * mean, 0.0) * n / (n - 1.0);
+    let standard_error = sqrt(variance / n);
+    // exposure and tone mapping change what is on screen without starting the image over, so
+    // the error is taken relative to the mean rather than through the display transform. The
+    // negative lobes of the Mitchell and Lanczos filters can make the mean of a pixel next to
+    // a bright edge negative, so it is the size of the mean that counts
+    return standard_error / max(abs(mean), 1e-3);
+}
+
+// flags the tile for the wavefront kernels and lists it for the megakernel
+fn activateTile(tile: u32) {
+    tile_flags[tile] = 1u;
+    active_tiles.tiles[atomicAdd(&active_tiles.count, 1u)] = tile;
+}
+
+@compute @workgroup_size(64)
+fn updateTiles(@builtin(global_invocation_id) id: vec3u) {
+    let tile_columns = (frame_buffer.width + TILE_SIZE - 1u) / TILE_SIZE;
+    let tile_rows = (frame_buffer.height + TILE_SIZE - 1u) / TILE_SIZE;
+    let tile = id.x;
+    if tile >= tile_columns * tile_rows {
+        return;
+    }
+
+    // without adaptive sampling every tile stays active and no pixel needs looking at
+    if sampling_parameters.adaptive_sampling == 0u {
+        activateTile(tile);
+        return;
+    }
+
+    // one pixel that hasn't converged keeps the whole tile going, so the rest can be skipped
+    let origin = vec2(tile % tile_columns, tile / tile_columns) * TILE_SIZE;
+    let end = min(origin + TILE_SIZE, vec2(frame_buffer.width, frame_buffer.height));
+    for (var y = origin.y; y < end.y; y++) {
+        for (var x = origin.x; x < end.x; x++) {
+            if pixelError(x + y * frame_buffer.width) > sampling_parameters.noise_threshold {
+                activateTile(tile);
+                return;
+            }
+        }
+    }
+
+    tile_flags[tile] = 0u;
+}
+
+// the megakernel runs (TILE_SIZE / 4)² workgroups per tile along x, and the tiles are spread
+// over y and z since there can be more of them than fit along one dimension
+@compute @workgroup_size(1)
+fn writeTileDispatch() {
+    let count = atomicLoad(&active_tiles.count);
+    let tile_workgroups = (TILE_SIZE / 4u) * (TILE_SIZE / 4u);
+    let rows = min(count, MAX_DISPATCH_WORKGROUPS);
+    let slices = (count + MAX_DISPATCH_WORKGROUPS - 1u) / MAX_DISPATCH_WORKGROUPS;
+    tile_dispatch = DispatchArgs(tile_workgroups, rows, slices);
+}
@@ -0,0 +1,499 @@
+// the BSDFs of the material types, appended to path_tracing_common.wgsl. Everything is
+// worked out in the shading frame, where the normal is +z, the tangent +x and wo (pointing
+// away from the surface, back along the incoming ray) is on the positive side.
+// the conductor and the dielectric are GGX microfacet models with anisotropic roughness,
+// height-correlated Smith masking-shadowing and visible normal sampling. Roughness is
+// perceptual, alpha = roughness^2 stretched along the tangent by the anisotropy
+
+// the values of MaterialType on the Rust side
+const MATERIAL_DIFFUSE = 0u;
+const MATERIAL_CONDUCTOR = 1u;
+const MATERIAL_DIELECTRIC = 2u;
+const MATERIAL_PRINCIPLED = 3u;
+
+// below this alpha (in both directions) a surface is treated as perfectly smooth, i.e. as a
+// delta distribution
+const MIN_ALPHA = 1e-3f;
+// the principled lobes never go below this roughness, so that they stay out of the delta
+// case and can always be evaluated and mixed with each other
+const MIN_PRINCIPLED_ROUGHNESS = 0.04f;
+
+struct ScatterRecord {
+    ray: Ray,
+    // bsdf * cos / pdf; zero when the path should end
+    attenuation: vec3f,
+    // the pdf of the direction and whether it came from a delta lobe, which light sampling
+    // can't reach
+    pdf: f32,
+    is_delta: bool,
+}
+
+// a sampled direction in the shading frame with its weight (bsdf * cos / pdf) and pdf. The
+// pdf of a delta lobe is only the probability of picking that lobe, so is_delta has to be
+// checked before using it for anything else
+struct BsdfSample {
+    wi: vec3f,
+    weight: vec3f,
+    pdf: f32,
+    is_delta: bool,
+}
+
+// bsdf * cos for a pair of directions and the pdf sampleBsdf would have picked wi with
+struct BsdfEval {
+    value: vec3f,
+    pdf: f32,
+}
+
+const NO_SAMPLE = BsdfSample(vec3(0.0), vec3(0.0), 0.0, false);
+const NO_EVAL = BsdfEval(vec3(0.0), 0.0);
+
+// samples the hit's BSDF and turns the result into the next ray of the path
+fn scatter(ray: Ray, hit: HitRecord, state: ptr<function, Sampler>) -> ScatterRecord {
+    let onb = shadingFrame(hit);
+    let wo = -ray.direction * onb;
+    let sample = sampleBsdf(hit, wo, state);
+    if sample.pdf <= 0.0 {
+        return ScatterRecord(ray, vec3(0.0), 0.0, false);
+    }
+
+    // transmitted rays leave from the other side of the surface
+    let direction = normalize(onb * sample.wi);
+    let offset = select(-EPSILON, EPSILON, sample.wi.z > 0.0) * hit.normal;
+    return ScatterRecord(Ray(hit.position + offset, direction), sample.weight, sample.pdf, sample.is_delta);
+}
+
+// a perfectly smooth conductor or dielectric only scatters into single directions, so there
+// is no point in sampling lights for it
+fn isDeltaBsdf(material: Material) -> bool {
+    let has_delta_type = material.material_type == MATERIAL_CONDUCTOR || material.material_type == MATERIAL_DIELECTRIC;
+    return has_delta_type && isSmooth(ggxAlpha(material));
+}
+
+fn sampleBsdf(hit: HitRecord, wo: vec3f, state: ptr<function, Sampler>) -> BsdfSample {
+    // interpolated normals can face away from the incoming ray
+    if wo.z <= 0.0 {
+        return NO_SAMPLE;
+    }
+
+    let material = hit.material;
+    switch material.material_type {
+        case MATERIAL_CONDUCTOR: {
+            return sampleConductor(material, wo, state);
+        }
+        case MATERIAL_DIELECTRIC: {
+            return sampleDielectric(material, relativeIor(hit), wo, state);
+        }
+        case MATERIAL_PRINCIPLED: {
+            return samplePrincipled(material, relativeIor(hit), wo, state);
+        }
+        default: {
+            return sampleDiffuse(material, state);
+        }
+    }
+}
+
+// wo and wi in the shading frame; delta lobes can't be evaluated and give zero
+fn evalBsdf(hit: HitRecord, wo: vec3f, wi: vec3f) -> BsdfEval {
+    if wo.z <= 0.0 {
+        return NO_EVAL;
+    }
+
+    let material = hit.material;
+    switch material.material_type {
+        case MATERIAL_CONDUCTOR: {
+            return evalConductor(material, wo, wi);
+        }
+        case MATERIAL_DIELECTRIC: {
+            return evalDielectric(material, relativeIor(hit), wo, wi);
+        }
+        case MATERIAL_PRINCIPLED: {
+            let lobes = principledLobes(material, wo);
+            return evalPrincipled(material, lobes, relativeIor(hit), wo, wi);
+        }
+        default: {
+            return evalDiffuse(material, wi);
+        }
+    }
+}
+
+// the part of what the BSDF scatters from the ray into direction that the diffuse lobe (with
+// the sheen) is responsible for, per channel; the AOVs split the light into diffuse and
+// specular by it. Delta lobes can't be evaluated, so what they scatter counts as specular
+fn diffuseFraction(ray: Ray, hit: HitRecord, direction: vec3f) -> vec3f {
+    let onb = shadingFrame(hit);
+    let wo = -ray.direction * onb;
+    let wi = direction * onb;
+    let material = hit.material;
+    switch material.material_type {
+        case MATERIAL_DIFFUSE: {
+            return vec3(1.0);
+        }
+        case MATERIAL_PRINCIPLED: {
+            if wo.z <= 0.0 {
+                return vec3(0.0);
+            }
+            let lobes = principledLobes(material, wo);
+            let total = evalPrincipled(material, lobes, relativeIor(hit), wo, wi).value;
+            let diffuse = evalPrincipledDiffuse(material, lobes, wo, wi).value;
+            return select(vec3(0.0), diffuse / total, total > vec3(0.0));
+        }
+        default: {
+            return vec3(0.0);
+        }
+    }
+}
+
+// the tangent made perpendicular to the normal, or any tangent if the surface has none
+fn shadingFrame(hit: HitRecord) -> mat3x3f {
+    let tangent = hit.tangent - hit.normal * dot(hit.normal, hit.tangent);
+    if dot(tangent, tangent) < 1e-12f {
+        return onbFromNormal(hit.normal);
+    }
+    let t = normalize(tangent);
+    return mat3x3f(t, cross(hit.normal, t), hit.normal);
+}
+
+// the ior on the far side of the surface over the one on the side the ray came from
+fn relativeIor(hit: HitRecord) -> f32 {
+    return select(1.0 / hit.material.ior, hit.material.ior, hit.front_face);
+}
+
+fn sampleDiffuse(material: Material, state: ptr<function, Sampler>) -> BsdfSample {
+    // with cosine weighted sampling the cos/pdf terms cancel
+    let wi = rngNextInCosineWeightedHemisphere(state);
+    return BsdfSample(wi, material.base_color, wi.z * FRAC_1_PI, false);
+}
+
+fn evalDiffuse(material: Material, wi: vec3f) -> BsdfEval {
+    if wi.z <= 0.0 {
+        return NO_EVAL;
+    }
+    return BsdfEval(material.base_color * FRAC_1_PI * wi.z, wi.z * FRAC_1_PI);
+}
+
+// a metal, using Schlick's approximation with the base color as the reflectance at normal
+// incidence
+fn sampleConductor(material: Material, wo: vec3f, state: ptr<function, Sampler>) -> BsdfSample {
+    let alpha = ggxAlpha(material);
+    if isSmooth(alpha) {
+        let wi = vec3(-wo.x, -wo.y, wo.z);
+        return BsdfSample(wi, fresnelSchlick(material.base_color, wo.z), 1.0, true);
+    }
+
+    let m = sampleGgxVisibleNormal(wo, alpha, state);
+    let wo_dot_m = dot(wo, m);
+    let wi = reflect(-wo, m);
+    if wi.z <= 0.0 {
+        return NO_SAMPLE;
+    }
+
+    // with visible normal sampling everything but F * G2 / G1 cancels against the pdf
+    let g1 = smithG1(wo, alpha);
+    let weight = fresnelSchlick(material.base_color, wo_dot_m) * smithG2(wo, wi, alpha) / g1;
+    let pdf = g1 * ggxD(m, alpha) / (4.0 * wo.z);
+    return BsdfSample(wi, weight, pdf, false);
+}
+
+fn evalConductor(material: Material, wo: vec3f, wi: vec3f) -> BsdfEval {
+    let alpha = ggxAlpha(material);
+    if isSmooth(alpha) || wi.z <= 0.0 {
+        return NO_EVAL;
+    }
+
+    let m = normalize(wo + wi);
+    let wo_dot_m = dot(wo, m);
+    let d = ggxD(m, alpha);
+    let value = fresnelSchlick(material.base_color, wo_dot_m) * d * smithG2(wo, wi, alpha) / (4.0 * wo.z);
+    return BsdfEval(value, smithG1(wo, alpha) * d / (4.0 * wo.z));
+}
+
+// glass and the like. Reflection and refraction are picked in proportion to the Fresnel
+// reflectance, so both lobes end up with the same weight; past the critical angle the
+// reflectance is one and everything gets reflected. The transmitted radiance is scaled by
+// 1 / eta^2 for the change in solid angle, and tinted by the base color
+fn sampleDielectric(material: Material, eta: f32, wo: vec3f, state: ptr<function, Sampler>) -> BsdfSample {
+    let alpha = ggxAlpha(material);
+    if isSmooth(alpha) {
+        let f = fresnelDielectric(wo.z, eta);
+        if rngNextFloat(state) < f {
+            return BsdfSample(vec3(-wo.x, -wo.y, wo.z), vec3(1.0), f, true);
+        }
+        let wi = refractDirection(wo, vec3(0.0, 0.0, 1.0), eta);
+        return BsdfSample(wi, material.base_color / (eta * eta), 1.0 - f, true);
+    }
+
+    let m = sampleGgxVisibleNormal(wo, alpha, state);
+    let wo_dot_m = dot(wo, m);
+    let f = fresnelDielectric(wo_dot_m, eta);
+    // the pdf of the visible normal
+    let g1 = smithG1(wo, alpha);
+    let pdf_m = g1 * wo_dot_m * ggxD(m, alpha) / wo.z;
+
+    if rngNextFloat(state) < f {
+        let wi = reflect(-wo, m);
+        if wi.z <= 0.0 {
+            return NO_SAMPLE;
+        }
+        let weight = vec3(smithG2(wo, wi, alpha) / g1);
+        return BsdfSample(wi, weight, f * pdf_m / (4.0 * wo_dot_m), false);
+    }
+
+    let wi = refractDirection(wo, m, eta);
+    let wi_dot_m = dot(wi, m);
+    if wi.z >= 0.0 {
+        return NO_SAMPLE;
+    }
+    let weight = material.base_color * smithG2(wo, wi, alpha) / (g1 * eta * eta);
+    let denominator = wo_dot_m + eta * wi_dot_m;
+    let pdf = (1.0 - f) * pdf_m * eta * eta * abs(wi_dot_m) / (denominator * denominator);
+    return BsdfSample(wi, weight, pdf, false);
+}
+
+fn evalDielectric(material: Material, eta: f32, wo: vec3f, wi: vec3f) -> BsdfEval {
+    let alpha = ggxAlpha(material);
+    if isSmooth(alpha) || wi.z == 0.0 {
+        return NO_EVAL;
+    }
+
+    // the generalized half vector covers both reflection and refraction
+    let reflected = wi.z > 0.0;
+    var m = select(normalize(wo + eta * wi), normalize(wo + wi), reflected);
+    m = select(-m, m, m.z > 0.0);
+    let wo_dot_m = dot(wo, m);
+    let wi_dot_m = dot(wi, m);
+    // microfacets seen from behind don't contribute
+    if wo_dot_m <= 0.0 || (reflected && wi_dot_m <= 0.0) || (!reflected && wi_dot_m >= 0.0) {
+        return NO_EVAL;
+    }
+
+    let d = ggxD(m, alpha);
+    let g = smithG2(wo, wi, alpha);
+    let f = fresnelDielectric(wo_dot_m, eta);
+    let pdf_m = smithG1(wo, alpha) * wo_dot_m * d / wo.z;
+    if reflected {
+        return BsdfEval(vec3(f * d * g / (4.0 * wo.z)), f * pdf_m / (4.0 * wo_dot_m));
+    }
+
+    let denominator = wo_dot_m + eta * wi_dot_m;
+    let jacobian = abs(wi_dot_m) / (denominator * denominator);
+    let value = material.base_color * (1.0 - f) * d * g * wo_dot_m * jacobian / wo.z;
+    return BsdfEval(value, (1.0 - f) * pdf_m * eta * eta * jacobian);
+}
+
+// alpha along the tangent and the bitangent, with the anisotropy mapping of the Disney BRDF
+fn ggxAlpha(material: Material) -> vec2f {
+    let alpha = material.roughness * material.roughness;
+    let aspect = sqrt(1.0 - 0.9 * saturate(material.anisotropy));
+    return vec2(alpha / aspect, alpha * aspect);
+}
+
+fn isSmooth(alpha: vec2f) -> bool {
+    return all(alpha < vec2(MIN_ALPHA));
+}
+
+// the lobes of the principled BSDF, in the order diffuse (with sheen), specular reflection,
+// transmission and clearcoat: their weights and the probabilities of sampling them, and the
+// materials the specular, transmission and clearcoat lobes are evaluated with
+struct PrincipledLobes {
+    weights: vec4f,
+    probabilities: vec4f,
+    sheen_color: vec3f,
+    specular: Material,
+    transmission: Material,
+    clearcoat: Material,
+}
+
+// a Disney style principled BSDF made from the other BSDFs. The specular lobe is a conductor
+// whose color goes from the dielectric reflectance to the base color with metallic, the
+// transmission lobe the rough dielectric, and the clearcoat a colorless conductor with the
+// reflectance of an ior of 1.5 on top of the rest. One lobe is sampled, picked by how much
+// each one is likely to reflect, and the direction weighted by all of them
+fn samplePrincipled(material: Material, eta: f32, wo: vec3f, state: ptr<function, Sampler>) -> BsdfSample {
+    let lobes = principledLobes(material, wo);
+    let p = lobes.probabilities;
+
+    let u = rngNextFloat(state);
+    var sample: BsdfSample;
+    if u < p.x {
+        sample = sampleDiffuse(material, state);
+    } else if u < p.x + p.y {
+        sample = sampleConductor(lobes.specular, wo, state);
+    } else if u < p.x + p.y + p.z {
+        sample = sampleDielectric(lobes.transmission, eta, wo, state);
+    } else {
+        sample = sampleConductor(lobes.clearcoat, wo, state);
+    }
+    if sample.pdf <= 0.0 {
+        return NO_SAMPLE;
+    }
+
+    let eval = evalPrincipled(material, lobes, eta, wo, sample.wi);
+    if eval.pdf <= 0.0 {
+        return NO_SAMPLE;
+    }
+    return BsdfSample(sample.wi, eval.value / eval.pdf, eval.pdf, false);
+}
+
+fn evalPrincipled(material: Material, lobes: PrincipledLobes, eta: f32, wo: vec3f, wi: vec3f) -> BsdfEval {
+    var result = evalPrincipledDiffuse(material, lobes, wo, wi);
+
+    let specular = evalConductor(lobes.specular, wo, wi);
+    result.value += lobes.weights.y * specular.value;
+    result.pdf += lobes.probabilities.y * specular.pdf;
+
+    let transmission = evalDielectric(lobes.transmission, eta, wo, wi);
+    result.value += lobes.weights.z * transmission.value;
+    result.pdf += lobes.probabilities.z * transmission.pdf;
+
+    let clearcoat = evalConductor(lobes.clearcoat, wo, wi);
+    result.value += lobes.weights.w * clearcoat.value;
+    result.pdf += lobes.probabilities.w * clearcoat.pdf;
+
+    return result;
+}
+
+// the diffuse lobe with the sheen, weighted like in evalPrincipled
+fn evalPrincipledDiffuse(material: Material, lobes: PrincipledLobes, wo: vec3f, wi: vec3f) -> BsdfEval {
+    if wi.z <= 0.0 {
+        return NO_EVAL;
+    }
+    let diffuse = evalDiffuse(material, wi);
+    // the sheen grows towards grazing angles between wi and the half vector
+    let cos_d = dot(wi, normalize(wo + wi));
+    let sheen = lobes.sheen_color * pow(1.0 - saturate(cos_d), 5.0) * wi.z;
+    return BsdfEval(lobes.weights.x * (diffuse.value + sheen), lobes.probabilities.x * diffuse.pdf);
+}
+
+fn principledLobes(material: Material, wo: vec3f) -> PrincipledLobes {
+    var lobes: PrincipledLobes;
+    let roughness = max(material.roughness, MIN_PRINCIPLED_ROUGHNESS);
+    let tint = tintColor(material.base_color);
+
+    // the reflectance of the dielectric at normal incidence, given by the ior and scaled by
+    // specular, where 0.5 keeps it as it is
+    let r = (material.ior - 1.0) / (material.ior + 1.0);
+    let specular_color = mix(vec3(1.0), tint, material.specular_tint);
+    let dielectric_f0 = min(vec3(1.0), r * r * 2.0 * material.specular * specular_color);
+
+    lobes.specular = material;
+    lobes.specular.base_color = mix(dielectric_f0, material.base_color, material.metallic);
+    lobes.specular.roughness = roughness;
+
+    lobes.transmission = material;
+    lobes.transmission.roughness = roughness;
+
+    lobes.clearcoat = material;
+    lobes.clearcoat.base_color = vec3(0.04);
+    lobes.clearcoat.roughness = max(material.clearcoat_roughness, MIN_PRINCIPLED_ROUGHNESS);
+    lobes.clearcoat.anisotropy = 0.0;
+
+    lobes.sheen_color = material.sheen * mix(vec3(1.0), tint, material.sheen_tint);
+
+    // the clearcoat takes what it reflects away from everything underneath it
+    let clearcoat_reflectance = material.clearcoat * fresnelSchlick(vec3(0.04), wo.z).x;
+    let base = 1.0 - clearcoat_reflectance;
+    let dielectric = 1.0 - material.metallic;
+    lobes.weights = vec4(
+        base * dielectric * (1.0 - material.transmission),
+        base * (1.0 - dielectric * material.transmission),
+        base * dielectric * material.transmission,
+        material.clearcoat,
+    );
+
+    // rough estimates of how much each lobe reflects towards wo
+    let albedos = vec4(
+        luminance(material.base_color) + material.sheen,
+        luminance(fresnelSchlick(lobes.specular.base_color, wo.z)),
+        1.0,
+        fresnelSchlick(vec3(0.04), wo.z).x,
+    );
+    let estimates = lobes.weights * albedos;
+    let total = dot(estimates, vec4(1.0));
+    lobes.probabilities = select(vec4(0.0), estimates / total, total > 0.0);
+    return lobes;
+}
+
+fn luminance(color: vec3f) -> f32 {
+    return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// the hue and saturation of a color at a luminance of one
+fn tintColor(color: vec3f) -> vec3f {
+    let l = luminance(color);
+    return select(vec3(1.0), color / l, l > 0.0);
+}
+
+// anisotropic GGX (Trowbridge-Reitz) normal distribution
+fn ggxD(m: vec3f, alpha: vec2f) -> f32 {
+    let stretched = vec3(m.x / alpha.x, m.y / alpha.y, m.z);
+    let length2 = dot(stretched, stretched);
+    return 1.0 / (PI * alpha.x * alpha.y * length2 * length2);
+}
+
+// the Smith Lambda function of GGX, which both masking-shadowing terms are built from
+fn smithLambda(v: vec3f, alpha: vec2f) -> f32 {
+    let cos2 = max(v.z * v.z, 1e-8f);
+    let alpha2_tan2 = (alpha.x * alpha.x * v.x * v.x + alpha.y * alpha.y * v.y * v.y) / cos2;
+    return 0.5 * (sqrt(1.0 + alpha2_tan2) - 1.0);
+}
+
+fn smithG1(v: vec3f, alpha: vec2f) -> f32 {
+    return 1.0 / (1.0 + smithLambda(v, alpha));
+}
+
+// height-correlated masking-shadowing, which unlike the product of the two G1 terms doesn't
+// count microfacets that are both masked and shadowed twice
+fn smithG2(wo: vec3f, wi: vec3f, alpha: vec2f) -> f32 {
+    return 1.0 / (1.0 + smithLambda(wo, alpha) + smithLambda(wi, alpha));
+}
+
+// a microfacet normal distributed as the normals visible from wo,
+// G1(wo) * max(0, wo.m) * D(m) / wo.z (Heitz 2018, "Sampling the GGX Distribution of Visible
+// Normals"); wo.m is never negative
+fn sampleGgxVisibleNormal(wo: vec3f, alpha: vec2f, state: ptr<function, Sampler>) -> vec3f {
+    // stretch the view direction into the configuration where the distribution is a hemisphere
+    let v = normalize(vec3(alpha.x * wo.x, alpha.y * wo.y, wo.z));
+
+    let length2 = v.x * v.x + v.y * v.y;
+    let t1 = select(vec3(1.0, 0.0, 0.0), vec3(-v.y, v.x, 0.0) * inverseSqrt(length2), length2 > 0.0);
+    let t2 = cross(v, t1);
+
+    // a point on the disk, squashed onto the part of it that is visible from v
+    let u = rngNext2D(state);
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    let p1 = r * cos(phi);
+    let s = 0.5 * (1.0 + v.z);
+    let p2 = mix(sqrt(max(0.0, 1.0 - p1 * p1)), r * sin(phi), s);
+    let p3 = sqrt(max(0.0, 1.0 - p1 * p1 - p2 * p2));
+    let n = p1 * t1 + p2 * t2 + p3 * v;
+
+    // and unstretch the normal
+    return normalize(vec3(alpha.x * n.x, alpha.y * n.y, max(1e-6f, n.z)));
+}
+
+fn fresnelSchlick(f0: vec3f, cos_theta: f32) -> vec3f {
+    return f0 + (1.0 - f0) * pow(1.0 - saturate(cos_theta), 5.0);
+}
+
+// unpolarized Fresnel reflectance of a dielectric interface; eta is the ior on the far side
+// over the near one, and the reflectance is one past the critical angle
+fn fresnelDielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = max(0.0, 1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = sqrt(1.0 - sin2_t);
+    let r_s = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let r_p = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    return 0.5 * (r_s * r_s + r_p * r_p);
+}
+
+// refracts wo through the surface with normal m, wo and m on the same side. Only called when
+// fresnelDielectric is below one, so there is no total internal reflection to deal with
+fn refractDirection(wo: vec3f, m: vec3f, eta: f32) -> vec3f {
+    let cos_i = dot(wo, m);
+    let sin2_t = max(0.0, 1.0 - cos_i * cos_i) / (eta * eta);
+    let cos_t = sqrt(max(0.0, 1.0 - sin2_t));
+    return -wo / eta + (cos_i / eta - cos_t) * m;
+}
@@ -1,150 +1,189 @@
-const EPSILON = 0.001f;
-const PI = 3.1415927f;
-const FRAC_1_PI = 0.31830987f;
-const FRAC_PI_2 = 1.5707964f;
-
-struct FrameBuffer {
-    width: u32,
-    height: u32,
-    frame: u32,
-    accumulated_samples: u32
-}
-
-struct ProjectionBuffer {
-    invProj: mat4x4<f32>
-}
-
-struct ViewBuffer {
-    view: mat4x4<f32>
-}
+// appended to path_tracing_common.wgsl, bsdf.wgsl and lights.wgsl, which have the scene
+// bindings, the tracing functions, the materials and the light sampling
 
-struct SamplingParametersBuffer {
-    samples_per_frame: u32,
-    samples_per_pixel: u32,
-    number_of_bounces: u32,
-    clear_image_buffer: u32,
-}
+// the dispatch spreads the tiles over y and z, this many along y
+const MAX_DISPATCH_WORKGROUPS = 65535u;
 
-struct CameraBuffer {
-    position: vec4<f32>,
-    defocus_radius: f32,
-    focus_distance: f32,
+// the tiles adaptive sampling keeps active; adaptive_sampling.wgsl lists them and writes the
+// indirect dispatch arguments over them
+struct ActiveTiles {
+    count: u32,
+    tiles: array<u32>,
 }
 
 @group(0) @binding(0) var<storage, read_write> image_buffer: array<array<f32, 3>>;
 @group(0) @binding(1) var<uniform> frame_buffer: FrameBuffer;
-@group(1) @binding(0) var<uniform> inv_projection_matrix: ProjectionBuffer;
-@group(1) @binding(1) var<uniform> view_matrix: ViewBuffer;
-@group(1) @binding(2) var<uniform> sampling_parameters: SamplingParametersBuffer;
-@group(1) @binding(3) var<uniform> camera: CameraBuffer;
-
-@compute @workgroup_size(4,4,1)
-fn main(@builtin(global_invocation_id) id: vec3u) {
-
-    let image_size = vec2(frame_buffer.width, frame_buffer.height);
-    let screen_pos = id.xy;
-    let idx = id.x + id.y * image_size.x;
-
-    // load the stored pixel color
-    var pixel_color: vec3f = vec3f(image_buffer[idx][0], image_buffer[idx][1], image_buffer[idx][2]);
-    var rng_state:u32 = initRng(screen_pos, image_size, frame_buffer.frame);
-
-    image_buffer[idx][0] = 0.5; // pixel_color.x;
-    image_buffer[idx][1] = 0.1; //pixel_color.y;
-    image_buffer[idx][2] = 0.1; //pixel_color.z;
+@group(0) @binding(2) var<storage, read_write> pixel_stats: array<PixelStats>;
+@group(0) @binding(3) var<storage, read> active_tiles: ActiveTiles;
+@group(0) @binding(4) var<storage, read_write> pixel_features: array<PixelFeatures>;
+// only written with aov_output set, see AOV_GEOMETRIC_NORMAL for the layout
+@group(0) @binding(5) var<storage, read_write> aov_buffer: array<f32>;
+
+fn addToAovPlanes(idx: u32, plane: u32, value: vec3f) {
+    let pixel_count = frame_buffer.width * frame_buffer.height;
+    aov_buffer[plane * pixel_count + idx] += value.x;
+    aov_buffer[(plane + 1u) * pixel_count + idx] += value.y;
+    aov_buffer[(plane + 2u) * pixel_count + idx] += value.z;
 }
 
-fn rngNextInUnitHemisphere(state: ptr<function, u32>) -> vec3<f32> {
-    let r1 = rngNextFloat(state);
-    let r2 = rngNextFloat(state);
-
-    let phi = 2.0 * PI * r1;
-    let sinTheta = sqrt(1.0 - r2 * r2);
-
-    let x = cos(phi) * sinTheta;
-    let y = sin(phi) * sinTheta;
-    let z = r2;
-
-    return vec3(x, y, z);
+// the ids are those of the last sample that hit something rather than a sum
+fn addToAovs(idx: u32, aovs: SampleAovs) {
+    let pixel_count = frame_buffer.width * frame_buffer.height;
+    addToAovPlanes(idx, AOV_GEOMETRIC_NORMAL, aovs.geometric_normal);
+    if aovs.object_id != 0u {
+        aov_buffer[AOV_OBJECT_ID * pixel_count + idx] = f32(aovs.object_id);
+        aov_buffer[AOV_MATERIAL_ID * pixel_count + idx] = f32(aovs.material_id);
+    }
+    addToAovPlanes(idx, AOV_EMISSION, aovs.light.emission);
+    addToAovPlanes(idx, AOV_DIRECT_DIFFUSE, aovs.light.direct_diffuse);
+    addToAovPlanes(idx, AOV_DIRECT_SPECULAR, aovs.light.direct_specular);
+    addToAovPlanes(idx, AOV_INDIRECT_DIFFUSE, aovs.light.indirect_diffuse);
+    addToAovPlanes(idx, AOV_INDIRECT_SPECULAR, aovs.light.indirect_specular);
 }
 
-fn rngNextVec3InUnitDisk(state: ptr<function, u32>) -> vec3<f32> {
-    // r^2 is distributed as U(0, 1).
-    let r = sqrt(rngNextFloat(state));
-    let alpha = 2.0 * PI * rngNextFloat(state);
-
-    let x = r * cos(alpha);
-    let y = r * sin(alpha);
-
-    return vec3(x, y, 0.0);
-}
+// every tile gets (TILE_SIZE / 4)² workgroups along x
+@compute @workgroup_size(4,4,1)
+fn main(@builtin(workgroup_id) workgroup_id: vec3u,
+        @builtin(local_invocation_id) local_id: vec3u,
+        @builtin(local_invocation_index) local_index: u32) {
+
+    bvh_stack_offset = local_index * stackSize;
+    let tile_index = workgroup_id.y + workgroup_id.z * MAX_DISPATCH_WORKGROUPS;
+    if tile_index >= active_tiles.count {
+        return;
+    }
 
-fn rngNextVec3InUnitSphere(state: ptr<function, u32>) -> vec3<f32> {
-    // probability density is uniformly distributed over r^3
-    let r = pow(rngNextFloat(state), 0.33333f);
-    // and need to distribute theta according to arccos(U[-1,1])
-    // let theta = acos(1.0 - 2f * rngNextFloat(state));
-    let cosTheta = 1f - 2f * rngNextFloat(state);
-    let sinTheta = sqrt(1 - cosTheta * cosTheta);
-    let phi = 2.0 * PI * rngNextFloat(state);
+    let image_size = vec2(frame_buffer.width, frame_buffer.height);
+    let tile = active_tiles.tiles[tile_index];
+    let tile_columns = (image_size.x + TILE_SIZE - 1u) / TILE_SIZE;
+    let tile_workgroups = TILE_SIZE / 4u;
+    let screen_pos = vec2(tile % tile_columns, tile / tile_columns) * TILE_SIZE
+        + vec2(workgroup_id.x % tile_workgroups, workgroup_id.x / tile_workgroups) * 4u
+        + local_id.xy;
+    // tiles along the right and bottom edge stick out of the image
+    if screen_pos.x >= image_size.x || screen_pos.y >= image_size.y {
+        return;
+    }
+    let idx = screen_pos.x + screen_pos.y * image_size.x;
+
+    // adaptive sampling leaves pixels behind, so every pixel counts its own samples
+    var stats = pixel_stats[idx];
+
+    var pixel_color = vec3f(0.0);
+    var features = pixel_features[idx];
+    var aovs: SampleAovs;
+    for (var i = 0u; i < sampling_parameters.samples_per_frame; i++) {
+        var sampler_state = initSampler(screen_pos, image_size, stats.sample_count + i);
+        let filter_sample = samplePixelFilter(&sampler_state);
+        let ray = getCameraRay(screen_pos, image_size, filter_sample.offset, &sampler_state);
+        var sample_features = PixelFeatures(vec3(0.0), 0.0, vec3(0.0));
+        var sample_aovs: SampleAovs;
+        let sample_color = rayColor(ray, filter_sample.weight, &sampler_state, &sample_features, &sample_aovs);
+        pixel_color += sample_color;
+        stats.luminance_squared += luminance(sample_color) * luminance(sample_color);
+        features.albedo += sample_features.albedo;
+        features.depth += sample_features.depth;
+        features.normal += sample_features.normal;
+
+        aovs.geometric_normal += sample_aovs.geometric_normal;
+        if sample_aovs.object_id != 0u {
+            aovs.object_id = sample_aovs.object_id;
+            aovs.material_id = sample_aovs.material_id;
+        }
+        aovs.light.emission += sample_aovs.light.emission;
+        aovs.light.direct_diffuse += sample_aovs.light.direct_diffuse;
+        aovs.light.direct_specular += sample_aovs.light.direct_specular;
+        aovs.light.indirect_diffuse += sample_aovs.light.indirect_diffuse;
+        aovs.light.indirect_specular += sample_aovs.light.indirect_specular;
+    }
+    stats.sample_count += sampling_parameters.samples_per_frame;
+    pixel_stats[idx] = stats;
+    pixel_features[idx] = features;
+    if sampling_parameters.aov_output != 0u {
+        addToAovs(idx, aovs);
+    }
 
-    let x = r * sinTheta * cos(phi);
-    let y = r * sinTheta * sin(phi);
-    let z = r * cosTheta;
+    // the first frame after a reset overwrites whatever was accumulated before
+    if sampling_parameters.clear_image_buffer == 0u {
+        pixel_color += vec3f(image_buffer[idx][0], image_buffer[idx][1], image_buffer[idx][2]);
+    }
 
-    return vec3(x, y, z);
+    image_buffer[idx][0] = pixel_color.x;
+    image_buffer[idx][1] = pixel_color.y;
+    image_buffer[idx][2] = pixel_color.z;
 }
 
-fn rngNextUintInRange(state: ptr<function, u32>, min: u32, max: u32) -> u32 {
-    let next_int = rngNextInt(state);
-    return min + (next_int) % (max - min);
-}
+// weight is that of the filter sample the ray comes from; aovs only gets filled in with
+// aov_output set
+fn rayColor(primary_ray: Ray, weight: f32, state: ptr<function, Sampler>, features: ptr<function, PixelFeatures>,
+            aovs: ptr<function, SampleAovs>) -> vec3f {
+    var ray = primary_ray;
+    var throughput = vec3f(weight);
+    var radiance = vec3f(0.0);
+    // the camera ray counts as a delta bounce: nothing but the ray itself can see a light
+    var delta_bounce = true;
+    var bsdf_pdf = 0.0;
+    let write_aovs = sampling_parameters.aov_output != 0u;
+    // how much of the first scattering event was diffuse, for splitting up the light
+    var first_diffuse = vec3(0.0);
+
+    // number_of_bounces counts the scattering events, so a path has one more segment than that
+    for (var bounce = 0u; bounce <= sampling_parameters.number_of_bounces; bounce++) {
+        let intersection = traceRay(ray, MISS);
+        if intersection.t == MISS {
+            let escaped = throughput * escapedRadiance(ray.direction, delta_bounce, bsdf_pdf);
+            radiance += escaped;
+            if write_aovs {
+                addPathLight(aovs, bounce, escaped, first_diffuse);
+            }
+            break;
+        }
 
-fn rngNextFloat(state: ptr<function, u32>) -> f32 {
-    let next_int = rngNextInt(state);
-    return f32(next_int) * 2.3283064365387e-10f;  // / f32(0xffffffffu - 1f);
-}
+        let hit = surfaceInteraction(ray, intersection);
+        if bounce == 0u {
+            *features = primaryFeatures(ray, hit);
+            if write_aovs {
+                (*aovs).geometric_normal = hit.geometric_normal;
+                (*aovs).object_id = objectId(hit);
+                (*aovs).material_id = hit.material_id + 1u;
+            }
+        }
+        if any(hit.material.emission > vec3(0.0)) {
+            let emitted = throughput * hit.material.emission * emissionWeight(ray, hit, delta_bounce, bsdf_pdf);
+            radiance += emitted;
+            if write_aovs {
+                addPathLight(aovs, bounce, emitted, first_diffuse);
+            }
+        }
 
-fn initRng(pixel: vec2<u32>, resolution: vec2<u32>, frame: u32) -> u32 {
-    let seed = dot(pixel, vec2<u32>(1u, resolution.x)) ^ jenkinsHash(frame);
-    return jenkinsHash(seed);
-}
+        if bounce == sampling_parameters.number_of_bounces {
+            break;
+        }
 
-// I've altered the code I copied to implement what I believe is now a correct
-// PCG-RXS-M-XS; specifically, the state is only based on the LCG
-// rngNextInt will update the state, but then return a rng via the output function
-fn rngNextInt(state: ptr<function, u32>) -> u32 {
-    // PCG hash RXS-M-XS
-    let oldState = *state * 747796405u + 2891336453u;
-    *state = oldState;
-    let word = ((oldState >> ((oldState >> 28u) + 4u)) ^ oldState) * 277803737u;
-    return (word >> 22u) ^ word;
-}
+        // next event estimation
+        samplerStartDimension(state, bounce, SAMPLER_LIGHT_DIMENSION);
+        let connection = connectToLight(ray, hit, state);
+        if any(connection.contribution > vec3(0.0)) && !isOccluded(connection.ray, connection.t_max) {
+            radiance += throughput * connection.contribution;
+            if write_aovs {
+                let diffuse = select(first_diffuse, diffuseFraction(ray, hit, connection.ray.direction), bounce == 0u);
+                addPathLight(aovs, bounce + 1u, throughput * connection.contribution, diffuse);
+            }
+        }
 
-fn advance(state: ptr<function, u32>, advance_by: u32) {
-    var acc_mult = 1u;
-    var acc_plus = 0u;
-    var cur_mult = 747796405u;
-    var cur_plus = 2891336453u;
-    var delta = advance_by;
-    while delta > 0 {
-        if delta == 1 {
-            acc_mult *= cur_mult;
-            acc_plus = acc_plus * cur_mult + cur_plus;
+        samplerStartDimension(state, bounce, SAMPLER_BSDF_DIMENSION);
+        let scattered = scatter(ray, hit, state);
+        if write_aovs && bounce == 0u {
+            first_diffuse = diffuseFraction(ray, hit, scattered.ray.direction);
+        }
+        ray = scattered.ray;
+        throughput *= scattered.attenuation;
+        delta_bounce = scattered.is_delta;
+        bsdf_pdf = scattered.pdf;
+        if all(throughput == vec3(0.0)) {
+            break;
         }
-        cur_plus = (cur_mult + 1u) * cur_plus;
-        cur_mult *= cur_mult;
-        delta = delta >> 1;
     }
-    *state = *state * acc_mult + acc_plus;
-}
 
-fn jenkinsHash(input: u32) -> u32 {
-    var x = input;
-    x += x << 10u;
-    x ^= x >> 6u;
-    x += x << 3u;
-    x ^= x >> 11u;
-    x += x << 15u;
-    return x;
-}
\ No newline at end of file
+    return radiance;
+}
@@ -0,0 +1,314 @@
+// a spatiotemporal variance-guided filter (Schied et al., "Spatiotemporal Variance-Guided
+// Filtering: Real-Time Reconstruction for Path-Traced Global Illumination", 2017) on top of the
+// edge-avoiding à-trous wavelet filter (Dammertz et al., "Edge-Avoiding À-Trous Wavelet Transform
+// for fast Global Illumination Filtering", 2010).
+//
+// prepareDenoiser averages the features over the samples of every pixel. Whenever the image
+// starts over, reproject follows every pixel back into the view of the last denoised frame and
+// takes what was integrated there as its history, unless the surface it lands on has a
+// different depth or normal. integrate adds the history to the samples accumulated since, with
+// the albedo divided out so the filter blurs the lighting rather than the textures, and keeps
+// the first two moments of the luminance. estimateVariance turns those into the variance of
+// every pixel, or takes it from the neighborhood while a pixel has too short a history to tell.
+// Every atrous pass then takes a 5x5 B3 spline kernel with holes of step pixels between the
+// taps, weighted down across edges in the normals and the depth and by luminance differences
+// relative to the noise that is left. The last pass multiplies the albedo back in
+
+// the shortest history the variance of a pixel is trusted from
+const MIN_VARIANCE_HISTORY = 4.0;
+
+struct FrameBuffer {
+    width: u32,
+    height: u32,
+    frame: u32,
+    accumulated_samples: u32
+}
+
+struct PixelStats {
+    sample_count: u32,
+    luminance_squared: f32,
+    sample_luminance: f32,
+}
+
+struct PixelFeatures {
+    albedo: vec3f,
+    depth: f32,
+    normal: vec3f,
+}
+
+// the parameters of one pass, see GPUDenoisePass
+struct DenoisePass {
+    step: u32,
+    remodulate: u32,
+    color_phi: f32,
+    normal_phi: f32,
+    depth_phi: f32,
+}
+
+// the cameras the image is traced with now and the history was, see GPUReprojection
+struct Reprojection {
+    inv_projection: mat4x4f,
+    view: mat4x4f,
+    previous_clip_from_world: mat4x4f,
+    previous_position: vec4f,
+    previous_size: vec2u,
+    temporal: u32,
+    max_history: f32,
+}
+
+// the demodulated color of a pixel integrated over time, the number of samples in it and the
+// first two moments of their luminance
+struct TemporalSample {
+    color: vec3f,
+    length: f32,
+    moments: vec2f,
+}
+
+@group(0) @binding(0) var<storage, read> image_buffer: array<array<f32, 3>>;
+@group(0) @binding(1) var<uniform> frame_buffer: FrameBuffer;
+@group(0) @binding(2) var<storage, read> pixel_stats: array<PixelStats>;
+@group(0) @binding(3) var<storage, read> pixel_features: array<PixelFeatures>;
+// the averaged features, now and as of the last denoised frame
+@group(0) @binding(4) var<storage, read_write> guide: array<PixelFeatures>;
+@group(0) @binding(5) var<storage, read> color_in: array<vec4f>;
+// the color with its variance in w
+@group(0) @binding(6) var<storage, read_write> color_out: array<vec4f>;
+@group(0) @binding(7) var<uniform> denoise_pass: DenoisePass;
+@group(0) @binding(8) var<uniform> reprojection: Reprojection;
+@group(0) @binding(9) var<storage, read> previous_guide: array<PixelFeatures>;
+@group(0) @binding(10) var<storage, read_write> history: array<TemporalSample>;
+@group(0) @binding(11) var<storage, read_write> integrated: array<TemporalSample>;
+// where every pixel was on the screen of the history, relative to where it is now, in pixels
+@group(0) @binding(12) var<storage, read_write> motion_vectors: array<vec2f>;
+
+fn luminance(color: vec3f) -> f32 {
+    return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// the albedo the lighting is divided by; pixels without one keep their color as it is
+fn demodulationAlbedo(albedo: vec3f) -> vec3f {
+    return select(vec3(1.0), max(albedo, vec3(1e-3)), albedo > vec3(0.0));
+}
+
+// the weight of a neighbor by how far its surface is from the one of the center
+fn geometryWeight(center: PixelFeatures, features: PixelFeatures) -> f32 {
+    let normal_difference = center.normal - features.normal;
+    let normal_weight = exp(-dot(normal_difference, normal_difference) / denoise_pass.normal_phi);
+    // relative to the depth, so that distant surfaces get as much blur as near ones
+    let depth_difference = abs(center.depth - features.depth) / max(max(center.depth, features.depth), 1e-3);
+    let depth_weight = exp(-depth_difference / denoise_pass.depth_phi);
+    return normal_weight * depth_weight;
+}
+
+fn pixelIndex(pixel: vec2i) -> u32 {
+    return u32(pixel.x + pixel.y * i32(frame_buffer.width));
+}
+
+@compute @workgroup_size(8, 8)
+fn prepareDenoiser(@builtin(global_invocation_id) id: vec3u) {
+    if id.x >= frame_buffer.width || id.y >= frame_buffer.height {
+        return;
+    }
+    let idx = id.x + id.y * frame_buffer.width;
+
+    let inv_samples = 1.0 / f32(max(pixel_stats[idx].sample_count, 1u));
+    let features = pixel_features[idx];
+    // the normals of a pixel's samples only cancel out on edges, where a short normal is fine
+    guide[idx] = PixelFeatures(inv_samples * features.albedo, inv_samples * features.depth, inv_samples * features.normal);
+}
+
+// whether a pixel of the last denoised frame shows the same surface as the center
+fn consistentHistory(center: PixelFeatures, expected_depth: f32, previous: PixelFeatures) -> bool {
+    // a pixel that sees the sky can only continue one that did
+    if center.depth == 0.0 || previous.depth == 0.0 {
+        return center.depth == previous.depth;
+    }
+    let depth_difference = abs(previous.depth - expected_depth) / expected_depth;
+    return depth_difference < 0.1 && dot(center.normal, previous.normal) > 0.8;
+}
+
+@compute @workgroup_size(8, 8)
+fn reproject(@builtin(global_invocation_id) id: vec3u) {
+    if id.x >= frame_buffer.width || id.y >= frame_buffer.height {
+        return;
+    }
+    let idx = id.x + id.y * frame_buffer.width;
+    history[idx] = TemporalSample(vec3(0.0), 0.0, vec2(0.0));
+    motion_vectors[idx] = vec2(0.0);
+    if reprojection.temporal == 0u {
+        return;
+    }
+
+    // the ray through the center of the pixel, the same way getCameraRay shoots it
+    let pixel_center = vec2f(id.xy) + 0.5;
+    let uv = pixel_center / vec2f(f32(frame_buffer.width), f32(frame_buffer.height));
+    let ndc = vec2(2.0 * uv.x - 1.0, 1.0 - 2.0 * uv.y);
+    let target_point = reprojection.inv_projection * vec4(ndc, 1.0, 1.0);
+    let direction = normalize(target_point.xyz / target_point.w) * vec3(1.0, 1.0, -1.0);
+    let world_direction = normalize((reprojection.view * vec4(direction, 0.0)).xyz);
+
+    // pixels that hit something follow the point they hit, the sky follows the direction
+    let center = guide[idx];
+    let position = reprojection.view[3].xyz + center.depth * world_direction;
+    let expected_depth = distance(position, reprojection.previous_position.xyz);
+    let clip = reprojection.previous_clip_from_world * select(vec4(world_direction, 0.0), vec4(position, 1.0), center.depth > 0.0);
+    if clip.w <= 0.0 {
+        return;
+    }
+    let previous_ndc = clip.xy / clip.w;
+    let previous_size = vec2f(reprojection.previous_size);
+    let previous_pixel = vec2(0.5 * previous_ndc.x + 0.5, 0.5 - 0.5 * previous_ndc.y) * previous_size;
+    motion_vectors[idx] = previous_pixel - pixel_center;
+
+    // bilinear over the four pixels around, leaving out the ones that show something else
+    let corner = previous_pixel - 0.5;
+    let base = vec2i(floor(corner));
+    let f = corner - floor(corner);
+    var sum = TemporalSample(vec3(0.0), 0.0, vec2(0.0));
+    var weight_sum = 0.0;
+    for (var i = 0; i < 4; i++) {
+        let offset = vec2(i % 2, i / 2);
+        let tap = base + offset;
+        if any(tap < vec2(0)) || any(tap >= vec2i(reprojection.previous_size)) {
+            continue;
+        }
+        let tap_idx = u32(tap.x + tap.y * i32(reprojection.previous_size.x));
+        if !consistentHistory(center, expected_depth, previous_guide[tap_idx]) {
+            continue;
+        }
+        let bilinear = select(1.0 - f, f, vec2<bool>(offset));
+        let weight = bilinear.x * bilinear.y;
+        let previous = integrated[tap_idx];
+        sum.color += weight * previous.color;
+        sum.length += weight * previous.length;
+        sum.moments += weight * previous.moments;
+        weight_sum += weight;
+    }
+
+    if weight_sum > 1e-3 {
+        history[idx] = TemporalSample(sum.color / weight_sum, sum.length / weight_sum, sum.moments / weight_sum);
+    }
+}
+
+@compute @workgroup_size(8, 8)
+fn integrate(@builtin(global_invocation_id) id: vec3u) {
+    if id.x >= frame_buffer.width || id.y >= frame_buffer.height {
+        return;
+    }
+    let idx = id.x + id.y * frame_buffer.width;
+
+    // the samples since the image started over, demodulated
+    let stats = pixel_stats[idx];
+    let samples = f32(stats.sample_count);
+    let inv_samples = 1.0 / max(samples, 1.0);
+    let albedo = demodulationAlbedo(guide[idx].albedo);
+    let color = inv_samples * vec3(image_buffer[idx][0], image_buffer[idx][1], image_buffer[idx][2]) / albedo;
+    // the wavefront kernels only square the luminance of the last sample once the next starts
+    let luminance_squared = stats.luminance_squared + stats.sample_luminance * stats.sample_luminance;
+    let moments = vec2(luminance(color), inv_samples * luminance_squared / (luminance(albedo) * luminance(albedo)));
+
+    // the history counts for at most max_history samples, so that it fades out as the new
+    // samples come in and doesn't hold on to lighting that has changed
+    var previous = history[idx];
+    previous.length = select(0.0, min(previous.length, reprojection.max_history), reprojection.temporal != 0u);
+    let length = previous.length + samples;
+    if length == 0.0 {
+        integrated[idx] = TemporalSample(vec3(0.0), 0.0, vec2(0.0));
+        return;
+    }
+    let current_weight = samples / length;
+    integrated[idx] = TemporalSample(
+        mix(previous.color, color, current_weight),
+        length,
+        mix(previous.moments, moments, current_weight),
+    );
+}
+
+@compute @workgroup_size(8, 8)
+fn estimateVariance(@builtin(global_invocation_id) id: vec3u) {
+    let image_size = vec2i(i32(frame_buffer.width), i32(frame_buffer.height));
+    let pixel = vec2i(id.xy);
+    if pixel.x >= image_size.x || pixel.y >= image_size.y {
+        return;
+    }
+    let idx = pixelIndex(pixel);
+    let sample = integrated[idx];
+
+    // the variance of the mean from the moments of the samples
+    var variance = max(sample.moments.y - sample.moments.x * sample.moments.x, 0.0) / max(sample.length, 1.0);
+    if sample.length < MIN_VARIANCE_HISTORY {
+        // too few samples to go by, so the spread of the pixels around on the same surface
+        // stands in
+        let center = guide[idx];
+        var moments = vec2(0.0);
+        var weight_sum = 0.0;
+        for (var y = -3; y <= 3; y++) {
+            for (var x = -3; x <= 3; x++) {
+                let tap = clamp(pixel + vec2(x, y), vec2(0), image_size - 1);
+                let tap_idx = pixelIndex(tap);
+                let weight = geometryWeight(center, guide[tap_idx]);
+                let tap_luminance = luminance(integrated[tap_idx].color);
+                moments += weight * vec2(tap_luminance, tap_luminance * tap_luminance);
+                weight_sum += weight;
+            }
+        }
+        moments /= weight_sum;
+        variance = max(moments.y - moments.x * moments.x, 0.0);
+    }
+
+    color_out[idx] = vec4(sample.color, variance);
+}
+
+// the variance of a pixel blurred with its neighbors, since a single pixel's is noisy itself
+fn filteredVariance(pixel: vec2i, image_size: vec2i) -> f32 {
+    let kernel = array(array(1.0 / 16.0, 1.0 / 8.0, 1.0 / 16.0), array(1.0 / 8.0, 1.0 / 4.0, 1.0 / 8.0), array(1.0 / 16.0, 1.0 / 8.0, 1.0 / 16.0));
+    var variance = 0.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let tap = clamp(pixel + vec2(x, y), vec2(0), image_size - 1);
+            variance += kernel[y + 1][x + 1] * color_in[pixelIndex(tap)].w;
+        }
+    }
+    return variance;
+}
+
+@compute @workgroup_size(8, 8)
+fn atrous(@builtin(global_invocation_id) id: vec3u) {
+    let image_size = vec2i(i32(frame_buffer.width), i32(frame_buffer.height));
+    let pixel = vec2i(id.xy);
+    if pixel.x >= image_size.x || pixel.y >= image_size.y {
+        return;
+    }
+    let idx = pixelIndex(pixel);
+
+    let kernel = array(1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0);
+    let center = guide[idx];
+    let center_luminance = luminance(color_in[idx].xyz);
+    // luminance differences count relative to the noise the center has left
+    let luminance_scale = denoise_pass.color_phi * sqrt(filteredVariance(pixel, image_size)) + 1e-6;
+
+    var color_sum = vec3(0.0);
+    var variance_sum = 0.0;
+    var weight_sum = 0.0;
+    for (var y = -2; y <= 2; y++) {
+        for (var x = -2; x <= 2; x++) {
+            let tap = clamp(pixel + vec2(x, y) * i32(denoise_pass.step), vec2(0), image_size - 1);
+            let tap_idx = pixelIndex(tap);
+            let color = color_in[tap_idx];
+
+            let luminance_weight = exp(-abs(center_luminance - luminance(color.xyz)) / luminance_scale);
+            let weight = kernel[x + 2] * kernel[y + 2] * luminance_weight * geometryWeight(center, guide[tap_idx]);
+            color_sum += weight * color.xyz;
+            // the variance of a weighted sum goes with the squared weights
+            variance_sum += weight * weight * color.w;
+            weight_sum += weight;
+        }
+    }
+
+    // the center tap always has a weight, so weight_sum is never zero
+    var color = color_sum / weight_sum;
+    if denoise_pass.remodulate != 0u {
+        color *= demodulationAlbedo(center.albedo);
+    }
+    color_out[idx] = vec4(color, variance_sum / (weight_sum * weight_sum));
+}
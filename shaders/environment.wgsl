@@ -0,0 +1,234 @@
+// the environment that lights the scene from infinitely far away, appended to
+// path_tracing_common.wgsl. It is the sky gradient, an equirectangular map or the Preetham
+// sky with a sun disk. The map is importance sampled by the piecewise constant distribution
+// in environment_cdf: the conditional cdfs of the rows one after another, followed by the
+// marginal cdf over the rows. Of the sky model only the sun is sampled, the sky is smooth
+// enough for BSDF sampling
+
+// the values of Background on the Rust side
+const BACKGROUND_GRADIENT = 0u;
+const BACKGROUND_ENVIRONMENT_MAP = 1u;
+const BACKGROUND_SUN_SKY = 2u;
+
+// the Preetham sky, set up by GPUSky
+struct Sky {
+    // the Perez coefficients A to E for Y, x and y
+    perez: array<vec4f, 5>,
+    zenith: vec3f,
+    cos_sun_radius: f32,
+    sun_direction: vec3f,
+    sun_solid_angle: f32,
+    sun_radiance: vec3f,
+    ground_radiance: vec3f,
+}
+
+struct EnvironmentParameters {
+    // rotation of the map about the y axis in radians
+    rotation: f32,
+    intensity: f32,
+    background: u32,
+    environment_map: u32,
+    sky: Sky,
+}
+
+// a direction towards the environment and the radiance coming from it
+struct EnvironmentSample {
+    direction: vec3f,
+    radiance: vec3f,
+    // solid angle pdf; zero if there is no sample
+    pdf: f32,
+}
+
+@group(1) @binding(4) var<uniform> environment: EnvironmentParameters;
+@group(2) @binding(5) var environment_map: texture_2d<f32>;
+@group(2) @binding(6) var<storage, read> environment_cdf: array<f32>;
+
+const NO_ENVIRONMENT_SAMPLE = EnvironmentSample(vec3(0.0), vec3(0.0), 0.0);
+
+fn hasEnvironmentMap() -> bool {
+    return environment.background == BACKGROUND_ENVIRONMENT_MAP;
+}
+
+fn hasSun() -> bool {
+    return environment.background == BACKGROUND_SUN_SKY;
+}
+
+// whether any part of the environment is sampled as a light
+fn hasEnvironmentLight() -> bool {
+    return hasEnvironmentMap() || hasSun();
+}
+
+fn rotateY(v: vec3f, angle: f32) -> vec3f {
+    let c = cos(angle);
+    let s = sin(angle);
+    return vec3(c * v.x + s * v.z, v.y, c * v.z - s * v.x);
+}
+
+// the map wraps around the y axis, with u = 0.5 looking down -z and v = 0 straight up
+fn directionToUv(direction: vec3f) -> vec2f {
+    let u = 0.5 + atan2(direction.x, -direction.z) / (2.0 * PI);
+    let v = acos(clamp(direction.y, -1.0, 1.0)) / PI;
+    return vec2(u, v);
+}
+
+fn uvToDirection(uv: vec2f) -> vec3f {
+    let phi = 2.0 * PI * uv.x - PI;
+    let theta = PI * uv.y;
+    let sin_theta = sin(theta);
+    return vec3(sin_theta * sin(phi), cos(theta), -sin_theta * cos(phi));
+}
+
+fn environmentTexel(uv: vec2f) -> vec2u {
+    let size = textureDimensions(environment_map);
+    return min(vec2u(uv * vec2f(size)), size - 1u);
+}
+
+// the radiance arriving from infinitely far away along -direction
+fn environmentColor(direction: vec3f) -> vec3f {
+    switch environment.background {
+        case BACKGROUND_ENVIRONMENT_MAP: {
+            return environmentMapColor(direction);
+        }
+        case BACKGROUND_SUN_SKY: {
+            return environment.intensity * (skyModelRadiance(direction) + sunRadiance(direction));
+        }
+        default: {
+            return environment.intensity * skyColor(direction);
+        }
+    }
+}
+
+// the part of environmentColor that sampleEnvironment samples
+fn sampledEnvironmentColor(direction: vec3f) -> vec3f {
+    if hasEnvironmentMap() {
+        return environmentMapColor(direction);
+    }
+    if hasSun() {
+        return environment.intensity * sunRadiance(direction);
+    }
+    return vec3(0.0);
+}
+
+fn environmentMapColor(direction: vec3f) -> vec3f {
+    let uv = directionToUv(rotateY(direction, -environment.rotation));
+    return environment.intensity * textureLoad(environment_map, environmentTexel(uv), 0).rgb;
+}
+
+// the Perez function for Y, x and y
+fn perez(cos_theta: f32, gamma: f32) -> vec3f {
+    let p = environment.sky.perez;
+    let cos_gamma = cos(gamma);
+    return (1.0 + p[0].xyz * exp(p[1].xyz / max(cos_theta, 1e-3)))
+        * (1.0 + p[2].xyz * exp(p[3].xyz * gamma) + p[4].xyz * cos_gamma * cos_gamma);
+}
+
+// the sky without the sun in linear sRGB, or the ground below the horizon
+fn skyModelRadiance(direction: vec3f) -> vec3f {
+    if direction.y < 0.0 {
+        return environment.sky.ground_radiance;
+    }
+    let gamma = acos(clamp(dot(direction, environment.sky.sun_direction), -1.0, 1.0));
+    let yxy = environment.sky.zenith * perez(direction.y, gamma);
+    if yxy.z <= 0.0 {
+        return vec3(0.0);
+    }
+    let xyz = vec3(yxy.y / yxy.z * yxy.x, yxy.x, (1.0 - yxy.y - yxy.z) / yxy.z * yxy.x);
+    let rgb = mat3x3f(
+        3.2406, -0.9689, 0.0557,
+        -1.5372, 1.8758, -0.2040,
+        -0.4986, 0.0415, 1.0570,
+    ) * xyz;
+    return max(rgb, vec3(0.0));
+}
+
+fn sunRadiance(direction: vec3f) -> vec3f {
+    if dot(direction, environment.sky.sun_direction) < environment.sky.cos_sun_radius {
+        return vec3(0.0);
+    }
+    return environment.sky.sun_radiance;
+}
+
+// the index of the first of the count cdf entries from start on that is above u
+fn searchCdf(start: u32, count: u32, u: f32) -> u32 {
+    var low = 0u;
+    var high = count - 1u;
+    while low < high {
+        let middle = (low + high) / 2u;
+        if u < environment_cdf[start + middle] {
+            high = middle;
+        } else {
+            low = middle + 1u;
+        }
+    }
+    return low;
+}
+
+fn cdfPmf(start: u32, index: u32) -> f32 {
+    let below = select(0.0, environment_cdf[start + index - 1u], index > 0u);
+    return environment_cdf[start + index] - below;
+}
+
+// the probability of picking a texel, with the marginal cdf behind the conditional ones
+fn texelPmf(texel: vec2u) -> f32 {
+    let size = textureDimensions(environment_map);
+    return cdfPmf(size.x * size.y, texel.y) * cdfPmf(texel.y * size.x, texel.x);
+}
+
+fn sampleEnvironment(state: ptr<function, Sampler>) -> EnvironmentSample {
+    if hasSun() {
+        return sampleSun(state);
+    }
+    return sampleEnvironmentMap(state);
+}
+
+// uniform within the cone of the sun disk
+fn sampleSun(state: ptr<function, Sampler>) -> EnvironmentSample {
+    let one_minus_cos_max = environment.sky.sun_solid_angle / (2.0 * PI);
+
+    var sample: EnvironmentSample;
+    sample.direction = rngNextInCone(environment.sky.sun_direction, one_minus_cos_max, state);
+    sample.radiance = environment.intensity * environment.sky.sun_radiance;
+    sample.pdf = 1.0 / environment.sky.sun_solid_angle;
+    return sample;
+}
+
+fn sampleEnvironmentMap(state: ptr<function, Sampler>) -> EnvironmentSample {
+    let size = textureDimensions(environment_map);
+    let u = rngNext2D(state);
+    let y = searchCdf(size.x * size.y, size.y, u.x);
+    let x = searchCdf(y * size.x, size.x, u.y);
+    let texel = vec2(x, y);
+
+    // uniform within the texel, whose solid angle shrinks with the sine of the latitude
+    let uv = (vec2f(texel) + rngNext2D(state)) / vec2f(size);
+    let sin_theta = sin(PI * uv.y);
+    let pmf = texelPmf(texel);
+    if sin_theta <= 0.0 || pmf <= 0.0 {
+        return NO_ENVIRONMENT_SAMPLE;
+    }
+
+    var sample: EnvironmentSample;
+    sample.direction = rotateY(uvToDirection(uv), environment.rotation);
+    sample.radiance = environment.intensity * textureLoad(environment_map, texel, 0).rgb;
+    sample.pdf = pmf * f32(size.x * size.y) / (2.0 * PI * PI * sin_theta);
+    return sample;
+}
+
+// the solid angle pdf with which sampleEnvironment picks a direction
+fn environmentPdf(direction: vec3f) -> f32 {
+    if hasSun() {
+        let in_sun = dot(direction, environment.sky.sun_direction) >= environment.sky.cos_sun_radius;
+        return select(0.0, 1.0 / environment.sky.sun_solid_angle, in_sun);
+    }
+    if !hasEnvironmentMap() {
+        return 0.0;
+    }
+    let local_direction = rotateY(direction, -environment.rotation);
+    let sin_theta = sqrt(max(0.0, 1.0 - local_direction.y * local_direction.y));
+    if sin_theta <= 0.0 {
+        return 0.0;
+    }
+    let size = textureDimensions(environment_map);
+    let pmf = texelPmf(environmentTexel(directionToUv(local_direction)));
+    return pmf * f32(size.x * size.y) / (2.0 * PI * PI * sin_theta);
+}
@@ -0,0 +1,519 @@
+// next event estimation, appended to path_tracing_common.wgsl and bsdf.wgsl. The emissive
+// spheres and triangles make up the light list, which is sorted by primitive. The light tree
+// over the list picks a light in proportion to how much it might contribute at the shading
+// point. The punctual lights are in the list too, but can only be reached by light sampling;
+// directional lights are kept out of the tree and picked uniformly, alongside the tree. An
+// environment map or the sun is sampled as a light as well, with half of the samples if
+// there are other lights.
+// lights are found by light sampling, by scattering into them or by both combined with
+// multiple importance sampling, as set by sampling_parameters.light_sampling
+
+const NO_LIGHT = 0xffffffffu;
+const PUNCTUAL_PRIMITIVE = 0x40000000u;
+
+// the flags of GPULightNode
+const LIGHT_NODE_LEAF = 1u;
+const LIGHT_NODE_TWO_SIDED = 2u;
+
+// the largest f32 below 1
+const ONE_MINUS_EPSILON = 0.99999994;
+
+// the values of PunctualLightType on the Rust side
+const PUNCTUAL_POINT = 0u;
+const PUNCTUAL_SPOT = 1u;
+const PUNCTUAL_DIRECTIONAL = 2u;
+
+// the values of LightSampling on the Rust side
+const LIGHT_SAMPLING_BSDF = 0u;
+const LIGHT_SAMPLING_NEE = 1u;
+const LIGHT_SAMPLING_MIS_BALANCE = 2u;
+const LIGHT_SAMPLING_MIS_POWER = 3u;
+
+struct Light {
+    primitive: u32,
+    // bit i is set if the way down the tree to the light takes the second child at depth i
+    bit_trail: u32,
+}
+
+struct LightNode {
+    aabb_min: vec3f,
+    // the power of the lights below the node
+    phi: f32,
+    aabb_max: vec3f,
+    // the normals of the lights below the node are within theta_o of axis, and they emit up
+    // to theta_e beyond their normals
+    cos_theta_o: f32,
+    axis: vec3f,
+    cos_theta_e: f32,
+    // the second child of an interior node, whose first child follows it, or the light of a leaf
+    child_or_light: u32,
+    flags: u32,
+}
+
+struct LightTree {
+    tree_lights: u32,
+    // the directional lights at the start of punctual_lights
+    infinite_lights: u32,
+}
+
+// a light picked by the tree and the probability of picking it; zero if none was picked
+struct TreeLightSample {
+    light: u32,
+    pmf: f32,
+}
+
+struct PunctualLight {
+    position: vec3f,
+    light_type: u32,
+    // the direction the light shines in
+    direction: vec3f,
+    radius: f32,
+    // radiant intensity, or irradiance for directional lights
+    intensity: vec3f,
+    cos_inner_angle: f32,
+    cos_outer_angle: f32,
+    solid_angle: f32,
+}
+
+// a point on a light as seen from the shading point
+struct LightSample {
+    direction: vec3f,
+    distance: f32,
+    radiance: vec3f,
+    // solid angle pdf, including the probability of picking the light; zero if there is no sample.
+    // for lights without extent it is just the probability of picking them
+    pdf: f32,
+    // whether BSDF sampling can find the light too, so that the sample is weighted by MIS
+    bsdf_reachable: bool,
+}
+
+// a shadow ray and what it adds to the path if nothing blocks it
+struct LightConnection {
+    ray: Ray,
+    t_max: f32,
+    contribution: vec3f,
+}
+
+@group(2) @binding(4) var<storage, read> lights: array<Light>;
+@group(2) @binding(7) var<storage, read> punctual_lights: array<PunctualLight>;
+@group(2) @binding(8) var<storage, read> light_nodes: array<LightNode>;
+@group(2) @binding(9) var<uniform> light_tree: LightTree;
+
+const NO_LIGHT_SAMPLE = LightSample(vec3(0.0), 0.0, vec3(0.0), 0.0, false);
+const NO_TREE_LIGHT = TreeLightSample(NO_LIGHT, 0.0);
+
+fn hasLights() -> bool {
+    return light_tree.tree_lights + light_tree.infinite_lights > 0u;
+}
+
+// cos(max(0, a - b)) and sin(max(0, a - b)) from the sines and cosines of a and b
+fn cosSubClamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b {
+        return 1.0;
+    }
+    return cos_a * cos_b + sin_a * sin_b;
+}
+
+fn sinSubClamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b {
+        return 0.0;
+    }
+    return sin_a * cos_b - cos_a * sin_b;
+}
+
+// an estimate of how much the lights below a node contribute at a point, after pbrt-v4: their
+// power over the squared distance, times the cosine of the smallest angle between the
+// direction to the point and any of their normals, as far as the bounds tell
+fn lightImportance(position: vec3f, node: LightNode) -> f32 {
+    if node.phi <= 0.0 {
+        return 0.0;
+    }
+
+    // the distance is kept from going to zero close to or inside the bounds
+    let center = 0.5 * (node.aabb_min + node.aabb_max);
+    let diagonal = length(node.aabb_max - node.aabb_min);
+    let to_position = position - center;
+    let distance2 = max(dot(to_position, to_position), 0.5 * diagonal);
+
+    var cos_theta_w = dot(node.axis, to_position) * inverseSqrt(max(dot(to_position, to_position), 1e-20));
+    if (node.flags & LIGHT_NODE_TWO_SIDED) != 0u {
+        cos_theta_w = abs(cos_theta_w);
+    }
+    let sin_theta_w = sqrt(max(0.0, 1.0 - cos_theta_w * cos_theta_w));
+
+    // the cone of directions from the point that the bounding sphere of the bounds subtends
+    let radius2 = 0.25 * diagonal * diagonal;
+    var cos_theta_b = -1.0;
+    if dot(to_position, to_position) > radius2 {
+        cos_theta_b = sqrt(max(0.0, 1.0 - radius2 / dot(to_position, to_position)));
+    }
+    let sin_theta_b = sqrt(max(0.0, 1.0 - cos_theta_b * cos_theta_b));
+
+    let sin_theta_o = sqrt(max(0.0, 1.0 - node.cos_theta_o * node.cos_theta_o));
+    let cos_theta_x = cosSubClamped(sin_theta_w, cos_theta_w, sin_theta_o, node.cos_theta_o);
+    let sin_theta_x = sinSubClamped(sin_theta_w, cos_theta_w, sin_theta_o, node.cos_theta_o);
+    let cos_theta_p = cosSubClamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+    if cos_theta_p <= node.cos_theta_e {
+        return 0.0;
+    }
+    return node.phi * cos_theta_p / distance2;
+}
+
+// the probability of taking the first child of an interior node at a point
+fn firstChildProbability(position: vec3f, node_index: u32) -> f32 {
+    let first = lightImportance(position, light_nodes[node_index + 1u]);
+    let second = lightImportance(position, light_nodes[light_nodes[node_index].child_or_light]);
+    if first + second <= 0.0 {
+        return -1.0;
+    }
+    return first / (first + second);
+}
+
+// walks down the tree, reusing u for every decision
+fn pickTreeLight(position: vec3f, u_start: f32) -> TreeLightSample {
+    var u = u_start;
+    var node_index = 0u;
+    var pmf = 1.0;
+    for (var depth = 0u; depth <= 32u; depth++) {
+        let node = light_nodes[node_index];
+        if (node.flags & LIGHT_NODE_LEAF) != 0u {
+            if depth == 0u && lightImportance(position, node) <= 0.0 {
+                return NO_TREE_LIGHT;
+            }
+            return TreeLightSample(node.child_or_light, pmf);
+        }
+
+        let p_first = firstChildProbability(position, node_index);
+        if p_first < 0.0 {
+            return NO_TREE_LIGHT;
+        }
+        if u < p_first {
+            node_index += 1u;
+            pmf *= p_first;
+            u = min(u / p_first, ONE_MINUS_EPSILON);
+        } else {
+            node_index = node.child_or_light;
+            pmf *= 1.0 - p_first;
+            u = min((u - p_first) / (1.0 - p_first), ONE_MINUS_EPSILON);
+        }
+    }
+    return NO_TREE_LIGHT;
+}
+
+// the probability that pickTreeLight picks a light at a point, found by following its bit trail
+fn treeLightPmf(position: vec3f, light_index: u32) -> f32 {
+    let bit_trail = lights[light_index].bit_trail;
+    var node_index = 0u;
+    var pmf = 1.0;
+    for (var depth = 0u; depth <= 32u; depth++) {
+        let node = light_nodes[node_index];
+        if (node.flags & LIGHT_NODE_LEAF) != 0u {
+            if depth == 0u && lightImportance(position, node) <= 0.0 {
+                return 0.0;
+            }
+            return pmf;
+        }
+
+        let p_first = firstChildProbability(position, node_index);
+        if p_first < 0.0 {
+            return 0.0;
+        }
+        if ((bit_trail >> depth) & 1u) == 0u {
+            node_index += 1u;
+            pmf *= p_first;
+        } else {
+            node_index = node.child_or_light;
+            pmf *= 1.0 - p_first;
+        }
+    }
+    return 0.0;
+}
+
+// the probability of sampling the light tree rather than one of the directional lights
+fn treeProbability() -> f32 {
+    if light_tree.tree_lights == 0u {
+        return 0.0;
+    }
+    return 1.0 / f32(light_tree.infinite_lights + 1u);
+}
+
+// the index of the light of a primitive, or NO_LIGHT if the primitive isn't in the light list
+fn findLight(primitive: u32) -> u32 {
+    var low = 0u;
+    var high = light_tree.tree_lights;
+    while low < high {
+        let middle = (low + high) / 2u;
+        if lights[middle].primitive < primitive {
+            low = middle + 1u;
+        } else {
+            high = middle;
+        }
+    }
+    if low < light_tree.tree_lights && lights[low].primitive == primitive {
+        return low;
+    }
+    return NO_LIGHT;
+}
+
+// the probability of sampling the environment rather than the light list
+fn environmentProbability() -> f32 {
+    if !hasEnvironmentLight() {
+        return 0.0;
+    }
+    return select(1.0, 0.5, hasLights());
+}
+
+fn sampleLight(position: vec3f, state: ptr<function, Sampler>) -> LightSample {
+    let environment_probability = environmentProbability();
+    if rngNextFloat(state) < environment_probability {
+        let sample = sampleEnvironment(state);
+        return LightSample(sample.direction, MISS, sample.radiance, environment_probability * sample.pdf, true);
+    }
+    var sample = sampleLightList(position, state);
+    sample.pdf *= 1.0 - environment_probability;
+    return sample;
+}
+
+fn sampleLightList(position: vec3f, state: ptr<function, Sampler>) -> LightSample {
+    // one of the directional lights, or the tree
+    let choices = light_tree.infinite_lights + select(0u, 1u, light_tree.tree_lights > 0u);
+    let choice = min(u32(rngNextFloat(state) * f32(choices)), choices - 1u);
+    if choice < light_tree.infinite_lights {
+        return samplePunctualLight(punctual_lights[choice], 1.0 / f32(choices), position, state);
+    }
+
+    let picked = pickTreeLight(position, rngNextFloat(state));
+    if picked.pmf <= 0.0 {
+        return NO_LIGHT_SAMPLE;
+    }
+    let light = lights[picked.light];
+    let pmf = treeProbability() * picked.pmf;
+
+    if (light.primitive & PUNCTUAL_PRIMITIVE) != 0u {
+        return samplePunctualLight(punctual_lights[light.primitive & ~PUNCTUAL_PRIMITIVE], pmf, position, state);
+    }
+
+    var sample: LightSample;
+    sample.bsdf_reachable = true;
+    var intersection: Intersection;
+    if (light.primitive & SPHERE_PRIMITIVE) != 0u {
+        // a direction in the cone the sphere subtends, which unlike a point on the whole
+        // sphere is never on its far side
+        let sphere = spheres[light.primitive & ~SPHERE_PRIMITIVE];
+        let to_center = sphere.center - position;
+        let sin2_max = sphere.radius * sphere.radius / dot(to_center, to_center);
+        if sin2_max >= 1.0 {
+            return NO_LIGHT_SAMPLE;
+        }
+        let cos_max = sqrt(1.0 - sin2_max);
+        sample.direction = rngNextInCone(normalize(to_center), 1.0 - cos_max, state);
+
+        // directions grazing the silhouette can miss in floating point; they touch it at
+        // the closest approach to the center
+        var t = hitSphere(sphere, Ray(position, sample.direction), 0.0, MISS);
+        if t == MISS {
+            t = dot(to_center, sample.direction);
+        }
+        intersection = Intersection(t, light.primitive, vec2(0.0));
+        sample.pdf = pmf / (2.0 * PI * (1.0 - cos_max));
+    } else {
+        // uniform barycentrics
+        let triangle = triangles[light.primitive];
+        let p0 = vertices[triangle.indices.x].position;
+        let e1 = vertices[triangle.indices.y].position - p0;
+        let e2 = vertices[triangle.indices.z].position - p0;
+        let u = rngNext2D(state);
+        let su = sqrt(u.x);
+        let barycentrics = vec2(u.y * su, 1.0 - su);
+        let point = p0 + barycentrics.x * e1 + barycentrics.y * e2;
+
+        let to_light = point - position;
+        let distance2 = dot(to_light, to_light);
+        let distance = sqrt(distance2);
+        sample.direction = to_light / distance;
+
+        // emitters are two sided, like when they are hit by a ray
+        let area_normal = cross(e1, e2);
+        let double_area = length(area_normal);
+        let cos_light = abs(dot(area_normal, sample.direction)) / double_area;
+        if cos_light <= 0.0 || distance2 == 0.0 {
+            return NO_LIGHT_SAMPLE;
+        }
+        intersection = Intersection(distance, light.primitive, barycentrics);
+        sample.pdf = pmf * distance2 / (0.5 * double_area * cos_light);
+    }
+
+    // the emission can be textured, so it is looked up like for any other hit
+    let hit = surfaceInteraction(Ray(position, sample.direction), intersection);
+    sample.distance = intersection.t;
+    sample.radiance = hit.material.emission;
+    return sample;
+}
+
+fn samplePunctualLight(light: PunctualLight, pmf: f32, position: vec3f, state: ptr<function, Sampler>) -> LightSample {
+    var sample: LightSample;
+    sample.bsdf_reachable = false;
+
+    if light.light_type == PUNCTUAL_DIRECTIONAL {
+        // a disk in the sky spreads the irradiance over its solid angle
+        sample.distance = MISS;
+        if light.solid_angle > 0.0 {
+            sample.direction = rngNextInCone(-light.direction, light.solid_angle / (2.0 * PI), state);
+            sample.radiance = light.intensity / light.solid_angle;
+            sample.pdf = pmf / light.solid_angle;
+        } else {
+            sample.direction = -light.direction;
+            sample.radiance = light.intensity;
+            sample.pdf = pmf;
+        }
+        return sample;
+    }
+
+    let to_light = light.position - position;
+    let distance2 = dot(to_light, to_light);
+    if distance2 == 0.0 {
+        return NO_LIGHT_SAMPLE;
+    }
+    let distance = sqrt(distance2);
+    let to_center = to_light / distance;
+
+    var falloff = 1.0;
+    if light.light_type == PUNCTUAL_SPOT {
+        falloff = smoothstep(light.cos_outer_angle, light.cos_inner_angle, dot(-to_center, light.direction));
+        if falloff <= 0.0 {
+            return NO_LIGHT_SAMPLE;
+        }
+    }
+
+    let radius2 = light.radius * light.radius;
+    if radius2 > 0.0 && distance2 > radius2 {
+        // a sphere with the radiance that gives the intensity of the light, sampled within the
+        // cone it subtends like the sphere lights
+        let sin2_max = radius2 / distance2;
+        let one_minus_cos_max = sin2_max / (1.0 + sqrt(1.0 - sin2_max));
+        sample.direction = rngNextInCone(to_center, one_minus_cos_max, state);
+        let along = dot(to_light, sample.direction);
+        sample.distance = along - sqrt(max(0.0, radius2 - (distance2 - along * along)));
+        sample.radiance = falloff * light.intensity / (PI * radius2);
+        sample.pdf = pmf / (2.0 * PI * one_minus_cos_max);
+    } else {
+        sample.direction = to_center;
+        sample.distance = distance;
+        sample.radiance = falloff * light.intensity / distance2;
+        sample.pdf = pmf;
+    }
+    return sample;
+}
+
+// the solid angle pdf with which sampleLight picks the point of a hit on a light, as seen
+// from the origin of the ray that found it
+fn lightPdf(light_index: u32, ray: Ray, hit: HitRecord) -> f32 {
+    let pmf = (1.0 - environmentProbability()) * treeProbability() * treeLightPmf(ray.origin, light_index);
+    if pmf <= 0.0 {
+        return 0.0;
+    }
+    return pmf * areaLightPdf(lights[light_index].primitive, ray, hit);
+}
+
+// the solid angle pdf of the point on the light, once the light is picked
+fn areaLightPdf(primitive: u32, ray: Ray, hit: HitRecord) -> f32 {
+    if (primitive & SPHERE_PRIMITIVE) != 0u {
+        let sphere = spheres[primitive & ~SPHERE_PRIMITIVE];
+        let to_center = sphere.center - ray.origin;
+        let sin2_max = sphere.radius * sphere.radius / dot(to_center, to_center);
+        if sin2_max >= 1.0 {
+            return 0.0;
+        }
+        return 1.0 / (2.0 * PI * (1.0 - sqrt(1.0 - sin2_max)));
+    }
+
+    let triangle = triangles[primitive];
+    let p0 = vertices[triangle.indices.x].position;
+    let area_normal = cross(vertices[triangle.indices.y].position - p0, vertices[triangle.indices.z].position - p0);
+    let double_area = length(area_normal);
+    let cos_light = abs(dot(area_normal, ray.direction)) / double_area;
+    if cos_light <= 0.0 {
+        return 0.0;
+    }
+    return hit.t * hit.t / (0.5 * double_area * cos_light);
+}
+
+// the weight of the strategy with pdf a when it is combined with the one with pdf b
+fn misWeight(a: f32, b: f32) -> f32 {
+    if sampling_parameters.light_sampling == LIGHT_SAMPLING_MIS_BALANCE {
+        return a / (a + b);
+    }
+    let a2 = a * a;
+    return a2 / (a2 + b * b);
+}
+
+// picks a point on a light for the hit and works out the shadow ray towards it and the
+// contribution (without the path throughput) that gets added if nothing is in the way
+fn connectToLight(ray: Ray, hit: HitRecord, state: ptr<function, Sampler>) -> LightConnection {
+    var connection = LightConnection(ray, 0.0, vec3(0.0));
+    let light_sampling = sampling_parameters.light_sampling;
+    if light_sampling == LIGHT_SAMPLING_BSDF || !(hasLights() || hasEnvironmentLight()) || isDeltaBsdf(hit.material) {
+        return connection;
+    }
+
+    let light = sampleLight(hit.position, state);
+    if light.pdf <= 0.0 {
+        return connection;
+    }
+
+    let onb = shadingFrame(hit);
+    let wo = -ray.direction * onb;
+    let wi = light.direction * onb;
+    let bsdf = evalBsdf(hit, wo, wi);
+    if all(bsdf.value == vec3(0.0)) {
+        return connection;
+    }
+
+    // leave from the side of the surface the light is on and stop short of the light; the
+    // environment is at MISS, which stays out of reach
+    let offset = select(-EPSILON, EPSILON, wi.z > 0.0) * hit.normal;
+    connection.ray = Ray(hit.position + offset, light.direction);
+    connection.t_max = light.distance - 2.0 * EPSILON;
+    connection.contribution = bsdf.value * light.radiance / light.pdf;
+    if light_sampling != LIGHT_SAMPLING_NEE && light.bsdf_reachable {
+        connection.contribution *= misWeight(light.pdf, bsdf.pdf);
+    }
+    return connection;
+}
+
+// the weight of the emission of a hit that a path scattered into with the given pdf. Light
+// sampling can't follow a delta bounce, and knows nothing about emitters outside the light
+// list, so those always count in full
+fn emissionWeight(ray: Ray, hit: HitRecord, delta_bounce: bool, bsdf_pdf: f32) -> f32 {
+    let light_sampling = sampling_parameters.light_sampling;
+    if delta_bounce || light_sampling == LIGHT_SAMPLING_BSDF {
+        return 1.0;
+    }
+    let light_index = findLight(hit.primitive);
+    if light_index == NO_LIGHT {
+        return 1.0;
+    }
+    if light_sampling == LIGHT_SAMPLING_NEE {
+        return 0.0;
+    }
+    return misWeight(bsdf_pdf, lightPdf(light_index, ray, hit));
+}
+
+// the radiance a path that scattered out of the scene with the given pdf sees. Only the part
+// of the environment that is light sampled gets weighted, the rest counts in full
+fn escapedRadiance(direction: vec3f, delta_bounce: bool, bsdf_pdf: f32) -> vec3f {
+    let sampled = sampledEnvironmentColor(direction);
+    let weight = environmentWeight(direction, delta_bounce, bsdf_pdf);
+    return environmentColor(direction) - sampled + weight * sampled;
+}
+
+fn environmentWeight(direction: vec3f, delta_bounce: bool, bsdf_pdf: f32) -> f32 {
+    let light_sampling = sampling_parameters.light_sampling;
+    if delta_bounce || light_sampling == LIGHT_SAMPLING_BSDF || !hasEnvironmentLight() {
+        return 1.0;
+    }
+    if light_sampling == LIGHT_SAMPLING_NEE {
+        return 0.0;
+    }
+    return misWeight(bsdf_pdf, environmentProbability() * environmentPdf(direction));
+}
@@ -0,0 +1,896 @@
+// everything the megakernel and the wavefront kernels have in common: the scene bindings
+// (groups 1 to 3), ray generation, scene intersection and the samplers. The BSDFs live in
+// bsdf.wgsl and the light sampling in lights.wgsl. group 0 differs between the two designs
+// and is declared by each kernel file, which gets appended to these when the shader module
+// is created
+
+const EPSILON = 0.001f;
+const PI = 3.1415927f;
+const FRAC_1_PI = 0.31830987f;
+const FRAC_PI_2 = 1.5707964f;
+const WORKGROUP_INVOCATIONS = 16u;
+const MISS = 1e30f;
+// set on Intersection.primitive when the primitive is a sphere rather than a triangle
+const SPHERE_PRIMITIVE = 0x80000000u;
+// Material.base_color_texture of a material without a texture
+const NO_TEXTURE = 0xffffffffu;
+
+// the values of AlphaMode on the Rust side
+const ALPHA_MODE_OPAQUE = 0u;
+const ALPHA_MODE_MASK = 1u;
+const ALPHA_MODE_BLEND = 2u;
+
+// the values of SamplerType on the Rust side
+const SAMPLER_INDEPENDENT = 0u;
+const SAMPLER_SOBOL = 1u;
+const SAMPLER_STRATIFIED = 2u;
+const SAMPLER_BLUE_NOISE = 3u;
+// the side of the blue noise mask, BlueNoise::SIZE on the Rust side
+const BLUE_NOISE_SIZE = 64u;
+// the dimensions of a sample: the camera ray takes the first ones, then every bounce gets a
+// block of its own, with the light sample at the start and the BSDF sample halfway through.
+// a bounce that draws fewer numbers than it could doesn't shift the dimensions of the next one
+const SAMPLER_CAMERA_DIMENSIONS = 4u;
+const SAMPLER_BOUNCE_DIMENSIONS = 16u;
+const SAMPLER_LIGHT_DIMENSION = 0u;
+const SAMPLER_BSDF_DIMENSION = 8u;
+// adaptive sampling decides per tile of TILE_SIZE by TILE_SIZE pixels whether to keep
+// sampling, AdaptiveSampling::TILE_SIZE on the Rust side
+const TILE_SIZE = 16u;
+
+// depth of the bvh, set from the Rust side when the pipeline is created
+override stackSize: u32 = 32u;
+
+struct FrameBuffer {
+    width: u32,
+    height: u32,
+    frame: u32,
+    accumulated_samples: u32
+}
+
+struct ProjectionBuffer {
+    invProj: mat4x4<f32>
+}
+
+struct ViewBuffer {
+    view: mat4x4<f32>
+}
+
+struct SamplingParametersBuffer {
+    samples_per_frame: u32,
+    samples_per_pixel: u32,
+    number_of_bounces: u32,
+    clear_image_buffer: u32,
+    light_sampling: u32,
+    sampler_type: u32,
+    adaptive_sampling: u32,
+    noise_threshold: f32,
+    min_adaptive_samples: u32,
+    aov_output: u32,
+}
+
+// the reconstruction filter as a table of its integral over PIXEL_FILTER_BINS equal bins across
+// [-radius, radius], normalized so that the magnitudes add up to 1. See GPUPixelFilter
+struct PixelFilter {
+    radius: f32,
+    // the integral of |f| over the integral of f, which the samples are weighted with
+    weight_scale: f32,
+    bins: array<vec4f, 16>,
+}
+
+const PIXEL_FILTER_BINS = 64u;
+
+// the samples a pixel has taken and the sum of their squared luminances, for adaptive
+// sampling. The wavefront kernels add up the luminance of the sample in flight separately
+// and square it once the sample is done
+struct PixelStats {
+    sample_count: u32,
+    luminance_squared: f32,
+    sample_luminance: f32,
+}
+
+// what the camera ray of a sample hits, summed over the samples of the pixel like the image.
+// The denoiser tells edges apart by them
+struct PixelFeatures {
+    albedo: vec3f,
+    depth: f32,
+    normal: vec3f,
+}
+
+// the AOVs the features don't already have, each in its own plane of the AOV buffer. A plane
+// holds one value per pixel and starts at its index times the pixel count; the vec3 AOVs take
+// three planes in a row. The values match the planes of Aov on the Rust side
+const AOV_GEOMETRIC_NORMAL = 0u;
+const AOV_OBJECT_ID = 3u;
+const AOV_MATERIAL_ID = 4u;
+const AOV_EMISSION = 5u;
+const AOV_DIRECT_DIFFUSE = 8u;
+const AOV_DIRECT_SPECULAR = 11u;
+const AOV_INDIRECT_DIFFUSE = 14u;
+const AOV_INDIRECT_SPECULAR = 17u;
+
+// the light of a sample split up by the number of scattering events it took to get to the
+// camera (none for emission, one for direct, more for indirect light) and by whether the
+// first of them was diffuse or specular. The parts add up to the radiance of the sample
+struct PathLight {
+    emission: vec3f,
+    direct_diffuse: vec3f,
+    direct_specular: vec3f,
+    indirect_diffuse: vec3f,
+    indirect_specular: vec3f,
+}
+
+// what a sample adds to the AOVs besides the features: the geometric normal and the ids of
+// the first hit, which stay zero if the camera ray escapes, and the light
+struct SampleAovs {
+    geometric_normal: vec3f,
+    object_id: u32,
+    material_id: u32,
+    light: PathLight,
+}
+
+// diffuse is the part of the first scattering event that was diffuse, per channel
+fn addPathLight(aovs: ptr<function, SampleAovs>, scatter_count: u32, radiance: vec3f, diffuse: vec3f) {
+    if scatter_count == 0u {
+        (*aovs).light.emission += radiance;
+        return;
+    }
+    let diffuse_radiance = saturate(diffuse) * radiance;
+    if scatter_count == 1u {
+        (*aovs).light.direct_diffuse += diffuse_radiance;
+        (*aovs).light.direct_specular += radiance - diffuse_radiance;
+    } else {
+        (*aovs).light.indirect_diffuse += diffuse_radiance;
+        (*aovs).light.indirect_specular += radiance - diffuse_radiance;
+    }
+}
+
+struct CameraBuffer {
+    position: vec4<f32>,
+    defocus_radius: f32,
+    focus_distance: f32,
+}
+
+struct Sphere {
+    center: vec3f,
+    radius: f32,
+    material_id: u32,
+    object_id: u32,
+}
+
+struct Material {
+    base_color: vec3f,
+    base_color_texture: u32,
+    emission: vec3f,
+    emission_texture: u32,
+    roughness: f32,
+    metallic: f32,
+    specular: f32,
+    specular_tint: f32,
+    sheen: f32,
+    sheen_tint: f32,
+    clearcoat: f32,
+    clearcoat_roughness: f32,
+    transmission: f32,
+    ior: f32,
+    opacity: f32,
+    anisotropy: f32,
+    metallic_roughness_texture: u32,
+    material_type: u32,
+    alpha_mode: u32,
+    alpha_cutoff: f32,
+}
+
+// a texture is width * height RGBA8 texels starting at offset in the texel buffer
+struct Texture {
+    offset: u32,
+    width: u32,
+    height: u32,
+    srgb: u32,
+}
+
+struct Vertex {
+    position: vec3f,
+    u: f32,
+    normal: vec3f,
+    v: f32,
+}
+
+struct Triangle {
+    indices: vec3u,
+    material_id: u32,
+}
+
+struct BvhNode {
+    aabb_min: vec3f,
+    left_first: u32,
+    aabb_max: vec3f,
+    triangle_count: u32,
+}
+
+struct Ray {
+    origin: vec3f,
+    direction: vec3f,
+}
+
+// the bare minimum found by the intersection tests; the rest of the surface data is only
+// worked out for the closest hit by surfaceInteraction
+struct Intersection {
+    t: f32,
+    primitive: u32,
+    barycentrics: vec2f,
+}
+
+struct HitRecord {
+    t: f32,
+    primitive: u32,
+    position: vec3f,
+    normal: vec3f,
+    // the normal of the surface itself, facing the ray like normal
+    geometric_normal: vec3f,
+    front_face: bool,
+    uv: vec2f,
+    // direction of increasing u, not necessarily perpendicular to the normal; zero if the
+    // surface has no usable uvs
+    tangent: vec3f,
+    material_id: u32,
+    // the hit's material with its textures applied
+    material: Material,
+}
+
+@group(1) @binding(0) var<uniform> inv_projection_matrix: ProjectionBuffer;
+@group(1) @binding(1) var<uniform> view_matrix: ViewBuffer;
+@group(1) @binding(2) var<uniform> sampling_parameters: SamplingParametersBuffer;
+@group(1) @binding(3) var<uniform> camera: CameraBuffer;
+@group(1) @binding(5) var<storage, read> blue_noise: array<f32>;
+@group(1) @binding(6) var<uniform> pixel_filter: PixelFilter;
+@group(2) @binding(0) var<storage, read> spheres: array<Sphere>;
+@group(2) @binding(1) var<storage, read> materials: array<Material>;
+@group(2) @binding(2) var<storage, read> textures: array<Texture>;
+@group(2) @binding(3) var<storage, read> texels: array<u32>;
+@group(3) @binding(0) var<storage, read> vertices: array<Vertex>;
+@group(3) @binding(1) var<storage, read> triangles: array<Triangle>;
+@group(3) @binding(2) var<storage, read> bvh_nodes: array<BvhNode>;
+@group(3) @binding(3) var<storage, read> object_ids: array<u32>;
+
+// function scope arrays can't be sized by an override, so every invocation gets its own
+// stackSize long slice of one workgroup array instead; every kernel that traces rays has to
+// set bvh_stack_offset from its local_invocation_index first
+var<workgroup> bvh_stack: array<u32, stackSize * WORKGROUP_INVOCATIONS>;
+var<private> bvh_stack_offset: u32;
+
+// an offset from the pixel center and what the sample taken there has to be weighted with
+struct FilterSample {
+    offset: vec2f,
+    weight: f32,
+}
+
+// one coordinate of a filter sample: a bin drawn by the magnitude of the filter in it, a point
+// in the bin, and the sign of the bin times the weight scale as its weight
+fn samplePixelFilter1D(u: f32) -> vec2f {
+    var cdf = 0.0;
+    var i = 0u;
+    // the last bin takes whatever is left over from rounding
+    for (; i < PIXEL_FILTER_BINS - 1u; i++) {
+        let p = abs(pixel_filter.bins[i / 4u][i % 4u]);
+        if u < cdf + p {
+            break;
+        }
+        cdf += p;
+    }
+    let bin = pixel_filter.bins[i / 4u][i % 4u];
+    let t = saturate((u - cdf) / max(abs(bin), 1e-8));
+    let x = pixel_filter.radius * (2.0 * (f32(i) + t) / f32(PIXEL_FILTER_BINS) - 1.0);
+    return vec2(x, select(-1.0, 1.0, bin >= 0.0) * pixel_filter.weight_scale);
+}
+
+// filter importance sampling: the camera rays are spread over the pixel's neighbourhood like
+// the (separable) filter, so the image stays a plain average of weighted samples
+fn samplePixelFilter(state: ptr<function, Sampler>) -> FilterSample {
+    let u = rngNext2D(state);
+    let x = samplePixelFilter1D(u.x);
+    let y = samplePixelFilter1D(u.y);
+    return FilterSample(vec2(x.x, y.x), x.y * y.y);
+}
+
+// pixel_offset is where the ray goes through relative to the center of the pixel
+fn getCameraRay(screen_pos: vec2u, image_size: vec2u, pixel_offset: vec2f, state: ptr<function, Sampler>) -> Ray {
+    // y is flipped since row 0 is the top of the image
+    let uv = (vec2f(screen_pos) + 0.5 + pixel_offset) / vec2f(image_size);
+    let ndc = vec2(2.0 * uv.x - 1.0, 1.0 - 2.0 * uv.y);
+
+    // take a point on the far plane back into camera space to get the ray direction
+    let target_point = inv_projection_matrix.invProj * vec4(ndc, 1.0, 1.0);
+    var direction = normalize(target_point.xyz / target_point.w);
+
+    // thin lens: start the ray somewhere on the lens and aim it at the point on the focus plane
+    let focus_point = direction * (camera.focus_distance / -direction.z);
+    let lens_point = camera.defocus_radius * rngNextVec3InUnitDisk(state);
+    direction = normalize(focus_point - lens_point);
+
+    // the view matrix maps +z_camera onto the look direction, whereas the projection
+    // looks down -z_camera, so z has to be flipped before going to world space
+    let flip = vec3(1.0, 1.0, -1.0);
+    let origin = view_matrix.view * vec4(flip * lens_point, 1.0);
+    let world_direction = view_matrix.view * vec4(flip * direction, 0.0);
+
+    return Ray(origin.xyz, normalize(world_direction.xyz));
+}
+
+fn skyColor(direction: vec3f) -> vec3f {
+    let a = 0.5 * (direction.y + 1.0);
+    return mix(vec3(1.0), vec3(0.5, 0.7, 1.0), a);
+}
+
+// closest hit along the ray closer than t_max; t is MISS if there is none
+fn traceRay(ray: Ray, t_max: f32) -> Intersection {
+    var closest = Intersection(t_max, 0u, vec2(0.0));
+    for (var i = 0u; i < arrayLength(&spheres); i++) {
+        let sphere = spheres[i];
+        var t = hitSphere(sphere, ray, EPSILON, closest.t);
+        // the ray can pass through the near side and still hit the far one
+        let primitive = i | SPHERE_PRIMITIVE;
+        if t != MISS && !coversHit(ray, Intersection(t, primitive, vec2(0.0)), sphere.material_id) {
+            t = hitSphere(sphere, ray, t, closest.t);
+            if t != MISS && !coversHit(ray, Intersection(t, primitive, vec2(0.0)), sphere.material_id) {
+                t = MISS;
+            }
+        }
+        if t != MISS {
+            closest = Intersection(t, primitive, vec2(0.0));
+        }
+    }
+    hitBvh(ray, EPSILON, &closest);
+
+    if closest.t >= t_max {
+        closest.t = MISS;
+    }
+    return closest;
+}
+
+fn isOccluded(ray: Ray, t_max: f32) -> bool {
+    return traceRay(ray, t_max).t != MISS;
+}
+
+// whether a hit stops the ray. The coverage is the opacity, times the alpha of the base color
+// texture unless the material is opaque. A masked material is there where the coverage
+// reaches the cutoff; otherwise that share of the rays stops, picked by a hash of the ray and
+// t, so the traversal stays free of sampler dimensions and a hit that is passed through never
+// gets shaded
+fn coversHit(ray: Ray, intersection: Intersection, material_id: u32) -> bool {
+    let material = materials[material_id];
+    var coverage = material.opacity;
+    if material.alpha_mode != ALPHA_MODE_OPAQUE && material.base_color_texture != NO_TEXTURE {
+        coverage *= sampleTexture(material.base_color_texture, intersectionUv(ray, intersection)).a;
+    }
+    if material.alpha_mode == ALPHA_MODE_MASK {
+        return coverage >= material.alpha_cutoff;
+    }
+    if coverage >= 1.0 {
+        return true;
+    }
+    var hash = jenkinsHash(bitcast<u32>(intersection.t));
+    hash = hashCombine(hash, bitcast<u32>(ray.origin.x));
+    hash = hashCombine(hash, bitcast<u32>(ray.origin.y));
+    hash = hashCombine(hash, bitcast<u32>(ray.origin.z));
+    hash = hashCombine(hash, bitcast<u32>(ray.direction.x));
+    hash = hashCombine(hash, bitcast<u32>(ray.direction.y));
+    hash = hashCombine(hash, bitcast<u32>(ray.direction.z));
+    return uintToFloat(hash) < coverage;
+}
+
+// longitude and latitude, with v = 0 at the top of the sphere
+fn sphereUv(outward_normal: vec3f) -> vec2f {
+    return vec2(atan2(-outward_normal.z, outward_normal.x) + PI, acos(outward_normal.y)) * vec2(0.5 * FRAC_1_PI, FRAC_1_PI);
+}
+
+// the uv of a hit on its own, for the alpha test during traversal
+fn intersectionUv(ray: Ray, intersection: Intersection) -> vec2f {
+    if (intersection.primitive & SPHERE_PRIMITIVE) != 0u {
+        let sphere = spheres[intersection.primitive & ~SPHERE_PRIMITIVE];
+        return sphereUv((ray.origin + intersection.t * ray.direction - sphere.center) / sphere.radius);
+    }
+    let triangle = triangles[intersection.primitive];
+    let v0 = vertices[triangle.indices.x];
+    let v1 = vertices[triangle.indices.y];
+    let v2 = vertices[triangle.indices.z];
+    let b1 = intersection.barycentrics.x;
+    let b2 = intersection.barycentrics.y;
+    return (1.0 - b1 - b2) * vec2(v0.u, v0.v) + b1 * vec2(v1.u, v1.v) + b2 * vec2(v2.u, v2.v);
+}
+
+fn surfaceInteraction(ray: Ray, intersection: Intersection) -> HitRecord {
+    var hit: HitRecord;
+    hit.t = intersection.t;
+    hit.primitive = intersection.primitive;
+    hit.position = ray.origin + intersection.t * ray.direction;
+
+    if (intersection.primitive & SPHERE_PRIMITIVE) != 0u {
+        let sphere = spheres[intersection.primitive & ~SPHERE_PRIMITIVE];
+        let outward_normal = (hit.position - sphere.center) / sphere.radius;
+        hit.front_face = dot(ray.direction, outward_normal) < 0.0;
+        hit.normal = select(-outward_normal, outward_normal, hit.front_face);
+        hit.geometric_normal = hit.normal;
+        hit.uv = sphereUv(outward_normal);
+        hit.tangent = vec3(outward_normal.z, 0.0, -outward_normal.x);
+        applyMaterial(&hit, sphere.material_id);
+        return hit;
+    }
+
+    let triangle = triangles[intersection.primitive];
+    let v0 = vertices[triangle.indices.x];
+    let v1 = vertices[triangle.indices.y];
+    let v2 = vertices[triangle.indices.z];
+    let b1 = intersection.barycentrics.x;
+    let b2 = intersection.barycentrics.y;
+    let b0 = 1.0 - b1 - b2;
+
+    let geometric_normal = normalize(cross(v1.position - v0.position, v2.position - v0.position));
+    hit.front_face = dot(ray.direction, geometric_normal) < 0.0;
+    let oriented_normal = select(-geometric_normal, geometric_normal, hit.front_face);
+    hit.geometric_normal = oriented_normal;
+
+    // interpolate the vertex normals if the mesh has them, keeping them on the side of the
+    // surface the ray arrived from
+    var normal = b0 * v0.normal + b1 * v1.normal + b2 * v2.normal;
+    if dot(normal, normal) > 0.0 {
+        normal = normalize(normal);
+        normal = select(-normal, normal, dot(normal, oriented_normal) > 0.0);
+    } else {
+        normal = oriented_normal;
+    }
+
+    hit.normal = normal;
+    let uv0 = vec2(v0.u, v0.v);
+    let uv1 = vec2(v1.u, v1.v);
+    let uv2 = vec2(v2.u, v2.v);
+    hit.uv = b0 * uv0 + b1 * uv1 + b2 * uv2;
+
+    // dp/du from the edges of the triangle and their uv differences
+    let duv1 = uv1 - uv0;
+    let duv2 = uv2 - uv0;
+    let uv_det = duv1.x * duv2.y - duv1.y * duv2.x;
+    if abs(uv_det) > 1e-12f {
+        hit.tangent = (duv2.y * (v1.position - v0.position) - duv1.y * (v2.position - v0.position)) / uv_det;
+    }
+    applyMaterial(&hit, triangle.material_id);
+    return hit;
+}
+
+// the features of a camera ray that hit; one that escapes has none
+fn primaryFeatures(ray: Ray, hit: HitRecord) -> PixelFeatures {
+    return PixelFeatures(hit.material.base_color, distance(ray.origin, hit.position), hit.normal);
+}
+
+// the sphere or mesh a hit is on, counted from 1
+fn objectId(hit: HitRecord) -> u32 {
+    if (hit.primitive & SPHERE_PRIMITIVE) != 0u {
+        return spheres[hit.primitive & ~SPHERE_PRIMITIVE].object_id;
+    }
+    return object_ids[hit.primitive];
+}
+
+fn applyMaterial(hit: ptr<function, HitRecord>, material_id: u32) {
+    var material = materials[material_id];
+    let uv = (*hit).uv;
+    if material.base_color_texture != NO_TEXTURE {
+        material.base_color *= sampleTexture(material.base_color_texture, uv).rgb;
+    }
+    // roughness in green and metallic in blue, as in glTF
+    if material.metallic_roughness_texture != NO_TEXTURE {
+        let metallic_roughness = sampleTexture(material.metallic_roughness_texture, uv);
+        material.roughness *= metallic_roughness.g;
+        material.metallic *= metallic_roughness.b;
+    }
+    if material.emission_texture != NO_TEXTURE {
+        material.emission *= sampleTexture(material.emission_texture, uv).rgb;
+    }
+    (*hit).material_id = material_id;
+    (*hit).material = material;
+}
+
+fn srgbToLinear(c: vec3f) -> vec3f {
+    let low = c / 12.92;
+    let high = pow((c + 0.055) / 1.055, vec3(2.4));
+    return select(high, low, c <= vec3(0.04045));
+}
+
+fn textureTexel(texture: Texture, x: u32, y: u32) -> vec4f {
+    let texel = unpack4x8unorm(texels[texture.offset + y * texture.width + x]);
+    if texture.srgb == 0u {
+        return texel;
+    }
+    return vec4(srgbToLinear(texel.rgb), texel.a);
+}
+
+// bilinear filtering with the uvs wrapping around
+fn sampleTexture(texture_id: u32, uv: vec2f) -> vec4f {
+    let texture = textures[texture_id];
+    let size = vec2f(f32(texture.width), f32(texture.height));
+    let st = fract(uv) * size - 0.5;
+    let f = fract(st);
+    let base = vec2i(floor(st));
+    let w = i32(texture.width);
+    let h = i32(texture.height);
+    let x0 = u32((base.x % w + w) % w);
+    let y0 = u32((base.y % h + h) % h);
+    let x1 = (x0 + 1u) % texture.width;
+    let y1 = (y0 + 1u) % texture.height;
+
+    let top = mix(textureTexel(texture, x0, y0), textureTexel(texture, x1, y0), f.x);
+    let bottom = mix(textureTexel(texture, x0, y1), textureTexel(texture, x1, y1), f.x);
+    return mix(top, bottom, f.y);
+}
+
+// returns the distance to the nearest hit in (t_min, t_max) or MISS
+fn hitSphere(sphere: Sphere, ray: Ray, t_min: f32, t_max: f32) -> f32 {
+    // zero radius spheres are only there to pad an empty sphere buffer
+    if sphere.radius <= 0.0 {
+        return MISS;
+    }
+
+    let oc = sphere.center - ray.origin;
+    let a = dot(ray.direction, ray.direction);
+    let h = dot(ray.direction, oc);
+    let c = dot(oc, oc) - sphere.radius * sphere.radius;
+    let discriminant = h * h - a * c;
+    if discriminant < 0.0 {
+        return MISS;
+    }
+
+    // find the nearest root in the acceptable range
+    let sqrt_d = sqrt(discriminant);
+    var root = (h - sqrt_d) / a;
+    if root <= t_min || root >= t_max {
+        root = (h + sqrt_d) / a;
+        if root <= t_min || root >= t_max {
+            return MISS;
+        }
+    }
+    return root;
+}
+
+// Moeller-Trumbore ray/triangle intersection; only updates the intersection if the
+// triangle is hit closer than the intersection's current t
+fn hitTriangle(triangle_index: u32, ray: Ray, t_min: f32, intersection: ptr<function, Intersection>) -> bool {
+    let triangle = triangles[triangle_index];
+    let p0 = vertices[triangle.indices.x].position;
+    let p1 = vertices[triangle.indices.y].position;
+    let p2 = vertices[triangle.indices.z].position;
+
+    let e1 = p1 - p0;
+    let e2 = p2 - p0;
+    let p = cross(ray.direction, e2);
+    let det = dot(e1, p);
+    // also rejects the degenerate triangles used to pad an empty triangle buffer
+    if abs(det) < 1e-12f {
+        return false;
+    }
+
+    let inv_det = 1.0 / det;
+    let s = ray.origin - p0;
+    let b1 = dot(s, p) * inv_det;
+    if b1 < 0.0 || b1 > 1.0 {
+        return false;
+    }
+
+    let q = cross(s, e1);
+    let b2 = dot(ray.direction, q) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return false;
+    }
+
+    let t = dot(e2, q) * inv_det;
+    if t <= t_min || t >= (*intersection).t {
+        return false;
+    }
+    let hit = Intersection(t, triangle_index, vec2(b1, b2));
+    if !coversHit(ray, hit, triangle.material_id) {
+        return false;
+    }
+
+    *intersection = hit;
+    return true;
+}
+
+fn hitBvh(ray: Ray, t_min: f32, closest: ptr<function, Intersection>) {
+    let inv_direction = 1.0 / ray.direction;
+
+    if intersectAabb(bvh_nodes[0], ray, inv_direction, (*closest).t) == MISS {
+        return;
+    }
+
+    var node_index = 0u;
+    var stack_pointer = 0u;
+    loop {
+        let node = bvh_nodes[node_index];
+        if node.triangle_count > 0u {
+            for (var i = node.left_first; i < node.left_first + node.triangle_count; i++) {
+                hitTriangle(i, ray, t_min, closest);
+            }
+            if stack_pointer == 0u {
+                break;
+            }
+            stack_pointer -= 1u;
+            node_index = bvh_stack[bvh_stack_offset + stack_pointer];
+            continue;
+        }
+
+        // visit the nearer child first and keep the other one for later
+        var near_index = node.left_first;
+        var far_index = node.left_first + 1u;
+        var t_near = intersectAabb(bvh_nodes[near_index], ray, inv_direction, (*closest).t);
+        var t_far = intersectAabb(bvh_nodes[far_index], ray, inv_direction, (*closest).t);
+        if t_far < t_near {
+            let t = t_near;
+            t_near = t_far;
+            t_far = t;
+            near_index = far_index;
+            far_index = node.left_first;
+        }
+
+        if t_near == MISS {
+            if stack_pointer == 0u {
+                break;
+            }
+            stack_pointer -= 1u;
+            node_index = bvh_stack[bvh_stack_offset + stack_pointer];
+        } else {
+            node_index = near_index;
+            if t_far != MISS {
+                bvh_stack[bvh_stack_offset + stack_pointer] = far_index;
+                stack_pointer += 1u;
+            }
+        }
+    }
+}
+
+// slab test; returns the distance to the box or MISS
+fn intersectAabb(node: BvhNode, ray: Ray, inv_direction: vec3f, t_max: f32) -> f32 {
+    let t0 = (node.aabb_min - ray.origin) * inv_direction;
+    let t1 = (node.aabb_max - ray.origin) * inv_direction;
+    let t_enter = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z));
+    let t_exit = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z));
+    if t_exit >= t_enter && t_exit > 0.0 && t_enter < t_max {
+        return t_enter;
+    }
+    return MISS;
+}
+
+// builds an orthonormal basis with n as the z axis (Duff et al. 2017)
+fn onbFromNormal(n: vec3f) -> mat3x3f {
+    let s = select(-1.0, 1.0, n.z >= 0.0);
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+    let t = vec3(1.0 + s * n.x * n.x * a, s * b, -s * n.x);
+    let bt = vec3(b, s + n.y * n.y * a, -n.y);
+    return mat3x3f(t, bt, n);
+}
+
+// a uniform direction in the cone around axis, given by 1 - cos of its half angle so that
+// narrow cones like the sun don't lose all their precision
+fn rngNextInCone(axis: vec3f, one_minus_cos_max: f32, state: ptr<function, Sampler>) -> vec3f {
+    let u = rngNext2D(state);
+    let one_minus_cos_theta = u.x * one_minus_cos_max;
+    let cos_theta = 1.0 - one_minus_cos_theta;
+    let sin_theta = sqrt(max(0.0, one_minus_cos_theta * (2.0 - one_minus_cos_theta)));
+    let phi = 2.0 * PI * u.y;
+    let local_direction = vec3(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+    return normalize(onbFromNormal(axis) * local_direction);
+}
+
+fn rngNextInCosineWeightedHemisphere(state: ptr<function, Sampler>) -> vec3<f32> {
+    // project a uniform point in the unit disk up onto the hemisphere (Malley's method)
+    let p = rngNextVec3InUnitDisk(state);
+    let z = sqrt(max(0.0, 1.0 - p.x * p.x - p.y * p.y));
+    return vec3(p.x, p.y, z);
+}
+
+fn rngNextVec3InUnitDisk(state: ptr<function, Sampler>) -> vec3<f32> {
+    // r^2 is distributed as U(0, 1).
+    let u = rngNext2D(state);
+    let r = sqrt(u.x);
+    let alpha = 2.0 * PI * u.y;
+
+    let x = r * cos(alpha);
+    let y = r * sin(alpha);
+
+    return vec3(x, y, 0.0);
+}
+
+fn rngNextFloat(state: ptr<function, Sampler>) -> f32 {
+    let dimension = (*state).dimension;
+    (*state).dimension += 1u;
+    return samplerValue(state, dimension);
+}
+
+// two numbers that are stratified against each other; they start at an even dimension, so
+// that they are the two dimensions of one Sobol pair or one set of strata
+fn rngNext2D(state: ptr<function, Sampler>) -> vec2f {
+    let dimension = ((*state).dimension + 1u) & ~1u;
+    (*state).dimension = dimension + 2u;
+    return vec2(samplerValue(state, dimension), samplerValue(state, dimension + 1u));
+}
+
+// the top 24 bits, which an f32 holds exactly, so the result stays below 1
+fn uintToFloat(x: u32) -> f32 {
+    return f32(x >> 8u) * 5.9604645e-8f;
+}
+
+// PCG-RXS-M-XS: the state only advances the LCG, and the output function makes the number
+fn pcgNextFloat(state: ptr<function, Sampler>) -> f32 {
+    let oldState = (*state).rng * 747796405u + 2891336453u;
+    (*state).rng = oldState;
+    let word = ((oldState >> ((oldState >> 28u) + 4u)) ^ oldState) * 277803737u;
+    let next_int = (word >> 22u) ^ word;
+    return f32(next_int) * 2.3283064365387e-10f;  // / f32(0xffffffffu - 1f);
+}
+
+// the random numbers of one sample of a pixel. The independent sampler draws everything from
+// a PCG stream. The others give every dimension of the sample its own number, so each
+// dimension spreads evenly over the samples of the pixel: the Sobol sampler shuffles and
+// scrambles a two dimensional Sobol sequence for every pair of dimensions, and the
+// stratified sampler jitters within strata, which are only all covered once the pixel has
+// samples_per_pixel samples. The blue noise sampler spreads the numbers evenly over
+// neighboring pixels as well
+struct Sampler {
+    // the PCG state of the independent sampler, and for the jitter within strata
+    rng: u32,
+    // which sample of the pixel this is
+    index: u32,
+    // the next dimension to draw
+    dimension: u32,
+    // a hash of the pixel, so that neighboring pixels don't get the same sequence
+    seed: u32,
+    pixel: vec2u,
+}
+
+fn initSampler(pixel: vec2<u32>, resolution: vec2<u32>, sample_index: u32) -> Sampler {
+    let seed = jenkinsHash(dot(pixel, vec2<u32>(1u, resolution.x)) + 1u);
+    return Sampler(initRng(pixel, resolution, sample_index), sample_index, 0u, seed, pixel);
+}
+
+// the first dimension of a part of a bounce, e.g. SAMPLER_BSDF_DIMENSION
+fn samplerStartDimension(state: ptr<function, Sampler>, bounce: u32, offset: u32) {
+    (*state).dimension = SAMPLER_CAMERA_DIMENSIONS + bounce * SAMPLER_BOUNCE_DIMENSIONS + offset;
+}
+
+fn samplerValue(state: ptr<function, Sampler>, dimension: u32) -> f32 {
+    switch sampling_parameters.sampler_type {
+        case SAMPLER_SOBOL: {
+            return sobolSample((*state).index, dimension, (*state).seed);
+        }
+        case SAMPLER_STRATIFIED: {
+            return stratifiedSample(state, dimension);
+        }
+        case SAMPLER_BLUE_NOISE: {
+            return blueNoiseSample((*state).pixel, (*state).index, dimension);
+        }
+        default: {
+            return pcgNextFloat(state);
+        }
+    }
+}
+
+fn hashCombine(seed: u32, value: u32) -> u32 {
+    return jenkinsHash(seed ^ (value * 0x9e3779b9u));
+}
+
+// Owen scrambling, after Burley ("Practical Hash-based Owen Scrambling", 2020): every bit is
+// flipped depending on the bits above it, by a hash that only propagates upwards on the
+// reversed bits
+fn laineKarrasPermutation(value: u32, seed: u32) -> u32 {
+    var x = value + seed;
+    x ^= x * 0x6c50b47cu;
+    x ^= x * 0xb82f1e52u;
+    x ^= x * 0xc7afe638u;
+    x ^= x * 0x8d22f6e6u;
+    return x;
+}
+
+fn nestedUniformScramble(value: u32, seed: u32) -> u32 {
+    return reverseBits(laineKarrasPermutation(reverseBits(value), seed));
+}
+
+// the first two dimensions of the Sobol sequence: the van der Corput sequence, and the one
+// whose direction numbers are the rows of Pascal's triangle mod 2
+fn sobol(index: u32, dimension: u32) -> u32 {
+    if dimension == 0u {
+        return reverseBits(index);
+    }
+    var result = 0u;
+    var direction = 0x80000000u;
+    for (var i = index; i != 0u; i >>= 1u) {
+        if (i & 1u) != 0u {
+            result ^= direction;
+        }
+        direction ^= direction >> 1u;
+    }
+    return result;
+}
+
+// every pair of dimensions shuffles the samples differently, so that the pairs don't correlate
+fn sobolSample(index: u32, dimension: u32, seed: u32) -> f32 {
+    let pair_seed = hashCombine(seed, dimension >> 1u);
+    let shuffled_index = nestedUniformScramble(index, pair_seed);
+    let value = sobol(shuffled_index, dimension & 1u);
+    return uintToFloat(nestedUniformScramble(value, hashCombine(pair_seed, dimension & 1u)));
+}
+
+// a pair of dimensions splits the square into n by n strata, with n the largest that
+// samples_per_pixel fills, and visits them in an order that is shuffled per pixel and pair.
+// each of the two dimensions takes one coordinate of the stratum
+fn stratifiedSample(state: ptr<function, Sampler>, dimension: u32) -> f32 {
+    let n = max(1u, u32(sqrt(f32(sampling_parameters.samples_per_pixel))));
+    let strata = n * n;
+    let pass_seed = hashCombine(hashCombine((*state).seed, dimension >> 1u), (*state).index / strata);
+    let stratum = permute((*state).index % strata, strata, pass_seed);
+    let coordinate = select(stratum / n, stratum % n, (dimension & 1u) == 0u);
+    return (f32(coordinate) + pcgNextFloat(state)) / f32(n);
+}
+
+// every dimension looks the blue noise mask up with an offset of its own, which makes the
+// first sample of the pixels blue noise over the image. Later samples step away from it
+// along the R2 sequence (Roberts 2018), a rank-1 lattice, so every sample stays blue noise
+// over the image and the samples of a pixel stay evenly spread. The steps are in 0.32 fixed
+// point, since an f32 would lose the fraction after a few thousand samples
+fn blueNoiseSample(pixel: vec2u, index: u32, dimension: u32) -> f32 {
+    let offset_hash = jenkinsHash(dimension + 1u);
+    let offset = vec2(offset_hash, offset_hash >> 16u) % BLUE_NOISE_SIZE;
+    let texel = (pixel + offset) % BLUE_NOISE_SIZE;
+    let value = u32(blue_noise[texel.y * BLUE_NOISE_SIZE + texel.x] * 4294967296.0);
+    // 1 / g and 1 / g^2 for the plastic number g
+    let step = select(0x91e10da6u, 0xc13fa9a9u, (dimension & 1u) == 0u);
+    return uintToFloat(value + index * step);
+}
+
+// a pseudorandom permutation of 0..length - 1 picked by seed (Kensler, "Correlated
+// Multi-Jittered Sampling", 2013)
+fn permute(index: u32, length: u32, seed: u32) -> u32 {
+    var w = length - 1u;
+    w |= w >> 1u;
+    w |= w >> 2u;
+    w |= w >> 4u;
+    w |= w >> 8u;
+    w |= w >> 16u;
+    var i = index;
+    loop {
+        i ^= seed;
+        i *= 0xe170893du;
+        i ^= seed >> 16u;
+        i ^= (i & w) >> 4u;
+        i ^= seed >> 8u;
+        i *= 0x0929eb3fu;
+        i ^= seed >> 23u;
+        i ^= (i & w) >> 1u;
+        i *= 1u | seed >> 27u;
+        i *= 0x6935fa69u;
+        i ^= (i & w) >> 11u;
+        i *= 0x74dcb303u;
+        i ^= (i & w) >> 2u;
+        i *= 0x9e501cc3u;
+        i ^= (i & w) >> 2u;
+        i *= 0xc860a3dfu;
+        i &= w;
+        i ^= i >> 5u;
+        if i < length {
+            break;
+        }
+    }
+    return (i + seed) % length;
+}
+
+fn initRng(pixel: vec2<u32>, resolution: vec2<u32>, frame: u32) -> u32 {
+    let seed = dot(pixel, vec2<u32>(1u, resolution.x)) ^ jenkinsHash(frame);
+    return jenkinsHash(seed);
+}
+
+fn jenkinsHash(input: u32) -> u32 {
+    var x = input;
+    x += x << 10u;
+    x ^= x >> 6u;
+    x += x << 3u;
+    x ^= x >> 11u;
+    x += x << 15u;
+    return x;
+}
\ No newline at end of file
@@ -1,5 +1,13 @@
 @group(0) @binding(0) var<storage, read> image_buffer: array<array<f32, 3>>;
 @group(0) @binding(1) var<uniform> frame_buffer: FrameBuffer;
+@group(0) @binding(2) var<storage, read> pixel_stats: array<PixelStats>;
+@group(0) @binding(3) var<uniform> display_parameters: DisplayParameters;
+// the averaged image after the denoiser, with its variance in w
+@group(0) @binding(4) var<storage, read> denoised_buffer: array<vec4f>;
+// the AOVs the kernels write in planes (see path_tracing_common.wgsl) and the features of the
+// first hits, which the rest of the AOVs come from
+@group(0) @binding(5) var<storage, read> aov_buffer: array<f32>;
+@group(0) @binding(6) var<storage, read> pixel_features: array<PixelFeatures>;
 
 struct FrameBuffer {
     width: u32,
@@ -8,6 +16,57 @@ struct FrameBuffer {
     accumulated_samples: u32
 }
 
+struct PixelStats {
+    sample_count: u32,
+    luminance_squared: f32,
+    sample_luminance: f32,
+}
+
+struct DisplayParameters {
+    exposure_scale: f32,
+    tone_mapper: u32,
+    sample_heatmap: u32,
+    denoised: u32,
+    aov: u32,
+}
+
+struct PixelFeatures {
+    albedo: vec3f,
+    depth: f32,
+    normal: vec3f,
+}
+
+// the values of Aov on the Rust side, 0 being the image
+const AOV_ALBEDO = 1u;
+const AOV_SHADING_NORMAL = 2u;
+const AOV_GEOMETRIC_NORMAL = 3u;
+const AOV_DEPTH = 4u;
+const AOV_OBJECT_ID = 5u;
+const AOV_MATERIAL_ID = 6u;
+const AOV_EMISSION = 7u;
+const AOV_DIRECT_DIFFUSE = 8u;
+const AOV_DIRECT_SPECULAR = 9u;
+const AOV_INDIRECT_DIFFUSE = 10u;
+const AOV_INDIRECT_SPECULAR = 11u;
+const AOV_SAMPLE_COUNT = 12u;
+
+// where the AOVs start in the aov buffer, in planes of one value per pixel
+const PLANE_GEOMETRIC_NORMAL = 0u;
+const PLANE_OBJECT_ID = 3u;
+const PLANE_MATERIAL_ID = 4u;
+const PLANE_EMISSION = 5u;
+const PLANE_DIRECT_DIFFUSE = 8u;
+const PLANE_DIRECT_SPECULAR = 11u;
+const PLANE_INDIRECT_DIFFUSE = 14u;
+const PLANE_INDIRECT_SPECULAR = 17u;
+
+// the values of ToneMapper on the Rust side
+const TONE_MAPPER_CLAMP = 0u;
+const TONE_MAPPER_REINHARD = 1u;
+const TONE_MAPPER_ACES_FITTED = 2u;
+const TONE_MAPPER_AGX = 3u;
+const TONE_MAPPER_PBR_NEUTRAL = 4u;
+
 struct VertexOutput {
     @builtin(position) Position: vec4<f32>,
     @location(0) TexCoord: vec2<f32>,
@@ -41,15 +100,189 @@ fn vs(
     return output;
 }
 
+// Stephen Hill's fit of the ACES reference rendering and output transforms
+fn acesFitted(color: vec3f) -> vec3f {
+    // sRGB to the ACES rendering space with the RRT's saturation tweak, and back
+    let input = mat3x3f(
+        0.59719, 0.07600, 0.02840,
+        0.35458, 0.90834, 0.13383,
+        0.04823, 0.01566, 0.83777,
+    );
+    let output = mat3x3f(
+        1.60475, -0.10208, -0.00327,
+        -0.53108, 1.10813, -0.07276,
+        -0.07367, -0.00605, 1.07602,
+    );
+    let v = input * color;
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.432951) + 0.238081;
+    return clamp(output * (a / b), vec3(0.0), vec3(1.0));
+}
+
+// Troy Sobotka's AgX, after the minimal polynomial version by Benjamin Wrensch
+fn agx(color: vec3f) -> vec3f {
+    let inset = mat3x3f(
+        0.8424791, 0.04232824, 0.04237565,
+        0.0784336, 0.8784686, 0.0784336,
+        0.07922375, 0.07916613, 0.879143,
+    );
+    let outset = mat3x3f(
+        1.196879, -0.05289685, -0.05297164,
+        -0.09802088, 1.151903, -0.09804345,
+        -0.09902974, -0.09896118, 1.151074,
+    );
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+
+    // log encoded between the two exposures, then through the sigmoid
+    let x = (clamp(log2(max(inset * color, vec3(1e-10))), vec3(min_ev), vec3(max_ev)) - min_ev) / (max_ev - min_ev);
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let curve = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+    // the curve comes out with a gamma of 2.2 built in
+    return min(pow(max(outset * curve, vec3(0.0)), vec3(2.2)), vec3(1.0));
+}
+
+// the Khronos PBR Neutral tone mapper
+fn pbrNeutral(color: vec3f) -> vec3f {
+    let start_compression = 0.8 - 0.04;
+    let desaturation = 0.15;
+
+    let x = min(color.r, min(color.g, color.b));
+    let offset = select(0.04, x - 6.25 * x * x, x < 0.08);
+    var c = color - offset;
+
+    let peak = max(c.r, max(c.g, c.b));
+    if peak < start_compression {
+        return c;
+    }
+
+    let d = 1.0 - start_compression;
+    let new_peak = 1.0 - d * d / (peak + d - start_compression);
+    c *= new_peak / peak;
+    let g = 1.0 - 1.0 / (desaturation * (peak - new_peak) + 1.0);
+    return mix(c, vec3(new_peak), g);
+}
+
+// from linear radiance to linear display values in [0, 1]; a twin of ToneMapper::apply in
+// display_parameters.rs, and the two have to be kept in sync
+fn toneMap(radiance: vec3f) -> vec3f {
+    let color = max(radiance, vec3(0.0));
+    switch display_parameters.tone_mapper {
+        case TONE_MAPPER_REINHARD: {
+            return color / (1.0 + color);
+        }
+        case TONE_MAPPER_ACES_FITTED: {
+            return acesFitted(color);
+        }
+        case TONE_MAPPER_AGX: {
+            return agx(color);
+        }
+        case TONE_MAPPER_PBR_NEUTRAL: {
+            return pbrNeutral(color);
+        }
+        default: {
+            return min(color, vec3(1.0));
+        }
+    }
+}
+
+// the display pass renders into a view without the sRGB suffix, so the encoding happens here
+fn srgbOetf(color: vec3f) -> vec3f {
+    return select(1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055, 12.92 * color, color <= vec3(0.0031308));
+}
+
+// blue through green to red as t goes from 0 to 1
+fn heatmap(t: f32) -> vec3f {
+    let x = clamp(t, 0.0, 1.0);
+    return clamp(vec3(4.0 * x - 2.0, 2.0 - abs(4.0 * x - 2.0), 2.0 - 4.0 * x), vec3(0.0), vec3(1.0));
+}
+
+fn aovPlanes(idx: u32, plane: u32) -> vec3f {
+    let pixel_count = frame_buffer.width * frame_buffer.height;
+    return vec3(aov_buffer[plane * pixel_count + idx],
+                aov_buffer[(plane + 1u) * pixel_count + idx],
+                aov_buffer[(plane + 2u) * pixel_count + idx]);
+}
+
+// a color per id that sets neighbouring ids apart, black for the background
+fn idColor(id: f32) -> vec3f {
+    if id == 0.0 {
+        return vec3(0.0);
+    }
+    var h = u32(id) * 747796405u + 2891336453u;
+    h = ((h >> ((h >> 28u) + 4u)) ^ h) * 277803737u;
+    h = (h >> 22u) ^ h;
+    return 0.2 + 0.8 * vec3f(vec3(h & 0xffu, (h >> 8u) & 0xffu, (h >> 16u) & 0xffu)) / 255.0;
+}
+
+// the AOVs shown in the viewport: lighting like the image, colors as they are, normals mapped
+// from [-1, 1] and depth squeezed into [0, 1]
+fn aovColor(idx: u32, invN: f32, sample_count: u32) -> vec3f {
+    let pixel_count = frame_buffer.width * frame_buffer.height;
+    let features = pixel_features[idx];
+    switch display_parameters.aov {
+        case AOV_ALBEDO: {
+            return srgbOetf(clamp(invN * features.albedo, vec3(0.0), vec3(1.0)));
+        }
+        case AOV_SHADING_NORMAL: {
+            return 0.5 * invN * features.normal + 0.5;
+        }
+        case AOV_GEOMETRIC_NORMAL: {
+            return 0.5 * invN * aovPlanes(idx, PLANE_GEOMETRIC_NORMAL) + 0.5;
+        }
+        case AOV_DEPTH: {
+            let depth = invN * features.depth;
+            return vec3(depth / (1.0 + depth));
+        }
+        case AOV_OBJECT_ID: {
+            return idColor(aov_buffer[PLANE_OBJECT_ID * pixel_count + idx]);
+        }
+        case AOV_MATERIAL_ID: {
+            return idColor(aov_buffer[PLANE_MATERIAL_ID * pixel_count + idx]);
+        }
+        case AOV_SAMPLE_COUNT: {
+            return heatmap(f32(sample_count) / f32(max(frame_buffer.accumulated_samples, 1u)));
+        }
+        default: {
+            var plane = PLANE_EMISSION;
+            switch display_parameters.aov {
+                case AOV_DIRECT_DIFFUSE: { plane = PLANE_DIRECT_DIFFUSE; }
+                case AOV_DIRECT_SPECULAR: { plane = PLANE_DIRECT_SPECULAR; }
+                case AOV_INDIRECT_DIFFUSE: { plane = PLANE_INDIRECT_DIFFUSE; }
+                case AOV_INDIRECT_SPECULAR: { plane = PLANE_INDIRECT_SPECULAR; }
+                default: {}
+            }
+            return srgbOetf(toneMap(display_parameters.exposure_scale * invN * aovPlanes(idx, plane)));
+        }
+    }
+}
+
 @fragment
 fn fs(@location(0) TexCoord: vec2<f32>) -> @location(0) vec4<f32> {
     let x = u32(TexCoord.x * f32(frame_buffer.width));
     let y = u32(TexCoord.y * f32(frame_buffer.height));
     let idx = x + y * frame_buffer.width;
 
-    let invN = 1.0 / f32(frame_buffer.accumulated_samples);
-    var color = vec3(image_buffer[idx][0], image_buffer[idx][1], image_buffer[idx][2]);
-    color = sqrt(invN * color);
+    // with adaptive sampling the pixels don't all have the same number of samples
+    let sample_count = pixel_stats[idx].sample_count;
+    let invN = 1.0 / f32(max(sample_count, 1u));
+    var radiance = invN * vec3(image_buffer[idx][0], image_buffer[idx][1], image_buffer[idx][2]);
+    if display_parameters.denoised != 0u {
+        radiance = denoised_buffer[idx].xyz;
+    }
+    var color = srgbOetf(toneMap(display_parameters.exposure_scale * radiance));
+    if display_parameters.aov != 0u {
+        color = aovColor(idx, invN, sample_count);
+    }
+
+    // the samples of the pixel relative to the most any pixel can have, shaded by the image
+    // underneath so the scene stays recognizable
+    if display_parameters.sample_heatmap != 0u {
+        let heat = heatmap(f32(sample_count) / f32(max(frame_buffer.accumulated_samples, 1u)));
+        let shade = 0.25 + 0.75 * clamp(dot(color, vec3(0.2126, 0.7152, 0.0722)), 0.0, 1.0);
+        color = heat * shade;
+    }
 
     return vec4(color.xyz, 1.0);
-}
\ No newline at end of file
+}
@@ -0,0 +1,311 @@
+// appended to path_tracing_common.wgsl, bsdf.wgsl and lights.wgsl, which have the scene
+// bindings, the tracing functions, the materials and the light sampling
+//
+// the wavefront design splits the megakernel into one kernel per stage of a path. A fixed
+// pool of paths is pushed through the stages a batch of pixels at a time:
+//   resetQueues   starts the next batch of pixels
+//   generate      camera rays for the paths of the batch in tiles adaptive sampling keeps active
+//   extend        closest hit for every ray in the extension queue
+//   shade         adds the sky to escaped paths, scatters the others into the next extension queue
+//                 and pushes shadow rays into the shadow queue
+//   advanceQueues swaps the queues over and writes the indirect dispatch sizes for them
+//   connect       traces the shadow rays and adds the unoccluded contributions to the image
+// advanceQueues also runs after generate, and extend, shade, advanceQueues and connect run
+// once per bounce. The queue lengths are atomic
+// counters and the kernels are dispatched indirectly, so only live rays get a thread
+
+struct PathState {
+    throughput: vec3f,
+    rng_state: u32,
+    // whether the path got to its current ray through a delta lobe (or is a camera ray),
+    // and the pdf of the direction otherwise
+    delta_bounce: u32,
+    bsdf_pdf: f32,
+    // how much of the first scattering event was diffuse, for splitting up the light in the AOVs
+    first_diffuse: vec3f,
+}
+
+struct ExtensionRay {
+    origin: vec3f,
+    path_index: u32,
+    direction: vec3f,
+}
+
+struct ShadowRay {
+    origin: vec3f,
+    t_max: f32,
+    direction: vec3f,
+    path_index: u32,
+    contribution: vec3f,
+    // the number of scattering events the light takes to the camera and the diffuse part of
+    // the first one, see addPathLight
+    scatter_count: u32,
+    diffuse: vec3f,
+}
+
+struct Queues {
+    extension_ray_count: atomic<u32>,
+    shadow_ray_count: atomic<u32>,
+    // queue lengths frozen by advanceQueues for the kernels that consume them
+    active_ray_count: u32,
+    active_shadow_ray_count: u32,
+    bounce: u32,
+    // the batch of pixels currently in flight starts at first_pixel
+    first_pixel: u32,
+    next_pixel: u32,
+}
+
+struct DispatchArgs {
+    x: u32,
+    y: u32,
+    z: u32,
+}
+
+struct Dispatches {
+    rays: DispatchArgs,
+    shadow_rays: DispatchArgs,
+}
+
+@group(0) @binding(0) var<storage, read_write> image_buffer: array<array<f32, 3>>;
+@group(0) @binding(1) var<uniform> frame_buffer: FrameBuffer;
+@group(0) @binding(2) var<storage, read_write> paths: array<PathState>;
+// two halves: the rays of the current bounce and the extension rays for the next one
+@group(0) @binding(3) var<storage, read_write> rays: array<ExtensionRay>;
+@group(0) @binding(4) var<storage, read_write> intersections: array<Intersection>;
+@group(0) @binding(5) var<storage, read_write> shadow_rays: array<ShadowRay>;
+@group(0) @binding(6) var<storage, read_write> queues: Queues;
+// only bound for resetQueues and advanceQueues, since a buffer can't be written as storage
+// by the same dispatch that reads it as indirect arguments
+@group(0) @binding(7) var<storage, read_write> dispatches: Dispatches;
+@group(0) @binding(8) var<storage, read_write> pixel_stats: array<PixelStats>;
+// whether adaptive sampling keeps a tile active
+@group(0) @binding(9) var<storage, read> tile_flags: array<u32>;
+@group(0) @binding(10) var<storage, read_write> pixel_features: array<PixelFeatures>;
+// only written with aov_output set, see AOV_GEOMETRIC_NORMAL for the layout
+@group(0) @binding(11) var<storage, read_write> aov_buffer: array<f32>;
+
+fn dispatchSize(count: u32) -> DispatchArgs {
+    return DispatchArgs((count + WORKGROUP_INVOCATIONS - 1u) / WORKGROUP_INVOCATIONS, 1u, 1u);
+}
+
+// the number of paths in flight at once is set by the size of the path buffer
+fn pathPoolSize() -> u32 {
+    return arrayLength(&paths);
+}
+
+fn rayOffset(bounce: u32) -> u32 {
+    return (bounce & 1u) * pathPoolSize();
+}
+
+fn addToImage(path_index: u32, color: vec3f) {
+    let idx = queues.first_pixel + path_index;
+    image_buffer[idx][0] += color.x;
+    image_buffer[idx][1] += color.y;
+    image_buffer[idx][2] += color.z;
+    pixel_stats[idx].sample_luminance += luminance(color);
+}
+
+fn addToAovPlanes(idx: u32, plane: u32, value: vec3f) {
+    let pixel_count = frame_buffer.width * frame_buffer.height;
+    aov_buffer[plane * pixel_count + idx] += value.x;
+    aov_buffer[(plane + 1u) * pixel_count + idx] += value.y;
+    aov_buffer[(plane + 2u) * pixel_count + idx] += value.z;
+}
+
+// the light AOVs get every contribution to the image on its own
+fn addLightToAovs(path_index: u32, scatter_count: u32, radiance: vec3f, diffuse: vec3f) {
+    if sampling_parameters.aov_output == 0u {
+        return;
+    }
+    var aovs: SampleAovs;
+    addPathLight(&aovs, scatter_count, radiance, diffuse);
+    let idx = queues.first_pixel + path_index;
+    addToAovPlanes(idx, AOV_EMISSION, aovs.light.emission);
+    addToAovPlanes(idx, AOV_DIRECT_DIFFUSE, aovs.light.direct_diffuse);
+    addToAovPlanes(idx, AOV_DIRECT_SPECULAR, aovs.light.direct_specular);
+    addToAovPlanes(idx, AOV_INDIRECT_DIFFUSE, aovs.light.indirect_diffuse);
+    addToAovPlanes(idx, AOV_INDIRECT_SPECULAR, aovs.light.indirect_specular);
+}
+
+@compute @workgroup_size(1)
+fn resetQueues() {
+    let pixel_count = frame_buffer.width * frame_buffer.height;
+    if queues.next_pixel >= pixel_count {
+        queues.next_pixel = 0u;
+    }
+
+    queues.first_pixel = queues.next_pixel;
+    let path_count = min(pathPoolSize(), pixel_count - queues.first_pixel);
+    queues.next_pixel = queues.first_pixel + path_count;
+
+    queues.active_ray_count = path_count;
+    queues.active_shadow_ray_count = 0u;
+    // generate pushes the camera rays like shade pushes extension rays, and the advanceQueues
+    // after it takes the bounce to 0
+    queues.bounce = 0xffffffffu;
+    atomicStore(&queues.extension_ray_count, 0u);
+    atomicStore(&queues.shadow_ray_count, 0u);
+
+    dispatches.rays = dispatchSize(path_count);
+    dispatches.shadow_rays = dispatchSize(0u);
+}
+
+@compute @workgroup_size(1)
+fn advanceQueues() {
+    queues.active_ray_count = atomicExchange(&queues.extension_ray_count, 0u);
+    queues.active_shadow_ray_count = atomicExchange(&queues.shadow_ray_count, 0u);
+    queues.bounce += 1u;
+
+    dispatches.rays = dispatchSize(queues.active_ray_count);
+    dispatches.shadow_rays = dispatchSize(queues.active_shadow_ray_count);
+}
+
+// the sampler of the sample a path is taking. Only its PCG state has to be kept in the path
+// between the kernels, the rest follows from the pixel, its sample count and the bounce
+fn pathSampler(path_index: u32) -> Sampler {
+    let image_size = vec2(frame_buffer.width, frame_buffer.height);
+    let pixel = queues.first_pixel + path_index;
+    let screen_pos = vec2(pixel % image_size.x, pixel / image_size.x);
+    return initSampler(screen_pos, image_size, pixel_stats[pixel].sample_count - 1u);
+}
+
+@compute @workgroup_size(WORKGROUP_INVOCATIONS)
+fn generate(@builtin(global_invocation_id) id: vec3u) {
+    let path_index = id.x;
+    if path_index >= queues.active_ray_count {
+        return;
+    }
+
+    let image_size = vec2(frame_buffer.width, frame_buffer.height);
+    let pixel = queues.first_pixel + path_index;
+    let screen_pos = vec2(pixel % image_size.x, pixel / image_size.x);
+
+    let tile_columns = (image_size.x + TILE_SIZE - 1u) / TILE_SIZE;
+    let tile = screen_pos / TILE_SIZE;
+    if tile_flags[tile.x + tile.y * tile_columns] == 0u {
+        return;
+    }
+
+    // the previous sample of the pixel is done by now, so its luminance can be squared
+    var stats = pixel_stats[pixel];
+    stats.luminance_squared += stats.sample_luminance * stats.sample_luminance;
+    stats.sample_luminance = 0.0;
+    stats.sample_count += 1u;
+    pixel_stats[pixel] = stats;
+
+    // several samples can be taken per frame, so the sample count picks the sample rather than
+    // the frame
+    var sampler_state = pathSampler(path_index);
+    let filter_sample = samplePixelFilter(&sampler_state);
+    let ray = getCameraRay(screen_pos, image_size, filter_sample.offset, &sampler_state);
+
+    paths[path_index] = PathState(vec3(filter_sample.weight), sampler_state.rng, 1u, 0.0, vec3(0.0));
+    let slot = atomicAdd(&queues.extension_ray_count, 1u);
+    rays[rayOffset(0u) + slot] = ExtensionRay(ray.origin, path_index, ray.direction);
+}
+
+@compute @workgroup_size(WORKGROUP_INVOCATIONS)
+fn extend(@builtin(global_invocation_id) id: vec3u, @builtin(local_invocation_index) local_index: u32) {
+    bvh_stack_offset = local_index * stackSize;
+    if id.x >= queues.active_ray_count {
+        return;
+    }
+
+    let extension_ray = rays[rayOffset(queues.bounce) + id.x];
+    let ray = Ray(extension_ray.origin, extension_ray.direction);
+    intersections[id.x] = traceRay(ray, MISS);
+}
+
+@compute @workgroup_size(WORKGROUP_INVOCATIONS)
+fn shade(@builtin(global_invocation_id) id: vec3u) {
+    if id.x >= queues.active_ray_count {
+        return;
+    }
+
+    let extension_ray = rays[rayOffset(queues.bounce) + id.x];
+    let ray = Ray(extension_ray.origin, extension_ray.direction);
+    let path_index = extension_ray.path_index;
+    var path = paths[path_index];
+
+    let intersection = intersections[id.x];
+    if intersection.t == MISS {
+        let escaped = path.throughput * escapedRadiance(ray.direction, path.delta_bounce != 0u, path.bsdf_pdf);
+        addToImage(path_index, escaped);
+        addLightToAovs(path_index, queues.bounce, escaped, path.first_diffuse);
+        return;
+    }
+
+    let hit = surfaceInteraction(ray, intersection);
+    if queues.bounce == 0u {
+        let features = primaryFeatures(ray, hit);
+        let idx = queues.first_pixel + path_index;
+        pixel_features[idx].albedo += features.albedo;
+        pixel_features[idx].depth += features.depth;
+        pixel_features[idx].normal += features.normal;
+
+        if sampling_parameters.aov_output != 0u {
+            let pixel_count = frame_buffer.width * frame_buffer.height;
+            addToAovPlanes(idx, AOV_GEOMETRIC_NORMAL, hit.geometric_normal);
+            aov_buffer[AOV_OBJECT_ID * pixel_count + idx] = f32(objectId(hit));
+            aov_buffer[AOV_MATERIAL_ID * pixel_count + idx] = f32(hit.material_id + 1u);
+        }
+    }
+    if any(hit.material.emission > vec3(0.0)) {
+        let weight = emissionWeight(ray, hit, path.delta_bounce != 0u, path.bsdf_pdf);
+        let emitted = path.throughput * hit.material.emission * weight;
+        addToImage(path_index, emitted);
+        addLightToAovs(path_index, queues.bounce, emitted, path.first_diffuse);
+    }
+
+    // number_of_bounces counts the scattering events, so a path has one more segment than that
+    if queues.bounce == sampling_parameters.number_of_bounces {
+        return;
+    }
+
+    var sampler_state = pathSampler(path_index);
+    sampler_state.rng = path.rng_state;
+    samplerStartDimension(&sampler_state, queues.bounce, SAMPLER_LIGHT_DIMENSION);
+    let connection = connectToLight(ray, hit, &sampler_state);
+    if any(connection.contribution > vec3(0.0)) {
+        var diffuse = path.first_diffuse;
+        if sampling_parameters.aov_output != 0u && queues.bounce == 0u {
+            diffuse = diffuseFraction(ray, hit, connection.ray.direction);
+        }
+        let slot = atomicAdd(&queues.shadow_ray_count, 1u);
+        shadow_rays[slot] = ShadowRay(connection.ray.origin, connection.t_max, connection.ray.direction,
+                                      path_index, path.throughput * connection.contribution,
+                                      queues.bounce + 1u, diffuse);
+    }
+
+    samplerStartDimension(&sampler_state, queues.bounce, SAMPLER_BSDF_DIMENSION);
+    let scattered = scatter(ray, hit, &sampler_state);
+    if sampling_parameters.aov_output != 0u && queues.bounce == 0u {
+        path.first_diffuse = diffuseFraction(ray, hit, scattered.ray.direction);
+    }
+    path.throughput *= scattered.attenuation;
+    path.rng_state = sampler_state.rng;
+    path.delta_bounce = u32(scattered.is_delta);
+    path.bsdf_pdf = scattered.pdf;
+    paths[path_index] = path;
+    if all(path.throughput == vec3(0.0)) {
+        return;
+    }
+
+    let slot = atomicAdd(&queues.extension_ray_count, 1u);
+    rays[rayOffset(queues.bounce + 1u) + slot] =
+        ExtensionRay(scattered.ray.origin, path_index, scattered.ray.direction);
+}
+
+@compute @workgroup_size(WORKGROUP_INVOCATIONS)
+fn connect(@builtin(global_invocation_id) id: vec3u, @builtin(local_invocation_index) local_index: u32) {
+    bvh_stack_offset = local_index * stackSize;
+    if id.x >= queues.active_shadow_ray_count {
+        return;
+    }
+
+    let shadow_ray = shadow_rays[id.x];
+    if !isOccluded(Ray(shadow_ray.origin, shadow_ray.direction), shadow_ray.t_max) {
+        addToImage(shadow_ray.path_index, shadow_ray.contribution);
+        addLightToAovs(shadow_ray.path_index, shadow_ray.scatter_count, shadow_ray.contribution, shadow_ray.diffuse);
+    }
+}
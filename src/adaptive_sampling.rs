@@ -0,0 +1,223 @@
+use wgpu::{BindGroupDescriptor, BindGroupLayoutDescriptor, BufferDescriptor};
+use wgpu_profiler::Scope;
+use crate::utilities::u8cast::any_as_u8_slice;
+
+// what the kernels keep per pixel for adaptive sampling, on top of the accumulated color
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GPUPixelStats {
+    // the samples the pixel has taken, which the image is averaged over
+    sample_count: u32,
+    // the sum of the squared luminances of the samples, for their variance
+    luminance_squared: f32,
+    // the luminance of the sample the wavefront kernels are still adding up
+    sample_luminance: f32,
+}
+
+// adaptive sampling splits the image into tiles and stops sampling the tiles whose pixels have
+// converged: before every frame, updateTiles in adaptive_sampling.wgsl works out from the
+// per-pixel stats which tiles are still noisy, flags them and lists them along with the
+// indirect dispatch arguments of the megakernel, so only those tiles get any threads. The
+// wavefront kernels don't start paths in converged tiles. With adaptive sampling off every
+// tile stays active
+pub struct AdaptiveSampling {
+    pixel_stats_buffer: wgpu::Buffer,
+    tile_flag_buffer: wgpu::Buffer,
+    active_tile_buffer: wgpu::Buffer,
+    tile_dispatch_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    update_tiles_pipeline: wgpu::ComputePipeline,
+    write_tile_dispatch_pipeline: wgpu::ComputePipeline,
+}
+
+impl AdaptiveSampling {
+    // matches TILE_SIZE in adaptive_sampling.wgsl and compute_megakernel.wgsl
+    const TILE_SIZE: u32 = 16;
+
+    // sized for images of up to max_pixels pixels that are no wider or taller than max_dimension
+    pub fn new(device: &wgpu::Device,
+               image_buffer: &wgpu::Buffer,
+               frame_buffer: &wgpu::Buffer,
+               sampling_parameters_buffer: &wgpu::Buffer,
+               max_pixels: u32,
+               max_dimension: u32) -> Self {
+        let pixel_stats_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Pixel Stats Buffer"),
+            size: max_pixels as wgpu::BufferAddress * size_of::<GPUPixelStats>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        // partial tiles along the right and bottom edge come on top of the whole ones
+        let max_tiles = max_pixels / (Self::TILE_SIZE * Self::TILE_SIZE) + 2 * max_dimension / Self::TILE_SIZE + 1;
+        let tile_flag_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Tile Flag Buffer"),
+            size: max_tiles as wgpu::BufferAddress * size_of::<u32>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        // the number of active tiles followed by the tiles
+        let active_tile_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Active Tile Buffer"),
+            size: (max_tiles + 1) as wgpu::BufferAddress * size_of::<u32>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let tile_dispatch_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Tile Dispatch Buffer"),
+            size: 3 * size_of::<u32>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+            mapped_at_creation: false,
+        });
+
+        let image_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let frame_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 1,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let pixel_stats_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            ..image_buffer_layout
+        };
+
+        let sampling_parameters_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            ..frame_buffer_layout
+        };
+
+        let tile_flag_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 4,
+            ..pixel_stats_buffer_layout
+        };
+
+        let active_tile_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 5,
+            ..pixel_stats_buffer_layout
+        };
+
+        let tile_dispatch_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 6,
+            ..pixel_stats_buffer_layout
+        };
+
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("adaptive sampling bind group layout"),
+            entries: &[
+                image_buffer_layout,
+                frame_buffer_layout,
+                pixel_stats_buffer_layout,
+                sampling_parameters_buffer_layout,
+                tile_flag_buffer_layout,
+                active_tile_buffer_layout,
+                tile_dispatch_buffer_layout,
+            ],
+        });
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("adaptive sampling bind group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: image_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: frame_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: pixel_stats_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 3, resource: sampling_parameters_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 4, resource: tile_flag_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 5, resource: active_tile_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 6, resource: tile_dispatch_buffer.as_entire_binding() },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("adaptive sampling pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(
+            wgpu::include_wgsl!("../shaders/adaptive_sampling.wgsl")
+        );
+
+        let create_pipeline = |entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+
+        Self {
+            pixel_stats_buffer,
+            tile_flag_buffer,
+            active_tile_buffer,
+            tile_dispatch_buffer,
+            bind_group,
+            update_tiles_pipeline: create_pipeline("updateTiles"),
+            write_tile_dispatch_pipeline: create_pipeline("writeTileDispatch"),
+        }
+    }
+
+    pub fn pixel_stats_buffer(&self) -> &wgpu::Buffer {
+        &self.pixel_stats_buffer
+    }
+
+    pub fn tile_flag_buffer(&self) -> &wgpu::Buffer {
+        &self.tile_flag_buffer
+    }
+
+    pub fn active_tile_buffer(&self) -> &wgpu::Buffer {
+        &self.active_tile_buffer
+    }
+
+    // the indirect dispatch arguments of the megakernel over the active tiles
+    pub fn tile_dispatch_buffer(&self) -> &wgpu::Buffer {
+        &self.tile_dispatch_buffer
+    }
+
+    // forgets all samples, which makes every tile active again
+    pub fn reset(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.clear_buffer(&self.pixel_stats_buffer, 0, None);
+    }
+
+    // lists the tiles that still need samples; has to run before every frame
+    pub fn update_tiles(&self, queue: &wgpu::Queue, scope: &mut Scope<'_, wgpu::CommandEncoder>,
+                        width: u32, height: u32) {
+        // updateTiles counts the active tiles up from zero
+        let active_tile_count = 0u32;
+        queue.write_buffer(&self.active_tile_buffer, 0, unsafe { any_as_u8_slice(&active_tile_count) });
+
+        let tile_count = width.div_ceil(Self::TILE_SIZE) * height.div_ceil(Self::TILE_SIZE);
+        let mut compute_pass = scope.scoped_compute_pass("adaptive sampling");
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        compute_pass.set_pipeline(&self.update_tiles_pipeline);
+        compute_pass.dispatch_workgroups(tile_count.div_ceil(64), 1, 1);
+        compute_pass.set_pipeline(&self.write_tile_dispatch_pipeline);
+        compute_pass.dispatch_workgroups(1, 1, 1);
+    }
+}
@@ -0,0 +1,246 @@
+use wgpu::BufferDescriptor;
+
+// the arbitrary output variables the kernels can write next to the image; the values match
+// the AOV_ constants in screen_shader.wgsl, 0 being the image itself
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Aov {
+    // the base color of the first hit
+    Albedo = 1,
+    // the interpolated normal of the first hit
+    ShadingNormal = 2,
+    // the normal of the triangle or sphere itself
+    GeometricNormal = 3,
+    // the distance from the camera to the first hit
+    Depth = 4,
+    // the sphere or mesh of the first hit, counted from 1 with 0 for the background
+    ObjectId = 5,
+    // the material of the first hit, also counted from 1
+    MaterialId = 6,
+    // light that reaches the camera straight from an emitter or the environment
+    Emission = 7,
+    // light that scattered once, split up by the part of the scattering event that was diffuse
+    DirectDiffuse = 8,
+    DirectSpecular = 9,
+    // light that scattered more than once, split up by the first scattering event
+    IndirectDiffuse = 10,
+    IndirectSpecular = 11,
+    // the samples the pixel has taken, which vary with adaptive sampling
+    SampleCount = 12,
+}
+
+// where the value of an aov comes from when it is read back
+enum AovSource {
+    // the floats of a pixel in the feature buffer, starting at this one
+    Features(usize),
+    // the planes of the aov buffer, starting at this one
+    Planes(u32),
+    // the pixel stats
+    SampleCount,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 12] = [
+        Aov::Albedo,
+        Aov::ShadingNormal,
+        Aov::GeometricNormal,
+        Aov::Depth,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::Emission,
+        Aov::DirectDiffuse,
+        Aov::DirectSpecular,
+        Aov::IndirectDiffuse,
+        Aov::IndirectSpecular,
+        Aov::SampleCount,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "Albedo",
+            Aov::ShadingNormal => "Shading normal",
+            Aov::GeometricNormal => "Geometric normal",
+            Aov::Depth => "Depth",
+            Aov::ObjectId => "Object ID",
+            Aov::MaterialId => "Material ID",
+            Aov::Emission => "Emission",
+            Aov::DirectDiffuse => "Direct diffuse",
+            Aov::DirectSpecular => "Direct specular",
+            Aov::IndirectDiffuse => "Indirect diffuse",
+            Aov::IndirectSpecular => "Indirect specular",
+            Aov::SampleCount => "Sample count",
+        }
+    }
+
+    // the name of the layer in a multi-layer EXR
+    pub fn layer_name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::ShadingNormal => "normal",
+            Aov::GeometricNormal => "geometric_normal",
+            Aov::Depth => "depth",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::Emission => "emission",
+            Aov::DirectDiffuse => "direct_diffuse",
+            Aov::DirectSpecular => "direct_specular",
+            Aov::IndirectDiffuse => "indirect_diffuse",
+            Aov::IndirectSpecular => "indirect_specular",
+            Aov::SampleCount => "sample_count",
+        }
+    }
+
+    pub fn channel_names(&self) -> &'static [&'static str] {
+        match self {
+            Aov::ShadingNormal | Aov::GeometricNormal => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::ObjectId | Aov::MaterialId => &["id"],
+            Aov::SampleCount => &["count"],
+            _ => &["R", "G", "B"],
+        }
+    }
+
+    // the ids are the same for every sample, everything else is a sum over the samples
+    fn averaged(&self) -> bool {
+        !matches!(self, Aov::ObjectId | Aov::MaterialId | Aov::SampleCount)
+    }
+
+    // the planes match the AOV_ constants in path_tracing_common.wgsl, the feature offsets
+    // the layout of PixelFeatures
+    fn source(&self) -> AovSource {
+        match self {
+            Aov::Albedo => AovSource::Features(0),
+            Aov::Depth => AovSource::Features(3),
+            Aov::ShadingNormal => AovSource::Features(4),
+            Aov::GeometricNormal => AovSource::Planes(0),
+            Aov::ObjectId => AovSource::Planes(3),
+            Aov::MaterialId => AovSource::Planes(4),
+            Aov::Emission => AovSource::Planes(5),
+            Aov::DirectDiffuse => AovSource::Planes(8),
+            Aov::DirectSpecular => AovSource::Planes(11),
+            Aov::IndirectDiffuse => AovSource::Planes(14),
+            Aov::IndirectSpecular => AovSource::Planes(17),
+            Aov::SampleCount => AovSource::SampleCount,
+        }
+    }
+}
+
+// one aov of a whole image, a row-major plane of floats per channel
+pub struct AovLayer {
+    pub aov: Aov,
+    pub channels: Vec<Vec<f32>>,
+}
+
+// the AOVs the features and the pixel stats don't already have: one buffer of f32 planes,
+// each with a value per pixel, so that they only take up a single storage buffer binding.
+// A buffer per aov would take the megakernel past the 31 storage buffers Metal allows a stage.
+// It is only as large as the image while the AOVs are on, and a placeholder otherwise
+pub struct Aovs {
+    aov_buffer: wgpu::Buffer,
+}
+
+impl Aovs {
+    // the geometric normal, the two ids and five light AOVs of three channels each. They are
+    // planes of the one buffer rather than a binding each, which the megakernel has no room for
+    pub const PLANE_COUNT: u32 = 20;
+    // albedo, depth and shading normal and the padding at the end of PixelFeatures
+    pub const FEATURE_FLOATS: usize = 8;
+
+    // a single float, since a binding can't be empty
+    const PLACEHOLDER_SIZE: wgpu::BufferAddress = size_of::<f32>() as wgpu::BufferAddress;
+
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            aov_buffer: Self::create_buffer(device, Self::PLACEHOLDER_SIZE),
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, size: wgpu::BufferAddress) -> wgpu::Buffer {
+        device.create_buffer(&BufferDescriptor {
+            label: Some("AOV Buffer"),
+            size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+
+    // makes room for every plane of pixel_count pixels, or goes back to the placeholder with
+    // None; true if the buffer was replaced, in which case the bind groups holding it have to be
+    // made again. The new buffer starts out cleared
+    pub fn resize(&mut self, device: &wgpu::Device, pixel_count: Option<u32>) -> bool {
+        let size = match pixel_count {
+            Some(pixel_count) => Self::PLANE_COUNT as wgpu::BufferAddress * pixel_count as wgpu::BufferAddress
+                * size_of::<f32>() as wgpu::BufferAddress,
+            None => Self::PLACEHOLDER_SIZE,
+        };
+        if size == self.aov_buffer.size() {
+            return false;
+        }
+        self.aov_buffer = Self::create_buffer(device, size);
+        true
+    }
+
+    pub fn aov_buffer(&self) -> &wgpu::Buffer {
+        &self.aov_buffer
+    }
+
+    // the kernels add to the AOVs, so they start over at zero with the image
+    pub fn reset(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.clear_buffer(&self.aov_buffer, 0, None);
+    }
+
+    // averages what was read back of the aov buffer, the features and the sample counts of
+    // pixel_count pixels into one layer per aov
+    pub fn resolve(pixel_count: usize, planes: &[f32], features: &[f32], sample_counts: &[u32]) -> Vec<AovLayer> {
+        Aov::ALL.iter().map(|aov| {
+            let channels = (0..aov.channel_names().len()).map(|channel| {
+                (0..pixel_count).map(|pixel| {
+                    let value = match aov.source() {
+                        AovSource::Features(offset) => features[pixel * Self::FEATURE_FLOATS + offset + channel],
+                        AovSource::Planes(plane) => planes[(plane as usize + channel) * pixel_count + pixel],
+                        AovSource::SampleCount => sample_counts[pixel] as f32,
+                    };
+                    if aov.averaged() { value / sample_counts[pixel].max(1) as f32 } else { value }
+                }).collect()
+            }).collect();
+            AovLayer { aov: *aov, channels }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(layers: &[AovLayer], aov: Aov) -> &[Vec<f32>] {
+        &layers.iter().find(|layer| layer.aov == aov).unwrap().channels
+    }
+
+    #[test]
+    fn resolve_averages_all_but_the_ids() {
+        // two pixels, the first with four samples and the second with none yet
+        let pixel_count = 2;
+        let sample_counts = [4, 0];
+        let planes: Vec<f32> = (0..Aovs::PLANE_COUNT as usize * pixel_count).map(|i| 4.0 * i as f32).collect();
+        let features: Vec<f32> = (0..Aovs::FEATURE_FLOATS * pixel_count).map(|i| 4.0 * i as f32).collect();
+        let layers = Aovs::resolve(pixel_count, &planes, &features, &sample_counts);
+        assert_eq!(layers.len(), Aov::ALL.len());
+        for layer in &layers {
+            assert_eq!(layer.channels.len(), layer.aov.channel_names().len());
+            assert!(layer.channels.iter().all(|channel| channel.len() == pixel_count));
+        }
+
+        // the sums come out divided by the sample count, which counts as 1 before any samples
+        assert_eq!(layer(&layers, Aov::Albedo), [vec![0.0, 32.0], vec![1.0, 36.0], vec![2.0, 40.0]]);
+        assert_eq!(layer(&layers, Aov::Depth), [vec![3.0, 44.0]]);
+        assert_eq!(layer(&layers, Aov::IndirectSpecular)[2], [38.0, 156.0]);
+        // the normals are their own channels, from the features and from planes 0 to 2
+        assert_eq!(layer(&layers, Aov::ShadingNormal), [vec![4.0, 48.0], vec![5.0, 52.0], vec![6.0, 56.0]]);
+        assert_eq!(layer(&layers, Aov::GeometricNormal), [vec![0.0, 4.0], vec![2.0, 12.0], vec![4.0, 20.0]]);
+
+        // an id is the same in every sample, so it is kept as it was written
+        assert_eq!(layer(&layers, Aov::ObjectId), [vec![24.0, 28.0]]);
+        assert_eq!(layer(&layers, Aov::MaterialId), [vec![32.0, 36.0]]);
+        assert_eq!(layer(&layers, Aov::SampleCount), [vec![4.0, 0.0]]);
+    }
+}
@@ -1,13 +1,19 @@
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use glam::Vec3;
 use winit::application::ApplicationHandler;
 use winit::dpi::LogicalSize;
 use winit::event::{ElementState, KeyEvent, WindowEvent};
 use winit::event_loop::ActiveEventLoop;
 use winit::keyboard::{KeyCode, PhysicalKey};
 use winit::window::{Window, WindowId};
+use crate::environment::{Background, Environment};
 use crate::gui::{UserInput, GUI, RenderStats};
 use crate::pathtracer::PathTracer;
+use crate::scene::{Scene, Sphere};
+use crate::material::Material;
+use crate::mesh::Mesh;
 use crate::wgpu_state::WGPUState;
 
 #[derive(Default)]
@@ -15,7 +21,52 @@ pub struct App<'a> {
     gui_controller: Option<GUI>,
     path_tracer: Option<PathTracer<'a>>,
     user_input: UserInput,
-    render_stats: RenderStats
+    render_stats: RenderStats,
+    scene_path: Option<PathBuf>,
+    environment_path: Option<PathBuf>,
+}
+
+impl App<'_> {
+    pub fn new(scene_path: Option<PathBuf>, environment_path: Option<PathBuf>) -> Self {
+        Self {
+            scene_path,
+            environment_path,
+            ..Default::default()
+        }
+    }
+
+    fn load_scene(&self) -> Scene {
+        let mut scene = self.load_model();
+        if let Some(path) = &self.environment_path {
+            match Environment::load(path) {
+                Ok(environment) => scene.set_environment(environment),
+                Err(error) => println!("Failed to load {}: {}", path.display(), error),
+            }
+        }
+        scene
+    }
+
+    fn load_model(&self) -> Scene {
+        if let Some(path) = &self.scene_path {
+            match Scene::load(path) {
+                Ok(scene) => return scene,
+                Err(error) => println!("Failed to load {}: {}", path.display(), error),
+            }
+        }
+
+        let mut scene = Scene::two_spheres();
+        let blue = scene.add_material(&Material {
+            clearcoat: 1.0,
+            ..Material::principled(Vec3::new(0.2, 0.4, 0.8), 0.0, 0.6)
+        });
+        scene.add_mesh(&Mesh::cube(Vec3::new(1.1, -0.2, -1.2), 0.3, blue));
+        let glass = scene.add_material(&Material::dielectric(1.5, 0.0));
+        scene.add_sphere(Sphere::new(Vec3::new(-1.1, 0.0, -1.2), 0.5, glass));
+        let gold = scene.add_material(&Material::conductor(Vec3::new(1.0, 0.78, 0.34), 0.3));
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.5, -2.6), 1.0, gold));
+        scene.build_bvh();
+        scene
+    }
 }
 
 impl ApplicationHandler for App<'_> {
@@ -33,7 +84,17 @@ impl ApplicationHandler for App<'_> {
         let wgpu_state = pollster::block_on(WGPUState::new(window.clone()));
 
         self.gui_controller = GUI::new(&window, wgpu_state.surface_config(), wgpu_state.device(), wgpu_state.queue());
-        self.path_tracer = PathTracer::new(wgpu_state);
+        let scene = self.load_scene();
+        // the gui owns the field of view, so it has to start out at the one of the scene camera
+        if let Some(camera) = scene.camera() {
+            self.user_input.set_vfov(camera.vfov);
+        }
+        self.user_input.set_materials(scene.materials().to_vec());
+        self.render_stats.set_light_stats(scene.lights().emitter_count(), scene.lights().tree_depth());
+        if scene.environment().is_some() {
+            self.user_input.set_background(Background::EnvironmentMap);
+        }
+        self.path_tracer = PathTracer::new(wgpu_state, scene);
     }
 
     fn window_event(&mut self, event_loop: &ActiveEventLoop, window_id: WindowId, event: WindowEvent) {
@@ -49,7 +110,8 @@ impl ApplicationHandler for App<'_> {
 
         let progress = path_tracer.progress();
         let avg_kernel_time = path_tracer.avg_kernel_time();
-        self.render_stats.update_progress(progress, avg_kernel_time, dt);
+        let avg_pass_times = path_tracer.avg_pass_times();
+        self.render_stats.update_progress(progress, avg_kernel_time, avg_pass_times, dt);
 
         match event {
             WindowEvent::CloseRequested | WindowEvent::KeyboardInput {
@@ -76,6 +138,20 @@ impl ApplicationHandler for App<'_> {
                 //     println!("user_input {:?}", self.user_input);
                 // }
                 path_tracer.run_path_tracer(dt.as_secs_f32(), &mut self.user_input);
+                // an edited emission builds the lights again
+                let lights = path_tracer.scene().lights();
+                self.render_stats.set_light_stats(lights.emitter_count(), lights.tree_depth());
+                if self.user_input.take_save_image_request() {
+                    // timestamped, so that saving again doesn't overwrite the previous render
+                    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                    let path = PathBuf::from(format!("render_{}.{}", seconds, self.user_input.image_format().extension()));
+                    let save_status = match path_tracer.save_image(&path) {
+                        Ok(()) => format!("Saved {}", path.display()),
+                        Err(error) => format!("Failed to save {}: {}", path.display(), error),
+                    };
+                    println!("{}", save_status);
+                    self.render_stats.set_save_status(save_status);
+                }
                 path_tracer.display_image(gui);
                 window.request_redraw();
             },
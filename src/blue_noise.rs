@@ -0,0 +1,175 @@
+// a tileable blue noise mask made with the void and cluster method (Ulichney, "The void-and-
+// cluster method for dither array generation", 1993). Every pixel gets its rank in the order
+// the method fills the mask in, scaled to [0, 1), so any threshold of the mask gives evenly
+// spread points without low frequencies. The kernels offset the mask per pixel to get blue
+// noise between neighboring pixels rather than white noise
+pub struct BlueNoise {
+    values: Vec<f32>,
+}
+
+impl BlueNoise {
+    // matches BLUE_NOISE_SIZE in path_tracing_common.wgsl
+    pub const SIZE: u32 = 64;
+    // the width of the gaussian that measures how clustered the points are
+    const SIGMA: f32 = 1.5;
+
+    pub fn generate() -> Self {
+        let size = Self::SIZE as usize;
+        let count = size * size;
+
+        // the gaussian for every toroidal offset, which falls off well inside half the mask
+        let mut kernel = vec![0.0f32; count];
+        for dy in 0..size {
+            for dx in 0..size {
+                let x = dx.min(size - dx) as f32;
+                let y = dy.min(size - dy) as f32;
+                kernel[dy * size + dx] = (-(x * x + y * y) / (2.0 * Self::SIGMA * Self::SIGMA)).exp();
+            }
+        }
+        let mut mask = Mask { size, points: vec![false; count], energy: vec![0.0; count], kernel };
+
+        // a tenth of the pixels at random, spread out by moving the point in the tightest
+        // cluster into the largest void until that point would come straight back
+        let mut state = 0x2545_f491u32;
+        let mut initial_points = 0;
+        while initial_points < count / 10 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let pixel = state as usize % count;
+            if !mask.points[pixel] {
+                mask.toggle(pixel);
+                initial_points += 1;
+            }
+        }
+        loop {
+            let cluster = mask.tightest_cluster();
+            mask.toggle(cluster);
+            let void = mask.largest_void();
+            if void == cluster {
+                mask.toggle(cluster);
+                break;
+            }
+            mask.toggle(void);
+        }
+        let initial = mask.points.clone();
+        let initial_energy = mask.energy.clone();
+
+        let mut ranks = vec![0u32; count];
+        // the initial points get the lowest ranks, taken out from the tightest cluster on
+        for rank in (0..initial_points).rev() {
+            let cluster = mask.tightest_cluster();
+            mask.toggle(cluster);
+            ranks[cluster] = rank as u32;
+        }
+
+        // and the rest of the pixels are filled in from the largest void on
+        mask.points = initial;
+        mask.energy = initial_energy;
+        for rank in initial_points..count {
+            let void = mask.largest_void();
+            mask.toggle(void);
+            ranks[void] = rank as u32;
+        }
+
+        Self {
+            values: ranks.iter().map(|&rank| (rank as f32 + 0.5) / count as f32).collect(),
+        }
+    }
+
+    // row by row
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+}
+
+struct Mask {
+    size: usize,
+    points: Vec<bool>,
+    // the gaussian filtered points, kept up to date as points come and go
+    energy: Vec<f32>,
+    kernel: Vec<f32>,
+}
+
+impl Mask {
+    fn toggle(&mut self, pixel: usize) {
+        self.points[pixel] = !self.points[pixel];
+        let sign = if self.points[pixel] { 1.0 } else { -1.0 };
+        let (px, py) = (pixel % self.size, pixel / self.size);
+        for y in 0..self.size {
+            let dy = (y + self.size - py) % self.size;
+            let kernel = &self.kernel[dy * self.size..(dy + 1) * self.size];
+            let energy = &mut self.energy[y * self.size..(y + 1) * self.size];
+            // the kernel row wraps around at the point
+            let (left, right) = energy.split_at_mut(px);
+            for (e, k) in right.iter_mut().zip(kernel) {
+                *e += sign * k;
+            }
+            for (e, k) in left.iter_mut().zip(&kernel[self.size - px..]) {
+                *e += sign * k;
+            }
+        }
+    }
+
+    // the point with the most points around it
+    fn tightest_cluster(&self) -> usize {
+        (0..self.points.len())
+            .filter(|&pixel| self.points[pixel])
+            .max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+
+    // the empty pixel with the fewest points around it
+    fn largest_void(&self) -> usize {
+        (0..self.points.len())
+            .filter(|&pixel| !self.points[pixel])
+            .min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_rank_appears_once() {
+        let blue_noise = BlueNoise::generate();
+        let count = (BlueNoise::SIZE * BlueNoise::SIZE) as usize;
+        let mut ranks: Vec<usize> = blue_noise.values().iter()
+            .map(|value| (value * count as f32 - 0.5).round() as usize)
+            .collect();
+        ranks.sort();
+        assert!(ranks.into_iter().eq(0..count));
+
+        // the seed is fixed, so the mask is the same every time
+        assert_eq!(BlueNoise::generate().values(), blue_noise.values());
+    }
+
+    #[test]
+    fn thresholds_spread_out_across_the_edges() {
+        let size = BlueNoise::SIZE as i32;
+        let blue_noise = BlueNoise::generate();
+        // the darkest sixteenth, about one point in every 4 by 4 pixels
+        let points: Vec<(i32, i32)> = (0..size * size)
+            .filter(|&pixel| blue_noise.values()[pixel as usize] < 1.0 / 16.0)
+            .map(|pixel| (pixel % size, pixel / size))
+            .collect();
+        // the distances wrap around, so that the mask tiles without clumps at the seams
+        let wrapped = |a: i32, b: i32| (a - b).abs().min(size - (a - b).abs());
+        for (i, &(ax, ay)) in points.iter().enumerate() {
+            for &(bx, by) in &points[i + 1..] {
+                let (dx, dy) = (wrapped(ax, bx), wrapped(ay, by));
+                assert!(dx * dx + dy * dy >= 4, "({ax}, {ay}) and ({bx}, {by}) are too close");
+            }
+        }
+
+        // and every quarter of the mask gets its share
+        for (qx, qy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let quarter = points.iter()
+                .filter(|&&(x, y)| x / (size / 2) == qx && y / (size / 2) == qy)
+                .count();
+            assert!((56..=72).contains(&quarter), "{quarter} points in quarter ({qx}, {qy})");
+        }
+    }
+}
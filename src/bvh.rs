@@ -0,0 +1,321 @@
+use glam::Vec3;
+use crate::mesh::SceneGeometry;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GPUBvhNode {
+    aabb_min: Vec3,
+    // for an interior node the index of the left child (the right child follows it),
+    // for a leaf the index of its first triangle
+    left_first: u32,
+    aabb_max: Vec3,
+    // zero for interior nodes
+    triangle_count: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Aabb {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Aabb {
+    const EMPTY: Aabb = Aabb { min: Vec3::splat(f32::MAX), max: Vec3::splat(f32::MIN) };
+
+    fn grow(&mut self, other: &Aabb) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    fn grow_point(&mut self, p: Vec3) {
+        self.min = self.min.min(p);
+        self.max = self.max.max(p);
+    }
+
+    fn surface_area(&self) -> f32 {
+        if self.min.x > self.max.x {
+            return 0.0;
+        }
+        let e = self.max - self.min;
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+}
+
+struct Bin {
+    bounds: Aabb,
+    count: u32,
+}
+
+// a binned SAH bounding volume hierarchy over the triangles of the scene. The nodes are
+// stored flat in the order the kernel traverses them, with the two children of a node
+// always next to each other, and the scene's triangles get reordered so that every leaf
+// references one contiguous run of them
+pub struct Bvh {
+    nodes: Vec<GPUBvhNode>,
+    max_depth: u32,
+}
+
+impl Default for Bvh {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl Bvh {
+    const BIN_COUNT: usize = 16;
+    const TRAVERSAL_COST: f32 = 1.0;
+    const INTERSECTION_COST: f32 = 1.0;
+    // the traversal stack lives in workgroup memory, so the depth of the tree has to be capped
+    pub const MAX_DEPTH: u32 = 64;
+
+    // a single leaf with no triangles and an inverted box that no ray can ever hit
+    fn empty() -> Self {
+        Self {
+            nodes: vec![GPUBvhNode {
+                aabb_min: Aabb::EMPTY.min,
+                left_first: 0,
+                aabb_max: Aabb::EMPTY.max,
+                triangle_count: 0,
+            }],
+            max_depth: 1,
+        }
+    }
+
+    pub fn build(geometry: &mut SceneGeometry) -> Self {
+        let triangle_bounds = geometry.triangle_bounds();
+        if triangle_bounds.is_empty() {
+            return Self::empty();
+        }
+
+        let bounds: Vec<Aabb> = triangle_bounds.iter()
+            .map(|(min, max)| Aabb { min: *min, max: *max })
+            .collect();
+        let centroids: Vec<Vec3> = bounds.iter().map(|b| 0.5 * (b.min + b.max)).collect();
+        let mut order: Vec<u32> = (0..bounds.len() as u32).collect();
+
+        let mut nodes = Vec::with_capacity(2 * bounds.len() - 1);
+        nodes.push(GPUBvhNode {
+            left_first: 0,
+            triangle_count: bounds.len() as u32,
+            ..Default::default()
+        });
+
+        let mut max_depth = 1;
+        let mut stack = vec![(0usize, 1u32)];
+        while let Some((node_index, depth)) = stack.pop() {
+            max_depth = max_depth.max(depth);
+
+            let first = nodes[node_index].left_first as usize;
+            let count = nodes[node_index].triangle_count as usize;
+            let triangles = &mut order[first..first + count];
+
+            let mut node_bounds = Aabb::EMPTY;
+            let mut centroid_bounds = Aabb::EMPTY;
+            for &t in triangles.iter() {
+                node_bounds.grow(&bounds[t as usize]);
+                centroid_bounds.grow_point(centroids[t as usize]);
+            }
+            nodes[node_index].aabb_min = node_bounds.min;
+            nodes[node_index].aabb_max = node_bounds.max;
+
+            if count <= 1 || depth >= Self::MAX_DEPTH {
+                continue;
+            }
+
+            let leaf_cost = count as f32 * Self::INTERSECTION_COST;
+            let Some((axis, split, split_cost)) =
+                Self::find_best_split(triangles, &bounds, &centroids, &centroid_bounds)
+            else {
+                continue;
+            };
+            let split_cost = Self::TRAVERSAL_COST
+                + Self::INTERSECTION_COST * split_cost / node_bounds.surface_area();
+            if split_cost >= leaf_cost {
+                continue;
+            }
+
+            // partition the triangles in place around the split plane
+            let scale = Self::BIN_COUNT as f32 / (centroid_bounds.max[axis] - centroid_bounds.min[axis]);
+            let mut left_count = 0;
+            for i in 0..count {
+                let bin = Self::bin_index(centroids[triangles[i] as usize][axis], centroid_bounds.min[axis], scale);
+                if bin < split {
+                    triangles.swap(i, left_count);
+                    left_count += 1;
+                }
+            }
+            if left_count == 0 || left_count == count {
+                continue;
+            }
+
+            let left_index = nodes.len();
+            nodes.push(GPUBvhNode {
+                left_first: first as u32,
+                triangle_count: left_count as u32,
+                ..Default::default()
+            });
+            nodes.push(GPUBvhNode {
+                left_first: (first + left_count) as u32,
+                triangle_count: (count - left_count) as u32,
+                ..Default::default()
+            });
+            nodes[node_index].left_first = left_index as u32;
+            nodes[node_index].triangle_count = 0;
+
+            stack.push((left_index, depth + 1));
+            stack.push((left_index + 1, depth + 1));
+        }
+
+        geometry.reorder_triangles(&order);
+
+        Self {
+            nodes,
+            max_depth,
+        }
+    }
+
+    fn bin_index(centroid: f32, min: f32, scale: f32) -> usize {
+        (((centroid - min) * scale) as usize).min(Self::BIN_COUNT - 1)
+    }
+
+    // returns the axis, the bin the right half starts at and the unnormalized SAH cost
+    // (area times triangle count summed over both halves) of the cheapest split
+    fn find_best_split(triangles: &[u32], bounds: &[Aabb], centroids: &[Vec3],
+                       centroid_bounds: &Aabb) -> Option<(usize, usize, f32)> {
+        let mut best: Option<(usize, usize, f32)> = None;
+
+        for axis in 0..3 {
+            let min = centroid_bounds.min[axis];
+            let extent = centroid_bounds.max[axis] - min;
+            if extent <= 0.0 {
+                continue;
+            }
+
+            let scale = Self::BIN_COUNT as f32 / extent;
+            let mut bins: Vec<Bin> = (0..Self::BIN_COUNT)
+                .map(|_| Bin { bounds: Aabb::EMPTY, count: 0 })
+                .collect();
+            for &t in triangles {
+                let bin = &mut bins[Self::bin_index(centroids[t as usize][axis], min, scale)];
+                bin.bounds.grow(&bounds[t as usize]);
+                bin.count += 1;
+            }
+
+            // sweep from both ends to get the area and count on either side of every plane
+            let mut left_cost = [0.0f32; Self::BIN_COUNT - 1];
+            let mut left_bounds = Aabb::EMPTY;
+            let mut left_count = 0;
+            for i in 0..Self::BIN_COUNT - 1 {
+                left_bounds.grow(&bins[i].bounds);
+                left_count += bins[i].count;
+                left_cost[i] = left_bounds.surface_area() * left_count as f32;
+            }
+
+            let mut right_bounds = Aabb::EMPTY;
+            let mut right_count = 0;
+            for i in (1..Self::BIN_COUNT).rev() {
+                right_bounds.grow(&bins[i].bounds);
+                right_count += bins[i].count;
+                let cost = left_cost[i - 1] + right_bounds.surface_area() * right_count as f32;
+                if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, i, cost));
+                }
+            }
+        }
+
+        best
+    }
+
+    pub fn nodes(&self) -> &[GPUBvhNode] {
+        &self.nodes
+    }
+
+    // the traversal stack only ever holds one entry per level of the tree
+    pub fn stack_size(&self) -> u32 {
+        self.max_depth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::Mesh;
+
+    // a grid of cubes of different sizes, enough triangles for a few levels of the tree
+    fn cube_grid() -> SceneGeometry {
+        let mut geometry = SceneGeometry::default();
+        for x in 0..6 {
+            for y in 0..3 {
+                for z in 0..4 {
+                    let center = Vec3::new(x as f32, 1.5 * y as f32, -2.0 * z as f32);
+                    let half_size = 0.1 + 0.05 * ((x + y + z) % 4) as f32;
+                    let object_id = (x * 12 + y * 4 + z) as u32;
+                    geometry.add_mesh(&Mesh::cube(center, half_size, 0), object_id);
+                }
+            }
+        }
+        geometry
+    }
+
+    fn contains(outer: &GPUBvhNode, min: Vec3, max: Vec3) -> bool {
+        outer.aabb_min.cmple(min).all() && outer.aabb_max.cmpge(max).all()
+    }
+
+    // the triangle bounds in an order that doesn't depend on the order of the triangles
+    // with the object id, which has to move along with its triangle
+    fn sorted_bounds(geometry: &SceneGeometry) -> Vec<[u32; 7]> {
+        let mut bounds: Vec<[u32; 7]> = geometry.triangle_bounds().iter()
+            .zip(geometry.object_ids())
+            .map(|((min, max), &object_id)| {
+                let [a, b, c, d, e, f] = [min.x, min.y, min.z, max.x, max.y, max.z].map(f32::to_bits);
+                [a, b, c, d, e, f, object_id]
+            })
+            .collect();
+        bounds.sort();
+        bounds
+    }
+
+    #[test]
+    fn every_triangle_is_in_exactly_one_leaf() {
+        let mut geometry = cube_grid();
+        let triangles_before = sorted_bounds(&geometry);
+        let bvh = Bvh::build(&mut geometry);
+        assert!(bvh.nodes().len() > 1);
+
+        let triangle_bounds = geometry.triangle_bounds();
+        let mut references = vec![0; triangle_bounds.len()];
+        for node in bvh.nodes().iter().filter(|node| node.triangle_count > 0) {
+            let first = node.left_first as usize;
+            for (i, count) in references.iter_mut().enumerate().skip(first).take(node.triangle_count as usize) {
+                *count += 1;
+                let (min, max) = triangle_bounds[i];
+                assert!(contains(node, min, max));
+            }
+        }
+        assert!(references.iter().all(|&count| count == 1));
+
+        // reordering kept every triangle
+        assert_eq!(sorted_bounds(&geometry), triangles_before);
+    }
+
+    #[test]
+    fn children_are_inside_their_parent() {
+        let mut geometry = cube_grid();
+        let bvh = Bvh::build(&mut geometry);
+        let nodes = bvh.nodes();
+        for node in nodes.iter().filter(|node| node.triangle_count == 0) {
+            let left = node.left_first as usize;
+            for child in &nodes[left..left + 2] {
+                assert!(contains(node, child.aabb_min, child.aabb_max));
+            }
+        }
+        assert!(bvh.stack_size() <= Bvh::MAX_DEPTH);
+    }
+
+    #[test]
+    fn no_triangles_give_an_empty_leaf() {
+        let bvh = Bvh::build(&mut SceneGeometry::default());
+        assert_eq!(bvh.nodes().len(), 1);
+        assert_eq!(bvh.nodes()[0].triangle_count, 0);
+    }
+}
@@ -1,5 +1,5 @@
 use std::f32::consts::PI;
-use glam::{Mat4, Vec3, Vec4};
+use glam::{Vec3, Vec4};
 use crate::gui::UserInput;
 
 #[repr(C)]
@@ -49,6 +49,7 @@ pub struct CameraController {
 impl CameraController {
     const SAFE_FRAC_PI:f32 = PI - 0.001;
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(look_from: Vec3, look_at: Vec3, vfov: f32, defocus_angle: f32, focus_distance: f32,
                z_near:f32, z_far: f32, speed: f32, sensitivity: f32) -> Self {
         
@@ -56,7 +57,7 @@ impl CameraController {
         let forwards = (look_at - position).normalize();
 
         let pitch = forwards.y.acos();
-        let yaw = forwards.x.atan2(-forwards.z);
+        let yaw = (-forwards.x).atan2(-forwards.z);
         
         Self {
             position,
@@ -81,6 +82,7 @@ impl CameraController {
         }
     }
     
+    #[allow(dead_code)]
     pub fn updated(&self) -> bool { self.updated }
     
     pub fn reset(&mut self) { self.updated = false; }
@@ -157,11 +159,7 @@ impl CameraController {
         self.rotate_vertical = 0.0;
 
         // Keep the camera's angle from going too high/low.
-        if self.pitch < -Self::SAFE_FRAC_PI {
-            self.pitch = -Self::SAFE_FRAC_PI;
-        } else if self.pitch > Self::SAFE_FRAC_PI {
-            self.pitch = Self::SAFE_FRAC_PI;
-        }
+        self.pitch = self.pitch.clamp(-Self::SAFE_FRAC_PI, Self::SAFE_FRAC_PI);
 
         // after everything has been updated, set the updated flag back to false
         self.reset();
@@ -180,14 +178,12 @@ impl CameraController {
         let r = self.z_far / (self.z_near - self.z_far);
 
         // for the raytracer I need the inverse of the projection matrix
-        let p_inv = [
+        [
             [1.0/ w, 0.0, 0.0, 0.0],
             [0.0, 1.0 / h, 0.0, 0.0],
             [0.0, 0.0, 0.0, 1.0 / (r * self.z_near)],
             [0.0, 0.0, -1.0, 1.0 / self.z_near]
-        ];
-
-        p_inv
+        ]
     }
 
     pub fn get_view_transform(&self) -> [[f32; 4]; 4]
@@ -0,0 +1,450 @@
+use glam::{Mat4, Vec3};
+use wgpu::{BindGroupDescriptor, BindGroupLayoutDescriptor, BufferDescriptor};
+use wgpu_profiler::Scope;
+use crate::gui::UserInput;
+use crate::utilities::u8cast::any_as_u8_slice;
+
+// what the camera rays of a pixel hit, summed over its samples; see PixelFeatures in
+// path_tracing_common.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GPUPixelFeatures {
+    albedo: [f32; 3],
+    depth: f32,
+    normal: [f32; 3],
+    buffering: f32,
+}
+
+// the parameters of one à-trous pass; every pass gets its own slot in the pass buffer
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct GPUDenoisePass {
+    // the distance between the taps of the kernel, doubling from pass to pass
+    step: u32,
+    // 1 on the last pass, which multiplies the albedo back in
+    remodulate: u32,
+    // how quickly the weight of a tap falls off with its difference in color, normal and depth
+    color_phi: f32,
+    normal_phi: f32,
+    depth_phi: f32,
+    buffering: [u32; 3],
+}
+
+// the cameras the image is traced with now and the history was; see Reprojection in
+// denoiser.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct GPUReprojection {
+    inv_projection: [[f32; 4]; 4],
+    view: [[f32; 4]; 4],
+    previous_clip_from_world: [[f32; 4]; 4],
+    previous_position: [f32; 4],
+    previous_size: [u32; 2],
+    // 0 to start every pixel over without a history
+    temporal: u32,
+    // the most samples the history of a pixel counts for
+    max_history: f32,
+}
+
+// the camera matrices as the kernels get them, see PathTracer::update_buffers
+#[derive(Copy, Clone, Debug)]
+struct CameraMatrices {
+    inv_projection: Mat4,
+    view: Mat4,
+}
+
+impl CameraMatrices {
+    // from world space to the clip space of the camera
+    fn clip_from_world(&self) -> Mat4 {
+        // the view matrix maps +z_camera onto the look direction, whereas the projection
+        // looks down -z_camera
+        let flip = Mat4::from_scale(Vec3::new(1.0, 1.0, -1.0));
+        self.inv_projection.inverse() * flip * self.view.inverse()
+    }
+}
+
+// the spatiotemporal variance-guided filter in denoiser.wgsl, run over the accumulated image
+// before it is displayed. The kernels write the albedo, normal and depth of the first hit into
+// the feature buffer as they trace. When the image starts over, the pixels take what was
+// denoised before as their history, reprojected from where the camera was then, so moving
+// the camera doesn't throw everything away. The filtered image ends up in the denoised buffer,
+// which the display shader shows instead of the image when the denoiser is on
+pub struct Denoiser {
+    feature_buffer: wgpu::Buffer,
+    guide_buffer: wgpu::Buffer,
+    previous_guide_buffer: wgpu::Buffer,
+    denoised_buffer: wgpu::Buffer,
+    pass_buffer: wgpu::Buffer,
+    reprojection_buffer: wgpu::Buffer,
+    // per pass, one bind group that reads the scratch buffer and writes the denoised buffer
+    // and one the other way around
+    bind_groups: Vec<[wgpu::BindGroup; 2]>,
+    prepare_pipeline: wgpu::ComputePipeline,
+    reproject_pipeline: wgpu::ComputePipeline,
+    integrate_pipeline: wgpu::ComputePipeline,
+    variance_pipeline: wgpu::ComputePipeline,
+    atrous_pipeline: wgpu::ComputePipeline,
+    enabled: bool,
+    passes: Vec<GPUDenoisePass>,
+    temporal: bool,
+    max_history: u32,
+    // the camera the image is traced with, and the one and the image size of the last
+    // denoised frame, which the history is reprojected from
+    camera: Option<CameraMatrices>,
+    history_camera: Option<(CameraMatrices, u32, u32)>,
+    // set when the image starts over until the next frame has been denoised
+    reproject: bool,
+}
+
+impl Denoiser {
+    pub const MAX_ITERATIONS: u32 = 5;
+
+    pub fn new(device: &wgpu::Device,
+               image_buffer: &wgpu::Buffer,
+               frame_buffer: &wgpu::Buffer,
+               pixel_stats_buffer: &wgpu::Buffer,
+               max_pixels: u32) -> Self {
+        let feature_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Pixel Feature Buffer"),
+            size: max_pixels as wgpu::BufferAddress * size_of::<GPUPixelFeatures>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        // the features averaged over the samples, now and as of the last denoised frame
+        let guide_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Denoiser Guide Buffer"),
+            size: max_pixels as wgpu::BufferAddress * size_of::<GPUPixelFeatures>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        let previous_guide_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Denoiser Previous Guide Buffer"),
+            size: guide_buffer.size(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // a color with a variance or what a pixel integrated over time, 16 and 32 bytes
+        let pixel_buffer = |label: &str, size: wgpu::BufferAddress| device.create_buffer(&BufferDescriptor {
+            label: Some(label),
+            size: max_pixels as wgpu::BufferAddress * size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let denoised_buffer = pixel_buffer("Denoised Buffer", 16);
+        let scratch_buffer = pixel_buffer("Denoiser Scratch Buffer", 16);
+        let history_buffer = pixel_buffer("Denoiser History Buffer", 32);
+        let integrated_buffer = pixel_buffer("Denoiser Integrated Buffer", 32);
+        let motion_vector_buffer = pixel_buffer("Motion Vector Buffer", 2 * size_of::<f32>() as wgpu::BufferAddress);
+
+        let reprojection_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Reprojection Buffer"),
+            size: size_of::<GPUReprojection>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // uniform bindings have to start at a multiple of the offset alignment
+        let pass_stride = device.limits().min_uniform_buffer_offset_alignment as wgpu::BufferAddress;
+        let pass_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Denoise Pass Buffer"),
+            size: Self::MAX_ITERATIONS as wgpu::BufferAddress * pass_stride,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let image_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let frame_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 1,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let pixel_stats_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            ..image_buffer_layout
+        };
+
+        let feature_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            ..image_buffer_layout
+        };
+
+        let guide_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 4,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            ..image_buffer_layout
+        };
+
+        let color_in_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 5,
+            ..image_buffer_layout
+        };
+
+        let color_out_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 6,
+            ..guide_buffer_layout
+        };
+
+        let pass_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 7,
+            ..frame_buffer_layout
+        };
+
+        let reprojection_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 8,
+            ..frame_buffer_layout
+        };
+
+        let previous_guide_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 9,
+            ..image_buffer_layout
+        };
+
+        let history_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 10,
+            ..guide_buffer_layout
+        };
+
+        let integrated_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 11,
+            ..guide_buffer_layout
+        };
+
+        let motion_vector_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 12,
+            ..guide_buffer_layout
+        };
+
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("denoiser bind group layout"),
+            entries: &[
+                image_buffer_layout,
+                frame_buffer_layout,
+                pixel_stats_buffer_layout,
+                feature_buffer_layout,
+                guide_buffer_layout,
+                color_in_buffer_layout,
+                color_out_buffer_layout,
+                pass_buffer_layout,
+                reprojection_buffer_layout,
+                previous_guide_buffer_layout,
+                history_buffer_layout,
+                integrated_buffer_layout,
+                motion_vector_buffer_layout,
+            ],
+        });
+
+        let create_bind_group = |pass: u32, color_in: &wgpu::Buffer, color_out: &wgpu::Buffer| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: Some("denoiser bind group"),
+                layout: &bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry { binding: 0, resource: image_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 1, resource: frame_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 2, resource: pixel_stats_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 3, resource: feature_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 4, resource: guide_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 5, resource: color_in.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 6, resource: color_out.as_entire_binding() },
+                    wgpu::BindGroupEntry {
+                        binding: 7,
+                        resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                            buffer: &pass_buffer,
+                            offset: pass as wgpu::BufferAddress * pass_stride,
+                            size: wgpu::BufferSize::new(size_of::<GPUDenoisePass>() as u64),
+                        }),
+                    },
+                    wgpu::BindGroupEntry { binding: 8, resource: reprojection_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 9, resource: previous_guide_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 10, resource: history_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 11, resource: integrated_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 12, resource: motion_vector_buffer.as_entire_binding() },
+                ],
+            })
+        };
+
+        let bind_groups = (0..Self::MAX_ITERATIONS)
+            .map(|pass| [
+                create_bind_group(pass, &scratch_buffer, &denoised_buffer),
+                create_bind_group(pass, &denoised_buffer, &scratch_buffer),
+            ])
+            .collect();
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("denoiser pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(
+            wgpu::include_wgsl!("../shaders/denoiser.wgsl")
+        );
+
+        let create_pipeline = |entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: Default::default(),
+                cache: None,
+            })
+        };
+
+        Self {
+            feature_buffer,
+            guide_buffer,
+            previous_guide_buffer,
+            denoised_buffer,
+            pass_buffer,
+            reprojection_buffer,
+            bind_groups,
+            prepare_pipeline: create_pipeline("prepareDenoiser"),
+            reproject_pipeline: create_pipeline("reproject"),
+            integrate_pipeline: create_pipeline("integrate"),
+            variance_pipeline: create_pipeline("estimateVariance"),
+            atrous_pipeline: create_pipeline("atrous"),
+            enabled: false,
+            passes: Vec::new(),
+            temporal: false,
+            max_history: 0,
+            camera: None,
+            history_camera: None,
+            reproject: false,
+        }
+    }
+
+    pub fn feature_buffer(&self) -> &wgpu::Buffer {
+        &self.feature_buffer
+    }
+
+    pub fn denoised_buffer(&self) -> &wgpu::Buffer {
+        &self.denoised_buffer
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_camera(&mut self, inv_projection: [[f32; 4]; 4], view: [[f32; 4]; 4]) {
+        self.camera = Some(CameraMatrices {
+            inv_projection: Mat4::from_cols_array_2d(&inv_projection),
+            view: Mat4::from_cols_array_2d(&view),
+        });
+    }
+
+    // forgets the features along with the samples; the guide of the last denoised frame is
+    // kept to tell which pixels can be reprojected
+    pub fn reset(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.clear_buffer(&self.feature_buffer, 0, None);
+        encoder.copy_buffer_to_buffer(&self.guide_buffer, 0, &self.previous_guide_buffer, 0, self.guide_buffer.size());
+        self.reproject = true;
+    }
+
+    // the denoiser only changes what is shown, so its settings don't reset the image; returns
+    // whether they changed, in which case the image has to be denoised again
+    pub fn process_user_input(&mut self, queue: &wgpu::Queue, user_input: &UserInput) -> bool {
+        let iterations = user_input.denoise_iterations().clamp(1, Self::MAX_ITERATIONS);
+        let passes: Vec<GPUDenoisePass> = (0..iterations)
+            .map(|i| GPUDenoisePass {
+                step: 1 << i,
+                remodulate: (i == iterations - 1) as u32,
+                // the variance is filtered along, so the coarser passes get less leeway by themselves
+                color_phi: user_input.denoise_color_phi(),
+                normal_phi: user_input.denoise_normal_phi(),
+                depth_phi: user_input.denoise_depth_phi(),
+                buffering: [0; 3],
+            })
+            .collect();
+
+        let changed = user_input.denoise() != self.enabled
+            || passes != self.passes
+            || user_input.denoise_temporal() != self.temporal
+            || user_input.denoise_max_history() != self.max_history;
+        if passes != self.passes {
+            let stride = self.pass_buffer.size() / Self::MAX_ITERATIONS as wgpu::BufferAddress;
+            for (i, pass) in passes.iter().enumerate() {
+                queue.write_buffer(&self.pass_buffer, i as wgpu::BufferAddress * stride, unsafe { any_as_u8_slice(pass) });
+            }
+        }
+        self.enabled = user_input.denoise();
+        self.passes = passes;
+        self.temporal = user_input.denoise_temporal();
+        self.max_history = user_input.denoise_max_history();
+        changed
+    }
+
+    fn write_reprojection(&self, queue: &wgpu::Queue) {
+        let mut reprojection = GPUReprojection {
+            max_history: self.max_history as f32,
+            ..Default::default()
+        };
+        if let Some(camera) = self.camera {
+            reprojection.inv_projection = camera.inv_projection.to_cols_array_2d();
+            reprojection.view = camera.view.to_cols_array_2d();
+        }
+        // without a frame denoised before, there is nothing to reproject
+        if let (true, Some((history_camera, width, height))) = (self.temporal, self.history_camera) {
+            reprojection.previous_clip_from_world = history_camera.clip_from_world().to_cols_array_2d();
+            reprojection.previous_position = history_camera.view.w_axis.to_array();
+            reprojection.previous_size = [width, height];
+            reprojection.temporal = 1;
+        }
+        queue.write_buffer(&self.reprojection_buffer, 0, unsafe { any_as_u8_slice(&reprojection) });
+    }
+
+    // filters the image accumulated so far into the denoised buffer
+    pub fn denoise(&mut self, queue: &wgpu::Queue, scope: &mut Scope<'_, wgpu::CommandEncoder>, width: u32, height: u32) {
+        self.write_reprojection(queue);
+        // from here on, the frame being denoised is the history of the next reprojection
+        let reproject = std::mem::take(&mut self.reproject);
+        self.history_camera = self.camera.map(|camera| (camera, width, height));
+
+        // the passes go back and forth between the scratch and the denoised buffer, starting
+        // on whichever side makes the last one end up in the denoised buffer
+        let iterations = self.passes.len();
+        let direction = |pass: usize| (iterations - 1 - pass) % 2;
+        let workgroups = (width.div_ceil(8), height.div_ceil(8));
+
+        let mut compute_pass = scope.scoped_compute_pass("denoise");
+        compute_pass.set_bind_group(0, &self.bind_groups[0][1 - direction(0)], &[]);
+        compute_pass.set_pipeline(&self.prepare_pipeline);
+        compute_pass.dispatch_workgroups(workgroups.0, workgroups.1, 1);
+        if reproject {
+            compute_pass.set_pipeline(&self.reproject_pipeline);
+            compute_pass.dispatch_workgroups(workgroups.0, workgroups.1, 1);
+        }
+        compute_pass.set_pipeline(&self.integrate_pipeline);
+        compute_pass.dispatch_workgroups(workgroups.0, workgroups.1, 1);
+        compute_pass.set_pipeline(&self.variance_pipeline);
+        compute_pass.dispatch_workgroups(workgroups.0, workgroups.1, 1);
+
+        compute_pass.set_pipeline(&self.atrous_pipeline);
+        for pass in 0..iterations {
+            compute_pass.set_bind_group(0, &self.bind_groups[pass][direction(pass)], &[]);
+            compute_pass.dispatch_workgroups(workgroups.0, workgroups.1, 1);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use glam::{Mat3, Vec3};
+use crate::gui::UserInput;
+
+// how the display squeezes the radiance into what a screen can show; the values match the
+// TONE_MAPPER_ constants in screen_shader.wgsl
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ToneMapper {
+    // cuts everything above 1 off
+    #[default]
+    Clamp = 0,
+    // c / (1 + c) per channel
+    Reinhard = 1,
+    // Stephen Hill's fit of the ACES reference rendering and output transforms
+    AcesFitted = 2,
+    // Troy Sobotka's AgX, after the minimal polynomial version by Benjamin Wrensch
+    AgX = 3,
+    // the Khronos PBR Neutral tone mapper, which keeps the base colors of materials as they are
+    PbrNeutral = 4,
+}
+
+impl ToneMapper {
+    pub const ALL: [ToneMapper; 5] = [
+        ToneMapper::Clamp,
+        ToneMapper::Reinhard,
+        ToneMapper::AcesFitted,
+        ToneMapper::AgX,
+        ToneMapper::PbrNeutral,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToneMapper::Clamp => "Clamp",
+            ToneMapper::Reinhard => "Reinhard",
+            ToneMapper::AcesFitted => "ACES (fitted)",
+            ToneMapper::AgX => "AgX",
+            ToneMapper::PbrNeutral => "Khronos PBR Neutral",
+        }
+    }
+
+    // the name used on the command line
+    pub fn option_name(&self) -> &'static str {
+        match self {
+            ToneMapper::Clamp => "clamp",
+            ToneMapper::Reinhard => "reinhard",
+            ToneMapper::AcesFitted => "aces",
+            ToneMapper::AgX => "agx",
+            ToneMapper::PbrNeutral => "pbr-neutral",
+        }
+    }
+
+    // maps linear radiance to linear display values in [0, 1]; a twin of toneMap in
+    // screen_shader.wgsl, and the two have to be kept in sync
+    fn apply(&self, color: Vec3) -> Vec3 {
+        let color = color.max(Vec3::ZERO);
+        match self {
+            ToneMapper::Clamp => color.min(Vec3::ONE),
+            ToneMapper::Reinhard => color / (Vec3::ONE + color),
+            ToneMapper::AcesFitted => aces_fitted(color),
+            ToneMapper::AgX => agx(color),
+            ToneMapper::PbrNeutral => pbr_neutral(color),
+        }
+    }
+}
+
+fn aces_fitted(color: Vec3) -> Vec3 {
+    // sRGB to the ACES rendering space with the RRT's saturation tweak, and back
+    let input = Mat3::from_cols_array(&[
+        0.59719, 0.07600, 0.02840,
+        0.35458, 0.90834, 0.13383,
+        0.04823, 0.01566, 0.83777,
+    ]);
+    let output = Mat3::from_cols_array(&[
+        1.60475, -0.10208, -0.00327,
+        -0.53108, 1.10813, -0.07276,
+        -0.07367, -0.00605, 1.07602,
+    ]);
+    let v = input * color;
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.432951) + 0.238081;
+    (output * (a / b)).clamp(Vec3::ZERO, Vec3::ONE)
+}
+
+fn agx(color: Vec3) -> Vec3 {
+    let inset = Mat3::from_cols_array(&[
+        0.8424791, 0.04232824, 0.04237565,
+        0.0784336, 0.8784686, 0.0784336,
+        0.07922375, 0.07916613, 0.879143,
+    ]);
+    let outset = Mat3::from_cols_array(&[
+        1.196879, -0.05289685, -0.05297164,
+        -0.09802088, 1.151903, -0.09804345,
+        -0.09902974, -0.09896118, 1.151074,
+    ]);
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    // log encoded between the two exposures, then through the sigmoid
+    let log = (inset * color).max(Vec3::splat(1e-10)).to_array()
+        .map(|c| (c.log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV));
+    let curve = log.map(|x| {
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+    });
+    // the curve comes out with a gamma of 2.2 built in
+    (outset * Vec3::from_array(curve)).max(Vec3::ZERO).powf(2.2).min(Vec3::ONE)
+}
+
+fn pbr_neutral(color: Vec3) -> Vec3 {
+    const START_COMPRESSION: f32 = 0.8 - 0.04;
+    const DESATURATION: f32 = 0.15;
+
+    let x = color.min_element();
+    let offset = if x < 0.08 { x - 6.25 * x * x } else { 0.04 };
+    let color = color - offset;
+
+    let peak = color.max_element();
+    if peak < START_COMPRESSION {
+        return color;
+    }
+
+    let d = 1.0 - START_COMPRESSION;
+    let new_peak = 1.0 - d * d / (peak + d - START_COMPRESSION);
+    let color = color * new_peak / peak;
+    let g = 1.0 - 1.0 / (DESATURATION * (peak - new_peak) + 1.0);
+    color.lerp(Vec3::splat(new_peak), g)
+}
+
+fn srgb_oetf(c: f32) -> f32 {
+    if c <= 0.0031308 { 12.92 * c } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+// exposure, tone mapping and the sRGB encoding, the same as the display shader applies; the
+// PNG export uses it to write what is on screen
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DisplayTransform {
+    // in stops
+    exposure: f32,
+    tone_mapper: ToneMapper,
+}
+
+impl DisplayTransform {
+    pub fn new(exposure: f32, tone_mapper: ToneMapper) -> Self {
+        Self {
+            exposure,
+            tone_mapper,
+        }
+    }
+
+    // from averaged radiance to sRGB encoded values in [0, 1]
+    pub fn apply(&self, color: Vec3) -> Vec3 {
+        let display = self.tone_mapper.apply(color * self.exposure.exp2());
+        Vec3::from_array(display.to_array().map(srgb_oetf))
+    }
+}
+
+// how the display shader shows the accumulated image. None of it changes the image itself,
+// so it is written every frame rather than only when the user input resets the render
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct GPUDisplayParameters {
+    // 2^exposure, what the radiance gets scaled by before tone mapping
+    exposure_scale: f32,
+    tone_mapper: u32,
+    // 1 to overlay how many samples every pixel has taken
+    sample_heatmap: u32,
+    // 1 to show the denoised image rather than the accumulated one
+    denoised: u32,
+    // the Aov to show in place of the image, 0 for the image
+    aov: u32,
+    buffering: [u32; 3],
+}
+
+impl Default for GPUDisplayParameters {
+    fn default() -> Self {
+        Self {
+            exposure_scale: 1.0,
+            tone_mapper: ToneMapper::default() as u32,
+            sample_heatmap: 0,
+            denoised: 0,
+            aov: 0,
+            buffering: [0; 3],
+        }
+    }
+}
+
+impl GPUDisplayParameters {
+    pub fn process_user_input(&mut self, user_input: &UserInput) {
+        self.exposure_scale = user_input.exposure().exp2();
+        self.tone_mapper = user_input.tone_mapper() as u32;
+        self.sample_heatmap = user_input.sample_heatmap() as u32;
+        self.denoised = user_input.denoise() as u32;
+        self.aov = user_input.viewport_aov().map_or(0, |aov| aov as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!(a.abs_diff_eq(b, 1e-5), "{a} vs {b}");
+    }
+
+    #[test]
+    fn clamp_and_reinhard() {
+        assert_close(ToneMapper::Clamp.apply(Vec3::new(-1.0, 0.5, 2.0)), Vec3::new(0.0, 0.5, 1.0));
+        assert_close(ToneMapper::Reinhard.apply(Vec3::new(1.0, 3.0, 0.0)), Vec3::new(0.5, 0.75, 0.0));
+    }
+
+    #[test]
+    fn pbr_neutral_only_compresses_above_the_knee() {
+        // below the knee only the offset comes off
+        assert_close(ToneMapper::PbrNeutral.apply(Vec3::splat(0.5)), Vec3::splat(0.46));
+        assert_close(ToneMapper::PbrNeutral.apply(Vec3::new(0.5, 0.3, 0.2)), Vec3::new(0.46, 0.26, 0.16));
+        // the compression starts at a peak of 0.76 and joins on without a jump
+        assert_close(ToneMapper::PbrNeutral.apply(Vec3::splat(0.8)), Vec3::splat(0.76));
+        assert_close(ToneMapper::PbrNeutral.apply(Vec3::splat(0.8001)), Vec3::splat(0.7601));
+        // 1 - 0.24² / (0.96 + 0.24 - 0.76)
+        assert_close(ToneMapper::PbrNeutral.apply(Vec3::ONE), Vec3::splat(0.8690909));
+        let bright = ToneMapper::PbrNeutral.apply(Vec3::splat(1e4));
+        assert!(bright.cmplt(Vec3::ONE).all() && bright.cmpgt(Vec3::splat(0.999)).all());
+    }
+
+    #[test]
+    fn every_tone_mapper_stays_in_range_and_rises() {
+        for tone_mapper in ToneMapper::ALL {
+            let mut previous = tone_mapper.apply(Vec3::ZERO);
+            assert!(previous.abs_diff_eq(Vec3::ZERO, 1e-3), "{} maps black to {previous}", tone_mapper.name());
+            for i in 1..=64 {
+                let mapped = tone_mapper.apply(Vec3::splat(0.01 * 1.2f32.powi(i)));
+                assert!(mapped.cmpge(Vec3::ZERO).all() && mapped.cmple(Vec3::ONE).all());
+                assert!(mapped.cmpge(previous - 1e-6).all(), "{} falls at step {i}", tone_mapper.name());
+                previous = mapped;
+            }
+        }
+    }
+
+    #[test]
+    fn display_transform_exposes_then_encodes() {
+        // one stop down takes 2 to 1, which Reinhard maps to 0.5
+        let transform = DisplayTransform::new(-1.0, ToneMapper::Reinhard);
+        assert_close(transform.apply(Vec3::splat(2.0)), Vec3::splat(0.735357));
+        assert_close(transform.apply(Vec3::ZERO), Vec3::ZERO);
+    }
+}
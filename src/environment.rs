@@ -0,0 +1,239 @@
+use std::f32::consts::PI;
+use std::path::Path;
+use crate::gui::UserInput;
+use crate::sky::GPUSky;
+
+// what paths that leave the scene see; the values match the BACKGROUND_ constants in
+// environment.wgsl
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Background {
+    #[default]
+    Gradient = 0,
+    // falls back to the gradient if the scene has no environment map
+    EnvironmentMap = 1,
+    // the Preetham sky with a sun disk
+    SunSky = 2,
+}
+
+impl Background {
+    pub const ALL: [Background; 3] = [
+        Background::Gradient,
+        Background::EnvironmentMap,
+        Background::SunSky,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Background::Gradient => "Gradient",
+            Background::EnvironmentMap => "Environment map",
+            Background::SunSky => "Sun and sky",
+        }
+    }
+
+    // the name used on the command line
+    pub fn option_name(&self) -> &'static str {
+        match self {
+            Background::Gradient => "gradient",
+            Background::EnvironmentMap => "environment",
+            Background::SunSky => "sun-sky",
+        }
+    }
+}
+
+// an equirectangular environment map that lights the scene from infinitely far away, along
+// with the distribution the kernels importance sample it by. The distribution is piecewise
+// constant over the texels: a marginal cdf picks the row and the conditional cdf of that row
+// picks the column, both weighted by the luminance of the texels times the sine of their
+// latitude, since the rows near the poles cover less solid angle
+pub struct Environment {
+    width: u32,
+    height: u32,
+    texels: Vec<[f32; 4]>,
+    // the conditional cdfs of all the rows one after another, followed by the marginal cdf
+    cdf: Vec<f32>,
+}
+
+impl Environment {
+    // loads a Radiance .hdr or OpenEXR image
+    pub fn load(path: &Path) -> Result<Self, image::ImageError> {
+        let image = image::open(path)?.to_rgba32f();
+        Ok(Self::from_rgba32f(image.width(), image.height(), image.as_raw()))
+    }
+
+    pub fn from_rgba32f(width: u32, height: u32, rgba: &[f32]) -> Self {
+        assert_eq!(rgba.len(), 4 * (width * height) as usize, "environment data must be RGBA32F");
+
+        // the alpha channel isn't used, and NaNs or negative values would break the cdf
+        let texels: Vec<[f32; 4]> = rgba.chunks_exact(4)
+            .map(|texel| {
+                let channel = |value: f32| if value.is_finite() { value.max(0.0) } else { 0.0 };
+                [channel(texel[0]), channel(texel[1]), channel(texel[2]), 1.0]
+            })
+            .collect();
+
+        let mut cdf = Vec::with_capacity((width * height + height) as usize);
+        let mut row_weights = Vec::with_capacity(height as usize);
+        for row in texels.chunks_exact(width as usize) {
+            let weights: Vec<f32> = row.iter()
+                .map(|texel| 0.2126 * texel[0] + 0.7152 * texel[1] + 0.0722 * texel[2])
+                .collect();
+            let sin_theta = (PI * (row_weights.len() as f32 + 0.5) / height as f32).sin();
+            row_weights.push(sin_theta * weights.iter().sum::<f32>());
+            cdf.extend(Self::build_cdf(&weights));
+        }
+        cdf.extend(Self::build_cdf(&row_weights));
+
+        Self {
+            width,
+            height,
+            texels,
+            cdf,
+        }
+    }
+
+    // the normalized running sum of the weights; all black gives a uniform cdf instead
+    fn build_cdf(weights: &[f32]) -> Vec<f32> {
+        let total: f32 = weights.iter().sum();
+        let mut sum = 0.0;
+        let mut cdf: Vec<f32> = weights.iter()
+            .enumerate()
+            .map(|(i, weight)| {
+                if total > 0.0 {
+                    sum += weight / total;
+                    sum
+                } else {
+                    (i + 1) as f32 / weights.len() as f32
+                }
+            })
+            .collect();
+        // rounding must not leave a sliver at the top that no texel covers
+        if let Some(last) = cdf.last_mut() {
+            *last = 1.0;
+        }
+        cdf
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn texels(&self) -> &[[f32; 4]] {
+        &self.texels
+    }
+
+    pub fn cdf(&self) -> &[f32] {
+        &self.cdf
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct GPUEnvironmentParameters {
+    // rotation of the environment map about the y axis in radians
+    rotation: f32,
+    intensity: f32,
+    background: u32,
+    // 1 if an environment map is bound, 0 if there is just a placeholder
+    environment_map: u32,
+    sky: GPUSky,
+}
+
+impl GPUEnvironmentParameters {
+    pub fn new(environment_map: bool) -> Self {
+        Self {
+            rotation: 0.0,
+            intensity: 1.0,
+            background: Background::default() as u32,
+            environment_map: environment_map as u32,
+            sky: GPUSky::default(),
+        }
+    }
+
+    pub fn process_user_input(&mut self, user_input: &UserInput) {
+        self.rotation = user_input.environment_rotation().to_radians();
+        self.intensity = user_input.environment_intensity();
+        self.background = match user_input.background() {
+            Background::EnvironmentMap if self.environment_map == 0 => Background::Gradient,
+            background => background,
+        } as u32;
+        if self.background == Background::SunSky as u32 {
+            self.sky = GPUSky::new(
+                user_input.sun_elevation(),
+                user_input.sun_azimuth(),
+                user_input.turbidity(),
+                user_input.ground_albedo(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the probabilities the cdf was built from
+    fn pmf(cdf: &[f32]) -> Vec<f32> {
+        let mut previous = 0.0;
+        cdf.iter()
+            .map(|&value| {
+                let probability = value - previous;
+                previous = value;
+                probability
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_cdf_ends_at_one() {
+        let (width, height) = (7, 5);
+        let rgba: Vec<f32> = (0..4 * width * height)
+            .map(|i| ((i * 37) % 11) as f32 * 0.3)
+            .collect();
+        let environment = Environment::from_rgba32f(width, height, &rgba);
+        assert_eq!(environment.cdf().len(), (width * height + height) as usize);
+
+        // the rows one after another, then the marginal cdf
+        let rows = environment.cdf().chunks(width as usize).take(height as usize);
+        let marginal = &environment.cdf()[(width * height) as usize..];
+        for cdf in rows.chain(std::iter::once(marginal)) {
+            assert_eq!(*cdf.last().unwrap(), 1.0);
+            assert!(pmf(cdf).iter().all(|&probability| probability >= 0.0));
+        }
+    }
+
+    #[test]
+    fn rows_are_weighted_by_their_solid_angle() {
+        let (width, height) = (4, 6);
+        let environment = Environment::from_rgba32f(width, height, &vec![1.0; 4 * (width * height) as usize]);
+        let marginal = pmf(&environment.cdf()[(width * height) as usize..]);
+
+        let sin_theta: Vec<f32> = (0..height)
+            .map(|row| (PI * (row as f32 + 0.5) / height as f32).sin())
+            .collect();
+        let total: f32 = sin_theta.iter().sum();
+        for (probability, sin_theta) in marginal.iter().zip(&sin_theta) {
+            assert!((probability - sin_theta / total).abs() < 1e-5, "{probability} vs {}", sin_theta / total);
+        }
+        // and a uniform row is sampled uniformly
+        for probability in pmf(&environment.cdf()[..width as usize]) {
+            assert!((probability - 0.25).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn a_black_map_gives_uniform_cdfs() {
+        let (width, height) = (4, 2);
+        let mut rgba = vec![0.0; 4 * (width * height) as usize];
+        // negative and nan texels count as black
+        rgba[0] = -1.0;
+        rgba[5] = f32::NAN;
+        let environment = Environment::from_rgba32f(width, height, &rgba);
+        assert!(environment.texels().iter().flatten().all(|value| value.is_finite() && *value >= 0.0));
+        assert_eq!(environment.cdf(), [0.25, 0.5, 0.75, 1.0, 0.25, 0.5, 0.75, 1.0, 0.5, 1.0]);
+    }
+}
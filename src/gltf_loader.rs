@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::path::Path;
+use glam::{Mat3, Mat4, Vec2, Vec3};
+use gltf::texture::WrappingMode;
+use crate::light::PunctualLight;
+use crate::material::{AlphaMode, Material, MaterialType};
+use crate::mesh::Mesh;
+use crate::scene::{Scene, SceneCamera};
+
+// reads a .gltf or .glb file into the scene. The meshes and KHR_lights_punctual lights of the
+// default scene are flattened into world space by walking the node hierarchy, and the first
+// perspective camera found becomes the scene camera. gltf::import resolves external and embedded (data uri or glb)
+// buffers and images
+pub fn load_gltf(path: &Path, scene: &mut Scene) -> Result<(), gltf::Error> {
+    let (document, buffers, images) = gltf::import(path)?;
+
+    for extension in document.extensions_used().filter(|extension| IGNORED_EXTENSIONS.contains(extension)) {
+        println!("Ignoring {} in {}", extension, path.display());
+    }
+
+    let mut loader = GltfLoader {
+        buffers: &buffers,
+        images: &images,
+        scene,
+        material_ids: HashMap::new(),
+        texture_ids: HashMap::new(),
+    };
+
+    let Some(gltf_scene) = document.default_scene().or_else(|| document.scenes().next()) else {
+        return Ok(());
+    };
+    for node in gltf_scene.nodes() {
+        loader.load_node(&node, Mat4::IDENTITY);
+    }
+
+    Ok(())
+}
+
+// extensions that change how a scene looks, but that the loader doesn't implement
+const IGNORED_EXTENSIONS: [&str; 1] = ["KHR_texture_transform"];
+
+struct GltfLoader<'a> {
+    buffers: &'a [gltf::buffer::Data],
+    images: &'a [gltf::image::Data],
+    scene: &'a mut Scene,
+    // glTF material index (None for the default material) to scene material id
+    material_ids: HashMap<Option<usize>, u32>,
+    // (image index, srgb) to scene texture id, since an image can be used as color and as data
+    texture_ids: HashMap<(usize, bool), u32>,
+}
+
+impl GltfLoader<'_> {
+    fn load_node(&mut self, node: &gltf::Node, parent_transform: Mat4) {
+        let transform = parent_transform * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                self.load_primitive(&primitive, transform);
+            }
+        }
+
+        if let Some(camera) = node.camera() {
+            self.load_camera(&camera, transform);
+        }
+
+        if let Some(light) = node.light() {
+            self.load_light(&light, transform);
+        }
+
+        for child in node.children() {
+            self.load_node(&child, transform);
+        }
+    }
+
+    fn load_primitive(&mut self, primitive: &gltf::Primitive, transform: Mat4) {
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            println!("Skipping glTF primitive with unsupported mode {:?}", primitive.mode());
+            return;
+        }
+
+        let reader = primitive.reader(|buffer| Some(&self.buffers[buffer.index()]));
+        let Some(positions) = reader.read_positions() else {
+            return;
+        };
+
+        // normals go through the inverse transpose, so that non-uniform scales keep them
+        // perpendicular to the surface
+        let normal_transform = Mat3::from_mat4(transform).inverse().transpose();
+        let positions: Vec<Vec3> = positions
+            .map(|p| transform.transform_point3(Vec3::from(p)))
+            .collect();
+        let normals: Vec<Vec3> = reader.read_normals()
+            .map(|normals| normals.map(|n| (normal_transform * Vec3::from(n)).normalize_or_zero()).collect())
+            .unwrap_or_default();
+        let uvs: Vec<Vec2> = reader.read_tex_coords(0)
+            .map(|uvs| uvs.into_f32().map(Vec2::from).collect())
+            .unwrap_or_default();
+        let mut indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        let material_id = self.material_id(&primitive.material());
+        let triangle_count = indices.len() / 3;
+        indices.truncate(3 * triangle_count);
+        keep_winding(&transform, &mut indices);
+        let has_normals = !normals.is_empty();
+        let mut mesh = Mesh::new(positions, normals, uvs, indices, vec![material_id; triangle_count]);
+        if !has_normals {
+            mesh.generate_smooth_normals();
+        }
+        self.scene.add_mesh(&mesh);
+    }
+
+    // glTF cameras look down their local -z axis with +y up
+    fn load_camera(&mut self, camera: &gltf::Camera, transform: Mat4) {
+        let gltf::camera::Projection::Perspective(perspective) = camera.projection() else {
+            println!("Skipping orthographic glTF camera");
+            return;
+        };
+        if self.scene.camera().is_some() {
+            return;
+        }
+
+        let look_from = transform.transform_point3(Vec3::ZERO);
+        let forward = transform.transform_vector3(Vec3::NEG_Z).normalize();
+        self.scene.set_camera(SceneCamera {
+            look_from,
+            look_at: look_from + forward,
+            vfov: perspective.yfov().to_degrees(),
+        });
+    }
+
+    // lights shine down their local -z axis; the intensities (candela for point and spot
+    // lights, lux for directional lights) are taken as they are. The range isn't, the lights
+    // fall off with the inverse square of the distance all the way
+    fn load_light(&mut self, light: &gltf::khr_lights_punctual::Light, transform: Mat4) {
+        if let Some(range) = light.range() {
+            println!("Ignoring the range {} of glTF light {}", range, light.index());
+        }
+        let position = transform.transform_point3(Vec3::ZERO);
+        let direction = transform.transform_vector3(Vec3::NEG_Z);
+        let color = Vec3::from(light.color());
+        let punctual_light = match light.kind() {
+            gltf::khr_lights_punctual::Kind::Point => PunctualLight::point(position, color, light.intensity(), 0.0),
+            gltf::khr_lights_punctual::Kind::Spot { inner_cone_angle, outer_cone_angle } =>
+                PunctualLight::spot(position, direction, color, light.intensity(), inner_cone_angle, outer_cone_angle),
+            gltf::khr_lights_punctual::Kind::Directional => PunctualLight::directional(direction, color, light.intensity(), 0.0),
+        };
+        self.scene.add_punctual_light(punctual_light);
+    }
+
+    fn material_id(&mut self, material: &gltf::Material) -> u32 {
+        if let Some(material_id) = self.material_ids.get(&material.index()) {
+            return *material_id;
+        }
+
+        let pbr = material.pbr_metallic_roughness();
+        let base_color = pbr.base_color_factor();
+        let mut converted = Material {
+            material_type: MaterialType::Principled,
+            base_color: Vec3::new(base_color[0], base_color[1], base_color[2]),
+            roughness: pbr.roughness_factor(),
+            metallic: pbr.metallic_factor(),
+            emission: Vec3::from(material.emissive_factor()) * material.emissive_strength().unwrap_or(1.0),
+            ior: material.ior().unwrap_or(1.5),
+            transmission: material.transmission().map_or(0.0, |transmission| transmission.transmission_factor()),
+            // KHR_materials_specular has 1 for the reflectance given by the ior
+            specular: material.specular().map_or(0.5, |specular| 0.5 * specular.specular_factor()),
+            ..Default::default()
+        };
+        convert_alpha(material, &mut converted);
+
+        converted.base_color_texture = pbr.base_color_texture()
+            .map(|info| self.texture_id(&info.texture(), true));
+        converted.metallic_roughness_texture = pbr.metallic_roughness_texture()
+            .map(|info| self.texture_id(&info.texture(), false));
+        converted.emission_texture = material.emissive_texture()
+            .map(|info| self.texture_id(&info.texture(), true));
+
+        let material_id = self.scene.add_material(&converted);
+        self.material_ids.insert(material.index(), material_id);
+        material_id
+    }
+
+    fn texture_id(&mut self, texture: &gltf::Texture, srgb: bool) -> u32 {
+        let image_index = texture.source().index();
+        if let Some(texture_id) = self.texture_ids.get(&(image_index, srgb)) {
+            return *texture_id;
+        }
+
+        // the kernels always wrap around
+        let sampler = texture.sampler();
+        if sampler.wrap_s() != WrappingMode::Repeat || sampler.wrap_t() != WrappingMode::Repeat {
+            println!("glTF texture {} is {:?}/{:?}, which is read as repeating", texture.index(),
+                     sampler.wrap_s(), sampler.wrap_t());
+        }
+
+        let image = &self.images[image_index];
+        let rgba = image_to_rgba8(image, srgb);
+        let texture_id = self.scene.textures_mut().add_rgba8(image.width, image.height, &rgba, srgb);
+        self.texture_ids.insert((image_index, srgb), texture_id);
+        texture_id
+    }
+}
+
+// the alpha of the base color factor becomes the opacity, which the kernels multiply with the
+// alpha of the base color texture; an opaque material ignores both
+fn convert_alpha(material: &gltf::Material, converted: &mut Material) {
+    converted.alpha_mode = match material.alpha_mode() {
+        gltf::material::AlphaMode::Opaque => return,
+        gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+        gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+    };
+    converted.opacity = material.pbr_metallic_roughness().base_color_factor()[3];
+    converted.alpha_cutoff = material.alpha_cutoff().unwrap_or(0.5);
+}
+
+// a mirroring transform turns the triangles inside out, so their winding gets flipped back
+fn keep_winding(transform: &Mat4, indices: &mut [u32]) {
+    if transform.determinant() < 0.0 {
+        for triangle in indices.chunks_exact_mut(3) {
+            triangle.swap(1, 2);
+        }
+    }
+}
+
+// the decoded images come in whatever format the file had; the texel buffer wants RGBA8.
+// Two channel color images are grey with alpha, two channel data keeps its channels
+fn image_to_rgba8(image: &gltf::image::Data, srgb: bool) -> Vec<u8> {
+    use gltf::image::Format;
+
+    let (channels, bytes_per_channel) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+
+    let channel = |bytes: &[u8]| -> u8 {
+        match bytes_per_channel {
+            1 => bytes[0],
+            2 => (u16::from_ne_bytes([bytes[0], bytes[1]]) >> 8) as u8,
+            _ => (f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).clamp(0.0, 1.0) * 255.0).round() as u8,
+        }
+    };
+
+    image.pixels.chunks_exact(channels * bytes_per_channel)
+        .flat_map(|pixel| {
+            let c: Vec<u8> = pixel.chunks_exact(bytes_per_channel).map(channel).collect();
+            match channels {
+                1 => [c[0], c[0], c[0], 255],
+                2 if srgb => [c[0], c[0], c[0], c[1]],
+                2 => [c[0], c[1], 0, 255],
+                3 => [c[0], c[1], c[2], 255],
+                _ => [c[0], c[1], c[2], c[3]],
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gltf::image::Format;
+    use glam::Quat;
+
+    fn image(format: Format, pixels: Vec<u8>) -> gltf::image::Data {
+        gltf::image::Data { pixels, format, width: 1, height: 1 }
+    }
+
+    #[test]
+    fn images_expand_to_rgba8() {
+        assert_eq!(image_to_rgba8(&image(Format::R8, vec![7]), true), [7, 7, 7, 255]);
+        assert_eq!(image_to_rgba8(&image(Format::R8G8, vec![7, 9]), true), [7, 7, 7, 9]);
+        assert_eq!(image_to_rgba8(&image(Format::R8G8, vec![7, 9]), false), [7, 9, 0, 255]);
+        assert_eq!(image_to_rgba8(&image(Format::R8G8B8, vec![1, 2, 3]), true), [1, 2, 3, 255]);
+        assert_eq!(image_to_rgba8(&image(Format::R8G8B8A8, vec![1, 2, 3, 4]), false), [1, 2, 3, 4]);
+
+        // 16 bit channels keep their high byte
+        let r16: Vec<u8> = [0xabcdu16, 0x1234, 0xffff, 0x0100].iter().flat_map(|c| c.to_ne_bytes()).collect();
+        assert_eq!(image_to_rgba8(&image(Format::R16G16B16A16, r16), false), [0xab, 0x12, 0xff, 0x01]);
+
+        // floats get clamped to [0, 1] and rounded
+        let floats: Vec<u8> = [0.5f32, -1.0, 2.0].iter().flat_map(|c| c.to_ne_bytes()).collect();
+        assert_eq!(image_to_rgba8(&image(Format::R32G32B32FLOAT, floats), true), [128, 0, 255, 255]);
+    }
+
+    #[test]
+    fn alpha_modes_carry_the_base_color_alpha() {
+        let json = r#"{
+            "asset": { "version": "2.0" },
+            "materials": [
+                { "pbrMetallicRoughness": { "baseColorFactor": [1, 1, 1, 0.25] } },
+                { "pbrMetallicRoughness": { "baseColorFactor": [1, 1, 1, 0.25] }, "alphaMode": "BLEND" },
+                { "alphaMode": "MASK", "alphaCutoff": 0.75 },
+                { "alphaMode": "MASK" }
+            ]
+        }"#;
+        let document = gltf::Gltf::from_slice(json.as_bytes()).unwrap();
+        let converted: Vec<Material> = document.materials().map(|material| {
+            let mut converted = Material::default();
+            convert_alpha(&material, &mut converted);
+            converted
+        }).collect();
+
+        // an opaque material keeps its opacity whatever the alpha
+        assert_eq!(converted[0].alpha_mode, AlphaMode::Opaque);
+        assert_eq!(converted[0].opacity, 1.0);
+        assert_eq!(converted[1].alpha_mode, AlphaMode::Blend);
+        assert_eq!(converted[1].opacity, 0.25);
+        assert_eq!(converted[2].alpha_mode, AlphaMode::Mask);
+        assert_eq!((converted[2].opacity, converted[2].alpha_cutoff), (1.0, 0.75));
+        // the cutoff defaults to 0.5
+        assert_eq!(converted[3].alpha_cutoff, 0.5);
+    }
+
+    #[test]
+    fn mirroring_transforms_keep_the_winding() {
+        let positions = [Vec3::ZERO, Vec3::X, Vec3::Y];
+        let transforms = [
+            Mat4::IDENTITY,
+            Mat4::from_scale(Vec3::new(-1.0, 1.0, 1.0)),
+            Mat4::from_scale(Vec3::new(2.0, -3.0, 0.5)),
+            Mat4::from_scale(Vec3::NEG_ONE),
+            Mat4::from_rotation_translation(Quat::from_rotation_y(1.0), Vec3::ONE),
+        ];
+        for transform in transforms {
+            let mut indices = vec![0, 1, 2];
+            keep_winding(&transform, &mut indices);
+            let [a, b, c] = [0, 1, 2].map(|i| transform.transform_point3(positions[indices[i] as usize]));
+            // the face normal of the transformed triangle is the transformed normal
+            let normal = (Mat3::from_mat4(transform).inverse().transpose() * Vec3::Z).normalize();
+            assert!((b - a).cross(c - a).normalize().dot(normal) > 0.999, "{:?}", transform);
+        }
+    }
+}
@@ -2,13 +2,29 @@ use std::time::{Duration, Instant};
 use imgui::{FontSource, MouseCursor};
 use imgui_wgpu::{Renderer, RendererConfig};
 use imgui_winit_support::WinitPlatform;
+use glam::Vec3;
+use crate::aov::Aov;
+use crate::environment::Background;
+use crate::denoiser::Denoiser;
+use crate::display_parameters::ToneMapper;
 use crate::frames_per_second::FramesPerSecond;
+use crate::image_export::ImageFormat;
+use crate::material::{Material, MaterialType};
+use crate::pixel_filter::PixelFilter;
+use crate::pathtracer::RenderBackend;
+use crate::sampling_parameters::{LightSampling, SamplerType};
 
 
 pub struct RenderStats {
     progress: f32,
     avg_compute_kernel_time: f32,
-    frames_per_second: FramesPerSecond
+    avg_pass_times: Vec<(String, f32)>,
+    frames_per_second: FramesPerSecond,
+    // what happened to the last save request, shown under the save button
+    save_status: Option<String>,
+    // the lights next event estimation can pick from, and the depth of the light tree
+    emitters: u32,
+    light_tree_depth: u32,
 }
 
 impl Default for RenderStats {
@@ -16,17 +32,32 @@ impl Default for RenderStats {
         Self {
             progress: 0.0,
             avg_compute_kernel_time: 0.0,
-            frames_per_second: FramesPerSecond::new()
+            avg_pass_times: Vec::new(),
+            frames_per_second: FramesPerSecond::new(),
+            save_status: None,
+            emitters: 0,
+            light_tree_depth: 0,
         }
     }
 }
 
 impl RenderStats {
-    pub fn update_progress(&mut self, progress: f32, avg_kernel_time:f32, dt: Duration) {
+    pub fn update_progress(&mut self, progress: f32, avg_kernel_time:f32, avg_pass_times: Vec<(String, f32)>,
+                           dt: Duration) {
         self.progress = progress;
         self.avg_compute_kernel_time = avg_kernel_time;
+        self.avg_pass_times = avg_pass_times;
         self.frames_per_second.update(dt);
     }
+
+    pub fn set_save_status(&mut self, save_status: String) {
+        self.save_status = Some(save_status);
+    }
+
+    pub fn set_light_stats(&mut self, emitters: u32, light_tree_depth: u32) {
+        self.emitters = emitters;
+        self.light_tree_depth = light_tree_depth;
+    }
 }
 
 #[derive(Debug)]
@@ -41,6 +72,51 @@ pub struct UserInput {
     samples_per_frame: u32,
     samples_per_pixel: u32,
     number_of_bounces: u32,
+    light_sampling: LightSampling,
+    sampler: SamplerType,
+    pixel_filter: PixelFilter,
+    // in pixels from the pixel center
+    filter_radius: f32,
+    adaptive_sampling: bool,
+    // the error relative to the mean luminance below which a pixel counts as converged
+    noise_threshold: f32,
+    min_adaptive_samples: u32,
+    // like saving, the heatmap, the exposure and the tone mapper only change what is shown,
+    // so they don't set state_changed
+    sample_heatmap: bool,
+    // in stops
+    exposure: f32,
+    tone_mapper: ToneMapper,
+    // the denoiser, which doesn't set state_changed either
+    denoise: bool,
+    denoise_iterations: u32,
+    denoise_color_phi: f32,
+    denoise_normal_phi: f32,
+    denoise_depth_phi: f32,
+    denoise_temporal: bool,
+    denoise_max_history: u32,
+    // the kernels only write the AOVs when asked to, which starts the image over
+    aov_output: bool,
+    // what the viewport shows in place of the image, if anything
+    viewport_aov: Option<Aov>,
+    background: Background,
+    // rotation of the environment map about the y axis in degrees
+    environment_rotation: f32,
+    environment_intensity: f32,
+    // the sun of the sky model in degrees above the horizon and from -z towards +x
+    sun_elevation: f32,
+    sun_azimuth: f32,
+    turbidity: f32,
+    ground_albedo: f32,
+    render_backend: RenderBackend,
+    image_format: ImageFormat,
+    // saving doesn't change the image, so unlike the rest this doesn't set state_changed
+    save_image_requested: bool,
+    // a copy of the scene's materials for the material editor
+    materials: Vec<Material>,
+    selected_material: usize,
+    // the material changed in the editor since the path tracer last looked
+    edited_material: Option<usize>,
     state_changed: bool,
 }
 
@@ -57,6 +133,38 @@ impl Default for UserInput {
             samples_per_frame: 1,
             samples_per_pixel: 50,
             number_of_bounces: 1,
+            light_sampling: LightSampling::default(),
+            sampler: SamplerType::default(),
+            pixel_filter: PixelFilter::default(),
+            filter_radius: PixelFilter::default().default_radius(),
+            adaptive_sampling: false,
+            noise_threshold: 0.01,
+            min_adaptive_samples: 16,
+            sample_heatmap: false,
+            exposure: 0.0,
+            tone_mapper: ToneMapper::default(),
+            denoise: false,
+            denoise_iterations: 4,
+            denoise_color_phi: 4.0,
+            denoise_normal_phi: 0.1,
+            denoise_depth_phi: 0.1,
+            denoise_temporal: true,
+            denoise_max_history: 8,
+            aov_output: false,
+            viewport_aov: None,
+            background: Background::default(),
+            environment_rotation: 0.0,
+            environment_intensity: 1.0,
+            sun_elevation: 45.0,
+            sun_azimuth: 0.0,
+            turbidity: 3.0,
+            ground_albedo: 0.3,
+            render_backend: RenderBackend::default(),
+            image_format: ImageFormat::default(),
+            save_image_requested: false,
+            materials: Vec::new(),
+            selected_material: 0,
+            edited_material: None,
             state_changed: true,
         }
     }
@@ -108,7 +216,7 @@ impl UserInput {
         self.vfov
     }
     
-    fn set_vfov(&mut self, deg: f32) {
+    pub fn set_vfov(&mut self, deg: f32) {
         self.vfov = deg;
         self.state_changed = true;
     }
@@ -135,7 +243,7 @@ impl UserInput {
         self.samples_per_frame
     }
 
-    fn set_samples_per_frame(&mut self, samples_per_frame: u32) {
+    pub fn set_samples_per_frame(&mut self, samples_per_frame: u32) {
         self.samples_per_frame = samples_per_frame;
         self.state_changed = true;
     }
@@ -144,7 +252,7 @@ impl UserInput {
         self.samples_per_pixel
     }
 
-    fn set_samples_per_pixel(&mut self, samples_per_pixel: u32) {
+    pub fn set_samples_per_pixel(&mut self, samples_per_pixel: u32) {
         self.samples_per_pixel = samples_per_pixel;
         self.state_changed = true;
     }
@@ -153,12 +261,292 @@ impl UserInput {
         self.number_of_bounces
     }
 
-    fn set_number_of_bounces(&mut self, number_of_bounces: u32) {
+    pub fn set_number_of_bounces(&mut self, number_of_bounces: u32) {
         self.number_of_bounces = number_of_bounces;
         self.state_changed = true;
     }
+
+    pub fn light_sampling(&self) -> LightSampling {
+        self.light_sampling
+    }
+
+    pub fn set_light_sampling(&mut self, light_sampling: LightSampling) {
+        self.light_sampling = light_sampling;
+        self.state_changed = true;
+    }
+
+    pub fn sampler(&self) -> SamplerType {
+        self.sampler
+    }
+
+    pub fn set_sampler(&mut self, sampler: SamplerType) {
+        self.sampler = sampler;
+        self.state_changed = true;
+    }
+
+    pub fn pixel_filter(&self) -> PixelFilter {
+        self.pixel_filter
+    }
+
+    // a new filter starts out at its own radius
+    pub fn set_pixel_filter(&mut self, pixel_filter: PixelFilter) {
+        self.pixel_filter = pixel_filter;
+        self.filter_radius = pixel_filter.default_radius();
+        self.state_changed = true;
+    }
+
+    pub fn filter_radius(&self) -> f32 {
+        self.filter_radius
+    }
+
+    pub fn set_filter_radius(&mut self, filter_radius: f32) {
+        self.filter_radius = filter_radius;
+        self.state_changed = true;
+    }
+
+    pub fn adaptive_sampling(&self) -> bool {
+        self.adaptive_sampling
+    }
+
+    pub fn set_adaptive_sampling(&mut self, adaptive_sampling: bool) {
+        self.adaptive_sampling = adaptive_sampling;
+        self.state_changed = true;
+    }
+
+    pub fn noise_threshold(&self) -> f32 {
+        self.noise_threshold
+    }
+
+    pub fn set_noise_threshold(&mut self, noise_threshold: f32) {
+        self.noise_threshold = noise_threshold;
+        self.state_changed = true;
+    }
+
+    pub fn min_adaptive_samples(&self) -> u32 {
+        self.min_adaptive_samples
+    }
+
+    fn set_min_adaptive_samples(&mut self, min_adaptive_samples: u32) {
+        self.min_adaptive_samples = min_adaptive_samples;
+        self.state_changed = true;
+    }
+
+    pub fn sample_heatmap(&self) -> bool {
+        self.sample_heatmap
+    }
+
+    fn set_sample_heatmap(&mut self, sample_heatmap: bool) {
+        self.sample_heatmap = sample_heatmap;
+    }
+
+    pub fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = exposure;
+    }
+
+    pub fn tone_mapper(&self) -> ToneMapper {
+        self.tone_mapper
+    }
+
+    pub fn set_tone_mapper(&mut self, tone_mapper: ToneMapper) {
+        self.tone_mapper = tone_mapper;
+    }
+
+    pub fn denoise(&self) -> bool {
+        self.denoise
+    }
+
+    fn set_denoise(&mut self, denoise: bool) {
+        self.denoise = denoise;
+    }
+
+    pub fn denoise_iterations(&self) -> u32 {
+        self.denoise_iterations
+    }
+
+    fn set_denoise_iterations(&mut self, denoise_iterations: u32) {
+        self.denoise_iterations = denoise_iterations;
+    }
+
+    pub fn denoise_color_phi(&self) -> f32 {
+        self.denoise_color_phi
+    }
+
+    fn set_denoise_color_phi(&mut self, denoise_color_phi: f32) {
+        self.denoise_color_phi = denoise_color_phi;
+    }
+
+    pub fn denoise_normal_phi(&self) -> f32 {
+        self.denoise_normal_phi
+    }
+
+    fn set_denoise_normal_phi(&mut self, denoise_normal_phi: f32) {
+        self.denoise_normal_phi = denoise_normal_phi;
+    }
+
+    pub fn denoise_depth_phi(&self) -> f32 {
+        self.denoise_depth_phi
+    }
+
+    fn set_denoise_depth_phi(&mut self, denoise_depth_phi: f32) {
+        self.denoise_depth_phi = denoise_depth_phi;
+    }
+
+    pub fn denoise_temporal(&self) -> bool {
+        self.denoise_temporal
+    }
+
+    fn set_denoise_temporal(&mut self, denoise_temporal: bool) {
+        self.denoise_temporal = denoise_temporal;
+    }
+
+    pub fn denoise_max_history(&self) -> u32 {
+        self.denoise_max_history
+    }
+
+    fn set_denoise_max_history(&mut self, denoise_max_history: u32) {
+        self.denoise_max_history = denoise_max_history;
+    }
+
+    pub fn aov_output(&self) -> bool {
+        self.aov_output
+    }
+
+    pub fn set_aov_output(&mut self, aov_output: bool) {
+        self.aov_output = aov_output;
+        self.state_changed = true;
+    }
+
+    pub fn viewport_aov(&self) -> Option<Aov> {
+        self.viewport_aov
+    }
+
+    fn set_viewport_aov(&mut self, viewport_aov: Option<Aov>) {
+        self.viewport_aov = viewport_aov;
+    }
+
+    pub fn background(&self) -> Background {
+        self.background
+    }
+
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+        self.state_changed = true;
+    }
+
+    pub fn environment_rotation(&self) -> f32 {
+        self.environment_rotation
+    }
+
+    pub fn set_environment_rotation(&mut self, environment_rotation: f32) {
+        self.environment_rotation = environment_rotation;
+        self.state_changed = true;
+    }
+
+    pub fn environment_intensity(&self) -> f32 {
+        self.environment_intensity
+    }
+
+    pub fn set_environment_intensity(&mut self, environment_intensity: f32) {
+        self.environment_intensity = environment_intensity;
+        self.state_changed = true;
+    }
+
+    pub fn sun_elevation(&self) -> f32 {
+        self.sun_elevation
+    }
+
+    fn set_sun_elevation(&mut self, sun_elevation: f32) {
+        self.sun_elevation = sun_elevation;
+        self.state_changed = true;
+    }
+
+    pub fn sun_azimuth(&self) -> f32 {
+        self.sun_azimuth
+    }
+
+    fn set_sun_azimuth(&mut self, sun_azimuth: f32) {
+        self.sun_azimuth = sun_azimuth;
+        self.state_changed = true;
+    }
+
+    pub fn turbidity(&self) -> f32 {
+        self.turbidity
+    }
+
+    fn set_turbidity(&mut self, turbidity: f32) {
+        self.turbidity = turbidity;
+        self.state_changed = true;
+    }
+
+    pub fn ground_albedo(&self) -> f32 {
+        self.ground_albedo
+    }
+
+    fn set_ground_albedo(&mut self, ground_albedo: f32) {
+        self.ground_albedo = ground_albedo;
+        self.state_changed = true;
+    }
+
+    pub fn render_backend(&self) -> RenderBackend {
+        self.render_backend
+    }
+
+    pub fn set_render_backend(&mut self, render_backend: RenderBackend) {
+        self.render_backend = render_backend;
+        self.state_changed = true;
+    }
+
+    pub fn image_format(&self) -> ImageFormat {
+        self.image_format
+    }
+
+    fn set_image_format(&mut self, image_format: ImageFormat) {
+        self.image_format = image_format;
+    }
+
+    // returns whether saving the image was asked for since the last call
+    pub fn take_save_image_request(&mut self) -> bool {
+        std::mem::take(&mut self.save_image_requested)
+    }
+
+    fn request_save_image(&mut self) {
+        self.save_image_requested = true;
+    }
+
+    pub fn materials(&self) -> &[Material] {
+        &self.materials
+    }
+
+    pub fn set_materials(&mut self, materials: Vec<Material>) {
+        self.materials = materials;
+        self.selected_material = 0;
+    }
+
+    pub fn selected_material(&self) -> usize {
+        self.selected_material
+    }
+
+    fn select_material(&mut self, index: usize) {
+        self.selected_material = index;
+    }
+
+    fn set_material(&mut self, index: usize, material: Material) {
+        self.materials[index] = material;
+        self.edited_material = Some(index);
+        self.state_changed = true;
+    }
+
+    // returns the id and new parameters of the material edited since the last call, if any
+    pub fn take_edited_material(&mut self) -> Option<(u32, Material)> {
+        self.edited_material.take().map(|index| (index as u32, self.materials[index]))
+    }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct GUI {
     pub platform: WinitPlatform,
     pub imgui: imgui::Context,
@@ -176,7 +564,7 @@ impl GUI {
         let mut platform = WinitPlatform::new(&mut imgui);
         platform.attach_window(
             imgui.io_mut(),
-            &window,
+            window,
             imgui_winit_support::HiDpiMode::Default,
         );
         
@@ -200,7 +588,7 @@ impl GUI {
             ..Default::default()
         };
 
-        let imgui_renderer = Renderer::new(&mut imgui, &device, &queue, renderer_config);
+        let imgui_renderer = Renderer::new(&mut imgui, device, queue, renderer_config);
 
         let last_frame = Instant::now();
 
@@ -218,7 +606,7 @@ impl GUI {
                       user_input: &mut UserInput,
                       render_stats: &RenderStats) {
         let ui = self.imgui.new_frame();
-        self.platform.prepare_render(&ui, &window);
+        self.platform.prepare_render(ui, window);
         
         // process user input
         // if the right mouse button is held down and we move the mouse, we can orient the camera
@@ -268,6 +656,10 @@ impl GUI {
         if ui.is_key_released(imgui::Key::A) {
             user_input.set_key_released(imgui::Key::A);
         }
+        // save the render
+        if ui.is_key_pressed(imgui::Key::F12) {
+            user_input.request_save_image();
+        }
         
         {
             let window = ui.window("Hello Imgui from WGPU!");
@@ -284,6 +676,138 @@ impl GUI {
                                     render_stats.progress,
                                     render_stats.avg_compute_kernel_time,
                                     render_stats.frames_per_second.get_avg_fps()));
+                    for (label, time) in &render_stats.avg_pass_times {
+                        ui.text(format!("  {}: {:.3}us", label, time));
+                    }
+                    ui.text(format!("Emitters: {}  Light tree depth: {}",
+                                    render_stats.emitters,
+                                    render_stats.light_tree_depth));
+                    ui.separator();
+
+                    let backend_names = RenderBackend::ALL.map(|backend| backend.name());
+                    let mut backend_index = RenderBackend::ALL.iter()
+                        .position(|backend| *backend == user_input.render_backend())
+                        .unwrap_or(0);
+                    if ui.combo_simple_string("Backend", &mut backend_index, &backend_names) {
+                        user_input.set_render_backend(RenderBackend::ALL[backend_index]);
+                    }
+                    ui.separator();
+
+                    ui.text("Display");
+
+                    let mut exposure = user_input.exposure();
+                    if ui.slider(
+                        "exposure (EV)",
+                        -10.0,
+                        10.0,
+                        &mut exposure,
+                    ) {
+                        user_input.set_exposure(exposure);
+                    };
+
+                    let tone_mapper_names = ToneMapper::ALL.map(|tone_mapper| tone_mapper.name());
+                    let mut tone_mapper_index = ToneMapper::ALL.iter()
+                        .position(|tone_mapper| *tone_mapper == user_input.tone_mapper())
+                        .unwrap_or(0);
+                    if ui.combo_simple_string("Tone mapper", &mut tone_mapper_index, &tone_mapper_names) {
+                        user_input.set_tone_mapper(ToneMapper::ALL[tone_mapper_index]);
+                    }
+
+                    let mut denoise = user_input.denoise();
+                    if ui.checkbox("Denoise", &mut denoise) {
+                        user_input.set_denoise(denoise);
+                    }
+                    if denoise {
+                        let mut iterations = user_input.denoise_iterations();
+                        if ui.slider(
+                            "iterations",
+                            1,
+                            Denoiser::MAX_ITERATIONS,
+                            &mut iterations,
+                        ) {
+                            user_input.set_denoise_iterations(iterations);
+                        };
+
+                        let mut color_phi = user_input.denoise_color_phi();
+                        if ui.slider(
+                            "color phi",
+                            0.1,
+                            16.0,
+                            &mut color_phi,
+                        ) {
+                            user_input.set_denoise_color_phi(color_phi);
+                        };
+
+                        let mut normal_phi = user_input.denoise_normal_phi();
+                        if ui.slider(
+                            "normal phi",
+                            0.01,
+                            1.0,
+                            &mut normal_phi,
+                        ) {
+                            user_input.set_denoise_normal_phi(normal_phi);
+                        };
+
+                        let mut depth_phi = user_input.denoise_depth_phi();
+                        if ui.slider(
+                            "depth phi",
+                            0.01,
+                            1.0,
+                            &mut depth_phi,
+                        ) {
+                            user_input.set_denoise_depth_phi(depth_phi);
+                        };
+
+                        // keeps what was denoised so far when the camera moves
+                        let mut temporal = user_input.denoise_temporal();
+                        if ui.checkbox("Temporal reprojection", &mut temporal) {
+                            user_input.set_denoise_temporal(temporal);
+                        }
+                        if temporal {
+                            let mut max_history = user_input.denoise_max_history();
+                            if ui.slider(
+                                "max history",
+                                1,
+                                64,
+                                &mut max_history,
+                            ) {
+                                user_input.set_denoise_max_history(max_history);
+                            };
+                        }
+                    }
+
+                    // the first entry is the image itself
+                    let viewport_names: Vec<&str> = std::iter::once("Image")
+                        .chain(Aov::ALL.iter().map(|aov| aov.name()))
+                        .collect();
+                    let mut viewport_index = user_input.viewport_aov()
+                        .and_then(|viewport_aov| Aov::ALL.iter().position(|aov| *aov == viewport_aov))
+                        .map_or(0, |index| index + 1);
+                    if ui.combo_simple_string("Viewport", &mut viewport_index, &viewport_names) {
+                        user_input.set_viewport_aov(viewport_index.checked_sub(1).map(|index| Aov::ALL[index]));
+                    }
+
+                    // the lighting, the geometric normal and the ids, for the viewport and as
+                    // layers of a saved EXR
+                    let mut aov_output = user_input.aov_output();
+                    if ui.checkbox("Write AOVs", &mut aov_output) {
+                        user_input.set_aov_output(aov_output);
+                    }
+                    ui.separator();
+
+                    let format_names = ImageFormat::ALL.map(|format| format.name());
+                    let mut format_index = ImageFormat::ALL.iter()
+                        .position(|format| *format == user_input.image_format())
+                        .unwrap_or(0);
+                    if ui.combo_simple_string("Image format", &mut format_index, &format_names) {
+                        user_input.set_image_format(ImageFormat::ALL[format_index]);
+                    }
+                    if ui.button("Save image (F12)") {
+                        user_input.request_save_image();
+                    }
+                    if let Some(save_status) = &render_stats.save_status {
+                        ui.text(save_status);
+                    }
                     ui.separator();
 
                     ui.text("Camera parameters");
@@ -351,12 +875,212 @@ impl GUI {
                     ){
                         user_input.set_number_of_bounces(nb);
                     };
+
+                    let light_sampling_names = LightSampling::ALL.map(|light_sampling| light_sampling.name());
+                    let mut light_sampling_index = LightSampling::ALL.iter()
+                        .position(|light_sampling| *light_sampling == user_input.light_sampling())
+                        .unwrap_or(0);
+                    if ui.combo_simple_string("Light sampling", &mut light_sampling_index, &light_sampling_names) {
+                        user_input.set_light_sampling(LightSampling::ALL[light_sampling_index]);
+                    }
+
+                    let sampler_names = SamplerType::ALL.map(|sampler| sampler.name());
+                    let mut sampler_index = SamplerType::ALL.iter()
+                        .position(|sampler| *sampler == user_input.sampler())
+                        .unwrap_or(0);
+                    if ui.combo_simple_string("Sampler", &mut sampler_index, &sampler_names) {
+                        user_input.set_sampler(SamplerType::ALL[sampler_index]);
+                    }
+
+                    let filter_names = PixelFilter::ALL.map(|filter| filter.name());
+                    let mut filter_index = PixelFilter::ALL.iter()
+                        .position(|filter| *filter == user_input.pixel_filter())
+                        .unwrap_or(0);
+                    if ui.combo_simple_string("Pixel filter", &mut filter_index, &filter_names) {
+                        user_input.set_pixel_filter(PixelFilter::ALL[filter_index]);
+                    }
+
+                    let mut filter_radius = user_input.filter_radius();
+                    if ui.slider(
+                        "filter radius",
+                        0.5,
+                        4.0,
+                        &mut filter_radius,
+                    ) {
+                        user_input.set_filter_radius(filter_radius);
+                    };
+
+                    let mut adaptive_sampling = user_input.adaptive_sampling();
+                    if ui.checkbox("Adaptive sampling", &mut adaptive_sampling) {
+                        user_input.set_adaptive_sampling(adaptive_sampling);
+                    }
+                    if adaptive_sampling {
+                        let mut noise_threshold = user_input.noise_threshold();
+                        if ui.slider(
+                            "noise threshold",
+                            0.001,
+                            0.1,
+                            &mut noise_threshold,
+                        ) {
+                            user_input.set_noise_threshold(noise_threshold);
+                        };
+
+                        let mut min_samples = user_input.min_adaptive_samples();
+                        if ui.slider(
+                            "min samples",
+                            2,
+                            256,
+                            &mut min_samples,
+                        ) {
+                            user_input.set_min_adaptive_samples(min_samples);
+                        };
+                    }
+
+                    let mut sample_heatmap = user_input.sample_heatmap();
+                    if ui.checkbox("Samples per pixel heatmap", &mut sample_heatmap) {
+                        user_input.set_sample_heatmap(sample_heatmap);
+                    }
+                    ui.separator();
+
+                    ui.text("Environment");
+
+                    let background_names = Background::ALL.map(|background| background.name());
+                    let mut background_index = Background::ALL.iter()
+                        .position(|background| *background == user_input.background())
+                        .unwrap_or(0);
+                    if ui.combo_simple_string("Background", &mut background_index, &background_names) {
+                        user_input.set_background(Background::ALL[background_index]);
+                    }
+
+                    let mut rotation = user_input.environment_rotation();
+                    if ui.slider(
+                        "rotation",
+                        -180.0,
+                        180.0,
+                        &mut rotation,
+                    ) {
+                        user_input.set_environment_rotation(rotation);
+                    };
+
+                    let mut intensity = user_input.environment_intensity();
+                    if ui.slider(
+                        "intensity",
+                        0.0,
+                        10.0,
+                        &mut intensity,
+                    ) {
+                        user_input.set_environment_intensity(intensity);
+                    };
+
+                    if user_input.background() == Background::SunSky {
+                        let mut sun_elevation = user_input.sun_elevation();
+                        if ui.slider(
+                            "sun elevation",
+                            0.0,
+                            90.0,
+                            &mut sun_elevation,
+                        ) {
+                            user_input.set_sun_elevation(sun_elevation);
+                        };
+
+                        let mut sun_azimuth = user_input.sun_azimuth();
+                        if ui.slider(
+                            "sun azimuth",
+                            -180.0,
+                            180.0,
+                            &mut sun_azimuth,
+                        ) {
+                            user_input.set_sun_azimuth(sun_azimuth);
+                        };
+
+                        let mut turbidity = user_input.turbidity();
+                        if ui.slider(
+                            "turbidity",
+                            1.7,
+                            10.0,
+                            &mut turbidity,
+                        ) {
+                            user_input.set_turbidity(turbidity);
+                        };
+
+                        let mut ground_albedo = user_input.ground_albedo();
+                        if ui.slider(
+                            "ground albedo",
+                            0.0,
+                            1.0,
+                            &mut ground_albedo,
+                        ) {
+                            user_input.set_ground_albedo(ground_albedo);
+                        };
+                    }
+                });
+        }
+
+        if !user_input.materials().is_empty() {
+            let window = ui.window("Material editor");
+            window
+                .size([400.0, 380.0], imgui::Condition::FirstUseEver)
+                .position([0.0, 260.0], imgui::Condition::FirstUseEver)
+                .build(|| {
+                    let material_names: Vec<String> = user_input.materials().iter()
+                        .enumerate()
+                        .map(|(id, material)| format!("{}: {}", id, material.material_type.name()))
+                        .collect();
+                    let mut selected = user_input.selected_material();
+                    if ui.combo_simple_string("Material", &mut selected, &material_names) {
+                        user_input.select_material(selected);
+                    }
+                    ui.separator();
+
+                    // every parameter is shown, even those the material type doesn't use
+                    let mut material = user_input.materials()[selected];
+                    let mut changed = false;
+
+                    let type_names = MaterialType::ALL.map(|material_type| material_type.name());
+                    let mut type_index = MaterialType::ALL.iter()
+                        .position(|material_type| *material_type == material.material_type)
+                        .unwrap_or(0);
+                    if ui.combo_simple_string("Type", &mut type_index, &type_names) {
+                        material.material_type = MaterialType::ALL[type_index];
+                        changed = true;
+                    }
+
+                    let mut base_color = material.base_color.to_array();
+                    if ui.color_edit3("Base color", &mut base_color) {
+                        material.base_color = Vec3::from(base_color);
+                        changed = true;
+                    }
+                    changed |= ui.slider("Metallic", 0.0, 1.0, &mut material.metallic);
+                    changed |= ui.slider("Roughness", 0.0, 1.0, &mut material.roughness);
+                    changed |= ui.slider("Anisotropy", 0.0, 1.0, &mut material.anisotropy);
+                    changed |= ui.slider("Specular", 0.0, 1.0, &mut material.specular);
+                    changed |= ui.slider("Specular tint", 0.0, 1.0, &mut material.specular_tint);
+                    changed |= ui.slider("Sheen", 0.0, 1.0, &mut material.sheen);
+                    changed |= ui.slider("Sheen tint", 0.0, 1.0, &mut material.sheen_tint);
+                    changed |= ui.slider("Clearcoat", 0.0, 1.0, &mut material.clearcoat);
+                    changed |= ui.slider("Clearcoat roughness", 0.0, 1.0, &mut material.clearcoat_roughness);
+                    changed |= ui.slider("Transmission", 0.0, 1.0, &mut material.transmission);
+                    changed |= ui.slider("IOR", 1.0, 3.0, &mut material.ior);
+
+                    // emission is radiance rather than a color, so it isn't limited to one
+                    let mut emission = material.emission.to_array();
+                    if imgui::Drag::new("Emission")
+                        .range(0.0, 1000.0)
+                        .speed(0.01)
+                        .build_array(ui, &mut emission) {
+                        material.emission = Vec3::from(emission);
+                        changed = true;
+                    }
+
+                    if changed {
+                        user_input.set_material(selected, material);
+                    }
                 });
         }
 
         if self.last_cursor != ui.mouse_cursor() {
             self.last_cursor = ui.mouse_cursor();
-            self.platform.prepare_render(&ui, &window);
+            self.platform.prepare_render(ui, window);
         }
     }
 }
\ No newline at end of file
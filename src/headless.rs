@@ -0,0 +1,292 @@
+use std::path::PathBuf;
+use std::time::Instant;
+use crate::display_parameters::ToneMapper;
+use crate::environment::{Background, Environment};
+use crate::gui::UserInput;
+use crate::pixel_filter::PixelFilter;
+use crate::image_export::ImageFormat;
+use crate::pathtracer::{PathTracer, RenderBackend};
+use crate::sampling_parameters::{LightSampling, SamplerType};
+use crate::scene::Scene;
+use crate::wgpu_state::WGPUState;
+
+pub const USAGE: &str = "\
+usage: pathtracer_v2 [scene] [--environment <image>]
+       pathtracer_v2 render <scene> --out <image> [options]
+
+render options:
+  --out <path>          image to write; .exr, .hdr and .pfm keep the radiance, .png is tone mapped
+  --spp <n>             samples per pixel (default 1024)
+  --spf <n>             samples per frame, i.e. per submission (default 4)
+  --bounces <n>         number of bounces (default 8)
+  --width <n>           image width (default 1200)
+  --height <n>          image height (default 675)
+  --backend <name>      megakernel or wavefront (default megakernel)
+  --light-sampling <m>  bsdf, nee, mis-balance or mis-power (default mis-power)
+  --sampler <name>      independent, sobol, stratified or blue-noise (default sobol)
+  --filter <name>       box, tent, gaussian, mitchell or lanczos (default box)
+  --filter-radius <r>   filter radius in pixels (default depends on the filter)
+  --adaptive <error>    stop sampling pixels once their noise is below the given error (e.g. 0.01)
+  --exposure <ev>       exposure of the .png in stops (default 0)
+  --tone-mapper <name>  clamp, reinhard, aces, agx or pbr-neutral for the .png (default clamp)
+  --aovs                write the AOVs as extra layers of the .exr
+  --environment <path>  equirectangular .hdr or .exr environment map to light the scene with
+  --background <name>   gradient, environment or sun-sky (default environment with a map, else gradient)
+  --software            use the software (fallback) adapter even if there is a gpu";
+
+// the settings of an offline render, parsed from the arguments after `render`
+pub struct RenderOptions {
+    scene_path: PathBuf,
+    output_path: PathBuf,
+    samples_per_pixel: u32,
+    samples_per_frame: u32,
+    number_of_bounces: u32,
+    width: u32,
+    height: u32,
+    backend: RenderBackend,
+    light_sampling: LightSampling,
+    sampler: SamplerType,
+    pixel_filter: PixelFilter,
+    filter_radius: Option<f32>,
+    noise_threshold: Option<f32>,
+    exposure: f32,
+    tone_mapper: ToneMapper,
+    aovs: bool,
+    environment_path: Option<PathBuf>,
+    background: Option<Background>,
+    software: bool,
+}
+
+impl RenderOptions {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut scene_path = None;
+        let mut output_path = None;
+        let mut options = Self {
+            scene_path: PathBuf::new(),
+            output_path: PathBuf::new(),
+            samples_per_pixel: 1024,
+            samples_per_frame: 4,
+            number_of_bounces: 8,
+            width: 1200,
+            height: 675,
+            backend: RenderBackend::Megakernel,
+            light_sampling: LightSampling::default(),
+            sampler: SamplerType::default(),
+            pixel_filter: PixelFilter::default(),
+            filter_radius: None,
+            noise_threshold: None,
+            exposure: 0.0,
+            tone_mapper: ToneMapper::default(),
+            aovs: false,
+            environment_path: None,
+            background: None,
+            software: false,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            let number = |value: &String| value.parse::<u32>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or(format!("{} needs a positive number, got {}", arg, value));
+
+            match arg.as_str() {
+                "--out" => output_path = Some(PathBuf::from(value()?)),
+                "--spp" => options.samples_per_pixel = number(value()?)?,
+                "--spf" => options.samples_per_frame = number(value()?)?,
+                "--bounces" => options.number_of_bounces = number(value()?)?,
+                "--width" => options.width = number(value()?)?,
+                "--height" => options.height = number(value()?)?,
+                "--backend" => {
+                    let name = value()?;
+                    options.backend = RenderBackend::ALL.into_iter()
+                        .find(|backend| backend.name().eq_ignore_ascii_case(name))
+                        .ok_or(format!("unknown backend {}", name))?;
+                },
+                "--light-sampling" => {
+                    let name = value()?;
+                    options.light_sampling = LightSampling::ALL.into_iter()
+                        .find(|light_sampling| light_sampling.option_name() == name)
+                        .ok_or(format!("unknown light sampling {}", name))?;
+                },
+                "--sampler" => {
+                    let name = value()?;
+                    options.sampler = SamplerType::ALL.into_iter()
+                        .find(|sampler| sampler.option_name() == name)
+                        .ok_or(format!("unknown sampler {}", name))?;
+                },
+                "--filter" => {
+                    let name = value()?;
+                    options.pixel_filter = PixelFilter::ALL.into_iter()
+                        .find(|filter| filter.option_name() == name)
+                        .ok_or(format!("unknown filter {}", name))?;
+                },
+                "--filter-radius" => {
+                    let value = value()?;
+                    options.filter_radius = Some(value.parse::<f32>()
+                        .ok()
+                        .filter(|radius| *radius > 0.0 && radius.is_finite())
+                        .ok_or(format!("{} needs a positive radius, got {}", arg, value))?);
+                },
+                "--adaptive" => {
+                    let value = value()?;
+                    options.noise_threshold = Some(value.parse::<f32>()
+                        .ok()
+                        .filter(|threshold| *threshold > 0.0)
+                        .ok_or(format!("{} needs a positive error, got {}", arg, value))?);
+                },
+                "--exposure" => {
+                    let value = value()?;
+                    options.exposure = value.parse::<f32>()
+                        .ok()
+                        .filter(|exposure| exposure.is_finite())
+                        .ok_or(format!("{} needs a number of stops, got {}", arg, value))?;
+                },
+                "--tone-mapper" => {
+                    let name = value()?;
+                    options.tone_mapper = ToneMapper::ALL.into_iter()
+                        .find(|tone_mapper| tone_mapper.option_name() == name)
+                        .ok_or(format!("unknown tone mapper {}", name))?;
+                },
+                "--aovs" => options.aovs = true,
+                "--environment" => options.environment_path = Some(PathBuf::from(value()?)),
+                "--background" => {
+                    let name = value()?;
+                    options.background = Some(Background::ALL.into_iter()
+                        .find(|background| background.option_name() == name)
+                        .ok_or(format!("unknown background {}", name))?);
+                },
+                "--software" => options.software = true,
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                _ if scene_path.is_none() => scene_path = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+
+        options.scene_path = scene_path.ok_or("no scene given")?;
+        options.output_path = output_path.ok_or("no output image given (--out)")?;
+        if options.aovs && ImageFormat::from_path(&options.output_path) != Some(ImageFormat::Exr) {
+            return Err("--aovs needs an .exr output image".to_string());
+        }
+        Ok(options)
+    }
+}
+
+// renders the scene to samples_per_pixel without opening a window and writes the image to disk
+pub fn render(options: &RenderOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let mut scene = Scene::load(&options.scene_path)?;
+    if let Some(path) = &options.environment_path {
+        scene.set_environment(Environment::load(path)?);
+    }
+    println!("{} emitters, light tree depth {}", scene.lights().emitter_count(), scene.lights().tree_depth());
+
+    let wgpu_state = pollster::block_on(
+        WGPUState::new_headless(options.width, options.height, options.software)
+    ).ok_or("no suitable adapter found")?;
+
+    let mut user_input = UserInput::default();
+    user_input.set_samples_per_pixel(options.samples_per_pixel);
+    user_input.set_samples_per_frame(options.samples_per_frame);
+    user_input.set_number_of_bounces(options.number_of_bounces);
+    user_input.set_render_backend(options.backend);
+    user_input.set_light_sampling(options.light_sampling);
+    user_input.set_sampler(options.sampler);
+    user_input.set_pixel_filter(options.pixel_filter);
+    if let Some(filter_radius) = options.filter_radius {
+        user_input.set_filter_radius(filter_radius);
+    }
+    user_input.set_exposure(options.exposure);
+    user_input.set_tone_mapper(options.tone_mapper);
+    user_input.set_aov_output(options.aovs);
+    if let Some(noise_threshold) = options.noise_threshold {
+        user_input.set_adaptive_sampling(true);
+        user_input.set_noise_threshold(noise_threshold);
+    }
+    if let Some(camera) = scene.camera() {
+        user_input.set_vfov(camera.vfov);
+    }
+    let default_background = match scene.environment() {
+        Some(_) => Background::EnvironmentMap,
+        None => Background::Gradient,
+    };
+    user_input.set_background(options.background.unwrap_or(default_background));
+
+    let mut path_tracer = PathTracer::new(wgpu_state, scene).ok_or("failed to create the path tracer")?;
+
+    let start = Instant::now();
+    let mut reported_progress = 0;
+    // the sampling parameters only reach the path tracer with the first frame, so progress
+    // can't be checked before that
+    loop {
+        path_tracer.run_path_tracer(0.0, &mut user_input);
+        // waiting on every frame keeps the queue short, so a long render doesn't trip the
+        // driver's watchdog and the progress below is real
+        path_tracer.wgpu_state.device().poll(wgpu::PollType::Wait)?;
+
+        let progress = path_tracer.progress() as u32;
+        if progress >= reported_progress + 10 {
+            reported_progress = progress - progress % 10;
+            println!("{}% after {:.1}s", reported_progress, start.elapsed().as_secs_f32());
+        }
+        if path_tracer.progress() >= 100.0 {
+            break;
+        }
+    }
+
+    path_tracer.save_image(&options.output_path)?;
+    println!("Wrote {} in {:.1}s", options.output_path.display(), start.elapsed().as_secs_f32());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<RenderOptions, String> {
+        let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+        RenderOptions::parse(&args)
+    }
+
+    #[test]
+    fn defaults_and_options() {
+        let options = parse("scene.obj --out image.exr --spp 64 --backend wavefront --sampler stratified --software --aovs --filter gaussian --filter-radius 2.5").unwrap();
+        assert_eq!(options.scene_path, PathBuf::from("scene.obj"));
+        assert_eq!(options.output_path, PathBuf::from("image.exr"));
+        assert_eq!(options.samples_per_pixel, 64);
+        assert_eq!(options.number_of_bounces, 8);
+        assert_eq!(options.backend, RenderBackend::Wavefront);
+        assert_eq!(options.sampler, SamplerType::Stratified);
+        assert!(options.software);
+        assert!(options.aovs);
+        assert_eq!(options.pixel_filter, PixelFilter::Gaussian);
+        assert_eq!(options.filter_radius, Some(2.5));
+    }
+
+    #[test]
+    fn rejects_a_missing_output() {
+        assert!(parse("scene.obj --spp 64").is_err());
+        assert!(parse("scene.obj --out").is_err());
+    }
+
+    #[test]
+    fn rejects_aovs_without_exr() {
+        assert!(parse("scene.obj --out image.png --aovs").is_err());
+        assert!(parse("scene.obj --out image.hdr --aovs").is_err());
+        assert!(parse("scene.obj --out image.EXR --aovs").is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        assert!(parse("--out image.png").is_err());
+        assert!(parse("scene.obj other.obj --out image.png").is_err());
+        assert!(parse("scene.obj --out image.png --spp 0").is_err());
+        assert!(parse("scene.obj --out image.png --width -1").is_err());
+        assert!(parse("scene.obj --out image.png --backend gpu").is_err());
+        assert!(parse("scene.obj --out image.png --sampler halton").is_err());
+        assert!(parse("scene.obj --out image.png --filter sinc").is_err());
+        assert!(parse("scene.obj --out image.png --filter-radius -1").is_err());
+        assert!(parse("scene.obj --out image.png --verbose").is_err());
+    }
+}
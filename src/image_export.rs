@@ -0,0 +1,177 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use glam::Vec3;
+use crate::aov::AovLayer;
+use crate::display_parameters::DisplayTransform;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ImageFormat {
+    // 8 bits per channel with the display transform applied
+    #[default]
+    Png,
+    // floating point radiance
+    Hdr,
+    Pfm,
+    Exr,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 4] = [ImageFormat::Png, ImageFormat::Hdr, ImageFormat::Pfm, ImageFormat::Exr];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "PNG",
+            ImageFormat::Hdr => "Radiance HDR",
+            ImageFormat::Pfm => "PFM",
+            ImageFormat::Exr => "OpenEXR",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Hdr => "hdr",
+            ImageFormat::Pfm => "pfm",
+            ImageFormat::Exr => "exr",
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        Self::ALL.into_iter().find(|format| format.extension() == extension)
+    }
+}
+
+// writes the averaged radiance of the image (row 0 at the top) to disk in the format given by
+// the extension of the path: .hdr, .pfm and .exr (32 bit float) keep the linear radiance,
+// .png gets the display transform and is stored with 8 bits per channel. With any aovs, an
+// .exr becomes a multi-layer one with a layer for every aov next to the image
+pub fn save_image(path: &Path, width: u32, height: u32, pixels: &[Vec3], aovs: &[AovLayer],
+                  display_transform: &DisplayTransform) -> Result<(), Box<dyn std::error::Error>> {
+    assert_eq!(pixels.len(), (width * height) as usize, "pixel count must match the image size");
+
+    let format = ImageFormat::from_path(path)
+        .ok_or(format!("unsupported image format {}", path.display()))?;
+
+    match format {
+        ImageFormat::Png => {
+            let data = pixels.iter()
+                .flat_map(|p| (display_transform.apply(*p) * 255.0).round().to_array().map(|c| c as u8))
+                .collect();
+            let image = image::RgbImage::from_raw(width, height, data).unwrap();
+            image.save_with_format(path, image::ImageFormat::Png)?;
+        },
+        ImageFormat::Exr if !aovs.is_empty() => save_layered_exr(path, width, height, pixels, aovs)?,
+        ImageFormat::Hdr | ImageFormat::Exr => {
+            let data = pixels.iter().flat_map(|p| p.to_array()).collect();
+            let image = image::Rgb32FImage::from_raw(width, height, data).unwrap();
+            let image_format = if format == ImageFormat::Hdr { image::ImageFormat::Hdr } else { image::ImageFormat::OpenExr };
+            image.save_with_format(path, image_format)?;
+        },
+        ImageFormat::Pfm => save_pfm(path, width, height, pixels)?,
+    }
+
+    Ok(())
+}
+
+// the image goes into the R, G and B channels as usual and the aovs into channels named
+// layer.channel, which is how compositors tell the layers of a single-part EXR apart
+fn save_layered_exr(path: &Path, width: u32, height: u32, pixels: &[Vec3],
+                    aovs: &[AovLayer]) -> exr::error::UnitResult {
+    use exr::prelude::*;
+
+    let mut channels: SmallVec<[AnyChannel<FlatSamples>; 4]> = ["R", "G", "B"].iter().enumerate()
+        .map(|(i, name)| AnyChannel::new(*name, FlatSamples::F32(pixels.iter().map(|p| p[i]).collect())))
+        .collect();
+    for layer in aovs {
+        for (name, samples) in layer.aov.channel_names().iter().zip(&layer.channels) {
+            let name = format!("{}.{}", layer.aov.layer_name(), name);
+            channels.push(AnyChannel::new(name.as_str(), FlatSamples::F32(samples.clone())));
+        }
+    }
+
+    // the channels of an EXR have to be sorted by name
+    let layer = Layer::new((width as usize, height as usize), LayerAttributes::default(),
+                           Encoding::FAST_LOSSLESS, AnyChannels::sort(channels));
+    Image::from_layer(layer).write().to_file(path)
+}
+
+// the portable float map is simple enough to write by hand: a text header, with a negative
+// scale for little endian data, followed by the rows from the bottom of the image to the top
+fn save_pfm(path: &Path, width: u32, height: u32, pixels: &[Vec3]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(writer, "PF\n{} {}\n-1.0\n", width, height)?;
+    for row in pixels.chunks_exact(width as usize).rev() {
+        for pixel in row {
+            for channel in pixel.to_array() {
+                writer.write_all(&channel.to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}_{}", std::process::id(), name))
+    }
+
+    // a 3 x 2 image with a different color in every pixel, row 0 at the top
+    fn test_pixels() -> Vec<Vec3> {
+        (0..6).map(|i| Vec3::new(i as f32, 0.5 * i as f32, 0.25 + i as f32)).collect()
+    }
+
+    #[test]
+    fn formats_come_from_the_extension() {
+        assert_eq!(ImageFormat::from_path(Path::new("render.png")), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::from_path(Path::new("dir/render.EXR")), Some(ImageFormat::Exr));
+        assert_eq!(ImageFormat::from_path(Path::new("render.pfm")), Some(ImageFormat::Pfm));
+        assert_eq!(ImageFormat::from_path(Path::new("render.jpg")), None);
+        assert_eq!(ImageFormat::from_path(Path::new("render")), None);
+
+        let path = temp_path("render.jpg");
+        assert!(save_image(&path, 3, 2, &test_pixels(), &[], &DisplayTransform::default()).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn pfm_rows_go_bottom_up_in_little_endian() {
+        let path = temp_path("bottom_up.pfm");
+        save_pfm(&path, 3, 2, &test_pixels()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header = b"PF\n3 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let floats: Vec<f32> = bytes[header.len()..].chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        // the bottom row (pixels 3 to 5) comes first
+        let pixels = test_pixels();
+        let expected: Vec<f32> = pixels[3..].iter().chain(&pixels[..3]).flat_map(|p| p.to_array()).collect();
+        assert_eq!(floats, expected);
+    }
+
+    #[test]
+    fn hdr_and_exr_round_trip() {
+        let pixels = test_pixels();
+        for (name, tolerance) in [("round_trip.hdr", 0.01), ("round_trip.exr", 0.0)] {
+            let path = temp_path(name);
+            save_image(&path, 3, 2, &pixels, &[], &DisplayTransform::default()).unwrap();
+            let image = image::open(&path).unwrap().into_rgb32f();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(image.dimensions(), (3, 2));
+            for (read, written) in image.pixels().zip(&pixels) {
+                // RGBE keeps 8 bits of mantissa shared by the channels
+                let error = (Vec3::from(read.0) - *written).abs().max_element();
+                assert!(error <= tolerance * written.max_element(), "{}: {:?} {:?}", name, read, written);
+            }
+        }
+    }
+}
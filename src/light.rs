@@ -0,0 +1,368 @@
+use std::f32::consts::PI;
+use glam::Vec3;
+use crate::light_tree::{GPULightNode, LightBounds, LightTree};
+use crate::material::Material;
+use crate::mesh::SceneGeometry;
+use crate::scene::Sphere;
+
+// set on GPULight.primitive for spheres, like on the kernels' Intersection.primitive
+pub const SPHERE_PRIMITIVE: u32 = 0x8000_0000;
+// set on GPULight.primitive for punctual lights, which index into the punctual light buffer
+pub const PUNCTUAL_PRIMITIVE: u32 = 0x4000_0000;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GPULight {
+    // a triangle index, or a sphere or punctual light index with its flag set
+    primitive: u32,
+    // the way down the light tree to this light
+    bit_trail: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GPULightTree {
+    // the number of lights in the tree, and of directional lights, which come first in the
+    // punctual light buffer
+    tree_lights: u32,
+    infinite_lights: u32,
+    buffering: [u32; 2],
+}
+
+// the values match the PUNCTUAL_ constants in lights.wgsl
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PunctualLightType {
+    #[default]
+    Point = 0,
+    Spot = 1,
+    Directional = 2,
+}
+
+// a light that isn't part of the geometry, so paths can only reach it by next event
+// estimation. Point and spot lights with a radius are spheres that shine with the radiance
+// that gives the same intensity, and a directional light with an angular diameter is a disk
+// in the sky like the sun
+#[derive(Copy, Clone, Debug)]
+pub struct PunctualLight {
+    pub light_type: PunctualLightType,
+    pub position: Vec3,
+    // the direction the light shines in, for spot and directional lights
+    pub direction: Vec3,
+    pub color: Vec3,
+    // radiant intensity for point and spot lights, irradiance for directional lights
+    pub intensity: f32,
+    pub radius: f32,
+    // the cone of a spot light in radians, from the axis: full intensity within the inner
+    // angle, falling off smoothly to nothing at the outer angle
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    // in radians
+    pub angular_diameter: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GPUPunctualLight {
+    position: Vec3,
+    light_type: u32,
+    direction: Vec3,
+    radius: f32,
+    // color times intensity
+    intensity: Vec3,
+    cos_inner_angle: f32,
+    cos_outer_angle: f32,
+    // the solid angle of a directional light, zero if it has no angular diameter
+    solid_angle: f32,
+    buffering: [u32; 2],
+}
+
+impl Default for PunctualLight {
+    fn default() -> Self {
+        Self {
+            light_type: PunctualLightType::Point,
+            position: Vec3::ZERO,
+            direction: Vec3::NEG_Y,
+            color: Vec3::ONE,
+            intensity: 1.0,
+            radius: 0.0,
+            inner_angle: 0.0,
+            outer_angle: PI / 4.0,
+            angular_diameter: 0.0,
+        }
+    }
+}
+
+impl PunctualLight {
+    pub fn point(position: Vec3, color: Vec3, intensity: f32, radius: f32) -> Self {
+        Self {
+            position,
+            color,
+            intensity,
+            radius,
+            ..Default::default()
+        }
+    }
+
+    pub fn spot(position: Vec3, direction: Vec3, color: Vec3, intensity: f32, inner_angle: f32, outer_angle: f32) -> Self {
+        Self {
+            light_type: PunctualLightType::Spot,
+            position,
+            direction: direction.normalize(),
+            color,
+            intensity,
+            inner_angle,
+            outer_angle,
+            ..Default::default()
+        }
+    }
+
+    pub fn directional(direction: Vec3, color: Vec3, intensity: f32, angular_diameter: f32) -> Self {
+        Self {
+            light_type: PunctualLightType::Directional,
+            direction: direction.normalize(),
+            color,
+            intensity,
+            angular_diameter,
+            ..Default::default()
+        }
+    }
+
+    // directional lights are infinitely far away, so they can't go into the light tree
+    fn bounds(&self) -> Option<LightBounds> {
+        // spot lights count with the power of a point light, since the cone of the bounds
+        // already keeps them from being picked outside of it
+        let phi = 4.0 * PI * self.intensity * luminance(self.color);
+        let (axis, cos_theta_o, cos_theta_e) = match self.light_type {
+            PunctualLightType::Point => (Vec3::Z, -1.0, 0.0),
+            PunctualLightType::Spot => {
+                let inner_angle = self.inner_angle.clamp(0.0, self.outer_angle);
+                (self.direction, inner_angle.cos(), (self.outer_angle - inner_angle).cos())
+            },
+            PunctualLightType::Directional => return None,
+        };
+        Some(LightBounds {
+            min: self.position - Vec3::splat(self.radius.max(0.0)),
+            max: self.position + Vec3::splat(self.radius.max(0.0)),
+            phi,
+            axis,
+            cos_theta_o,
+            cos_theta_e,
+            two_sided: false,
+        })
+    }
+
+    pub fn gpu_light(&self) -> GPUPunctualLight {
+        let outer_angle = self.outer_angle.clamp(0.0, PI);
+        let half_angle = (0.5 * self.angular_diameter).clamp(0.0, 0.5 * PI);
+        GPUPunctualLight {
+            position: self.position,
+            light_type: self.light_type as u32,
+            direction: self.direction,
+            radius: self.radius.max(0.0),
+            intensity: self.intensity * self.color,
+            cos_inner_angle: self.inner_angle.clamp(0.0, outer_angle).cos(),
+            cos_outer_angle: outer_angle.cos(),
+            // 2π(1 - cos) without the cancellation
+            solid_angle: 4.0 * PI * (0.5 * half_angle).sin().powi(2),
+            buffering: [0; 2],
+        }
+    }
+}
+
+fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+// the lights the kernels sample for next event estimation: the emissive spheres and
+// triangles, and the punctual lights. All but the directional lights go into a light tree
+// that picks lights by how much they might contribute at the shading point, estimated from
+// their power, which for area lights comes from the luminance of their emission; emission
+// textures aren't taken into account. The directional lights are picked uniformly instead.
+// The lights are sorted by primitive, so that the kernels can find the light of a primitive
+// they hit by a binary search
+#[derive(Default)]
+pub struct Lights {
+    lights: Vec<GPULight>,
+    punctual_lights: Vec<GPUPunctualLight>,
+    tree: LightTree,
+    infinite_lights: u32,
+}
+
+impl Lights {
+    // the triangles have to be in their final (bvh) order already
+    pub fn build(spheres: &[Sphere], geometry: &SceneGeometry, materials: &[Material],
+                 punctual_lights: &[PunctualLight]) -> Self {
+        let emission = |material_id: u32| luminance(materials[material_id as usize].emission);
+
+        // emitters shine from both sides of a triangle
+        let triangle_bounds = (0..geometry.triangles().len())
+            .map(|i| {
+                let [a, b, c] = geometry.triangle_positions(i);
+                let area_normal = (b - a).cross(c - a);
+                let phi = 2.0 * PI * emission(geometry.triangles()[i].material_id()) * 0.5 * area_normal.length();
+                let bounds = LightBounds {
+                    min: a.min(b).min(c),
+                    max: a.max(b).max(c),
+                    phi,
+                    axis: area_normal.normalize_or(Vec3::Z),
+                    cos_theta_o: 1.0,
+                    cos_theta_e: 0.0,
+                    two_sided: true,
+                };
+                (i as u32, bounds)
+            });
+
+        // directional lights first, so the kernels can pick them by index
+        let punctual_lights: Vec<&PunctualLight> = punctual_lights.iter()
+            .filter(|light| light.light_type == PunctualLightType::Directional)
+            .chain(punctual_lights.iter().filter(|light| light.light_type != PunctualLightType::Directional))
+            .collect();
+        let infinite_lights = punctual_lights.iter()
+            .filter(|light| light.light_type == PunctualLightType::Directional)
+            .count() as u32;
+        let punctual_bounds = punctual_lights.iter()
+            .enumerate()
+            .filter_map(|(i, light)| Some((i as u32 | PUNCTUAL_PRIMITIVE, light.bounds()?)));
+
+        let sphere_bounds = spheres.iter()
+            .enumerate()
+            .map(|(i, sphere)| {
+                let (min, max) = sphere.bounds();
+                let bounds = LightBounds {
+                    min,
+                    max,
+                    phi: PI * emission(sphere.material_id()) * sphere.area(),
+                    axis: Vec3::Z,
+                    cos_theta_o: -1.0,
+                    cos_theta_e: 0.0,
+                    two_sided: false,
+                };
+                (i as u32 | SPHERE_PRIMITIVE, bounds)
+            });
+
+        let (primitives, bounds): (Vec<u32>, Vec<LightBounds>) = triangle_bounds
+            .chain(punctual_bounds)
+            .chain(sphere_bounds)
+            .filter(|(_, bounds)| bounds.phi > 0.0)
+            .unzip();
+
+        let tree = LightTree::build(&bounds);
+        let lights = primitives.iter()
+            .zip(tree.bit_trails())
+            .map(|(&primitive, &bit_trail)| GPULight { primitive, bit_trail })
+            .collect();
+
+        Self {
+            lights,
+            punctual_lights: punctual_lights.iter().map(|light| light.gpu_light()).collect(),
+            tree,
+            infinite_lights,
+        }
+    }
+
+    pub fn lights(&self) -> &[GPULight] {
+        &self.lights
+    }
+
+    pub fn punctual_lights(&self) -> &[GPUPunctualLight] {
+        &self.punctual_lights
+    }
+
+    pub fn nodes(&self) -> &[GPULightNode] {
+        self.tree.nodes()
+    }
+
+    pub fn gpu_light_tree(&self) -> GPULightTree {
+        GPULightTree {
+            tree_lights: self.lights.len() as u32,
+            infinite_lights: self.infinite_lights,
+            buffering: [0; 2],
+        }
+    }
+
+    // every light that can be sampled, in the tree or not
+    pub fn emitter_count(&self) -> u32 {
+        self.lights.len() as u32 + self.infinite_lights
+    }
+
+    pub fn tree_depth(&self) -> u32 {
+        self.tree.depth()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn punctual_lights_keep_their_cone_and_intensity() {
+        let point = PunctualLight::point(Vec3::new(1.0, 2.0, 3.0), Vec3::new(1.0, 0.5, 0.25), 4.0, -1.0).gpu_light();
+        assert_eq!(point.light_type, PunctualLightType::Point as u32);
+        assert_eq!(point.position, Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(point.intensity, Vec3::new(4.0, 2.0, 1.0));
+        // a negative radius is a point
+        assert_eq!(point.radius, 0.0);
+
+        let spot = PunctualLight::spot(Vec3::ZERO, Vec3::new(0.0, 0.0, -2.0), Vec3::ONE, 1.0, 0.2, 0.5).gpu_light();
+        assert_eq!(spot.light_type, PunctualLightType::Spot as u32);
+        assert_eq!(spot.direction, Vec3::NEG_Z);
+        assert_eq!(spot.cos_inner_angle, 0.2f32.cos());
+        assert_eq!(spot.cos_outer_angle, 0.5f32.cos());
+        // the inner cone can't be wider than the outer one
+        let inverted = PunctualLight::spot(Vec3::ZERO, Vec3::Z, Vec3::ONE, 1.0, 0.8, 0.5).gpu_light();
+        assert_eq!(inverted.cos_inner_angle, inverted.cos_outer_angle);
+
+        let sun = PunctualLight::directional(Vec3::new(0.0, -3.0, 0.0), Vec3::ONE, 2.0, 0.01).gpu_light();
+        assert_eq!(sun.light_type, PunctualLightType::Directional as u32);
+        assert_eq!(sun.direction, Vec3::NEG_Y);
+        // in double precision, where 1 - cos doesn't cancel out
+        let solid_angle = (2.0 * std::f64::consts::PI * (1.0 - 0.005f64.cos())) as f32;
+        assert!((sun.solid_angle - solid_angle).abs() < 1e-4 * solid_angle);
+        assert_eq!(PunctualLight::directional(Vec3::NEG_Y, Vec3::ONE, 2.0, 0.0).gpu_light().solid_angle, 0.0);
+    }
+
+    #[test]
+    fn punctual_light_bounds_hold_their_cone() {
+        let point = PunctualLight::point(Vec3::new(1.0, 2.0, 3.0), Vec3::ONE, 2.0, 0.5).bounds().unwrap();
+        assert_eq!((point.min, point.max), (Vec3::new(0.5, 1.5, 2.5), Vec3::new(1.5, 2.5, 3.5)));
+        assert!((point.phi - 8.0 * PI).abs() < 1e-5);
+        // all around
+        assert_eq!((point.cos_theta_o, point.cos_theta_e), (-1.0, 0.0));
+
+        let spot = PunctualLight::spot(Vec3::ONE, Vec3::new(0.0, -2.0, 0.0), Vec3::ONE, 2.0, 0.2, 0.5).bounds().unwrap();
+        assert_eq!((spot.min, spot.max), (Vec3::ONE, Vec3::ONE));
+        assert_eq!(spot.axis, Vec3::NEG_Y);
+        // the same power as a point light, the cone does the rest
+        assert_eq!(spot.phi, point.phi);
+        assert_eq!(spot.cos_theta_o, 0.2f32.cos());
+        assert!((spot.cos_theta_e - 0.3f32.cos()).abs() < 1e-6);
+        assert!(!spot.two_sided);
+
+        assert!(PunctualLight::directional(Vec3::NEG_Y, Vec3::ONE, 1.0, 0.0).bounds().is_none());
+    }
+
+    #[test]
+    fn directional_lights_stay_out_of_the_tree() {
+        let punctual_lights = [
+            PunctualLight::point(Vec3::ZERO, Vec3::ONE, 1.0, 0.0),
+            PunctualLight::directional(Vec3::NEG_Y, Vec3::ONE, 1.0, 0.0),
+            PunctualLight::spot(Vec3::ONE, Vec3::NEG_Y, Vec3::ONE, 1.0, 0.2, 0.5),
+        ];
+        let lights = Lights::build(&[], &SceneGeometry::default(), &[], &punctual_lights);
+        assert_eq!(lights.emitter_count(), 3);
+        assert_eq!(lights.gpu_light_tree().infinite_lights, 1);
+        assert_eq!(lights.gpu_light_tree().tree_lights, 2);
+        assert_eq!(lights.nodes().len(), 3);
+        // the directional light comes first, and the tree holds the others
+        assert_eq!(lights.punctual_lights()[0].light_type, PunctualLightType::Directional as u32);
+        let mut tree_lights: Vec<u32> = lights.lights().iter().map(|light| light.primitive).collect();
+        tree_lights.sort();
+        assert_eq!(tree_lights, [1 | PUNCTUAL_PRIMITIVE, 2 | PUNCTUAL_PRIMITIVE]);
+
+        // only directional lights leave the tree empty
+        let lights = Lights::build(&[], &SceneGeometry::default(), &[], &punctual_lights[1..2]);
+        assert_eq!(lights.emitter_count(), 1);
+        assert!(lights.lights().is_empty() && lights.nodes().is_empty());
+    }
+}
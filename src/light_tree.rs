@@ -0,0 +1,349 @@
+use std::f32::consts::PI;
+use glam::{Quat, Vec3};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GPULightNode {
+    aabb_min: Vec3,
+    // the power of the lights below the node
+    phi: f32,
+    aabb_max: Vec3,
+    // the cone around axis that the normals of the lights below the node lie in, and the
+    // angle beyond the normals that they still emit light at
+    cos_theta_o: f32,
+    axis: Vec3,
+    cos_theta_e: f32,
+    // for an interior node the index of its second child (the first one follows it),
+    // for a leaf the index of its light in the light list
+    child_or_light: u32,
+    // LEAF and TWO_SIDED
+    flags: u32,
+    buffering: [u32; 2],
+}
+
+impl GPULightNode {
+    const LEAF: u32 = 1;
+    const TWO_SIDED: u32 = 2;
+}
+
+// where a light is, which way it shines and how much, for deciding how much it might
+// contribute at a shading point
+#[derive(Copy, Clone, Debug)]
+pub struct LightBounds {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub phi: f32,
+    pub axis: Vec3,
+    pub cos_theta_o: f32,
+    pub cos_theta_e: f32,
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    fn union(&self, other: &LightBounds) -> LightBounds {
+        let (axis, cos_theta_o) = Self::cone_union(self.axis, self.cos_theta_o, other.axis, other.cos_theta_o);
+        LightBounds {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            phi: self.phi + other.phi,
+            axis,
+            cos_theta_o,
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    // the smallest cone that holds both cones
+    fn cone_union(axis_a: Vec3, cos_a: f32, axis_b: Vec3, cos_b: f32) -> (Vec3, f32) {
+        let theta_a = cos_a.clamp(-1.0, 1.0).acos();
+        let theta_b = cos_b.clamp(-1.0, 1.0).acos();
+        let theta_d = axis_a.dot(axis_b).clamp(-1.0, 1.0).acos();
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return (axis_a, cos_a);
+        }
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return (axis_b, cos_b);
+        }
+
+        let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+        let rotation_axis = axis_a.cross(axis_b);
+        if theta_o >= PI || rotation_axis.length_squared() == 0.0 {
+            return (axis_a, -1.0);
+        }
+        let axis = Quat::from_axis_angle(rotation_axis.normalize(), theta_o - theta_a) * axis_a;
+        (axis.normalize(), theta_o.cos())
+    }
+
+    fn centroid(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    fn surface_area(&self) -> f32 {
+        let e = self.max - self.min;
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    // the surface area orientation heuristic of Conty Estevez and Kulla ("Importance Sampling
+    // of Many Lights with Adaptive Tree Splitting", 2018): power times the solid angle the
+    // lights emit into times the area of the bounds. regularize favors splitting the longest
+    // axis of the parent's bounds
+    fn cost(&self, regularize: f32) -> f32 {
+        let theta_o = self.cos_theta_o.clamp(-1.0, 1.0).acos();
+        let theta_e = self.cos_theta_e.clamp(-1.0, 1.0).acos();
+        let theta_w = (theta_o + theta_e).min(PI);
+        let sin_theta_o = (1.0 - self.cos_theta_o * self.cos_theta_o).max(0.0).sqrt();
+        let orientation = 2.0 * PI * (1.0 - self.cos_theta_o)
+            + 0.5 * PI * (2.0 * theta_w * sin_theta_o - (theta_o - 2.0 * theta_w).cos()
+                - 2.0 * theta_o * sin_theta_o + self.cos_theta_o);
+        self.phi * orientation * regularize * self.surface_area()
+    }
+}
+
+// a bounding volume hierarchy over the lights with one light per leaf, which the kernels
+// walk down stochastically, at every node picking a child in proportion to how much its
+// lights might contribute at the shading point. Like the geometry bvh, the first child of a
+// node follows it. The path to every light is recorded as a bit trail (bit i set for taking
+// the second child at depth i), so the kernels can work out the probability of picking a
+// light that a path ran into
+#[derive(Default)]
+pub struct LightTree {
+    nodes: Vec<GPULightNode>,
+    bit_trails: Vec<u32>,
+    depth: u32,
+}
+
+impl LightTree {
+    const BUCKET_COUNT: usize = 12;
+    // the bit trails have to fit into a u32
+    pub const MAX_DEPTH: u32 = 32;
+
+    pub fn build(lights: &[LightBounds]) -> Self {
+        let mut tree = Self {
+            nodes: Vec::with_capacity((2 * lights.len()).saturating_sub(1)),
+            bit_trails: vec![0; lights.len()],
+            depth: 0,
+        };
+        if !lights.is_empty() {
+            let mut order: Vec<u32> = (0..lights.len() as u32).collect();
+            tree.build_node(lights, &mut order, 0, 0);
+        }
+        tree
+    }
+
+    fn build_node(&mut self, lights: &[LightBounds], order: &mut [u32], bit_trail: u32, depth: u32) -> LightBounds {
+        self.depth = self.depth.max(depth);
+        let node_index = self.nodes.len();
+        self.nodes.push(GPULightNode::default());
+
+        if order.len() == 1 {
+            let light = order[0];
+            self.bit_trails[light as usize] = bit_trail;
+            self.nodes[node_index] = Self::node(&lights[light as usize], light, GPULightNode::LEAF);
+            return lights[light as usize];
+        }
+
+        let mut centroid_min = Vec3::splat(f32::MAX);
+        let mut centroid_max = Vec3::splat(f32::MIN);
+        let mut bounds = lights[order[0] as usize];
+        for &light in order.iter() {
+            let light = &lights[light as usize];
+            centroid_min = centroid_min.min(light.centroid());
+            centroid_max = centroid_max.max(light.centroid());
+            bounds = bounds.union(light);
+        }
+
+        // a split by the heuristic can leave almost all of the lights on one side, so close to
+        // the depth limit the rest of the subtree gets balanced instead
+        let balanced_depth = usize::BITS - (order.len() - 1).leading_zeros();
+        let split = if depth + balanced_depth < Self::MAX_DEPTH {
+            Self::find_best_split(lights, order, &bounds, centroid_min, centroid_max)
+        } else {
+            None
+        };
+
+        let mut mid = match split {
+            Some((axis, bucket)) => {
+                let scale = Self::BUCKET_COUNT as f32 / (centroid_max[axis] - centroid_min[axis]);
+                let mut left_count = 0;
+                for i in 0..order.len() {
+                    let centroid = lights[order[i] as usize].centroid()[axis];
+                    if Self::bucket_index(centroid, centroid_min[axis], scale) < bucket {
+                        order.swap(i, left_count);
+                        left_count += 1;
+                    }
+                }
+                left_count
+            },
+            None => 0,
+        };
+        if mid == 0 || mid == order.len() {
+            let axis = (centroid_max - centroid_min).max_position();
+            mid = order.len() / 2;
+            order.select_nth_unstable_by(mid, |a, b| {
+                lights[*a as usize].centroid()[axis].total_cmp(&lights[*b as usize].centroid()[axis])
+            });
+        }
+
+        let (left, right) = order.split_at_mut(mid);
+        self.build_node(lights, left, bit_trail, depth + 1);
+        let second_child = self.nodes.len() as u32;
+        self.build_node(lights, right, bit_trail | 1 << depth, depth + 1);
+
+        self.nodes[node_index] = Self::node(&bounds, second_child, 0);
+        bounds
+    }
+
+    fn node(bounds: &LightBounds, child_or_light: u32, flags: u32) -> GPULightNode {
+        let two_sided = if bounds.two_sided { GPULightNode::TWO_SIDED } else { 0 };
+        GPULightNode {
+            aabb_min: bounds.min,
+            phi: bounds.phi,
+            aabb_max: bounds.max,
+            cos_theta_o: bounds.cos_theta_o,
+            axis: bounds.axis,
+            cos_theta_e: bounds.cos_theta_e,
+            child_or_light,
+            flags: flags | two_sided,
+            buffering: [0; 2],
+        }
+    }
+
+    fn bucket_index(centroid: f32, min: f32, scale: f32) -> usize {
+        (((centroid - min) * scale) as usize).min(Self::BUCKET_COUNT - 1)
+    }
+
+    // returns the axis and the bucket the second child starts at of the cheapest split
+    // that leaves lights on both sides
+    fn find_best_split(lights: &[LightBounds], order: &[u32], bounds: &LightBounds,
+                       centroid_min: Vec3, centroid_max: Vec3) -> Option<(usize, usize)> {
+        let extent = bounds.max - bounds.min;
+        let mut best: Option<(usize, usize, f32)> = None;
+
+        for axis in 0..3 {
+            let centroid_extent = centroid_max[axis] - centroid_min[axis];
+            if centroid_extent <= 0.0 {
+                continue;
+            }
+
+            let scale = Self::BUCKET_COUNT as f32 / centroid_extent;
+            let mut buckets: Vec<Option<LightBounds>> = vec![None; Self::BUCKET_COUNT];
+            for &light in order {
+                let light = &lights[light as usize];
+                let bucket = &mut buckets[Self::bucket_index(light.centroid()[axis], centroid_min[axis], scale)];
+                *bucket = Some(bucket.map_or(*light, |b| b.union(light)));
+            }
+
+            let regularize = extent.max_element() / extent[axis];
+            let union = |a: Option<LightBounds>, b: &Option<LightBounds>| match (a, b) {
+                (Some(a), Some(b)) => Some(a.union(b)),
+                (a, b) => a.or(*b),
+            };
+            for split in 1..Self::BUCKET_COUNT {
+                let left = buckets[..split].iter().fold(None, union);
+                let right = buckets[split..].iter().fold(None, union);
+                let (Some(left), Some(right)) = (left, right) else {
+                    continue;
+                };
+                let cost = left.cost(regularize) + right.cost(regularize);
+                if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, split, cost));
+                }
+            }
+        }
+
+        best.map(|(axis, split, _)| (axis, split))
+    }
+
+    pub fn nodes(&self) -> &[GPULightNode] {
+        &self.nodes
+    }
+
+    // indexed like the lights the tree was built over
+    pub fn bit_trails(&self) -> &[u32] {
+        &self.bit_trails
+    }
+
+    // the number of levels below the root
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point_light(position: Vec3, phi: f32) -> LightBounds {
+        LightBounds {
+            min: position,
+            max: position,
+            phi,
+            axis: Vec3::Z,
+            cos_theta_o: -1.0,
+            cos_theta_e: 0.0,
+            two_sided: false,
+        }
+    }
+
+    // walks down the tree the way the bit trail says and returns the light of the leaf it ends up at
+    fn follow(tree: &LightTree, bit_trail: u32) -> u32 {
+        let mut node = 0;
+        let mut depth = 0;
+        while tree.nodes()[node].flags & GPULightNode::LEAF == 0 {
+            node = if bit_trail & 1 << depth != 0 {
+                tree.nodes()[node].child_or_light as usize
+            } else {
+                node + 1
+            };
+            depth += 1;
+        }
+        tree.nodes()[node].child_or_light
+    }
+
+    fn check_tree(lights: &[LightBounds]) -> LightTree {
+        let tree = LightTree::build(lights);
+        assert_eq!(tree.nodes().len(), 2 * lights.len() - 1);
+        assert!(tree.depth() <= LightTree::MAX_DEPTH);
+        for (light, &bit_trail) in tree.bit_trails().iter().enumerate() {
+            assert_eq!(follow(&tree, bit_trail), light as u32);
+        }
+        tree
+    }
+
+    #[test]
+    fn bit_trails_lead_to_their_lights() {
+        // a small linear congruential generator, so the scattered lights are the same every run
+        let mut state = 12345u32;
+        let mut random = || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 24) as f32
+        };
+        let lights: Vec<LightBounds> = (0..500)
+            .map(|_| point_light(Vec3::new(random(), random(), random()) * 10.0, 0.1 + random()))
+            .collect();
+        check_tree(&lights);
+        assert_eq!(check_tree(&lights[..1]).depth(), 0);
+        assert_eq!(check_tree(&lights[..2]).depth(), 1);
+    }
+
+    #[test]
+    fn depth_stays_within_the_bit_trails() {
+        // lights that are exponentially further apart nearly all land in the first bucket, so
+        // every split only takes off the last few, until the rest has to be balanced to fit
+        let lights: Vec<LightBounds> = (0..400)
+            .map(|i| point_light(Vec3::X * 1.2f32.powi(i), 1.0))
+            .collect();
+        assert_eq!(check_tree(&lights).depth(), LightTree::MAX_DEPTH);
+
+        // with all the lights in one place there is nothing to split by
+        let lights = vec![point_light(Vec3::ONE, 1.0); 100];
+        check_tree(&lights);
+    }
+
+    #[test]
+    fn no_lights_give_an_empty_tree() {
+        let tree = LightTree::build(&[]);
+        assert!(tree.nodes().is_empty());
+        assert_eq!(tree.depth(), 0);
+    }
+}
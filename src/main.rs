@@ -5,18 +5,70 @@ mod pathtracer;
 mod utilities;
 mod camera;
 mod sampling_parameters;
+mod blue_noise;
+mod pixel_filter;
+mod adaptive_sampling;
+mod display_parameters;
+mod denoiser;
+mod aov;
 mod frames_per_second;
+mod scene;
+mod mesh;
+mod bvh;
+mod light;
+mod light_tree;
+mod environment;
+mod sky;
+mod material;
+mod texture;
+mod obj_loader;
+mod gltf_loader;
+mod headless;
+mod image_export;
 
+use std::path::PathBuf;
 use winit::event_loop::{ControlFlow, EventLoop};
 use crate::app::App;
+use crate::headless::RenderOptions;
 
 fn main() {
     env_logger::init();
-    
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("render") => {
+            // offline render straight to a file, without a window
+            let result = RenderOptions::parse(&args[1..])
+                .map_err(|error| format!("{}\n\n{}", error, headless::USAGE).into())
+                .and_then(|options| headless::render(&options));
+            if let Err(error) = result {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+            return;
+        },
+        Some("--help") | Some("-h") => {
+            println!("{}", headless::USAGE);
+            return;
+        },
+        _ => {},
+    }
+
     let event_loop = EventLoop::new().unwrap();
     
     event_loop.set_control_flow(ControlFlow::Poll);
     
-    let mut app = App::default();
+    // an optional model file to render instead of the built in scene, and an optional
+    // environment map to light it with
+    let mut scene_path = None;
+    let mut environment_path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--environment" => environment_path = args.next().map(PathBuf::from),
+            _ => scene_path = Some(PathBuf::from(arg)),
+        }
+    }
+    let mut app = App::new(scene_path, environment_path);
     event_loop.run_app(&mut app).unwrap();
 }
@@ -0,0 +1,212 @@
+use glam::Vec3;
+
+// marks a material without a texture for that slot
+pub const NO_TEXTURE: u32 = u32::MAX;
+
+// which BSDF the shader uses for a surface; the values match the MATERIAL_ constants in bsdf.wgsl
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MaterialType {
+    #[default]
+    Diffuse = 0,
+    // a metal reflecting with the base color as the Fresnel color at normal incidence
+    Conductor = 1,
+    // glass, water and the like: reflects and refracts according to the ior
+    Dielectric = 2,
+    // the Disney style uber material, mixing all of the lobes by its parameters
+    Principled = 3,
+}
+
+impl MaterialType {
+    pub const ALL: [MaterialType; 4] = [
+        MaterialType::Diffuse,
+        MaterialType::Conductor,
+        MaterialType::Dielectric,
+        MaterialType::Principled,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaterialType::Diffuse => "Diffuse",
+            MaterialType::Conductor => "Conductor",
+            MaterialType::Dielectric => "Dielectric",
+            MaterialType::Principled => "Principled",
+        }
+    }
+}
+
+// how the alpha of the base color texture covers a surface; the values match the ALPHA_MODE_
+// constants in path_tracing_common.wgsl. The opacity applies in every mode
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum AlphaMode {
+    // the texture alpha is ignored
+    #[default]
+    Opaque = 0,
+    // the surface is there where opacity times the texture alpha reaches the cutoff, and
+    // nowhere else
+    Mask = 1,
+    // rays pass through the surface with the chance of 1 - opacity times the texture alpha
+    Blend = 2,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct GPUMaterial {
+    base_color: Vec3,
+    base_color_texture: u32,
+    emission: Vec3,
+    emission_texture: u32,
+    roughness: f32,
+    metallic: f32,
+    specular: f32,
+    specular_tint: f32,
+    sheen: f32,
+    sheen_tint: f32,
+    clearcoat: f32,
+    clearcoat_roughness: f32,
+    transmission: f32,
+    ior: f32,
+    opacity: f32,
+    anisotropy: f32,
+    metallic_roughness_texture: u32,
+    material_type: u32,
+    alpha_mode: u32,
+    alpha_cutoff: f32,
+}
+
+// the material model every loader maps its materials onto. It follows the glTF metallic
+// roughness model, so roughness is the perceptual roughness (alpha = roughness^2), extended
+// by the lobes of the Disney principled BSDF. The diffuse, conductor and dielectric types
+// only use the parameters that make sense for them.
+// the textures are indices into the scene's textures and get multiplied with the factors
+#[derive(Copy, Clone, Debug)]
+pub struct Material {
+    pub material_type: MaterialType,
+    pub base_color: Vec3,
+    pub base_color_texture: Option<u32>,
+    pub roughness: f32,
+    // stretches the highlight along the surface tangent (the direction of increasing u),
+    // from 0 for isotropic to 1
+    pub anisotropy: f32,
+    pub metallic: f32,
+    // roughness in the green channel and metallic in the blue one, as in glTF
+    pub metallic_roughness_texture: Option<u32>,
+    pub emission: Vec3,
+    pub emission_texture: Option<u32>,
+    // scales the dielectric reflectance given by the ior; 0.5 leaves it as it is, 1 doubles it
+    pub specular: f32,
+    // tints the dielectric reflection towards the base color
+    pub specular_tint: f32,
+    // a retroreflective rim for cloth, tinted towards the base color by sheen_tint
+    pub sheen: f32,
+    pub sheen_tint: f32,
+    // a second, colorless specular layer (ior 1.5) on top of everything else
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    pub ior: f32,
+    // the share of rays a surface stops, the others go on as if it wasn't there
+    pub opacity: f32,
+    pub alpha_mode: AlphaMode,
+    pub alpha_cutoff: f32,
+    pub transmission: f32,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            material_type: MaterialType::Diffuse,
+            base_color: Vec3::splat(0.8),
+            base_color_texture: None,
+            roughness: 1.0,
+            anisotropy: 0.0,
+            metallic: 0.0,
+            metallic_roughness_texture: None,
+            emission: Vec3::ZERO,
+            emission_texture: None,
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.03,
+            ior: 1.5,
+            opacity: 1.0,
+            alpha_mode: AlphaMode::Opaque,
+            alpha_cutoff: 0.5,
+            transmission: 0.0,
+        }
+    }
+}
+
+impl Material {
+    pub fn diffuse(base_color: Vec3) -> Self {
+        Self {
+            base_color,
+            ..Default::default()
+        }
+    }
+
+    pub fn conductor(base_color: Vec3, roughness: f32) -> Self {
+        Self {
+            material_type: MaterialType::Conductor,
+            base_color,
+            roughness,
+            metallic: 1.0,
+            ..Default::default()
+        }
+    }
+
+    // a clear dielectric; the base color tints the transmitted light
+    pub fn dielectric(ior: f32, roughness: f32) -> Self {
+        Self {
+            material_type: MaterialType::Dielectric,
+            base_color: Vec3::ONE,
+            roughness,
+            ior,
+            transmission: 1.0,
+            ..Default::default()
+        }
+    }
+
+    pub fn principled(base_color: Vec3, metallic: f32, roughness: f32) -> Self {
+        Self {
+            material_type: MaterialType::Principled,
+            base_color,
+            metallic,
+            roughness,
+            ..Default::default()
+        }
+    }
+
+    // the Phong exponent Ns of an MTL file as a perceptual roughness, using the usual
+    // Blinn-Phong to Beckmann correspondence alpha = sqrt(2 / (Ns + 2))
+    pub fn roughness_from_shininess(shininess: f32) -> f32 {
+        (2.0 / (shininess.max(0.0) + 2.0)).sqrt().sqrt()
+    }
+
+    pub fn gpu_material(&self) -> GPUMaterial {
+        GPUMaterial {
+            base_color: self.base_color,
+            base_color_texture: self.base_color_texture.unwrap_or(NO_TEXTURE),
+            emission: self.emission,
+            emission_texture: self.emission_texture.unwrap_or(NO_TEXTURE),
+            roughness: self.roughness,
+            metallic: self.metallic,
+            specular: self.specular,
+            specular_tint: self.specular_tint,
+            sheen: self.sheen,
+            sheen_tint: self.sheen_tint,
+            clearcoat: self.clearcoat,
+            clearcoat_roughness: self.clearcoat_roughness,
+            transmission: self.transmission,
+            ior: self.ior,
+            opacity: self.opacity,
+            anisotropy: self.anisotropy,
+            metallic_roughness_texture: self.metallic_roughness_texture.unwrap_or(NO_TEXTURE),
+            material_type: self.material_type as u32,
+            alpha_mode: self.alpha_mode as u32,
+            alpha_cutoff: self.alpha_cutoff,
+        }
+    }
+}
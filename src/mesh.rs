@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use glam::{Vec2, Vec3};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GPUVertex {
+    position: Vec3,
+    u: f32,
+    normal: Vec3,
+    v: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GPUTriangle {
+    indices: [u32; 3],
+    material_id: u32,
+}
+
+impl GPUTriangle {
+    pub fn material_id(&self) -> u32 {
+        self.material_id
+    }
+}
+
+// a triangle mesh as it comes out of a model file; normals and uvs are optional
+// and may be left empty, in which case the kernel falls back to the face normal.
+// material_ids holds one entry per triangle, indexing into the scene's materials
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<Vec2>,
+    pub indices: Vec<u32>,
+    pub material_ids: Vec<u32>,
+}
+
+impl Mesh {
+    pub fn new(positions: Vec<Vec3>, normals: Vec<Vec3>, uvs: Vec<Vec2>, indices: Vec<u32>,
+               material_ids: Vec<u32>) -> Self {
+        assert_eq!(indices.len() % 3, 0, "mesh indices must form whole triangles");
+        assert_eq!(material_ids.len(), indices.len() / 3, "every triangle needs a material");
+        Self {
+            positions,
+            normals,
+            uvs,
+            indices,
+            material_ids,
+        }
+    }
+
+    // area weighted vertex normals; vertices at the same position share their normal, so
+    // vertices that were only split because of a uv seam don't end up with a visible crease
+    pub fn generate_smooth_normals(&mut self) {
+        let key = |p: Vec3| [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+
+        let mut position_normals: HashMap<[u32; 3], Vec3> = HashMap::new();
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| self.positions[triangle[i] as usize]);
+            // the length of the cross product is twice the area of the triangle
+            let face_normal = (b - a).cross(c - a);
+            for p in [a, b, c] {
+                *position_normals.entry(key(p)).or_insert(Vec3::ZERO) += face_normal;
+            }
+        }
+
+        self.normals = self.positions.iter()
+            .map(|p| position_normals.get(&key(*p)).copied().unwrap_or(Vec3::ZERO).normalize_or_zero())
+            .collect();
+    }
+
+    // an axis aligned cube with flat shaded faces, so every face gets its own four vertices
+    pub fn cube(center: Vec3, half_size: f32, material_id: u32) -> Self {
+        let faces = [
+            (Vec3::X, Vec3::Y), (Vec3::NEG_X, Vec3::Y),
+            (Vec3::Y, Vec3::NEG_Z), (Vec3::NEG_Y, Vec3::Z),
+            (Vec3::Z, Vec3::Y), (Vec3::NEG_Z, Vec3::Y),
+        ];
+
+        let mut positions = Vec::with_capacity(24);
+        let mut normals = Vec::with_capacity(24);
+        let mut uvs = Vec::with_capacity(24);
+        let mut indices = Vec::with_capacity(36);
+        for (normal, up) in faces {
+            let right = up.cross(normal);
+            let first = positions.len() as u32;
+            for (s, t) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                positions.push(center + half_size * (normal + s * right + t * up));
+                normals.push(normal);
+                uvs.push(Vec2::new(0.5 * (s + 1.0), 0.5 * (t + 1.0)));
+            }
+            indices.extend_from_slice(&[first, first + 1, first + 2, first, first + 2, first + 3]);
+        }
+
+        let material_ids = vec![material_id; indices.len() / 3];
+        Self::new(positions, normals, uvs, indices, material_ids)
+    }
+}
+
+// all the meshes of a scene packed together the way the kernel sees them:
+// one vertex array and one triangle array indexing into it. The object id of every triangle
+// is kept apart, since only the AOVs need it and the traversal shouldn't load it
+#[derive(Default)]
+pub struct SceneGeometry {
+    vertices: Vec<GPUVertex>,
+    triangles: Vec<GPUTriangle>,
+    object_ids: Vec<u32>,
+}
+
+impl SceneGeometry {
+    pub fn add_mesh(&mut self, mesh: &Mesh, object_id: u32) {
+        let first_vertex = self.vertices.len() as u32;
+
+        for (i, position) in mesh.positions.iter().enumerate() {
+            let normal = mesh.normals.get(i).copied().unwrap_or(Vec3::ZERO);
+            let uv = mesh.uvs.get(i).copied().unwrap_or(Vec2::ZERO);
+            self.vertices.push(GPUVertex {
+                position: *position,
+                u: uv.x,
+                normal,
+                v: uv.y,
+            });
+        }
+
+        for (triangle, material_id) in mesh.indices.chunks_exact(3).zip(&mesh.material_ids) {
+            self.triangles.push(GPUTriangle {
+                indices: [
+                    first_vertex + triangle[0],
+                    first_vertex + triangle[1],
+                    first_vertex + triangle[2],
+                ],
+                material_id: *material_id,
+            });
+            self.object_ids.push(object_id);
+        }
+    }
+
+    // the (min, max) corners of every triangle, in triangle order
+    pub fn triangle_bounds(&self) -> Vec<(Vec3, Vec3)> {
+        self.triangles.iter()
+            .map(|triangle| {
+                let [a, b, c] = triangle.indices.map(|i| self.vertices[i as usize].position);
+                (a.min(b).min(c), a.max(b).max(c))
+            })
+            .collect()
+    }
+
+    pub fn triangle_positions(&self, index: usize) -> [Vec3; 3] {
+        self.triangles[index].indices.map(|i| self.vertices[i as usize].position)
+    }
+
+    // puts the triangles into the order the bvh leaves reference them in
+    pub fn reorder_triangles(&mut self, order: &[u32]) {
+        self.triangles = order.iter().map(|&i| self.triangles[i as usize]).collect();
+        self.object_ids = order.iter().map(|&i| self.object_ids[i as usize]).collect();
+    }
+
+    pub fn vertices(&self) -> &[GPUVertex] {
+        &self.vertices
+    }
+
+    pub fn triangles(&self) -> &[GPUTriangle] {
+        &self.triangles
+    }
+
+    pub fn object_ids(&self) -> &[u32] {
+        &self.object_ids
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use glam::{Vec2, Vec3};
+use crate::material::{Material, MaterialType};
+use crate::mesh::Mesh;
+use crate::scene::Scene;
+
+// reads an OBJ file and the MTL files it references into the scene. tobj takes care of the
+// parsing, including negative (relative) indices, fan triangulation of polygons and merging
+// the separate position/normal/uv indices into a single index per vertex
+pub fn load_obj(path: &Path, scene: &mut Scene) -> Result<(), tobj::LoadError> {
+    let options = tobj::LoadOptions {
+        single_index: true,
+        triangulate: true,
+        ignore_points: true,
+        ignore_lines: true,
+    };
+    let (models, materials) = tobj::load_obj(path, &options)?;
+
+    // a missing or broken MTL file shouldn't stop the geometry from loading
+    let materials = materials.unwrap_or_else(|error| {
+        println!("Failed to load the materials of {}: {}", path.display(), error);
+        Vec::new()
+    });
+
+    // texture paths in an MTL file are relative to the directory of the model
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let mut texture_ids: HashMap<PathBuf, Option<u32>> = HashMap::new();
+    let material_ids: Vec<u32> = materials.iter()
+        .map(|material| {
+            let material = convert_material(material, directory, scene, &mut texture_ids);
+            scene.add_material(&material)
+        })
+        .collect();
+    let mut default_material_id = None;
+
+    for model in &models {
+        let mesh = &model.mesh;
+        if mesh.indices.is_empty() {
+            continue;
+        }
+
+        let material_id = match mesh.material_id.and_then(|id| material_ids.get(id)) {
+            Some(material_id) => *material_id,
+            None => *default_material_id.get_or_insert_with(|| scene.add_material(&Material::default())),
+        };
+
+        let positions = mesh.positions.chunks_exact(3)
+            .map(|p| Vec3::new(p[0], p[1], p[2]))
+            .collect();
+        let normals = mesh.normals.chunks_exact(3)
+            .map(|n| Vec3::new(n[0], n[1], n[2]))
+            .collect();
+        // OBJ puts v = 0 at the bottom of the image, whereas the texel rows start at the top
+        let uvs = mesh.texcoords.chunks_exact(2)
+            .map(|uv| Vec2::new(uv[0], 1.0 - uv[1]))
+            .collect();
+
+        let triangle_count = mesh.indices.len() / 3;
+        let mut triangle_mesh = Mesh::new(positions, normals, uvs, mesh.indices.clone(),
+                                          vec![material_id; triangle_count]);
+        if mesh.normals.is_empty() {
+            triangle_mesh.generate_smooth_normals();
+        }
+        scene.add_mesh(&triangle_mesh);
+    }
+
+    Ok(())
+}
+
+// maps the MTL parameters onto our material model:
+//   Kd -> base color, map_Kd -> base color texture, Ns -> roughness, Ni -> ior,
+//   d -> opacity, Ke -> emission, illum -> material type, Ks -> color of mirrors
+// and the parameters of the PBR extension, which make it a principled material:
+//   Pr -> roughness, Pm -> metallic, Ps -> sheen, Pc -> clearcoat,
+//   Pcr -> clearcoat roughness, aniso -> anisotropy
+fn convert_material(material: &tobj::Material, directory: &Path, scene: &mut Scene,
+                    texture_ids: &mut HashMap<PathBuf, Option<u32>>) -> Material {
+    let mut converted = Material::default();
+
+    if let Some(diffuse) = material.diffuse {
+        converted.base_color = Vec3::from(diffuse);
+    }
+    if let Some(shininess) = material.shininess {
+        converted.roughness = Material::roughness_from_shininess(shininess);
+    }
+    if let Some(ior) = material.optical_density {
+        converted.ior = ior;
+    }
+    if let Some(dissolve) = material.dissolve {
+        converted.opacity = dissolve;
+    }
+    // the illumination models with ray traced reflections (3, 5, 8) are mirrors, the ones with
+    // refraction (4, 6, 7, 9) glass; the specular color is what a mirror reflects
+    match material.illumination_model {
+        Some(3 | 5 | 8) => {
+            converted.material_type = MaterialType::Conductor;
+            converted.metallic = 1.0;
+            if let Some(specular) = material.specular.filter(|specular| *specular != [0.0; 3]) {
+                converted.base_color = Vec3::from(specular);
+            }
+        },
+        Some(4 | 6 | 7 | 9) => {
+            converted.material_type = MaterialType::Dielectric;
+            converted.base_color = Vec3::ONE;
+            converted.transmission = 1.0;
+        },
+        _ => {},
+    }
+    // tobj knows neither the PBR extension nor Ke, so they end up among the unknown parameters
+    let pbr_parameter = |name: &str| material.unknown_param.get(name)
+        .and_then(|value| value.trim().parse::<f32>().ok());
+    let pbr_parameters: [(&str, &mut f32); 6] = [
+        ("Pr", &mut converted.roughness),
+        ("Pm", &mut converted.metallic),
+        ("Ps", &mut converted.sheen),
+        ("Pc", &mut converted.clearcoat),
+        ("Pcr", &mut converted.clearcoat_roughness),
+        ("aniso", &mut converted.anisotropy),
+    ];
+    let mut is_pbr = false;
+    for (name, parameter) in pbr_parameters {
+        if let Some(value) = pbr_parameter(name) {
+            *parameter = value.clamp(0.0, 1.0);
+            is_pbr = true;
+        }
+    }
+    if is_pbr {
+        converted.material_type = MaterialType::Principled;
+    }
+    if let Some(emission) = material.unknown_param.get("Ke").and_then(|ke| parse_vec3(ke)) {
+        converted.emission = emission;
+    }
+
+    // texture statements can carry options (-bm, -s, ...) in front of the file name
+    if let Some(file_name) = material.diffuse_texture.as_deref().and_then(|map| map.split_whitespace().last()) {
+        let texture_path = directory.join(file_name.replace('\\', "/"));
+        converted.base_color_texture = *texture_ids.entry(texture_path.clone())
+            .or_insert_with(|| match scene.textures_mut().load(&texture_path, true) {
+                Ok(texture_id) => Some(texture_id),
+                Err(error) => {
+                    println!("Failed to load texture {}: {}", texture_path.display(), error);
+                    None
+                },
+            });
+    }
+
+    converted
+}
+
+fn parse_vec3(value: &str) -> Option<Vec3> {
+    let components: Vec<f32> = value.split_whitespace()
+        .map(|component| component.parse().ok())
+        .collect::<Option<_>>()?;
+    match components[..] {
+        [x, y, z] => Some(Vec3::new(x, y, z)),
+        // a single value is used for all three channels, like for Kd and friends
+        [v] => Some(Vec3::splat(v)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // tobj only reads from files, so the OBJ goes into a temporary one
+    fn load_inline_obj(name: &str, source: &str) -> Scene {
+        let path = std::env::temp_dir().join(format!("{}_{}.obj", name, std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let mut scene = Scene::default();
+        let result = load_obj(&path, &mut scene);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+        scene
+    }
+
+    fn convert(material: tobj::Material) -> Material {
+        let mut scene = Scene::default();
+        convert_material(&material, Path::new(""), &mut scene, &mut HashMap::new())
+    }
+
+    #[test]
+    fn negative_indices_quads_and_no_normals() {
+        let scene = load_inline_obj("negative_indices", "\
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+f -4 -3 -2 -1
+v 2 0 0
+v 3 0 0
+v 3 1 0
+f -3 -2 -1
+");
+        // without an MTL file everything gets the default material
+        assert_eq!(scene.materials().len(), 1);
+
+        // the quad is split into two triangles that both span it, and the negative indices
+        // of the second face are relative to the vertices read so far
+        let mut bounds = scene.geometry().triangle_bounds();
+        bounds.sort_by(|a, b| a.0.x.total_cmp(&b.0.x));
+        assert_eq!(bounds, vec![
+            (Vec3::ZERO, Vec3::new(1.0, 1.0, 0.0)),
+            (Vec3::ZERO, Vec3::new(1.0, 1.0, 0.0)),
+            (Vec3::new(2.0, 0.0, 0.0), Vec3::new(3.0, 1.0, 0.0)),
+        ]);
+    }
+
+    #[test]
+    fn mtl_parameters_map_onto_the_material() {
+        let material = convert(tobj::Material {
+            diffuse: Some([0.1, 0.2, 0.3]),
+            specular: Some([0.4, 0.5, 0.6]),
+            shininess: Some(98.0),
+            optical_density: Some(1.33),
+            dissolve: Some(0.25),
+            unknown_param: [("Ke".to_string(), "1 2 3".to_string())].into_iter().collect(),
+            ..Default::default()
+        });
+        assert_eq!(material.base_color, Vec3::new(0.1, 0.2, 0.3));
+        // without a mirror illumination model the specular color has no say
+        assert_eq!(material.material_type, MaterialType::Diffuse);
+        // alpha = sqrt(2 / (98 + 2)), and the roughness is its square root
+        assert!((material.roughness - 0.02f32.sqrt().sqrt()).abs() < 1e-6);
+        assert_eq!(material.ior, 1.33);
+        assert_eq!(material.opacity, 0.25);
+        assert_eq!(material.emission, Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(material.base_color_texture, None);
+    }
+
+    #[test]
+    fn illumination_models_pick_the_bsdf() {
+        let with_illum = |illumination_model| convert(tobj::Material {
+            diffuse: Some([0.1, 0.2, 0.3]),
+            specular: Some([0.9, 0.8, 0.7]),
+            illumination_model: Some(illumination_model),
+            ..Default::default()
+        });
+
+        let plastic = with_illum(2);
+        assert_eq!(plastic.material_type, MaterialType::Diffuse);
+        assert_eq!(plastic.base_color, Vec3::new(0.1, 0.2, 0.3));
+
+        // a mirror reflects the specular color
+        let mirror = with_illum(3);
+        assert_eq!(mirror.material_type, MaterialType::Conductor);
+        assert_eq!(mirror.base_color, Vec3::new(0.9, 0.8, 0.7));
+
+        let glass = with_illum(7);
+        assert_eq!(glass.material_type, MaterialType::Dielectric);
+        assert_eq!(glass.base_color, Vec3::ONE);
+        assert_eq!(glass.transmission, 1.0);
+    }
+
+    #[test]
+    fn pbr_extension_makes_a_principled_material() {
+        let parameters = [("Pr", "0.3"), ("Pm", "1"), ("Ps", "0.4"), ("Pc", "0.5"), ("Pcr", "0.6"), ("aniso", " 2 ")];
+        let material = convert(tobj::Material {
+            shininess: Some(10.0),
+            unknown_param: parameters.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            ..Default::default()
+        });
+        assert_eq!(material.material_type, MaterialType::Principled);
+        // Pr takes the place of Ns
+        assert_eq!(material.roughness, 0.3);
+        assert_eq!(material.metallic, 1.0);
+        assert_eq!(material.sheen, 0.4);
+        assert_eq!(material.clearcoat, 0.5);
+        assert_eq!(material.clearcoat_roughness, 0.6);
+        // clamped to [0, 1]
+        assert_eq!(material.anisotropy, 1.0);
+    }
+
+    #[test]
+    fn missing_mtl_parameters_keep_the_defaults() {
+        let material = convert(tobj::Material::default());
+        let default = Material::default();
+        assert_eq!(material.base_color, default.base_color);
+        assert_eq!(material.roughness, default.roughness);
+        assert_eq!(material.opacity, 1.0);
+        assert_eq!(material.emission, Vec3::ZERO);
+    }
+
+    #[test]
+    fn parse_vec3_takes_one_or_three_components() {
+        assert_eq!(parse_vec3("1 2 3"), Some(Vec3::new(1.0, 2.0, 3.0)));
+        assert_eq!(parse_vec3(" 4 "), Some(Vec3::splat(4.0)));
+        assert_eq!(parse_vec3("1 2"), None);
+        assert_eq!(parse_vec3("1 x 3"), None);
+    }
+}
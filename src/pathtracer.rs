@@ -1,14 +1,24 @@
 use std::collections::VecDeque;
+use std::path::Path;
 use glam::{Vec3};
 use wgpu::{BindGroupDescriptor, BindGroupLayoutDescriptor};
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
 use wgpu::wgt::BufferDescriptor;
-use wgpu_profiler::{GpuProfiler, GpuProfilerSettings, GpuTimerQueryResult};
+use wgpu_profiler::{GpuProfiler, GpuProfilerSettings, GpuTimerQueryResult, Scope};
+use crate::adaptive_sampling::{AdaptiveSampling, GPUPixelStats};
+use crate::aov::{AovLayer, Aovs};
+use crate::blue_noise::BlueNoise;
 use crate::camera::CameraController;
+use crate::denoiser::Denoiser;
+use crate::display_parameters::{DisplayTransform, GPUDisplayParameters};
+use crate::environment::GPUEnvironmentParameters;
 use crate::gui::{UserInput, GUI};
+use crate::image_export::{save_image, ImageFormat};
+use crate::material::GPUMaterial;
+use crate::pixel_filter::GPUPixelFilter;
 use crate::sampling_parameters::GPUSamplingParametersBuffer;
+use crate::scene::Scene;
 use crate::utilities::u8cast::{any_as_u8_slice, vec_as_u8_slice};
-use crate::utilities::print_profiling::{console_output};
 use crate::wgpu_state::WGPUState;
 
 
@@ -52,6 +62,465 @@ impl GPUFrameParameters {
 
 }
 
+// storage buffers can't be bound empty, so a scene without e.g. any triangles gets a single
+// zeroed element instead; the kernel treats zeroed primitives as degenerate and never hits them
+fn create_storage_buffer<T: Sized>(device: &wgpu::Device, label: &str, contents: &[T]) -> wgpu::Buffer {
+    let bytes = unsafe {
+        vec_as_u8_slice(contents)
+    };
+    let padding = vec![0u8; size_of::<T>()];
+
+    device.create_buffer_init(&BufferInitDescriptor {
+        label: Some(label),
+        contents: if bytes.is_empty() { &padding } else { bytes },
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+    })
+}
+
+// a bind group of whole buffers and texture views that keeps hold of them, so that it can be
+// made again with some of the buffers replaced
+struct BufferBindGroup {
+    label: &'static str,
+    layout: wgpu::BindGroupLayout,
+    buffers: Vec<(u32, wgpu::Buffer)>,
+    texture_views: Vec<(u32, wgpu::TextureView)>,
+    bind_group: wgpu::BindGroup,
+}
+
+impl BufferBindGroup {
+    fn new(device: &wgpu::Device, label: &'static str, layout: &wgpu::BindGroupLayout,
+           buffers: &[(u32, &wgpu::Buffer)]) -> Self {
+        Self::with_texture_views(device, label, layout, buffers, &[])
+    }
+
+    fn with_texture_views(device: &wgpu::Device, label: &'static str, layout: &wgpu::BindGroupLayout,
+                          buffers: &[(u32, &wgpu::Buffer)], texture_views: &[(u32, &wgpu::TextureView)]) -> Self {
+        let buffers: Vec<(u32, wgpu::Buffer)> = buffers.iter()
+            .map(|(binding, buffer)| (*binding, (*buffer).clone()))
+            .collect();
+        let texture_views: Vec<(u32, wgpu::TextureView)> = texture_views.iter()
+            .map(|(binding, texture_view)| (*binding, (*texture_view).clone()))
+            .collect();
+        let bind_group = Self::create_bind_group(device, label, layout, &buffers, &texture_views);
+        Self {
+            label,
+            layout: layout.clone(),
+            buffers,
+            texture_views,
+            bind_group,
+        }
+    }
+
+    fn create_bind_group(device: &wgpu::Device, label: &str, layout: &wgpu::BindGroupLayout,
+                         buffers: &[(u32, wgpu::Buffer)], texture_views: &[(u32, wgpu::TextureView)]) -> wgpu::BindGroup {
+        let entries: Vec<wgpu::BindGroupEntry> = buffers.iter()
+            .map(|(binding, buffer)| wgpu::BindGroupEntry { binding: *binding, resource: buffer.as_entire_binding() })
+            .chain(texture_views.iter().map(|(binding, texture_view)| wgpu::BindGroupEntry {
+                binding: *binding,
+                resource: wgpu::BindingResource::TextureView(texture_view),
+            }))
+            .collect();
+        device.create_bind_group(&BindGroupDescriptor{
+            label: Some(label),
+            layout,
+            entries: &entries,
+        })
+    }
+
+    fn replace(&mut self, device: &wgpu::Device, binding: u32, buffer: &wgpu::Buffer) {
+        self.replace_all(device, &[(binding, buffer)]);
+    }
+
+    fn replace_all(&mut self, device: &wgpu::Device, buffers: &[(u32, &wgpu::Buffer)]) {
+        for (bound, bound_buffer) in self.buffers.iter_mut() {
+            if let Some((_, buffer)) = buffers.iter().find(|(binding, _)| binding == bound) {
+                *bound_buffer = (*buffer).clone();
+            }
+        }
+        self.bind_group = Self::create_bind_group(device, self.label, &self.layout, &self.buffers, &self.texture_views);
+    }
+
+    fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+}
+
+// the tracing kernels are appended to the common shader code, which holds the scene bindings
+// and everything the megakernel and the wavefront kernels share
+fn create_kernel_module(device: &wgpu::Device, label: &str, source: &str) -> wgpu::ShaderModule {
+    device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some(label),
+        source: wgpu::ShaderSource::Wgsl(source.into()),
+    })
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RenderBackend {
+    #[default]
+    Megakernel,
+    Wavefront,
+}
+
+impl RenderBackend {
+    pub const ALL: [RenderBackend; 2] = [RenderBackend::Megakernel, RenderBackend::Wavefront];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RenderBackend::Megakernel => "Megakernel",
+            RenderBackend::Wavefront => "Wavefront",
+        }
+    }
+}
+
+// the wavefront backend keeps the state of every path in flight in storage buffers and runs
+// one kernel per stage (see wavefront.wgsl); the queue counters are atomics on the gpu and
+// the kernels consuming a queue are dispatched indirectly with its length
+struct WavefrontPipeline {
+    queues_buffer: wgpu::Buffer,
+    dispatch_buffer: wgpu::Buffer,
+    // holds the aov buffer, which gets replaced when the AOVs are turned on or off
+    path_bind_group: BufferBindGroup,
+    queue_bind_group: wgpu::BindGroup,
+    reset_queues_pipeline: wgpu::ComputePipeline,
+    advance_queues_pipeline: wgpu::ComputePipeline,
+    generate_pipeline: wgpu::ComputePipeline,
+    extend_pipeline: wgpu::ComputePipeline,
+    shade_pipeline: wgpu::ComputePipeline,
+    connect_pipeline: wgpu::ComputePipeline,
+}
+
+impl WavefrontPipeline {
+    // the number of paths in flight at once; larger images are traced in batches of this size
+    const PATH_POOL_SIZE: u64 = 1 << 19;
+    // byte offsets of the ray and shadow ray dispatch arguments in the dispatch buffer
+    const RAY_DISPATCH_OFFSET: wgpu::BufferAddress = 0;
+    const SHADOW_RAY_DISPATCH_OFFSET: wgpu::BufferAddress = 12;
+
+    // shared_bind_group_layouts are those of the render parameters, scene and geometry bind groups
+    #[allow(clippy::too_many_arguments)]
+    fn new(device: &wgpu::Device,
+           image_buffer: &wgpu::Buffer,
+           frame_buffer: &wgpu::Buffer,
+           adaptive_sampling: &AdaptiveSampling,
+           feature_buffer: &wgpu::Buffer,
+           aovs: &Aovs,
+           shared_bind_group_layouts: &[&wgpu::BindGroupLayout; 3],
+           constants: &[(&str, f64)]) -> Self {
+
+        // path state is a throughput, an rng state, a flag, a pdf and the diffuse part of the
+        // first bounce (48 bytes), rays and intersections 32 and 16 bytes, shadow rays 64 bytes;
+        // the ray buffer holds the current and the next extension queue
+        let path_buffer = device.create_buffer(&BufferDescriptor{
+            label: Some("Path State Buffer"),
+            size: 48 * Self::PATH_POOL_SIZE,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let ray_buffer = device.create_buffer(&BufferDescriptor{
+            label: Some("Extension Ray Buffer"),
+            size: 2 * 32 * Self::PATH_POOL_SIZE,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let intersection_buffer = device.create_buffer(&BufferDescriptor{
+            label: Some("Intersection Buffer"),
+            size: 16 * Self::PATH_POOL_SIZE,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let shadow_ray_buffer = device.create_buffer(&BufferDescriptor{
+            label: Some("Shadow Ray Buffer"),
+            size: 64 * Self::PATH_POOL_SIZE,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        // the queue counters and the batch bookkeeping, cleared whenever the image is
+        let queues_buffer = device.create_buffer(&BufferDescriptor{
+            label: Some("Queues Buffer"),
+            size: 8 * size_of::<u32>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // indirect dispatch arguments for the ray and the shadow ray queues
+        let dispatch_buffer = device.create_buffer(&BufferDescriptor{
+            label: Some("Dispatch Buffer"),
+            size: 6 * size_of::<u32>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT,
+            mapped_at_creation: false,
+        });
+
+        let image_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let frame_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 1,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let path_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            ..image_buffer_layout
+        };
+
+        let ray_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            ..image_buffer_layout
+        };
+
+        let intersection_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 4,
+            ..image_buffer_layout
+        };
+
+        let shadow_ray_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 5,
+            ..image_buffer_layout
+        };
+
+        let queues_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 6,
+            ..image_buffer_layout
+        };
+
+        let dispatch_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 7,
+            ..image_buffer_layout
+        };
+
+        let pixel_stats_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 8,
+            ..image_buffer_layout
+        };
+
+        let tile_flag_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 9,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            ..image_buffer_layout
+        };
+
+        // shade writes the features of the first hit for the denoiser
+        let feature_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 10,
+            ..image_buffer_layout
+        };
+
+        // and the AOVs along the whole path when they are on
+        let aov_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 11,
+            ..image_buffer_layout
+        };
+
+        // the stage kernels get everything but the dispatch buffer, which they are dispatched
+        // with and so can't also have bound as storage
+        let path_bind_group_layout = device.create_bind_group_layout(
+            &BindGroupLayoutDescriptor{
+                label: Some("wavefront path bind group layout"),
+                entries: &[
+                    image_buffer_layout,
+                    frame_buffer_layout,
+                    path_buffer_layout,
+                    ray_buffer_layout,
+                    intersection_buffer_layout,
+                    shadow_ray_buffer_layout,
+                    queues_buffer_layout,
+                    pixel_stats_buffer_layout,
+                    tile_flag_buffer_layout,
+                    feature_buffer_layout,
+                    aov_buffer_layout,
+                ],
+            });
+
+        let path_bind_group = BufferBindGroup::new(device, "wavefront path bind group", &path_bind_group_layout, &[
+            (0, image_buffer),
+            (1, frame_buffer),
+            (2, &path_buffer),
+            (3, &ray_buffer),
+            (4, &intersection_buffer),
+            (5, &shadow_ray_buffer),
+            (6, &queues_buffer),
+            (8, adaptive_sampling.pixel_stats_buffer()),
+            (9, adaptive_sampling.tile_flag_buffer()),
+            (10, feature_buffer),
+            (11, aovs.aov_buffer()),
+        ]);
+
+        // the queue kernels only touch the counters and write the dispatch arguments;
+        // the path buffer is there because its length is the size of the path pool
+        let queue_bind_group_layout = device.create_bind_group_layout(
+            &BindGroupLayoutDescriptor{
+                label: Some("wavefront queue bind group layout"),
+                entries: &[
+                    frame_buffer_layout,
+                    path_buffer_layout,
+                    queues_buffer_layout,
+                    dispatch_buffer_layout,
+                ],
+            });
+
+        let queue_bind_group = device.create_bind_group(&BindGroupDescriptor{
+            label: Some("wavefront queue bind group"),
+            layout: &queue_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 1, resource: frame_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: path_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 6, resource: queues_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 7, resource: dispatch_buffer.as_entire_binding() },
+            ],
+        });
+
+        let stage_pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("wavefront stage pipeline layout"),
+                bind_group_layouts: &[
+                    &path_bind_group_layout,
+                    shared_bind_group_layouts[0],
+                    shared_bind_group_layouts[1],
+                    shared_bind_group_layouts[2],
+                ],
+                push_constant_ranges: &[],
+            }
+        );
+
+        let queue_pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("wavefront queue pipeline layout"),
+                bind_group_layouts: &[&queue_bind_group_layout],
+                push_constant_ranges: &[],
+            }
+        );
+
+        let shader = create_kernel_module(device, "wavefront kernels", concat!(
+            include_str!("../shaders/path_tracing_common.wgsl"),
+            include_str!("../shaders/environment.wgsl"),
+            include_str!("../shaders/bsdf.wgsl"),
+            include_str!("../shaders/lights.wgsl"),
+            include_str!("../shaders/wavefront.wgsl"),
+        ));
+
+        let create_pipeline = |layout: &wgpu::PipelineLayout, entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: wgpu::PipelineCompilationOptions {
+                    constants,
+                    zero_initialize_workgroup_memory: false,
+                },
+                cache: None,
+            })
+        };
+
+        Self {
+            queues_buffer,
+            dispatch_buffer,
+            path_bind_group,
+            queue_bind_group,
+            reset_queues_pipeline: create_pipeline(&queue_pipeline_layout, "resetQueues"),
+            advance_queues_pipeline: create_pipeline(&queue_pipeline_layout, "advanceQueues"),
+            generate_pipeline: create_pipeline(&stage_pipeline_layout, "generate"),
+            extend_pipeline: create_pipeline(&stage_pipeline_layout, "extend"),
+            shade_pipeline: create_pipeline(&stage_pipeline_layout, "shade"),
+            connect_pipeline: create_pipeline(&stage_pipeline_layout, "connect"),
+        }
+    }
+
+    fn batch_count(pixel_count: u32) -> u32 {
+        (pixel_count as u64).div_ceil(Self::PATH_POOL_SIZE) as u32
+    }
+
+    // restarts the queues at the first pixel
+    fn reset(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.clear_buffer(&self.queues_buffer, 0, None);
+    }
+
+    fn set_aov_buffer(&mut self, device: &wgpu::Device, aov_buffer: &wgpu::Buffer) {
+        self.path_bind_group.replace(device, 11, aov_buffer);
+    }
+
+    fn set_stage_bind_groups(&self, compute_pass: &mut wgpu::ComputePass, shared_bind_groups: &[&wgpu::BindGroup; 3]) {
+        compute_pass.set_bind_group(0, self.path_bind_group.bind_group(), &[]);
+        for (i, bind_group) in shared_bind_groups.iter().enumerate() {
+            compute_pass.set_bind_group(i as u32 + 1, *bind_group, &[]);
+        }
+    }
+
+    // one sample for every pixel in an active tile: the image is traced a batch of paths at a
+    // time, and every batch runs generate once and then extend, shade and connect once per bounce.
+    // shared_bind_groups are the render parameters, scene and geometry bind groups
+    fn trace_sample(&self,
+                    scope: &mut Scope<'_, wgpu::CommandEncoder>,
+                    shared_bind_groups: &[&wgpu::BindGroup; 3],
+                    pixel_count: u32,
+                    number_of_bounces: u32) {
+        for _ in 0..Self::batch_count(pixel_count) {
+            {
+                let mut compute_pass = scope.scoped_compute_pass("generate");
+                compute_pass.set_pipeline(&self.reset_queues_pipeline);
+                compute_pass.set_bind_group(0, &self.queue_bind_group, &[]);
+                compute_pass.dispatch_workgroups(1, 1, 1);
+
+                compute_pass.set_pipeline(&self.generate_pipeline);
+                self.set_stage_bind_groups(&mut compute_pass, shared_bind_groups);
+                compute_pass.dispatch_workgroups_indirect(&self.dispatch_buffer, Self::RAY_DISPATCH_OFFSET);
+
+                // the camera rays of converged tiles are left out of the extension queue
+                compute_pass.set_pipeline(&self.advance_queues_pipeline);
+                compute_pass.set_bind_group(0, &self.queue_bind_group, &[]);
+                compute_pass.dispatch_workgroups(1, 1, 1);
+            }
+
+            for _ in 0..=number_of_bounces {
+                {
+                    let mut compute_pass = scope.scoped_compute_pass("extend");
+                    compute_pass.set_pipeline(&self.extend_pipeline);
+                    self.set_stage_bind_groups(&mut compute_pass, shared_bind_groups);
+                    compute_pass.dispatch_workgroups_indirect(&self.dispatch_buffer, Self::RAY_DISPATCH_OFFSET);
+                }
+                {
+                    let mut compute_pass = scope.scoped_compute_pass("shade");
+                    compute_pass.set_pipeline(&self.shade_pipeline);
+                    self.set_stage_bind_groups(&mut compute_pass, shared_bind_groups);
+                    compute_pass.dispatch_workgroups_indirect(&self.dispatch_buffer, Self::RAY_DISPATCH_OFFSET);
+                }
+                {
+                    let mut compute_pass = scope.scoped_compute_pass("connect");
+                    compute_pass.set_pipeline(&self.advance_queues_pipeline);
+                    compute_pass.set_bind_group(0, &self.queue_bind_group, &[]);
+                    compute_pass.dispatch_workgroups(1, 1, 1);
+
+                    compute_pass.set_pipeline(&self.connect_pipeline);
+                    self.set_stage_bind_groups(&mut compute_pass, shared_bind_groups);
+                    compute_pass.dispatch_workgroups_indirect(&self.dispatch_buffer, Self::SHADOW_RAY_DISPATCH_OFFSET);
+                }
+            }
+        }
+    }
+}
+
 pub struct PathTracer<'a> {
     pub wgpu_state: WGPUState<'a>,
     image_buffer: wgpu::Buffer,
@@ -60,35 +529,47 @@ pub struct PathTracer<'a> {
     view_transform_buffer: wgpu::Buffer,
     sampling_parameters_buffer: wgpu::Buffer,
     camera_buffer: wgpu::Buffer,
-    image_bind_group: wgpu::BindGroup,
+    environment_parameters_buffer: wgpu::Buffer,
+    pixel_filter_buffer: wgpu::Buffer,
+    // these two hold the aov buffer as well
+    image_bind_group: BufferBindGroup,
     render_parameters_bind_group: wgpu::BindGroup,
-    display_bind_group: wgpu::BindGroup,
+    // kept to build the lights again when the emission of a material is edited
+    scene: Scene,
+    material_buffer: wgpu::Buffer,
+    // holds the light buffers as well, which are made again when an edit changes the lights
+    scene_bind_group: BufferBindGroup,
+    geometry_bind_group: wgpu::BindGroup,
+    display_bind_group: BufferBindGroup,
+    display_parameters_buffer: wgpu::Buffer,
     compute_shader_pipeline: wgpu::ComputePipeline,
+    adaptive_sampling: AdaptiveSampling,
+    denoiser: Denoiser,
+    aovs: Aovs,
+    wavefront: WavefrontPipeline,
+    render_backend: RenderBackend,
     display_pipeline: wgpu::RenderPipeline,
     camera_controller: CameraController,
     frame_parameters: GPUFrameParameters,
     sampling_parameters: GPUSamplingParametersBuffer,
+    environment_parameters: GPUEnvironmentParameters,
+    pixel_filter: GPUPixelFilter,
+    display_parameters: GPUDisplayParameters,
+    // what the PNG export applies to match the display
+    display_transform: DisplayTransform,
     gpu_profiler: GpuProfiler,
     latest_profiler_results: Option<Vec<GpuTimerQueryResult>>,
     running_avg_kernel_time: VecDeque<f32>,
+    running_avg_pass_times: Vec<(String, VecDeque<f32>)>,
 }
 
 impl<'a> PathTracer<'a> {
     const RUNNING_AVG_LENGTH:usize = 100;
 
-    pub fn new(wgpu_state: WGPUState<'a>) -> Option<Self> {
-        let window = wgpu_state.get_window();
-        let size = window.inner_size();
-        let max_window_size = window
-            .available_monitors()
-            .map(|monitor| -> u32 {
-                let viewport = monitor.size();
-                let size = (viewport.width, viewport.height);
-                size.0 * size.1
-            })
-            .max()
-            .expect("must have at least one monitor");
-        
+    pub fn new(wgpu_state: WGPUState<'a>, scene: Scene) -> Option<Self> {
+        let size = wgpu_state.size();
+        let max_window_size = wgpu_state.max_image_size();
+
         let device = wgpu_state.device();
         
         let image = vec![[0.1f32, 0.2, 0.3]; max_window_size as usize];
@@ -99,7 +580,7 @@ impl<'a> PathTracer<'a> {
         let image_buffer = device.create_buffer_init(&BufferInitDescriptor {
             label: Some("Image Buffer"),
             contents: image_bytes,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
         });
 
         let mut image_buffer_layout = wgpu::BindGroupLayoutEntry {
@@ -113,8 +594,6 @@ impl<'a> PathTracer<'a> {
             count: None,
         };
 
-        let image_buffer_binding = wgpu::BindGroupEntry { binding: 0, resource: image_buffer.as_entire_binding() };
-
         let frame_buffer = device.create_buffer(&BufferDescriptor{
             label: Some("Frame Buffer"),
             size: 4 * size_of::<f32>() as wgpu::BufferAddress,
@@ -133,21 +612,248 @@ impl<'a> PathTracer<'a> {
             count: None,
         };
 
-        let frame_buffer_binding = wgpu::BindGroupEntry { binding: 1, resource: frame_buffer.as_entire_binding() };
+        // the megakernel also keeps the per-pixel stats of adaptive sampling and reads which
+        // tiles it runs on
+        let pixel_stats_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            ..image_buffer_layout
+        };
+
+        let active_tile_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            ..image_buffer_layout
+        };
+
+        // the features of the first hits, which the denoiser is guided by
+        let feature_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 4,
+            ..image_buffer_layout
+        };
+
+        // the rest of the AOVs, only written when they are on
+        let aov_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 5,
+            ..image_buffer_layout
+        };
 
         // group image and frame buffers into image_bind_group
         let image_bind_group_layout = device.create_bind_group_layout(
             &BindGroupLayoutDescriptor{
                 label: Some("image bind group layout"),
                 entries: &[image_buffer_layout,
-                    frame_buffer_layout
+                    frame_buffer_layout,
+                    pixel_stats_buffer_layout,
+                    active_tile_buffer_layout,
+                    feature_buffer_layout,
+                    aov_buffer_layout,
                 ],
             });
 
-        let image_bind_group = device.create_bind_group(&BindGroupDescriptor{
-            label: Some("image bind group"),
-            layout: &image_bind_group_layout,
-            entries: &[image_buffer_binding, frame_buffer_binding],
+        // the scene geometry only changes when a new scene is loaded
+        let sphere_buffer = create_storage_buffer(device, "Sphere Buffer", scene.spheres());
+        let material_buffer = create_storage_buffer(device, "Material Buffer", &scene.gpu_materials());
+        let texture_buffer = create_storage_buffer(device, "Texture Buffer", scene.textures().textures());
+        let texel_buffer = create_storage_buffer(device, "Texel Buffer", scene.textures().texels());
+        let light_buffer = create_storage_buffer(device, "Light Buffer", scene.lights().lights());
+        let punctual_light_buffer = create_storage_buffer(device, "Punctual Light Buffer", scene.lights().punctual_lights());
+        let light_node_buffer = create_storage_buffer(device, "Light Node Buffer", scene.lights().nodes());
+        let light_tree = scene.lights().gpu_light_tree();
+        let light_tree_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Light Tree Buffer"),
+            contents: unsafe { any_as_u8_slice(&light_tree) },
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        // the environment map is a float texture the kernels read texels from directly, so it
+        // needs neither a sampler nor a filterable format. Without one, a black texel stands
+        // in and the kernels fall back to the sky gradient
+        let (environment_size, environment_texels, environment_cdf) = match scene.environment() {
+            Some(environment) => (
+                (environment.width(), environment.height()),
+                environment.texels(),
+                environment.cdf(),
+            ),
+            None => ((1, 1), &[[0.0f32; 4]][..], &[1.0f32, 1.0][..]),
+        };
+        let environment_texture = device.create_texture_with_data(
+            wgpu_state.queue(),
+            &wgpu::TextureDescriptor {
+                label: Some("Environment Texture"),
+                size: wgpu::Extent3d {
+                    width: environment_size.0,
+                    height: environment_size.1,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            unsafe { vec_as_u8_slice(environment_texels) },
+        );
+        let environment_texture_view = environment_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let environment_cdf_buffer = create_storage_buffer(device, "Environment CDF Buffer", environment_cdf);
+
+        let sphere_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let material_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 1,
+            ..sphere_buffer_layout
+        };
+
+        let texture_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            ..sphere_buffer_layout
+        };
+
+        let texel_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            ..sphere_buffer_layout
+        };
+
+        let light_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 4,
+            ..sphere_buffer_layout
+        };
+
+        let environment_texture_layout = wgpu::BindGroupLayoutEntry {
+            binding: 5,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        let environment_cdf_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 6,
+            ..sphere_buffer_layout
+        };
+
+        let punctual_light_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 7,
+            ..sphere_buffer_layout
+        };
+
+        let light_node_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 8,
+            ..sphere_buffer_layout
+        };
+
+        let light_tree_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 9,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let scene_bind_group_layout = device.create_bind_group_layout(
+            &BindGroupLayoutDescriptor{
+                label: Some("scene bind group layout"),
+                entries: &[
+                    sphere_buffer_layout,
+                    material_buffer_layout,
+                    texture_buffer_layout,
+                    texel_buffer_layout,
+                    light_buffer_layout,
+                    environment_texture_layout,
+                    environment_cdf_buffer_layout,
+                    punctual_light_buffer_layout,
+                    light_node_buffer_layout,
+                    light_tree_buffer_layout,
+                ],
+            });
+
+        let scene_bind_group = BufferBindGroup::with_texture_views(device, "scene bind group", &scene_bind_group_layout, &[
+            (0, &sphere_buffer),
+            (1, &material_buffer),
+            (2, &texture_buffer),
+            (3, &texel_buffer),
+            (4, &light_buffer),
+            (6, &environment_cdf_buffer),
+            (7, &punctual_light_buffer),
+            (8, &light_node_buffer),
+            (9, &light_tree_buffer),
+        ], &[(5, &environment_texture_view)]);
+
+        // the triangle meshes: vertices (position, normal and uv packed together), triangles
+        // indexing into the vertices with their material, the bvh over the triangles and the
+        // object every triangle belongs to
+        let geometry = scene.geometry();
+        let vertex_buffer = create_storage_buffer(device, "Vertex Buffer", geometry.vertices());
+        let triangle_buffer = create_storage_buffer(device, "Triangle Buffer", geometry.triangles());
+        let bvh_buffer = create_storage_buffer(device, "BVH Buffer", scene.bvh().nodes());
+        let object_id_buffer = create_storage_buffer(device, "Object ID Buffer", geometry.object_ids());
+
+        let vertex_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let triangle_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 1,
+            ..vertex_buffer_layout
+        };
+
+        let bvh_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            ..vertex_buffer_layout
+        };
+
+        let object_id_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            ..vertex_buffer_layout
+        };
+
+        let geometry_bind_group_layout = device.create_bind_group_layout(
+            &BindGroupLayoutDescriptor{
+                label: Some("geometry bind group layout"),
+                entries: &[
+                    vertex_buffer_layout,
+                    triangle_buffer_layout,
+                    bvh_buffer_layout,
+                    object_id_buffer_layout,
+                ],
+            });
+
+        let geometry_bind_group = device.create_bind_group(&BindGroupDescriptor{
+            label: Some("geometry bind group"),
+            layout: &geometry_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: vertex_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: triangle_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: bvh_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 3, resource: object_id_buffer.as_entire_binding() },
+            ],
         });
 
         // set up the buffers for the inverse projection and view matrices
@@ -198,7 +904,7 @@ impl<'a> PathTracer<'a> {
         // create the sampling_parameters and camera buffers
         let sampling_parameters_buffer = device.create_buffer(&BufferDescriptor{
             label: Some("Sampling Parameters Buffer"),
-            size: 4 * size_of::<u32>() as wgpu::BufferAddress,
+            size: size_of::<GPUSamplingParametersBuffer>() as wgpu::BufferAddress,
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
@@ -240,6 +946,58 @@ impl<'a> PathTracer<'a> {
             binding: 3, resource: camera_buffer.as_entire_binding()
         };
 
+        let environment_parameters_buffer = device.create_buffer(&BufferDescriptor{
+            label: Some("Environment Parameters Buffer"),
+            size: size_of::<GPUEnvironmentParameters>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let environment_parameters_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 4,
+            ..camera_buffer_layout
+        };
+
+        let environment_parameters_buffer_binding = wgpu::BindGroupEntry {
+            binding: 4, resource: environment_parameters_buffer.as_entire_binding()
+        };
+
+        // the blue noise mask of the blue noise sampler never changes
+        let blue_noise = BlueNoise::generate();
+        let blue_noise_buffer = create_storage_buffer(device, "Blue Noise Buffer", blue_noise.values());
+
+        let blue_noise_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 5,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let blue_noise_buffer_binding = wgpu::BindGroupEntry {
+            binding: 5, resource: blue_noise_buffer.as_entire_binding()
+        };
+
+        // the table the camera rays are drawn from, rewritten whenever the filter changes
+        let pixel_filter_buffer = device.create_buffer(&BufferDescriptor{
+            label: Some("Pixel Filter Buffer"),
+            size: size_of::<GPUPixelFilter>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let pixel_filter_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 6,
+            ..camera_buffer_layout
+        };
+
+        let pixel_filter_buffer_binding = wgpu::BindGroupEntry {
+            binding: 6, resource: pixel_filter_buffer.as_entire_binding()
+        };
+
         // group the buffers that in some way are dependent on user input
         // the view and projection matrices, the camera, and the sampling parameters all go together
         // they don't need to be updated if there is no change to the user input
@@ -251,6 +1009,9 @@ impl<'a> PathTracer<'a> {
                     view_transform_buffer_layout,
                     sampling_parameters_buffer_layout,
                     camera_buffer_layout,
+                    environment_parameters_buffer_layout,
+                    blue_noise_buffer_layout,
+                    pixel_filter_buffer_layout,
                 ],
             });
 
@@ -262,6 +1023,9 @@ impl<'a> PathTracer<'a> {
                 view_transform_buffer_binding,
                 sampling_parameters_buffer_binding,
                 camera_buffer_binding,
+                environment_parameters_buffer_binding,
+                blue_noise_buffer_binding,
+                pixel_filter_buffer_binding,
             ],
         });
 
@@ -271,35 +1035,80 @@ impl<'a> PathTracer<'a> {
                 label: Some("compute shader pipeline layout"),
                 bind_group_layouts: &[
                     &image_bind_group_layout,
-                    &render_parameters_bind_group_layout
+                    &render_parameters_bind_group_layout,
+                    &scene_bind_group_layout,
+                    &geometry_bind_group_layout,
                 ],
                 push_constant_ranges: &[],
             }
         );
 
-        let mut shader = device.create_shader_module(
-            wgpu::include_wgsl!("../shaders/compute_megakernel.wgsl")
-        );
+        let mut shader = create_kernel_module(device, "megakernel", concat!(
+            include_str!("../shaders/path_tracing_common.wgsl"),
+            include_str!("../shaders/environment.wgsl"),
+            include_str!("../shaders/bsdf.wgsl"),
+            include_str!("../shaders/lights.wgsl"),
+            include_str!("../shaders/compute_megakernel.wgsl"),
+        ));
 
-        // if I want to pass in override values, I can do it here:
-        // let mut id:HashMap<String, f64> = HashMap::new();
-        // id.insert("stackSize".to_string(), (bvh_tree.nodes.len() - 1) as f64);
+        // the bvh traversal stack is sized to the depth of the tree through an override constant
+        let constants = [("stackSize", scene.bvh().stack_size() as f64)];
         let compute_shader_pipeline = device.create_compute_pipeline(
             &wgpu::ComputePipelineDescriptor {
                 label: Some("compute shader pipeline"),
                 layout: Some(&path_tracer_pipeline_layout),
                 module: &shader,
                 entry_point: Some("main"),
-                compilation_options: Default::default(),
-                // PipelineCompilationOptions {
-                //     constants: None, //&id,
-                //     zero_initialize_workgroup_memory: false,
-                //     vertex_pulling_transform: false,
-                // },
+                compilation_options: wgpu::PipelineCompilationOptions {
+                    constants: &constants,
+                    zero_initialize_workgroup_memory: false,
+                },
                 cache: None,
             }
         );
 
+        // images can't get wider or taller than a texture
+        let max_dimension = device.limits().max_texture_dimension_2d.max(size.width).max(size.height);
+        let adaptive_sampling = AdaptiveSampling::new(
+            device,
+            &image_buffer,
+            &frame_buffer,
+            &sampling_parameters_buffer,
+            max_window_size,
+            max_dimension,
+        );
+
+        let denoiser = Denoiser::new(
+            device,
+            &image_buffer,
+            &frame_buffer,
+            adaptive_sampling.pixel_stats_buffer(),
+            max_window_size,
+        );
+
+        // only a placeholder until the AOVs are turned on
+        let aovs = Aovs::new(device);
+
+        let image_bind_group = BufferBindGroup::new(device, "image bind group", &image_bind_group_layout, &[
+            (0, &image_buffer),
+            (1, &frame_buffer),
+            (2, adaptive_sampling.pixel_stats_buffer()),
+            (3, adaptive_sampling.active_tile_buffer()),
+            (4, denoiser.feature_buffer()),
+            (5, aovs.aov_buffer()),
+        ]);
+
+        let wavefront = WavefrontPipeline::new(
+            device,
+            &image_buffer,
+            &frame_buffer,
+            &adaptive_sampling,
+            denoiser.feature_buffer(),
+            &aovs,
+            &[&render_parameters_bind_group_layout, &scene_bind_group_layout, &geometry_bind_group_layout],
+            &constants,
+        );
+
         // now create the pipeline for the display shader
         // we need to reset the layout for VERTEX_FRAGMENT rather than COMPUTE
         // and for the image buffer, read_only has to be true
@@ -312,25 +1121,63 @@ impl<'a> PathTracer<'a> {
 
         frame_buffer_layout.visibility = wgpu::ShaderStages::VERTEX_FRAGMENT;
 
-        let image_buffer_binding = wgpu::BindGroupEntry { binding: 0, resource: image_buffer.as_entire_binding() };
-        let frame_buffer_binding = wgpu::BindGroupEntry { binding: 1, resource: frame_buffer.as_entire_binding() };
+        // the image is divided by the samples of every pixel, which adaptive sampling keeps
+        let pixel_stats_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            ..image_buffer_layout
+        };
+
+        let display_parameters_buffer = device.create_buffer(&BufferDescriptor{
+            label: Some("Display Parameters Buffer"),
+            size: size_of::<GPUDisplayParameters>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let display_parameters_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            ..frame_buffer_layout
+        };
+
+        // the display shows the denoised image instead of the image when the denoiser is on
+        let denoised_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 4,
+            ..image_buffer_layout
+        };
+
+        // any of the AOVs can be shown in place of the image
+        let aov_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 5,
+            ..image_buffer_layout
+        };
+
+        let feature_buffer_layout = wgpu::BindGroupLayoutEntry {
+            binding: 6,
+            ..image_buffer_layout
+        };
 
         let display_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("Display Bind Group Layout"),
             entries: &[
                 image_buffer_layout,
                 frame_buffer_layout,
+                pixel_stats_buffer_layout,
+                display_parameters_buffer_layout,
+                denoised_buffer_layout,
+                aov_buffer_layout,
+                feature_buffer_layout,
             ],
         });
 
-        let display_bind_group = device.create_bind_group(&BindGroupDescriptor{
-            label: Some("Display Bind Group"),
-            layout: &display_bind_group_layout,
-            entries: &[
-                image_buffer_binding,
-                frame_buffer_binding,
-            ],
-        });
+        let display_bind_group = BufferBindGroup::new(device, "Display Bind Group", &display_bind_group_layout, &[
+            (0, &image_buffer),
+            (1, &frame_buffer),
+            (2, adaptive_sampling.pixel_stats_buffer()),
+            (3, &display_parameters_buffer),
+            (4, denoiser.denoised_buffer()),
+            (5, aovs.aov_buffer()),
+            (6, denoiser.feature_buffer()),
+        ]);
 
         shader = device.create_shader_module(
             wgpu::include_wgsl!("../shaders/screen_shader.wgsl")
@@ -371,7 +1218,7 @@ impl<'a> PathTracer<'a> {
                 entry_point: Some("fs"),
                 compilation_options: Default::default(),
                 targets: &[Some(wgpu::ColorTargetState {
-                    format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                    format: wgpu_state.display_format(),
                     blend: Some(wgpu::BlendState::REPLACE),
                     write_mask: wgpu::ColorWrites::ALL,
                 })],
@@ -380,10 +1227,15 @@ impl<'a> PathTracer<'a> {
             cache: None,
         });
 
+        // a camera that came with the scene file takes the place of the default one
+        let (look_from, look_at, vfov) = match scene.camera() {
+            Some(camera) => (camera.look_from, camera.look_at, camera.vfov),
+            None => (Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, 0.0), 90.0),
+        };
         let camera_controller = CameraController::new(
-            Vec3::new(0.0, 0.0, 1.0),
-            Vec3::new(0.0, 0.0, 0.0),
-            90.0,
+            look_from,
+            look_at,
+            vfov,
             0.0,
             10.0,
             0.1,
@@ -398,7 +1250,11 @@ impl<'a> PathTracer<'a> {
         let sampling_parameters =
             GPUSamplingParametersBuffer::new(0, 0, 0);
 
-        let gpu_profiler = GpuProfiler::new(&device, GpuProfilerSettings::default())
+        let environment_parameters = GPUEnvironmentParameters::new(scene.environment().is_some());
+
+        let display_parameters = GPUDisplayParameters::default();
+
+        let gpu_profiler = GpuProfiler::new(device, GpuProfilerSettings::default())
             .expect("Failed to initialize GPUProfiler");
         
         Some(
@@ -410,17 +1266,34 @@ impl<'a> PathTracer<'a> {
                 view_transform_buffer,
                 sampling_parameters_buffer,
                 camera_buffer,
+                environment_parameters_buffer,
+                pixel_filter_buffer,
                 image_bind_group,
                 render_parameters_bind_group,
+                scene,
+                material_buffer,
+                scene_bind_group,
+                geometry_bind_group,
                 display_bind_group,
+                display_parameters_buffer,
                 display_pipeline,
                 compute_shader_pipeline,
+                adaptive_sampling,
+                denoiser,
+                aovs,
+                wavefront,
+                render_backend: RenderBackend::default(),
                 camera_controller,
                 frame_parameters,
                 sampling_parameters,
+                environment_parameters,
+                pixel_filter: GPUPixelFilter::default(),
+                display_parameters,
+                display_transform: DisplayTransform::default(),
                 gpu_profiler,
                 latest_profiler_results: None,
-                running_avg_kernel_time: VecDeque::with_capacity(Self::RUNNING_AVG_LENGTH)
+                running_avg_kernel_time: VecDeque::with_capacity(Self::RUNNING_AVG_LENGTH),
+                running_avg_pass_times: Vec::new(),
             }
         )
     }
@@ -446,7 +1319,7 @@ impl<'a> PathTracer<'a> {
     }
 
     pub fn progress(&self) -> f32 {
-        100.0 * self.frame_parameters.accumulated_samples as f32 / self.sampling_parameters.samples_per_pixel as f32
+        100.0 * self.frame_parameters.accumulated_samples as f32 / self.sampling_parameters.samples_per_pixel() as f32
     }
 
     pub fn avg_kernel_time(&self) -> f32 {
@@ -454,26 +1327,57 @@ impl<'a> PathTracer<'a> {
         sum / self.running_avg_kernel_time.len() as f32
     }
 
+    // the average time per frame spent in each kind of compute pass, in the order they run
+    pub fn avg_pass_times(&self) -> Vec<(String, f32)> {
+        self.running_avg_pass_times.iter()
+            .map(|(label, times)| {
+                let sum: f32 = times.iter().sum();
+                (label.clone(), sum / times.len() as f32)
+            })
+            .collect()
+    }
+
+    fn push_running_avg(times: &mut VecDeque<f32>, time: f32) {
+        if times.len() == Self::RUNNING_AVG_LENGTH {
+            times.pop_back();
+        }
+        times.push_front(time);
+    }
+
     fn process_gpu_profiler_results(&mut self) {
         // for some reason, the wgpu_profiler crate nests a simple compute kernel with a
         // compute pass kernel underneath that takes longer (so likely the one I want)
-        // thus, the top level result isn't what I want and I need to go one level deeper
+        // thus, the top level result isn't what I want and I need to go one level deeper.
+        // the wavefront backend runs many passes per frame, so the kernel time of a frame is
+        // the sum over all of them and the passes with the same label are summed up as well
 
         match &self.latest_profiler_results {
             Some(results) => {
+                let mut frame_time = None;
+                let mut pass_times: Vec<(String, f32)> = Vec::new();
                 for scope in results {
                     let nested_result = &scope.nested_queries;
                     for nested_scope in nested_result {
                         if let Some(time) = &nested_scope.time {
-                            let dt = (time.end - time.start) * 1000.0 * 1000.0;
-                            if self.running_avg_kernel_time.len() == Self::RUNNING_AVG_LENGTH {
-                                self.running_avg_kernel_time.pop_back();
+                            let dt = ((time.end - time.start) * 1000.0 * 1000.0) as f32;
+                            *frame_time.get_or_insert(0.0) += dt;
+                            match pass_times.iter_mut().find(|(label, _)| *label == nested_scope.label) {
+                                Some((_, pass_time)) => *pass_time += dt,
+                                None => pass_times.push((nested_scope.label.clone(), dt)),
                             }
-                            self.running_avg_kernel_time.push_front(dt as f32);
-                            // println!("scope: {}", nested_scope.label);
                         };
                     }
                 }
+
+                if let Some(frame_time) = frame_time {
+                    Self::push_running_avg(&mut self.running_avg_kernel_time, frame_time);
+                }
+                for (label, pass_time) in pass_times {
+                    match self.running_avg_pass_times.iter_mut().find(|(l, _)| *l == label) {
+                        Some((_, times)) => Self::push_running_avg(times, pass_time),
+                        None => self.running_avg_pass_times.push((label, VecDeque::from([pass_time]))),
+                    }
+                }
             },
             None => println!("No profiling results available yet!"),
         }
@@ -482,10 +1386,162 @@ impl<'a> PathTracer<'a> {
     pub fn process_user_input(&mut self, user_input: &mut UserInput) {
         self.camera_controller.process_user_input(user_input);
         self.sampling_parameters.process_user_input(user_input);
+        self.environment_parameters.process_user_input(user_input);
+        self.pixel_filter.process_user_input(user_input);
+
+        // an edited material only needs its own entry in the material buffer rewritten, unless
+        // its emission changed, which the lights are picked by
+        if let Some((material_id, material)) = user_input.take_edited_material() {
+            let offset = material_id as wgpu::BufferAddress * size_of::<GPUMaterial>() as wgpu::BufferAddress;
+            let gpu_material = material.gpu_material();
+            self.wgpu_state.queue().write_buffer(&self.material_buffer, offset, unsafe {
+                any_as_u8_slice(&gpu_material)
+            });
+            if self.scene.set_material(material_id, material) {
+                self.update_lights();
+            }
+        }
+
+        // the timings of one backend say nothing about the other one
+        if user_input.render_backend() != self.render_backend {
+            self.render_backend = user_input.render_backend();
+            self.running_avg_kernel_time.clear();
+            self.running_avg_pass_times.clear();
+        }
+    }
+
+    // the number of lights can change with them, so the light buffers are made anew rather
+    // than written to
+    fn update_lights(&mut self) {
+        let device = self.wgpu_state.device();
+        let lights = self.scene.lights();
+        let light_buffer = create_storage_buffer(device, "Light Buffer", lights.lights());
+        let punctual_light_buffer = create_storage_buffer(device, "Punctual Light Buffer", lights.punctual_lights());
+        let light_node_buffer = create_storage_buffer(device, "Light Node Buffer", lights.nodes());
+        let light_tree = lights.gpu_light_tree();
+        let light_tree_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Light Tree Buffer"),
+            contents: unsafe { any_as_u8_slice(&light_tree) },
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        self.scene_bind_group.replace_all(device, &[
+            (4, &light_buffer),
+            (7, &punctual_light_buffer),
+            (8, &light_node_buffer),
+            (9, &light_tree_buffer),
+        ]);
+    }
+
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    // copies the first byte_count bytes of every buffer back to the cpu; blocks until the gpu
+    // is done with the work submitted before
+    fn read_buffers(&self, buffers: &[(&wgpu::Buffer, wgpu::BufferAddress)]) -> Vec<Vec<u8>> {
+        let device = self.wgpu_state.device();
+
+        // all of the buffers one after the other
+        let staging_buffer = device.create_buffer(&BufferDescriptor{
+            label: Some("Readback Staging Buffer"),
+            size: buffers.iter().map(|(_, byte_count)| byte_count).sum(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor {
+                label: Some("readback encoder"),
+            });
+        let mut offset = 0;
+        for (buffer, byte_count) in buffers {
+            encoder.copy_buffer_to_buffer(buffer, 0, &staging_buffer, offset, *byte_count);
+            offset += byte_count;
+        }
+        self.wgpu_state.queue().submit(Some(encoder.finish()));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let buffer_slice = staging_buffer.slice(..);
+        buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+            sender.send(result).unwrap();
+        });
+        device.poll(wgpu::PollType::Wait).expect("Failed to wait for the readback");
+        receiver.recv().unwrap().expect("Failed to map the readback staging buffer");
+
+        let mapped_range = buffer_slice.get_mapped_range();
+        let mut remaining: &[u8] = &mapped_range;
+        let contents = buffers.iter().map(|(_, byte_count)| {
+            let (bytes, rest) = remaining.split_at(*byte_count as usize);
+            remaining = rest;
+            bytes.to_vec()
+        }).collect();
+        drop(mapped_range);
+        staging_buffer.unmap();
+
+        contents
+    }
+
+    fn pixel_count(&self) -> wgpu::BufferAddress {
+        let size = self.wgpu_state.size();
+        (size.width * size.height) as wgpu::BufferAddress
+    }
+
+    // the sample count comes first in the pixel stats
+    fn sample_counts(stats_bytes: &[u8]) -> Vec<u32> {
+        stats_bytes.chunks_exact(size_of::<GPUPixelStats>())
+            .map(|stats| u32::from_le_bytes(stats[0..4].try_into().unwrap()))
+            .collect()
+    }
+
+    fn floats(bytes: &[u8]) -> Vec<f32> {
+        bytes.chunks_exact(size_of::<f32>())
+            .map(|float| f32::from_le_bytes(float.try_into().unwrap()))
+            .collect()
+    }
+
+    // copies the accumulated image back to the cpu and averages every pixel over the samples
+    // it has taken so far
+    pub fn read_image(&self) -> Vec<Vec3> {
+        let pixel_count = self.pixel_count();
+        let contents = self.read_buffers(&[
+            (&self.image_buffer, pixel_count * 3 * size_of::<f32>() as wgpu::BufferAddress),
+            (self.adaptive_sampling.pixel_stats_buffer(), pixel_count * size_of::<GPUPixelStats>() as wgpu::BufferAddress),
+        ]);
+
+        Self::floats(&contents[0]).chunks_exact(3)
+            .zip(Self::sample_counts(&contents[1]))
+            .map(|(texel, sample_count)| Vec3::from_slice(texel) / sample_count.max(1) as f32)
+            .collect()
+    }
+
+    // the same for every aov, which only has anything in it with the AOVs turned on
+    pub fn read_aovs(&self) -> Vec<AovLayer> {
+        let pixel_count = self.pixel_count();
+        let float_size = size_of::<f32>() as wgpu::BufferAddress;
+        let contents = self.read_buffers(&[
+            (self.aovs.aov_buffer(), Aovs::PLANE_COUNT as wgpu::BufferAddress * pixel_count * float_size),
+            (self.denoiser.feature_buffer(), Aovs::FEATURE_FLOATS as wgpu::BufferAddress * pixel_count * float_size),
+            (self.adaptive_sampling.pixel_stats_buffer(), pixel_count * size_of::<GPUPixelStats>() as wgpu::BufferAddress),
+        ]);
+
+        Aovs::resolve(pixel_count as usize, &Self::floats(&contents[0]), &Self::floats(&contents[1]),
+                      &Self::sample_counts(&contents[2]))
+    }
+
+    // writes what has been accumulated so far to disk, in the format given by the extension;
+    // an EXR gets the AOVs as extra layers when they are on
+    pub fn save_image(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let size = self.wgpu_state.size();
+        let aovs = if self.sampling_parameters.aov_output() && ImageFormat::from_path(path) == Some(ImageFormat::Exr) {
+            self.read_aovs()
+        } else {
+            Vec::new()
+        };
+        save_image(path, size.width, size.height, &self.read_image(), &aovs, &self.display_transform)
     }
 
     pub fn display_image(&mut self, gui: &mut GUI) {
-        self.wgpu_state.render(gui, &self.display_pipeline, &self.display_bind_group);
+        self.wgpu_state.render(gui, &self.display_pipeline, self.display_bind_group.bind_group());
     }
 
     fn update_buffers(&mut self, ar: f32) {
@@ -496,31 +1552,89 @@ impl<'a> PathTracer<'a> {
             queue.write_buffer(self.inv_projection_buffer(), 0, any_as_u8_slice(&proj_mat));
             queue.write_buffer(self.view_transform_buffer(), 0, any_as_u8_slice(&view_mat));
         }
+        // the denoiser reprojects its history from one camera to the next
+        self.denoiser.set_camera(proj_mat, view_mat);
         let sampling_parameters = self.sampling_parameters;
         let camera = self.camera_controller.get_gpu_camera();
         unsafe {
             queue.write_buffer(self.sampling_parameters_buffer(), 0, any_as_u8_slice(&sampling_parameters));
             queue.write_buffer(self.camera_buffer(), 0, any_as_u8_slice(&camera));
+            queue.write_buffer(&self.environment_parameters_buffer, 0, any_as_u8_slice(&self.environment_parameters));
+            queue.write_buffer(&self.pixel_filter_buffer, 0, any_as_u8_slice(&self.pixel_filter));
         }
     }
 
-    fn run_compute_kernel(&mut self) {
-        let size = self.wgpu_state.get_window().inner_size();
+    // makes the aov buffer fit the image with the AOVs on, and shrinks it back to a placeholder
+    // with them off; true if the buffer was replaced
+    fn resize_aovs(&mut self, aov_output: bool) -> bool {
+        let size = self.wgpu_state.size();
+        let device = self.wgpu_state.device();
+        if !self.aovs.resize(device, aov_output.then_some(size.width * size.height)) {
+            return false;
+        }
+        self.image_bind_group.replace(device, 5, self.aovs.aov_buffer());
+        self.display_bind_group.replace(device, 5, self.aovs.aov_buffer());
+        self.wavefront.set_aov_buffer(device, self.aovs.aov_buffer());
+        true
+    }
+
+    // traces the next samples if trace is set, then denoises the image if the denoiser is on
+    fn run_compute_kernel(&mut self, trace: bool) {
+        let size = self.wgpu_state.size();
 
         let mut encoder = self.wgpu_state.device().create_command_encoder(
             &wgpu::CommandEncoderDescriptor {
                 label: Some("compute kernel encoder"),
             });
 
-        {
-            let mut scope = self.gpu_profiler.scope("Compute kernel", &mut encoder);
-            let mut compute_pass = scope.scoped_compute_pass("Scoped compute pass");
+        // a reset starts every pixel over at zero samples
+        if self.sampling_parameters.clear_image_flag() {
+            self.adaptive_sampling.reset(&mut encoder);
+            self.denoiser.reset(&mut encoder);
+            self.aovs.reset(&mut encoder);
+        }
+
+        if trace {
+            match self.render_backend {
+                RenderBackend::Megakernel => {
+                    let mut scope = self.gpu_profiler.scope("Megakernel", &mut encoder);
+                    self.adaptive_sampling.update_tiles(self.wgpu_state.queue(), &mut scope, size.width, size.height);
+
+                    // only the tiles that haven't converged get workgroups
+                    let mut compute_pass = scope.scoped_compute_pass("megakernel");
+                    compute_pass.set_pipeline(&self.compute_shader_pipeline);
+                    compute_pass.set_bind_group(0, self.image_bind_group.bind_group(), &[]);
+                    compute_pass.set_bind_group(1, &self.render_parameters_bind_group, &[]);
+                    compute_pass.set_bind_group(2, self.scene_bind_group.bind_group(), &[]);
+                    compute_pass.set_bind_group(3, &self.geometry_bind_group, &[]);
+                    compute_pass.dispatch_workgroups_indirect(self.adaptive_sampling.tile_dispatch_buffer(), 0);
+                },
+                RenderBackend::Wavefront => {
+                    // the wavefront kernels only ever add to the image, so it is cleared up front
+                    if self.sampling_parameters.clear_image_flag() {
+                        encoder.clear_buffer(&self.image_buffer, 0, None);
+                        self.wavefront.reset(&mut encoder);
+                    }
 
-            compute_pass.set_pipeline(&self.compute_shader_pipeline);
-            compute_pass.set_bind_group(0, &self.image_bind_group, &[]);
-            compute_pass.set_bind_group(1, &self.render_parameters_bind_group, &[]);
-            compute_pass.dispatch_workgroups(size.width / 4, size.height / 4, 1);
+                    let shared_bind_groups = [
+                        &self.render_parameters_bind_group,
+                        self.scene_bind_group.bind_group(),
+                        &self.geometry_bind_group,
+                    ];
+                    let mut scope = self.gpu_profiler.scope("Wavefront", &mut encoder);
+                    self.adaptive_sampling.update_tiles(self.wgpu_state.queue(), &mut scope, size.width, size.height);
+                    for _ in 0..self.sampling_parameters.samples_per_frame() {
+                        self.wavefront.trace_sample(&mut scope, &shared_bind_groups,
+                                                    size.width * size.height,
+                                                    self.sampling_parameters.number_of_bounces());
+                    }
+                },
+            }
+        }
 
+        if self.denoiser.enabled() {
+            let mut scope = self.gpu_profiler.scope("Denoiser", &mut encoder);
+            self.denoiser.denoise(self.wgpu_state.queue(), &mut scope, size.width, size.height);
         }
 
         self.gpu_profiler.resolve_queries(&mut encoder);
@@ -535,18 +1649,29 @@ impl<'a> PathTracer<'a> {
     }
 
     pub fn run_path_tracer(&mut self, dt: f32, user_input: &mut UserInput) {
-        let size = self.wgpu_state.get_window().inner_size();
+        let size = self.wgpu_state.size();
         self.frame_parameters.update_window_size(size.width, size.height);
 
+        // what is shown can change without the image having to start over
+        self.display_parameters.process_user_input(user_input);
+        self.display_transform = DisplayTransform::new(user_input.exposure(), user_input.tone_mapper());
+        unsafe {
+            self.wgpu_state.queue().write_buffer(&self.display_parameters_buffer, 0,
+                                                 any_as_u8_slice(&self.display_parameters));
+        }
+        let denoiser_changed = self.denoiser.process_user_input(self.wgpu_state.queue(), user_input);
+
+        // the AOVs are laid out by the size of the image, so a new aov buffer starts it over
+        let aovs_resized = self.resize_aovs(user_input.aov_output());
 
-        if user_input.state_changed() {
+        if user_input.state_changed() || aovs_resized {
             // this will update the camera controller and the sampling_parameters
             self.process_user_input(user_input);
 
             // explicitly update the camera
             self.camera_controller.update_camera(dt);
 
-            // we set and unset the clear_image_flag here
+            // we set the clear_image_flag here; it is unset once the first frame has been dispatched
             self.sampling_parameters.set_clear_image_flag(true);
 
             // reset the frame parameters to frame 1 and accumulated samples to 0
@@ -555,17 +1680,29 @@ impl<'a> PathTracer<'a> {
             // the projection matrix needs the current aspect ratio
             let ar = size.width as f32 / size.height as f32;
             self.update_buffers(ar);
-            self.sampling_parameters.set_clear_image_flag(false);
         }
 
         // regardless of user input, the frame_buffer has to be updated every frame until we hit samples_per_pixel
-        if self.frame_parameters.accumulated_samples < self.sampling_parameters.samples_per_pixel {
+        if self.frame_parameters.accumulated_samples < self.sampling_parameters.samples_per_pixel() {
             self.frame_parameters.increment_frame();
             self.frame_parameters.increment_accumulated_samples(self.sampling_parameters.samples_per_frame());
             let frame_data = unsafe { any_as_u8_slice(&self.frame_parameters) };
             self.wgpu_state.queue().write_buffer(self.frame_buffer(), 0, frame_data);
 
-            self.run_compute_kernel();
+            self.run_compute_kernel(true);
+
+            // every frame after the first one has to accumulate into the image buffer
+            if self.sampling_parameters.clear_image_flag() {
+                self.sampling_parameters.set_clear_image_flag(false);
+                let sampling_parameters = self.sampling_parameters;
+                unsafe {
+                    self.wgpu_state.queue().write_buffer(self.sampling_parameters_buffer(), 0,
+                                                         any_as_u8_slice(&sampling_parameters));
+                }
+            }
+        } else if denoiser_changed && self.denoiser.enabled() {
+            // the image is done, but it has to be denoised again with the new settings
+            self.run_compute_kernel(false);
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,198 @@
+use std::f32::consts::PI;
+use crate::gui::UserInput;
+
+// how the samples of a pixel are spread over the image plane and weighted. The camera rays are
+// drawn in proportion to the filter (filter importance sampling) rather than splatted, so the
+// image keeps being a plain average of the samples of every pixel
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PixelFilter {
+    // every sample in the square around the pixel center counts the same
+    #[default]
+    Box,
+    // falling off linearly with the distance from the pixel center
+    Tent,
+    // a gaussian with a standard deviation of a third of the radius, shifted down to reach 0 there
+    Gaussian,
+    // the Mitchell-Netravali cubic with B = C = 1/3, which sharpens with small negative lobes
+    MitchellNetravali,
+    // the sinc windowed by a sinc as wide as the radius, the sharpest and the most ringing
+    Lanczos,
+}
+
+impl PixelFilter {
+    pub const ALL: [PixelFilter; 5] = [
+        PixelFilter::Box,
+        PixelFilter::Tent,
+        PixelFilter::Gaussian,
+        PixelFilter::MitchellNetravali,
+        PixelFilter::Lanczos,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelFilter::Box => "Box",
+            PixelFilter::Tent => "Tent",
+            PixelFilter::Gaussian => "Gaussian",
+            PixelFilter::MitchellNetravali => "Mitchell-Netravali",
+            PixelFilter::Lanczos => "Lanczos",
+        }
+    }
+
+    // the name used on the command line
+    pub fn option_name(&self) -> &'static str {
+        match self {
+            PixelFilter::Box => "box",
+            PixelFilter::Tent => "tent",
+            PixelFilter::Gaussian => "gaussian",
+            PixelFilter::MitchellNetravali => "mitchell",
+            PixelFilter::Lanczos => "lanczos",
+        }
+    }
+
+    // in pixels; the box of radius 0.5 covers exactly the pixel
+    pub fn default_radius(&self) -> f32 {
+        match self {
+            PixelFilter::Box => 0.5,
+            PixelFilter::Tent => 1.0,
+            PixelFilter::Gaussian => 1.5,
+            PixelFilter::MitchellNetravali => 2.0,
+            PixelFilter::Lanczos => 2.0,
+        }
+    }
+
+    // the filters are separable, this is one of the two factors at x pixels from the center
+    fn evaluate(&self, x: f32, radius: f32) -> f32 {
+        let x = x.abs();
+        if x >= radius {
+            return 0.0;
+        }
+        match self {
+            PixelFilter::Box => 1.0,
+            PixelFilter::Tent => radius - x,
+            PixelFilter::Gaussian => {
+                let gaussian = |x: f32| (-x * x / (2.0 * (radius / 3.0).powi(2))).exp();
+                gaussian(x) - gaussian(radius)
+            },
+            PixelFilter::MitchellNetravali => {
+                const B: f32 = 1.0 / 3.0;
+                const C: f32 = 1.0 / 3.0;
+                // the cubic is defined over [-2, 2]
+                let t = 2.0 * x / radius;
+                if t < 1.0 {
+                    ((12.0 - 9.0 * B - 6.0 * C) * t * t * t + (-18.0 + 12.0 * B + 6.0 * C) * t * t + (6.0 - 2.0 * B)) / 6.0
+                } else {
+                    ((-B - 6.0 * C) * t * t * t + (6.0 * B + 30.0 * C) * t * t + (-12.0 * B - 48.0 * C) * t
+                        + (8.0 * B + 24.0 * C)) / 6.0
+                }
+            },
+            PixelFilter::Lanczos => sinc(x) * sinc(x / radius),
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x < 1e-5 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+// the filter as the kernels sample it: a table of its integral over equal bins across
+// [-radius, radius], matching PixelFilter in path_tracing_common.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct GPUPixelFilter {
+    radius: f32,
+    // the integral of |f| over the integral of f; the bins are drawn by magnitude, so a
+    // sample has to be weighted by this and the sign of its bin to average to the filter
+    weight_scale: f32,
+    buffering: [u32; 2],
+    // normalized so that the magnitudes add up to 1, four bins to a vec4
+    bins: [[f32; 4]; Self::BINS / 4],
+}
+
+impl GPUPixelFilter {
+    pub const BINS: usize = 64;
+    // the points every bin is integrated over
+    const BIN_SAMPLES: usize = 16;
+
+    pub fn new(filter: PixelFilter, radius: f32) -> Self {
+        let bin_width = 2.0 * radius / Self::BINS as f32;
+        let mut bins: Vec<f32> = (0..Self::BINS).map(|bin| {
+            (0..Self::BIN_SAMPLES).map(|i| {
+                let x = -radius + bin_width * (bin as f32 + (i as f32 + 0.5) / Self::BIN_SAMPLES as f32);
+                filter.evaluate(x, radius)
+            }).sum::<f32>() / Self::BIN_SAMPLES as f32
+        }).collect();
+
+        let magnitude: f32 = bins.iter().map(|bin| bin.abs()).sum();
+        bins.iter_mut().for_each(|bin| *bin /= magnitude);
+        let integral: f32 = bins.iter().sum();
+
+        let mut packed = [[0.0; 4]; Self::BINS / 4];
+        for (i, bin) in bins.into_iter().enumerate() {
+            packed[i / 4][i % 4] = bin;
+        }
+
+        Self {
+            radius,
+            weight_scale: 1.0 / integral,
+            buffering: [0; 2],
+            bins: packed,
+        }
+    }
+
+    pub fn process_user_input(&mut self, user_input: &UserInput) {
+        *self = Self::new(user_input.pixel_filter(), user_input.filter_radius());
+    }
+}
+
+impl Default for GPUPixelFilter {
+    fn default() -> Self {
+        let filter = PixelFilter::default();
+        Self::new(filter, filter.default_radius())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bins(filter: &GPUPixelFilter) -> Vec<f32> {
+        filter.bins.iter().flatten().copied().collect()
+    }
+
+    #[test]
+    fn bins_are_normalized() {
+        for filter in PixelFilter::ALL {
+            for radius in [filter.default_radius(), 0.75, 3.0] {
+                let gpu_filter = GPUPixelFilter::new(filter, radius);
+                let bins = bins(&gpu_filter);
+                let magnitude: f32 = bins.iter().map(|bin| bin.abs()).sum();
+                assert!((magnitude - 1.0).abs() < 1e-5, "{} {}", filter.name(), radius);
+                // a sample weighted by the sign of its bin and the weight scale averages to 1
+                let signed: f32 = bins.iter().sum();
+                assert!((signed * gpu_filter.weight_scale - 1.0).abs() < 1e-5, "{} {}", filter.name(), radius);
+            }
+        }
+    }
+
+    #[test]
+    fn bins_are_symmetric() {
+        for filter in PixelFilter::ALL {
+            let bins = bins(&GPUPixelFilter::new(filter, filter.default_radius()));
+            for (left, right) in bins.iter().zip(bins.iter().rev()) {
+                assert!((left - right).abs() < 1e-6, "{}", filter.name());
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_sharpening_filters_have_negative_lobes() {
+        for filter in PixelFilter::ALL {
+            let gpu_filter = GPUPixelFilter::new(filter, filter.default_radius());
+            let negative = bins(&gpu_filter).iter().any(|bin| *bin < 0.0);
+            let sharpening = matches!(filter, PixelFilter::MitchellNetravali | PixelFilter::Lanczos);
+            assert_eq!(negative, sharpening, "{}", filter.name());
+            // the negative bins are drawn as well, so the positive ones need more weight
+            assert_eq!(gpu_filter.weight_scale > 1.0 + 1e-4, sharpening, "{}", filter.name());
+        }
+    }
+}
@@ -1,12 +1,110 @@
 use crate::gui::UserInput;
 
+// how the paths find the lights; the values match the LIGHT_SAMPLING_ constants in lights.wgsl.
+// all of them converge to the same image, they only differ in noise
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LightSampling {
+    // only by scattering into them
+    Bsdf = 0,
+    // only by next event estimation, except after delta bounces
+    Nee = 1,
+    // both, combined by multiple importance sampling with the balance heuristic
+    MisBalance = 2,
+    // or the power heuristic
+    #[default]
+    MisPower = 3,
+}
+
+impl LightSampling {
+    pub const ALL: [LightSampling; 4] = [
+        LightSampling::Bsdf,
+        LightSampling::Nee,
+        LightSampling::MisBalance,
+        LightSampling::MisPower,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LightSampling::Bsdf => "BSDF only",
+            LightSampling::Nee => "NEE only",
+            LightSampling::MisBalance => "MIS (balance)",
+            LightSampling::MisPower => "MIS (power)",
+        }
+    }
+
+    // the name used on the command line
+    pub fn option_name(&self) -> &'static str {
+        match self {
+            LightSampling::Bsdf => "bsdf",
+            LightSampling::Nee => "nee",
+            LightSampling::MisBalance => "mis-balance",
+            LightSampling::MisPower => "mis-power",
+        }
+    }
+}
+
+// where the random numbers of the paths come from; the values match the SAMPLER_ constants in
+// path_tracing_common.wgsl
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SamplerType {
+    // a PCG stream per pixel and sample
+    Independent = 0,
+    // the Sobol sequence with Owen scrambling, which spreads the samples of a pixel evenly
+    #[default]
+    Sobol = 1,
+    // jittered strata, as many as fit into samples_per_pixel
+    Stratified = 2,
+    // a blue noise mask stepped along a rank-1 lattice from sample to sample, which leaves
+    // the error at low sample counts as fine grained noise rather than blotches
+    BlueNoise = 3,
+}
+
+impl SamplerType {
+    pub const ALL: [SamplerType; 4] = [
+        SamplerType::Independent,
+        SamplerType::Sobol,
+        SamplerType::Stratified,
+        SamplerType::BlueNoise,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SamplerType::Independent => "Independent",
+            SamplerType::Sobol => "Owen-scrambled Sobol",
+            SamplerType::Stratified => "Stratified",
+            SamplerType::BlueNoise => "Blue noise",
+        }
+    }
+
+    // the name used on the command line
+    pub fn option_name(&self) -> &'static str {
+        match self {
+            SamplerType::Independent => "independent",
+            SamplerType::Sobol => "sobol",
+            SamplerType::Stratified => "stratified",
+            SamplerType::BlueNoise => "blue-noise",
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct GPUSamplingParametersBuffer {
     samples_per_frame: u32,
-    pub(crate) samples_per_pixel: u32,
+    samples_per_pixel: u32,
     number_of_bounces: u32,
     clear_image_buffer: u32,
+    light_sampling: u32,
+    sampler_type: u32,
+    // stop sampling the tiles whose pixels are within noise_threshold, see adaptive_sampling.rs
+    adaptive_sampling: u32,
+    noise_threshold: f32,
+    min_adaptive_samples: u32,
+    // 1 to have the kernels write the AOVs, see aov.rs
+    aov_output: u32,
+    buffering: [u32; 2],
 }
 
 impl GPUSamplingParametersBuffer {
@@ -16,6 +114,13 @@ impl GPUSamplingParametersBuffer {
             samples_per_pixel,
             number_of_bounces,
             clear_image_buffer: 1,
+            light_sampling: LightSampling::default() as u32,
+            sampler_type: SamplerType::default() as u32,
+            adaptive_sampling: 0,
+            noise_threshold: 0.01,
+            min_adaptive_samples: 16,
+            aov_output: 0,
+            buffering: [0; 2],
         }
     }
     
@@ -23,6 +128,12 @@ impl GPUSamplingParametersBuffer {
         self.samples_per_frame = user_input.samples_per_frame();
         self.samples_per_pixel = user_input.samples_per_pixel();
         self.number_of_bounces = user_input.number_of_bounces();
+        self.light_sampling = user_input.light_sampling() as u32;
+        self.sampler_type = user_input.sampler() as u32;
+        self.adaptive_sampling = user_input.adaptive_sampling() as u32;
+        self.noise_threshold = user_input.noise_threshold();
+        self.min_adaptive_samples = user_input.min_adaptive_samples();
+        self.aov_output = user_input.aov_output() as u32;
     }
     
     pub fn set_clear_image_flag(&mut self, clear: bool) {
@@ -33,6 +144,10 @@ impl GPUSamplingParametersBuffer {
         }
     }
     
+    pub fn clear_image_flag(&self) -> bool {
+        self.clear_image_buffer == 1
+    }
+
     pub fn samples_per_frame(&self) -> u32 {
         self.samples_per_frame
     }
@@ -40,4 +155,12 @@ impl GPUSamplingParametersBuffer {
     pub fn samples_per_pixel(&self) -> u32 {
         self.samples_per_pixel
     }
+
+    pub fn number_of_bounces(&self) -> u32 {
+        self.number_of_bounces
+    }
+
+    pub fn aov_output(&self) -> bool {
+        self.aov_output != 0
+    }
 }
\ No newline at end of file
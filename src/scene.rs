@@ -0,0 +1,223 @@
+use std::path::Path;
+use glam::Vec3;
+use crate::bvh::Bvh;
+use crate::environment::Environment;
+use crate::material::{GPUMaterial, Material};
+use crate::mesh::{Mesh, SceneGeometry};
+use crate::gltf_loader;
+use crate::light::{Lights, PunctualLight};
+use crate::obj_loader;
+use crate::texture::Textures;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Sphere {
+    center: Vec3,
+    radius: f32,
+    material_id: u32,
+    // set by the scene, which numbers the spheres and meshes together
+    object_id: u32,
+    buffering: [u32; 2],
+}
+
+impl Sphere {
+    pub fn new(center: Vec3, radius: f32, material_id: u32) -> Self {
+        Self {
+            center,
+            radius,
+            material_id,
+            object_id: 0,
+            buffering: [0; 2],
+        }
+    }
+
+    pub fn material_id(&self) -> u32 {
+        self.material_id
+    }
+
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        (self.center - Vec3::splat(self.radius), self.center + Vec3::splat(self.radius))
+    }
+
+    pub fn area(&self) -> f32 {
+        4.0 * std::f32::consts::PI * self.radius * self.radius
+    }
+}
+
+// a viewpoint that came with the scene file, used to place the camera when the scene is loaded
+#[derive(Copy, Clone, Debug)]
+pub struct SceneCamera {
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    // vertical field of view in degrees
+    pub vfov: f32,
+}
+
+#[derive(Default)]
+pub struct Scene {
+    camera: Option<SceneCamera>,
+    spheres: Vec<Sphere>,
+    materials: Vec<Material>,
+    textures: Textures,
+    geometry: SceneGeometry,
+    bvh: Bvh,
+    punctual_lights: Vec<PunctualLight>,
+    lights: Lights,
+    environment: Option<Environment>,
+    // the spheres and meshes added so far, which the next one takes as its object id
+    object_count: u32,
+}
+
+impl Scene {
+    // the hard-coded scene from the to-do list: a small sphere sitting on a very large one
+    // that acts as the ground plane
+    pub fn two_spheres() -> Self {
+        let mut scene = Self::default();
+        let red = scene.add_material(&Material::diffuse(Vec3::new(0.7, 0.3, 0.3)));
+        let yellow = scene.add_material(&Material::diffuse(Vec3::new(0.8, 0.8, 0.0)));
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, red));
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, yellow));
+        scene
+    }
+
+    // loads a model file into a new scene, picking the loader by the file extension
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut scene = Self::default();
+        let extension = path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("obj") => obj_loader::load_obj(path, &mut scene)?,
+            Some("gltf") | Some("glb") => gltf_loader::load_gltf(path, &mut scene)?,
+            _ => return Err(format!("unsupported scene file {}", path.display()).into()),
+        }
+        scene.build_bvh();
+        Ok(scene)
+    }
+
+    pub fn camera(&self) -> Option<SceneCamera> {
+        self.camera
+    }
+
+    pub fn set_camera(&mut self, camera: SceneCamera) {
+        self.camera = Some(camera);
+    }
+
+    pub fn add_sphere(&mut self, sphere: Sphere) {
+        let object_id = self.next_object_id();
+        self.spheres.push(Sphere { object_id, ..sphere });
+    }
+
+    pub fn add_material(&mut self, material: &Material) -> u32 {
+        self.materials.push(*material);
+        self.materials.len() as u32 - 1
+    }
+
+    pub fn add_punctual_light(&mut self, light: PunctualLight) {
+        self.punctual_lights.push(light);
+    }
+
+    pub fn add_mesh(&mut self, mesh: &Mesh) {
+        let object_id = self.next_object_id();
+        self.geometry.add_mesh(mesh, object_id);
+    }
+
+    // object ids start at 1, leaving 0 for where the camera sees no object
+    fn next_object_id(&mut self) -> u32 {
+        self.object_count += 1;
+        self.object_count
+    }
+
+    // has to be called once all the meshes have been added, since it reorders the triangles.
+    // the light list refers to the triangles by index, so it gets built here as well
+    pub fn build_bvh(&mut self) {
+        self.bvh = Bvh::build(&mut self.geometry);
+        self.lights = Lights::build(&self.spheres, &self.geometry, &self.materials, &self.punctual_lights);
+    }
+
+    pub fn spheres(&self) -> &[Sphere] {
+        &self.spheres
+    }
+
+    pub fn materials(&self) -> &[Material] {
+        &self.materials
+    }
+
+    // true if the emission changed, in which case the lights have been built again
+    pub fn set_material(&mut self, material_id: u32, material: Material) -> bool {
+        let emission_changed = self.materials[material_id as usize].emission != material.emission;
+        self.materials[material_id as usize] = material;
+        if emission_changed {
+            self.lights = Lights::build(&self.spheres, &self.geometry, &self.materials, &self.punctual_lights);
+        }
+        emission_changed
+    }
+
+    pub fn gpu_materials(&self) -> Vec<GPUMaterial> {
+        self.materials.iter().map(Material::gpu_material).collect()
+    }
+
+    pub fn textures(&self) -> &Textures {
+        &self.textures
+    }
+
+    pub fn textures_mut(&mut self) -> &mut Textures {
+        &mut self.textures
+    }
+
+    pub fn geometry(&self) -> &SceneGeometry {
+        &self.geometry
+    }
+
+    pub fn bvh(&self) -> &Bvh {
+        &self.bvh
+    }
+
+    pub fn lights(&self) -> &Lights {
+        &self.lights
+    }
+
+    pub fn environment(&self) -> Option<&Environment> {
+        self.environment.as_ref()
+    }
+
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.environment = Some(environment);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editing_the_emission_rebuilds_the_lights() {
+        let mut scene = Scene::default();
+        let grey = scene.add_material(&Material::diffuse(Vec3::splat(0.5)));
+        let lamp = scene.add_material(&Material::diffuse(Vec3::splat(0.5)));
+        scene.add_mesh(&Mesh::cube(Vec3::ZERO, 1.0, grey));
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 3.0, 0.0), 0.5, lamp));
+        scene.build_bvh();
+        assert_eq!(scene.lights().emitter_count(), 0);
+        assert!(scene.lights().nodes().is_empty());
+
+        // other changes leave the lights alone
+        let rough = Material { roughness: 0.9, ..scene.materials()[lamp as usize] };
+        assert!(!scene.set_material(lamp, rough));
+
+        let emissive = Material { emission: Vec3::splat(4.0), ..rough };
+        assert!(scene.set_material(lamp, emissive));
+        assert_eq!(scene.lights().emitter_count(), 1);
+        assert_eq!(scene.lights().nodes().len(), 1);
+
+        let glowing_cube = Material { emission: Vec3::ONE, ..scene.materials()[grey as usize] };
+        assert!(scene.set_material(grey, glowing_cube));
+        assert_eq!(scene.lights().emitter_count(), 13);
+        assert_eq!(scene.lights().nodes().len(), 25);
+        assert!(scene.lights().tree_depth() > 0);
+
+        assert!(scene.set_material(lamp, rough));
+        assert_eq!(scene.lights().emitter_count(), 12);
+        assert_eq!(scene.lights().nodes().len(), 23);
+    }
+}
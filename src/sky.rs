@@ -0,0 +1,185 @@
+use std::f32::consts::PI;
+use glam::Vec3;
+
+// the sky and sun of the Preetham daylight model ("A Practical Analytic Model for Daylight",
+// Preetham, Shirley and Smits 1999). The sky is worked out here down to the Perez
+// coefficients and the zenith color, so the kernels only have to evaluate the Perez function.
+// radiance is in units of 10 kcd/m², which puts a clear midday sky at around 1
+
+// the angular radius of the sun in radians
+const SUN_RADIUS: f32 = 0.004_67;
+// the luminance of the sun outside the atmosphere, in the units above
+const SUN_LUMINANCE: f32 = 1.6e5;
+// the model gives the zenith luminance in kcd/m²
+const KCD_TO_RADIANCE: f32 = 0.1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GPUSky {
+    // the Perez coefficients A to E, each for the luminance Y and the chromaticities x and y
+    perez: [[f32; 4]; 5],
+    // the zenith Y, x and y divided by the Perez function at the zenith, so the color of a
+    // direction is just this times the Perez function
+    zenith: Vec3,
+    cos_sun_radius: f32,
+    sun_direction: Vec3,
+    sun_solid_angle: f32,
+    sun_radiance: Vec3,
+    buffering_0: u32,
+    // the ground below the horizon, lit by the sun and the sky
+    ground_radiance: Vec3,
+    buffering_1: u32,
+}
+
+impl GPUSky {
+    // elevation above the horizon and azimuth from -z towards +x, both in degrees
+    pub fn new(sun_elevation: f32, sun_azimuth: f32, turbidity: f32, ground_albedo: f32) -> Self {
+        let elevation = sun_elevation.clamp(0.0, 90.0).to_radians();
+        let azimuth = sun_azimuth.to_radians();
+        let theta_sun = 0.5 * PI - elevation;
+        let t = turbidity;
+
+        let perez = [
+            [0.1787 * t - 1.4630, -0.0193 * t - 0.2592, -0.0167 * t - 0.2608, 0.0],
+            [-0.3554 * t + 0.4275, -0.0665 * t + 0.0008, -0.0950 * t + 0.0092, 0.0],
+            [-0.0227 * t + 5.3251, -0.0004 * t + 0.2125, -0.0079 * t + 0.2102, 0.0],
+            [0.1206 * t - 2.5771, -0.0641 * t - 0.8989, -0.0441 * t - 1.6537, 0.0],
+            [-0.0670 * t + 0.3703, -0.0033 * t + 0.0452, -0.0109 * t + 0.0529, 0.0],
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192) * KCD_TO_RADIANCE;
+        let polynomial = |c: [[f32; 4]; 3]| {
+            let theta = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+            let row = |r: [f32; 4]| r.iter().zip(theta).map(|(a, b)| a * b).sum::<f32>();
+            t * t * row(c[0]) + t * row(c[1]) + row(c[2])
+        };
+        let zenith_x = polynomial([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = polynomial([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let mut sky = Self {
+            perez,
+            cos_sun_radius: SUN_RADIUS.cos(),
+            sun_direction: Vec3::new(
+                elevation.cos() * azimuth.sin(),
+                elevation.sin(),
+                -elevation.cos() * azimuth.cos(),
+            ),
+            // 2π(1 - cos r) without the cancellation
+            sun_solid_angle: 4.0 * PI * (0.5 * SUN_RADIUS).sin().powi(2),
+            sun_radiance: SUN_LUMINANCE * Self::sun_transmittance(theta_sun, t),
+            ..Default::default()
+        };
+        let perez_at_zenith = sky.perez(1.0, theta_sun);
+        sky.zenith = Vec3::new(zenith_luminance, zenith_x, zenith_y) / perez_at_zenith;
+
+        // the ground is diffuse and sees the whole sky, for the sky part by a midpoint rule
+        // over the upper hemisphere
+        let (theta_steps, phi_steps) = (16, 32);
+        let d_theta = 0.5 * PI / theta_steps as f32;
+        let d_phi = 2.0 * PI / phi_steps as f32;
+        let mut sky_irradiance = Vec3::ZERO;
+        for i in 0..theta_steps {
+            let theta = (i as f32 + 0.5) * d_theta;
+            for j in 0..phi_steps {
+                let phi = (j as f32 + 0.5) * d_phi;
+                let direction = Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+                sky_irradiance += sky.radiance(direction) * theta.cos() * theta.sin() * d_theta * d_phi;
+            }
+        }
+        let sun_irradiance = sky.sun_radiance * sky.sun_solid_angle * elevation.sin();
+        sky.ground_radiance = ground_albedo.clamp(0.0, 1.0) / PI * (sky_irradiance + sun_irradiance);
+
+        sky
+    }
+
+    fn perez(&self, cos_theta: f32, gamma: f32) -> Vec3 {
+        let coefficient = |i: usize| Vec3::new(self.perez[i][0], self.perez[i][1], self.perez[i][2]);
+        let (a, b, c, d, e) = (coefficient(0), coefficient(1), coefficient(2), coefficient(3), coefficient(4));
+        let cos_gamma = gamma.cos();
+        (Vec3::ONE + a * (b / cos_theta.max(1e-3)).exp())
+            * (Vec3::ONE + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+    }
+
+    // the sky without the sun, in linear sRGB; matches skyModelRadiance in environment.wgsl
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        let gamma = direction.dot(self.sun_direction).clamp(-1.0, 1.0).acos();
+        let yxy = self.zenith * self.perez(direction.y, gamma);
+        Self::yxy_to_rgb(yxy)
+    }
+
+    fn yxy_to_rgb(yxy: Vec3) -> Vec3 {
+        let (luminance, x, y) = (yxy.x, yxy.y, yxy.z);
+        if y <= 0.0 {
+            return Vec3::ZERO;
+        }
+        let xyz = Vec3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        Vec3::new(
+            3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+            -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+            0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+        ).max(Vec3::ZERO)
+    }
+
+    // the fraction of sunlight that makes it through the atmosphere at the red, green and blue
+    // wavelengths, from Rayleigh scattering by the air and scattering by aerosols, which
+    // grows with the turbidity (the appendix of Preetham et al., without ozone and water vapor)
+    fn sun_transmittance(theta_sun: f32, turbidity: f32) -> Vec3 {
+        // relative optical air mass (Kasten and Young), which stays finite at the horizon
+        let zenith_degrees = theta_sun.to_degrees();
+        let air_mass = 1.0 / (theta_sun.cos() + 0.50572 * (96.07995 - zenith_degrees).powf(-1.6364));
+        let beta = 0.04608 * turbidity - 0.04586;
+        let transmittance = |wavelength: f32| {
+            let rayleigh = 0.008735 * wavelength.powf(-4.08);
+            let aerosol = beta * wavelength.powf(-1.3);
+            (-(rayleigh + aerosol) * air_mass).exp()
+        };
+        // wavelengths in micrometers
+        Vec3::new(transmittance(0.680), transmittance(0.550), transmittance(0.440))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the zenith luminance (in kcd/m²) and chromaticities the formulas in the appendix of
+    // Preetham et al. give, worked out by hand
+    #[test]
+    fn zenith_matches_the_published_model() {
+        for (elevation, turbidity, luminance, x, y) in [
+            (90.0, 2.0, 15.5007, 0.26674, 0.27720),
+            (30.0, 3.0, 5.13916, 0.24493, 0.25257),
+        ] {
+            let sky = GPUSky::new(elevation, 0.0, turbidity, 0.3);
+            let theta_sun = (90.0f32 - elevation).to_radians();
+            let zenith = sky.zenith * sky.perez(1.0, theta_sun);
+            assert!((zenith.x - luminance * KCD_TO_RADIANCE).abs() < 1e-3, "{zenith}");
+            assert!((zenith.y - x).abs() < 1e-4, "{zenith}");
+            assert!((zenith.z - y).abs() < 1e-4, "{zenith}");
+        }
+    }
+
+    #[test]
+    fn the_sun_dims_and_reddens_as_it_sinks() {
+        let mut previous = GPUSky::new(90.0, 0.0, 3.0, 0.3).sun_radiance;
+        for elevation in [60.0, 30.0, 10.0, 2.0, 0.0] {
+            let sun_radiance = GPUSky::new(elevation, 0.0, 3.0, 0.3).sun_radiance;
+            assert!(sun_radiance.cmplt(previous).all(), "{sun_radiance} at {elevation}° vs {previous}");
+            assert!(sun_radiance.is_finite() && sun_radiance.cmpgt(Vec3::ZERO).all());
+            previous = sun_radiance;
+        }
+        // the blue is scattered out of the beam first
+        let low = GPUSky::new(5.0, 0.0, 3.0, 0.3).sun_radiance / SUN_LUMINANCE;
+        let high = GPUSky::new(80.0, 0.0, 3.0, 0.3).sun_radiance / SUN_LUMINANCE;
+        assert!(low.z / low.x < high.z / high.x);
+    }
+}
@@ -0,0 +1,50 @@
+use std::path::Path;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GPUTexture {
+    // index of the first texel in the texel buffer
+    offset: u32,
+    width: u32,
+    height: u32,
+    // 1 if the color channels are sRGB encoded, 0 for data like roughness that is linear
+    srgb: u32,
+}
+
+// all the textures of a scene packed one after another into a single texel buffer, one
+// RGBA8 texel per u32, so the kernel can sample them without a texture binding per image
+#[derive(Default)]
+pub struct Textures {
+    textures: Vec<GPUTexture>,
+    texels: Vec<u32>,
+}
+
+impl Textures {
+    pub fn add_rgba8(&mut self, width: u32, height: u32, rgba: &[u8], srgb: bool) -> u32 {
+        assert_eq!(rgba.len(), 4 * (width * height) as usize, "texture data must be RGBA8");
+
+        let texture_id = self.textures.len() as u32;
+        self.textures.push(GPUTexture {
+            offset: self.texels.len() as u32,
+            width,
+            height,
+            srgb: srgb as u32,
+        });
+        self.texels.extend(rgba.chunks_exact(4).map(|texel| u32::from_le_bytes([texel[0], texel[1], texel[2], texel[3]])));
+
+        texture_id
+    }
+
+    pub fn load(&mut self, path: &Path, srgb: bool) -> Result<u32, image::ImageError> {
+        let image = image::open(path)?.to_rgba8();
+        Ok(self.add_rgba8(image.width(), image.height(), image.as_raw(), srgb))
+    }
+
+    pub fn textures(&self) -> &[GPUTexture] {
+        &self.textures
+    }
+
+    pub fn texels(&self) -> &[u32] {
+        &self.texels
+    }
+}
@@ -1,3 +1,6 @@
+// debugging helpers; only called when the raw profiler output is wanted on the console
+#![allow(dead_code)]
+
 use wgpu_profiler::GpuTimerQueryResult;
 
 fn scopes_to_console_recursive(results: &[GpuTimerQueryResult], indentation: u32) {
@@ -1,19 +1,24 @@
 use std::sync::Arc;
 use imgui::Context;
-use wgpu_profiler::{GpuProfiler, GpuProfilerSettings};
+use wgpu_profiler::GpuProfiler;
 use winit::window::Window;
 use crate::gui::GUI;
 
-pub struct WGPUState<'a> {
+// the window and what is needed to present to it; a headless state has none of this
+struct SurfaceState<'a> {
     window: Arc<winit::window::Window>,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
-    size: winit::dpi::PhysicalSize<u32>,
     surface: wgpu::Surface<'a>,
     surface_format: wgpu::TextureFormat,
     surface_config: wgpu::SurfaceConfiguration,
 }
 
+pub struct WGPUState<'a> {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    size: winit::dpi::PhysicalSize<u32>,
+    surface_state: Option<SurfaceState<'a>>,
+}
+
 impl<'a> WGPUState<'a> {
     pub async fn new(window: Arc<winit::window::Window>) -> WGPUState<'a> {
         let size = window.inner_size();
@@ -33,21 +38,7 @@ impl<'a> WGPUState<'a> {
             },
         ).await.unwrap();
 
-        // Check timestamp features.
-        let features = adapter.features()
-            & GpuProfiler::ALL_WGPU_TIMER_FEATURES;
-
-        let (device, queue) = adapter
-            .request_device(
-            &wgpu::DeviceDescriptor {
-                required_features: features,
-                required_limits: wgpu::Limits::default(),
-                label: Some("device"),
-                memory_hints: wgpu::MemoryHints::Performance,
-                trace: wgpu::Trace::Off,
-            })
-            .await
-            .unwrap();
+        let (device, queue) = Self::request_device(&adapter).await;
 
         let surface_caps = surface.get_capabilities(&adapter);
 
@@ -64,17 +55,20 @@ impl<'a> WGPUState<'a> {
             present_mode: wgpu::PresentMode::AutoVsync,
             desired_maximum_frame_latency: 2,
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
-            view_formats: vec![surface_format.add_srgb_suffix()],
+            // the display shader does its own sRGB encoding, the gui relies on the view doing it
+            view_formats: vec![surface_format.add_srgb_suffix(), surface_format.remove_srgb_suffix()],
         };
 
         let mut wgpu_state = WGPUState {
-            window,
             device,
             queue,
             size,
-            surface,
-            surface_format,
-            surface_config,
+            surface_state: Some(SurfaceState {
+                window,
+                surface,
+                surface_format,
+                surface_config,
+            }),
         };
 
         wgpu_state.configure_surface();
@@ -82,6 +76,74 @@ impl<'a> WGPUState<'a> {
         wgpu_state
     }
 
+    // a device without a window or surface, for rendering straight to a file. With
+    // force_fallback_adapter the software adapter (e.g. lavapipe or WARP) is picked, which
+    // is also what gets used when there is no gpu at all
+    pub async fn new_headless(width: u32, height: u32, force_fallback_adapter: bool) -> Option<WGPUState<'a>> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::PRIMARY,
+            ..Default::default()
+        });
+
+        let mut adapter = instance.request_adapter(
+            &wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: None,
+                force_fallback_adapter,
+            },
+        ).await;
+        if adapter.is_err() && !force_fallback_adapter {
+            adapter = instance.request_adapter(
+                &wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::HighPerformance,
+                    compatible_surface: None,
+                    force_fallback_adapter: true,
+                },
+            ).await;
+        }
+        let adapter = adapter.ok()?;
+        println!("Using adapter {:?}", adapter.get_info().name);
+
+        let (device, queue) = Self::request_device(&adapter).await;
+
+        Some(WGPUState {
+            device,
+            queue,
+            size: winit::dpi::PhysicalSize::new(width, height),
+            surface_state: None,
+        })
+    }
+
+    async fn request_device(adapter: &wgpu::Adapter) -> (wgpu::Device, wgpu::Queue) {
+        // Check timestamp features.
+        let features = adapter.features()
+            & GpuProfiler::ALL_WGPU_TIMER_FEATURES;
+
+        // large models need storage buffers well beyond the 128MB default binding size,
+        // and the wavefront kernels bind more storage buffers than the default of 8.
+        // environment maps can be larger than the default texture size of 8192 as well
+        let adapter_limits = adapter.limits();
+        let limits = wgpu::Limits {
+            max_storage_buffer_binding_size: adapter_limits.max_storage_buffer_binding_size,
+            max_buffer_size: adapter_limits.max_buffer_size,
+            max_storage_buffers_per_shader_stage: adapter_limits.max_storage_buffers_per_shader_stage,
+            max_texture_dimension_2d: adapter_limits.max_texture_dimension_2d,
+            ..Default::default()
+        };
+
+        adapter
+            .request_device(
+            &wgpu::DeviceDescriptor {
+                required_features: features,
+                required_limits: limits,
+                label: Some("device"),
+                memory_hints: wgpu::MemoryHints::Performance,
+                trace: wgpu::Trace::Off,
+            })
+            .await
+            .unwrap()
+    }
+
     pub fn device(&self) -> &wgpu::Device {
         &self.device
     }
@@ -90,20 +152,63 @@ impl<'a> WGPUState<'a> {
         &self.queue
     }
 
+    fn surface_state(&self) -> &SurfaceState<'a> {
+        self.surface_state.as_ref().expect("a headless WGPUState has no window")
+    }
+
     pub fn surface_config(&self) -> &wgpu::SurfaceConfiguration {
-        &self.surface_config
+        &self.surface_state().surface_config
+    }
+
+    // the format the display pipeline renders in, which leaves the sRGB encoding to it
+    pub fn display_format(&self) -> wgpu::TextureFormat {
+        match &self.surface_state {
+            Some(surface_state) => surface_state.surface_format.remove_srgb_suffix(),
+            None => wgpu::TextureFormat::Bgra8Unorm,
+        }
     }
 
     pub fn get_window(&self) -> Arc<Window> {
-        self.window.clone()
+        self.surface_state().window.clone()
+    }
+
+    // the size of the image being rendered: the window's inner size, or the size given to
+    // new_headless
+    pub fn size(&self) -> winit::dpi::PhysicalSize<u32> {
+        self.size
+    }
+
+    // the largest image the window can ever show, so that buffers sized for it survive
+    // any resize
+    pub fn max_image_size(&self) -> u32 {
+        match &self.surface_state {
+            Some(surface_state) => surface_state.window
+                .available_monitors()
+                .map(|monitor| -> u32 {
+                    let viewport = monitor.size();
+                    viewport.width * viewport.height
+                })
+                .max()
+                .expect("must have at least one monitor")
+                .max(self.size.width * self.size.height),
+            None => self.size.width * self.size.height,
+        }
     }
 
     fn configure_surface(&mut self) {
-        self.surface_config.format = self.surface_format;
-        self.surface.configure(&self.device, &self.surface_config);
+        let device = &self.device;
+        let surface_state = self.surface_state.as_mut().expect("a headless WGPUState has no window");
+        surface_state.surface_config.width = self.size.width;
+        surface_state.surface_config.height = self.size.height;
+        surface_state.surface_config.format = surface_state.surface_format;
+        surface_state.surface.configure(device, &surface_state.surface_config);
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        // a minimized window has a zero size, which a surface can't be configured with
+        if new_size.width == 0 || new_size.height == 0 {
+            return;
+        }
         self.size = new_size;
         self.configure_surface();
     }
@@ -112,7 +217,8 @@ impl<'a> WGPUState<'a> {
                   display_pipeline: &wgpu::RenderPipeline, 
                   display_bind_group: &wgpu::BindGroup) {
         
-        let surface_texture = self
+        let surface_state = self.surface_state();
+        let surface_texture = surface_state
             .surface
             .get_current_texture()
             .expect("Failed to acquire next surface texture");
@@ -120,7 +226,14 @@ impl<'a> WGPUState<'a> {
         let texture_view = surface_texture
             .texture
             .create_view(&wgpu::TextureViewDescriptor {
-                format: Some(self.surface_format.add_srgb_suffix()),
+                format: Some(surface_state.surface_format.add_srgb_suffix()),
+                ..Default::default()
+            });
+
+        let display_view = surface_texture
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor {
+                format: Some(self.display_format()),
                 ..Default::default()
             });
 
@@ -128,7 +241,7 @@ impl<'a> WGPUState<'a> {
         let mut display_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor{
             label: None,
             color_attachments: &[Some(wgpu::RenderPassColorAttachment{
-                view: &texture_view,
+                view: &display_view,
                 resolve_target: None,
                 ops: wgpu::Operations {
                     load: wgpu::LoadOp::Clear(wgpu::Color::GREEN),
@@ -166,7 +279,7 @@ impl<'a> WGPUState<'a> {
         drop(pass);
 
         self.queue.submit([encoder.finish()]);
-        self.window.pre_present_notify();
+        surface_state.window.pre_present_notify();
         surface_texture.present();
     }
 }
\ No newline at end of file